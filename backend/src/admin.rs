@@ -0,0 +1,227 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{hub::publish_frames, protocol::WhiteboardEvent, store::Retention, webhook::Webhook, AppState};
+
+// HTTP counterparts of the owner-only room commands.
+// Every route needs `Authorization: Bearer <WB_ADMIN_TOKEN>` and is disabled without a token.
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/rooms/:room", get(room_state))
+        .route("/rooms/:room/clear", post(clear))
+        .route("/rooms/:room/lock", post(lock))
+        .route("/rooms/:room/owner", post(transfer_ownership))
+        .route("/rooms/:room/retention", post(retention))
+        .route(
+            "/rooms/:room/webhooks",
+            get(list_webhooks).post(add_webhook).delete(remove_webhook),
+        )
+        .route("/rooms/:room/connections/:conn/kick", post(kick))
+        .route("/rooms/:room/connections/:conn/mute", post(mute))
+}
+
+#[derive(Deserialize)]
+struct LockBody {
+    locked: bool,
+}
+
+#[derive(Deserialize)]
+struct MuteBody {
+    muted: bool,
+}
+
+#[derive(Deserialize)]
+struct OwnerBody {
+    user: String,
+}
+
+#[derive(Serialize)]
+struct OwnerTokenBody {
+    owner_token: String,
+}
+
+#[derive(Deserialize)]
+struct RetentionBody {
+    retention: Option<Retention>,
+}
+
+#[derive(Deserialize)]
+struct WebhookQuery {
+    url: String,
+}
+
+async fn room_state(State(state): State<AppState>, headers: HeaderMap, Path(room): Path<String>) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    match state.existing_room(&room, |_, room| room.state_frame()) {
+        Ok(frame) => Json(frame.event).into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn clear(State(state): State<AppState>, headers: HeaderMap, Path(room): Path<String>) -> Response {
+    administer(&state, &headers, &room, WhiteboardEvent::ClearBoard)
+}
+
+async fn lock(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+    Json(body): Json<LockBody>,
+) -> Response {
+    administer(&state, &headers, &room, WhiteboardEvent::LockRoom { locked: body.locked })
+}
+
+async fn transfer_ownership(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+    Json(body): Json<OwnerBody>,
+) -> Response {
+    let owner_token = state.rooms.owner_token(&room, &body.user);
+    let response = administer(&state, &headers, &room, WhiteboardEvent::TransferOwnership { to: body.user });
+    if response.status() != StatusCode::NO_CONTENT {
+        return response;
+    }
+    // For the new owner to connect with, as they may not be connected to be given it
+    Json(OwnerTokenBody { owner_token }).into_response()
+}
+
+// Not a room command, so nothing is broadcast
+async fn retention(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+    Json(body): Json<RetentionBody>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers).and_then(|()| check_name(&room)) {
+        return rejection.into_response();
+    }
+    info!(room, retention = ?body.retention, "retention changed over HTTP");
+    state.rooms.with(&room, |_, room| room.set_retention(body.retention));
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// Secrets are write-only, so only the URLs are listed
+async fn list_webhooks(State(state): State<AppState>, headers: HeaderMap, Path(room): Path<String>) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    match state.existing_room(&room, |_, room| room.webhook_urls()) {
+        Ok(urls) => Json(urls).into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn add_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+    Json(hook): Json<Webhook>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers).and_then(|()| check_name(&room)) {
+        return rejection.into_response();
+    }
+    let valid_url = reqwest::Url::parse(&hook.url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+    if !valid_url {
+        return (StatusCode::BAD_REQUEST, "webhook url must be an http or https URL").into_response();
+    }
+    if hook.secret.is_empty() {
+        return (StatusCode::BAD_REQUEST, "webhook needs a secret to sign deliveries with").into_response();
+    }
+    info!(room, url = %hook.url, "webhook added over HTTP");
+    state.rooms.with(&room, |_, room| room.add_webhook(hook));
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn remove_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+    Query(query): Query<WebhookQuery>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    match state.existing_room(&room, |_, room| room.remove_webhook(&query.url)) {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::NOT_FOUND, "no such webhook").into_response(),
+        Err(rejection) => return rejection.into_response(),
+    }
+    info!(room, url = %query.url, "webhook removed over HTTP");
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn kick(State(state): State<AppState>, headers: HeaderMap, Path((room, conn)): Path<(String, u64)>) -> Response {
+    administer(&state, &headers, &room, WhiteboardEvent::Kick { conn })
+}
+
+async fn mute(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((room, conn)): Path<(String, u64)>,
+    Json(body): Json<MuteBody>,
+) -> Response {
+    administer(&state, &headers, &room, WhiteboardEvent::Mute { conn, muted: body.muted })
+}
+
+fn administer(state: &AppState, headers: &HeaderMap, room: &str, command: WhiteboardEvent) -> Response {
+    if let Err(rejection) = authorize(state, headers).and_then(|()| check_name(room)) {
+        return rejection.into_response();
+    }
+
+    // Commands naming a connection fail when it is not there; others only when they are invalid
+    let names_connection = matches!(command, WhiteboardEvent::Kick { .. } | WhiteboardEvent::Mute { .. });
+    info!(room, ?command, "admin command over HTTP");
+    state.rooms.with(room, |_, room_state| match room_state.administer(None, command) {
+        Ok(frames) => {
+            publish_frames(&*state.hub, room, frames, room_state.take_update());
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(reason) if names_connection => (StatusCode::NOT_FOUND, reason).into_response(),
+        Err(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+    })
+}
+
+pub fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    let Some(token) = &state.admin_token else {
+        return Err((StatusCode::NOT_FOUND, "admin API disabled"));
+    };
+    if has_admin_token(token, headers) {
+        Ok(())
+    } else {
+        warn!("rejected admin request with a bad token");
+        Err((StatusCode::UNAUTHORIZED, "bad admin token"))
+    }
+}
+
+// Commands and settings may make a room, so its name has to be one a client could join
+fn check_name(room: &str) -> Result<(), (StatusCode, &'static str)> {
+    if room.is_empty() || room.len() > 64 {
+        return Err((StatusCode::BAD_REQUEST, "room names must be 1 to 64 bytes"));
+    }
+    Ok(())
+}
+
+// Whether the request comes with `Authorization: Bearer <token>`
+pub(crate) fn has_admin_token(token: &str, headers: &HeaderMap) -> bool {
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    constant_time_eq(presented.as_bytes(), token.as_bytes())
+}
+
+// Compare without leaking how many leading bytes matched
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,207 @@
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use ring::digest;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{protocol::WhiteboardEvent, store, AppState};
+
+// Images uploaded for image elements, stored on local disk under the hex SHA-256 of
+// their content, so uploading the same image twice stores it once.
+// Elements refer to them by that hash.
+#[derive(Clone, Debug)]
+pub struct Assets {
+    dir: PathBuf,
+}
+
+// Image formats accepted, told apart by their content rather than what the uploader claims
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+impl Kind {
+    pub fn sniff(data: &[u8]) -> Option<Kind> {
+        match data {
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some(Kind::Png),
+            [0xff, 0xd8, 0xff, ..] => Some(Kind::Jpeg),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Kind::Gif),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(Kind::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Kind::Png => "image/png",
+            Kind::Jpeg => "image/jpeg",
+            Kind::Gif => "image/gif",
+            Kind::Webp => "image/webp",
+        }
+    }
+}
+
+// What an upload was stored as
+#[derive(Serialize, Debug)]
+pub struct Stored {
+    pub asset: String,
+    #[serde(rename = "type")]
+    pub mime: &'static str,
+    pub size: usize,
+    // False when the same content was already stored
+    #[serde(skip)]
+    pub created: bool,
+}
+
+// Stored images by asset id, with their format, for drawing image elements in exports
+pub type Images = HashMap<String, (Kind, Vec<u8>)>;
+
+// An asset id is the lowercase hex SHA-256 of the content
+pub fn is_asset_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+impl Assets {
+    pub fn new(dir: PathBuf) -> Self {
+        Assets { dir }
+    }
+
+    // Store an image. Fails with `InvalidData` for anything that is not a supported image.
+    pub fn put(&self, data: &[u8]) -> io::Result<Stored> {
+        let kind = Kind::sniff(data).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "only PNG, JPEG, GIF and WebP images are accepted")
+        })?;
+        let asset = store::hex(digest::digest(&digest::SHA256, data).as_ref());
+        let path = self.dir.join(&asset);
+        let created = !path.exists();
+        if created {
+            fs::create_dir_all(&self.dir)?;
+            // A concurrent upload of the same image may have got there first, which is just as good
+            if let Err(e) = store::write_atomic(&path, data) {
+                if !path.exists() {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(Stored {
+            asset,
+            mime: kind.mime(),
+            size: data.len(),
+            created,
+        })
+    }
+
+    // The stored image named `id` and its format, if there is one
+    pub fn get(&self, id: &str) -> io::Result<Option<(Kind, Vec<u8>)>> {
+        if !is_asset_id(id) {
+            return Ok(None);
+        }
+        match fs::read(self.path(id)) {
+            Ok(data) => Ok(Kind::sniff(&data).map(|kind| (kind, data))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The stored images `elements` show; ones that cannot be read are left out
+    pub fn images(&self, elements: &[WhiteboardEvent]) -> Images {
+        let mut images = Images::new();
+        for element in elements {
+            let WhiteboardEvent::AddImage { asset, .. } = element else { continue };
+            if images.contains_key(asset) {
+                continue;
+            }
+            match self.get(asset) {
+                Ok(Some(image)) => {
+                    images.insert(asset.clone(), image);
+                }
+                Ok(None) => {}
+                Err(e) => warn!(asset, error = %e, "failed to read image for export"),
+            }
+        }
+        images
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+}
+
+// Uploads are open to anyone who can draw, like the board itself; bodies over
+// `max_bytes` are refused with 413 before they are read in full
+pub fn routes(max_bytes: usize) -> Router<AppState> {
+    Router::new()
+        .route("/assets", post(upload).layer(DefaultBodyLimit::max(max_bytes)))
+        .route("/assets/:asset", get(download))
+}
+
+async fn upload(State(state): State<AppState>, body: Bytes) -> Response {
+    let Some(assets) = &state.assets else {
+        return (StatusCode::NOT_FOUND, "asset storage is not configured").into_response();
+    };
+    // Hashing and writing a big image takes a while; keep it off the async workers
+    let stored = {
+        let assets = assets.clone();
+        tokio::task::spawn_blocking(move || assets.put(&body))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)))
+    };
+    match stored {
+        Ok(stored) => {
+            let status = if stored.created {
+                info!(asset = %stored.asset, size = stored.size, mime = stored.mime, "stored asset");
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            };
+            (status, Json(stored)).into_response()
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()).into_response()
+        }
+        Err(e) => {
+            error!(error = %e, "failed to store asset");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to store asset").into_response()
+        }
+    }
+}
+
+async fn download(State(state): State<AppState>, Path(asset): Path<String>) -> Response {
+    let Some(assets) = &state.assets else {
+        return (StatusCode::NOT_FOUND, "asset storage is not configured").into_response();
+    };
+    let found = {
+        let (assets, asset) = (assets.clone(), asset.clone());
+        tokio::task::spawn_blocking(move || assets.get(&asset))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)))
+    };
+    match found {
+        // The id is the content's hash, so it can be cached for good
+        Ok(Some((kind, data))) => (
+            [
+                (header::CONTENT_TYPE, kind.mime()),
+                (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            ],
+            data,
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "no such asset").into_response(),
+        Err(e) => {
+            error!(asset, error = %e, "failed to read asset");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to read asset").into_response()
+        }
+    }
+}
@@ -0,0 +1,380 @@
+// Load generator for capacity planning. Opens simulated clients spread across rooms on a
+// running backend, has each of them draw freehand strokes and shapes at steady rates, and
+// reports end-to-end latency, throughput and the messages that never arrived.
+//
+//     cargo run --release --bin wb-load -- --clients 200 --rooms 20 --secs 60
+
+use std::{
+    collections::HashMap,
+    process::exit,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use backend::protocol::{Frame, WhiteboardEvent};
+use futures_util::{future::join_all, SinkExt, StreamExt};
+use tokio::time::{interval_at, sleep_until, timeout_at, MissedTickBehavior};
+use tokio_tungstenite::{
+    connect_async_with_config,
+    tungstenite::{protocol::frame::coding::CloseCode, Message},
+};
+
+const USAGE: &str = "\
+usage: wb-load [options]
+
+  --url URL        backend to load (default ws://127.0.0.1:3000)
+  --clients N      simulated clients (default 20)
+  --rooms M        rooms the clients are spread across (default 4)
+  --secs S         how long to draw for (default 30)
+  --points RATE    freehand points each client sends per second (default 30)
+  --stroke N       points per freehand stroke (default 40)
+  --shapes RATE    lines, rectangles, circles and text each client adds per second (default 0.5)
+  --drain S        how long to wait for the last messages after drawing stops (default 3)
+  --prefix NAME    rooms are named NAME-0, NAME-1, ... (default load-<unix time>)";
+
+// Close code the backend uses for clients that fell too far behind
+const CLOSE_SLOW: u16 = 4002;
+
+const COLORS: [&str; 5] = ["#1e293b", "#e11d48", "#2563eb", "#16a34a", "#d97706"];
+
+struct Options {
+    url: String,
+    clients: usize,
+    rooms: usize,
+    secs: f64,
+    points: f64,
+    stroke: usize,
+    shapes: f64,
+    drain: f64,
+    prefix: String,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut options = Options {
+            url: "ws://127.0.0.1:3000".to_string(),
+            clients: 20,
+            rooms: 4,
+            secs: 30.0,
+            points: 30.0,
+            stroke: 40,
+            shapes: 0.5,
+            drain: 3.0,
+            prefix: format!("load-{}", now),
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(flag) = args.next() {
+            if flag == "-h" || flag == "--help" {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--url" => options.url = value.trim_end_matches('/').to_string(),
+                "--clients" => options.clients = number(&flag, &value)?,
+                "--rooms" => options.rooms = number(&flag, &value)?,
+                "--secs" => options.secs = number(&flag, &value)?,
+                "--points" => options.points = number(&flag, &value)?,
+                "--stroke" => options.stroke = number(&flag, &value)?,
+                "--shapes" => options.shapes = number(&flag, &value)?,
+                "--drain" => options.drain = number(&flag, &value)?,
+                "--prefix" => options.prefix = value,
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        if options.clients == 0 || options.rooms == 0 || options.stroke == 0 {
+            return Err("--clients, --rooms and --stroke must be at least 1".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// A non-negative number given for `flag`
+fn number<T: std::str::FromStr + PartialOrd + Default>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .ok()
+        .filter(|parsed| *parsed >= T::default())
+        .ok_or_else(|| format!("invalid {} {:?}: expected a non-negative number", flag, value))
+}
+
+// Events sent and not yet seen by every client in their room, by what identifies them on the way back
+type Pending = Arc<Mutex<HashMap<String, Sent>>>;
+
+struct Sent {
+    at: Instant,
+    // Clients in the room, the sender included, that have yet to receive it
+    awaiting: usize,
+}
+
+// How one client's run went
+#[derive(Default)]
+struct Stats {
+    sent: u64,
+    received: u64,
+    // End-to-end latency of every board change received, in microseconds
+    latencies: Vec<u64>,
+    acked: u64,
+    rejected: u64,
+    // Board changes the client never saw, going by the room's seqs
+    skipped: u64,
+    disconnected_slow: u64,
+    disconnected: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.sent += other.sent;
+        self.received += other.received;
+        self.latencies.extend(other.latencies);
+        self.acked += other.acked;
+        self.rejected += other.rejected;
+        self.skipped += other.skipped;
+        self.disconnected_slow += other.disconnected_slow;
+        self.disconnected += other.disconnected;
+    }
+}
+
+// What identifies a board change when it comes back: freehand points by their stroke and
+// position, anything else by its element id. Positions are rounded, as parsing them back
+// need not give exactly the number sent.
+fn key(frame: &Frame) -> Option<String> {
+    let id = frame.id.as_deref()?;
+    match &frame.event {
+        WhiteboardEvent::DrawFreehand { x, y, .. } => Some(format!("{}:{:.3}:{:.3}", id, x, y)),
+        event if event.is_element() => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+// A random shape somewhere on the board
+fn shape() -> WhiteboardEvent {
+    let point = || (fastrand::f64() * 1600.0, fastrand::f64() * 900.0);
+    let color = COLORS[fastrand::usize(..COLORS.len())].to_string();
+    let width = 1.0 + fastrand::f64() * 6.0;
+    match fastrand::u8(..4) {
+        0 => WhiteboardEvent::DrawLine { from: point(), to: point(), color, width },
+        1 => WhiteboardEvent::DrawRect { from: point(), to: point(), color, width },
+        2 => WhiteboardEvent::DrawCircle { center: point(), radius: 10.0 + fastrand::f64() * 150.0, color, width },
+        _ => WhiteboardEvent::AddText { pos: point(), text: "load test".to_string(), color, size: 16.0 + fastrand::f64() * 24.0 },
+    }
+}
+
+// Ticks `rate` times a second, starting at a random point of the first period so clients
+// do not all send at once; never ticks for a rate of 0
+fn ticker(rate: f64) -> Option<tokio::time::Interval> {
+    if rate <= 0.0 {
+        return None;
+    }
+    let period = Duration::from_secs_f64(1.0 / rate);
+    let mut ticker = interval_at(tokio::time::Instant::now() + period.mul_f64(fastrand::f64()), period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    Some(ticker)
+}
+
+async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Draw until `stop`, then keep receiving until `end`
+async fn run_client(
+    client: usize,
+    options: Arc<Options>,
+    socket: tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    room_size: usize,
+    pending: Pending,
+    stop: tokio::time::Instant,
+    end: tokio::time::Instant,
+) -> Stats {
+    let (mut writer, mut reader) = socket.split();
+
+    let sender = {
+        let pending = pending.clone();
+        let options = options.clone();
+        async move {
+            let mut sent = 0;
+            let mut points = ticker(options.points);
+            let mut shapes = ticker(options.shapes);
+            // Where this client's current stroke has got to
+            let (mut stroke, mut point) = (0, 0);
+            let (mut x, mut y) = (0.0, 0.0);
+            loop {
+                let (id, event) = tokio::select! {
+                    _ = sleep_until(stop) => break,
+                    _ = tick(&mut points) => {
+                        if point == options.stroke {
+                            (stroke, point) = (stroke + 1, 0);
+                        }
+                        if point == 0 {
+                            (x, y) = (fastrand::f64() * 1400.0, fastrand::f64() * 800.0);
+                        }
+                        // Strokes always move right, so no two of their points are the same
+                        x += 1.0 + fastrand::f64() * 4.0;
+                        y += fastrand::f64() * 6.0 - 3.0;
+                        point += 1;
+                        let event = WhiteboardEvent::DrawFreehand { x, y, dragging: point > 1 };
+                        (format!("c{}-stroke{}", client, stroke), event)
+                    }
+                    _ = tick(&mut shapes) => (format!("c{}-shape{}", client, sent), shape()),
+                };
+                let frame = Frame {
+                    id: Some(id),
+                    reference: Some(sent.to_string()),
+                    ..Frame::server(event)
+                };
+                let key = key(&frame).unwrap();
+                pending.lock().unwrap().insert(key, Sent { at: Instant::now(), awaiting: room_size });
+                if writer.send(Message::Text(serde_json::to_string(&frame).unwrap())).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+            sent
+        }
+    };
+
+    let receiver = async move {
+        let mut stats = Stats::default();
+        // Seq of the last board change seen, once the welcome has said where the room was
+        let mut last_seq = None;
+        loop {
+            let message = match timeout_at(end, reader.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(_))) | Ok(None) => {
+                    stats.disconnected += 1;
+                    break;
+                }
+                Err(_) => break,
+            };
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Close(close) => {
+                    match close.map(|close| close.code) {
+                        Some(CloseCode::Library(CLOSE_SLOW)) => stats.disconnected_slow += 1,
+                        _ => stats.disconnected += 1,
+                    }
+                    break;
+                }
+                _ => continue,
+            };
+            let Ok(frame) = serde_json::from_str::<Frame>(&text) else { continue };
+            match &frame.event {
+                WhiteboardEvent::Welcome { .. } => last_seq = frame.seq,
+                WhiteboardEvent::Ack => stats.acked += 1,
+                WhiteboardEvent::Reject { .. } => stats.rejected += 1,
+                _ => {}
+            }
+            let (Some(seq), Some(last)) = (frame.seq, last_seq) else { continue };
+            // Snapshot frames carry the seqs they were made at, before the welcome's
+            if frame.reference.is_some() || !frame.event.changes_board() || seq <= last {
+                continue;
+            }
+            stats.skipped += seq - last - 1;
+            last_seq = Some(seq);
+            stats.received += 1;
+            let Some(key) = key(&frame) else { continue };
+            let mut pending = pending.lock().unwrap();
+            if let Some(sent) = pending.get_mut(&key) {
+                stats.latencies.push(sent.at.elapsed().as_micros() as u64);
+                sent.awaiting -= 1;
+                if sent.awaiting == 0 {
+                    pending.remove(&key);
+                }
+            }
+        }
+        stats
+    };
+
+    let (sent, mut stats) = tokio::join!(sender, receiver);
+    stats.sent = sent;
+    stats
+}
+
+fn percentile(sorted: &[u64], p: f64) -> String {
+    if sorted.is_empty() {
+        return "-".to_string();
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    format!("{:.1}ms", sorted[index] as f64 / 1000.0)
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::parse() {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            exit(2);
+        }
+    };
+    println!(
+        "wb-load: {} clients in {} rooms for {}s against {}",
+        options.clients, options.rooms, options.secs, options.url
+    );
+
+    let connecting = (0..options.clients).map(|client| {
+        let url = format!("{}/ws?room={}-{}&user=load-{}", options.url, options.prefix, client % options.rooms, client);
+        // Nagle's algorithm would hold small events back and show up as latency
+        async move { connect_async_with_config(url, None, true).await.map(|(socket, _)| socket) }
+    });
+    let started = Instant::now();
+    let mut sockets = Vec::new();
+    for (client, socket) in join_all(connecting).await.into_iter().enumerate() {
+        match socket {
+            Ok(socket) => sockets.push((client, socket)),
+            Err(e) => {
+                eprintln!("client {} failed to connect: {}", client, e);
+                exit(1);
+            }
+        }
+    }
+    println!("connected in {:.1}s", started.elapsed().as_secs_f64());
+
+    let room_size = |room: usize| (0..options.clients).filter(|client| client % options.rooms == room).count();
+    let pending: Pending = Arc::default();
+    let stop = tokio::time::Instant::now() + Duration::from_secs_f64(options.secs);
+    let end = stop + Duration::from_secs_f64(options.drain);
+    let clients = sockets.into_iter().map(|(client, socket)| {
+        let size = room_size(client % options.rooms);
+        tokio::spawn(run_client(client, options.clone(), socket, size, pending.clone(), stop, end))
+    });
+    let mut total = Stats::default();
+    for stats in join_all(clients).await {
+        total.merge(stats.unwrap());
+    }
+
+    let missing: usize = pending.lock().unwrap().values().map(|sent| sent.awaiting).sum();
+    let expected = total.received as usize + missing;
+    total.latencies.sort_unstable();
+    let latencies = &total.latencies;
+    println!("sent        {} events ({:.0}/s)", total.sent, total.sent as f64 / options.secs);
+    println!(
+        "received    {} board changes ({:.0}/s), {} acks",
+        total.received,
+        total.received as f64 / (options.secs + options.drain),
+        total.acked
+    );
+    println!(
+        "latency     p50 {}  p90 {}  p99 {}  p99.9 {}  max {}",
+        percentile(latencies, 0.5),
+        percentile(latencies, 0.9),
+        percentile(latencies, 0.99),
+        percentile(latencies, 0.999),
+        percentile(latencies, 1.0)
+    );
+    println!(
+        "dropped     {} of {} deliveries never arrived, {} skipped seqs",
+        missing, expected, total.skipped
+    );
+    println!("rejected    {} events", total.rejected);
+    println!(
+        "disconnects {} for falling behind, {} otherwise",
+        total.disconnected_slow, total.disconnected
+    );
+}
@@ -0,0 +1,667 @@
+// Command-line client for scripting boards from shell scripts and CI.
+//
+//     wb tail retro > events.jsonl
+//     wb post retro events.jsonl
+//     wb dump retro --output retro.png
+//     wb copy retro retro-backup
+//     wb play retro-1760859350000.wbrec retro-again
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    process::exit,
+    time::Duration,
+};
+
+use backend::{
+    protocol::{BoardSnapshot, Frame, WhiteboardEvent},
+    recording::{self, Recording},
+};
+use futures_util::{stream::SplitSink, stream, SinkExt, Stream, StreamExt};
+use reqwest::Url;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+    time::{sleep_until, timeout, Instant},
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header::AUTHORIZATION, HeaderValue},
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+const USAGE: &str = "\
+usage: wb [--url URL] [--user ID] [--owner-token TOKEN] [--token TOKEN] COMMAND
+
+commands:
+  tail ROOM [--live]        print the room's frames as JSON lines as they arrive;
+                            --live leaves out the board and history sent on joining
+  post ROOM [FILE]          send events, one JSON frame per line, from FILE or stdin,
+                            and wait for the server to accept each; server messages
+                            (e.g. from `wb tail`) are skipped
+  dump ROOM [--format json|svg|png] [--width PX] [--output FILE]
+                            write the room's board to FILE or stdout; the format
+                            follows FILE's extension unless given
+  clear ROOM                clear the room's board
+  copy FROM TO [--clear]    draw FROM's board into TO, clearing TO first with --clear
+  play RECORDING ROOM [--speed X] [--clear]
+                            play a session recording back into ROOM, each user's
+                            events from a connection of their own, X times as fast
+                            as recorded (0 for no pauses)
+  render RECORDING [--format json|svg|png] [--width PX] [--output FILE]
+                            write the board a recording ends with, without a server
+
+options:
+  --url URL                 backend to use (default $WB_URL or http://127.0.0.1:3000)
+  --user ID                 user id to join rooms as (default wb)
+  --owner-token TOKEN       token the room's owner was given, to act as them
+                            (default $WB_OWNER_TOKEN)
+  --token TOKEN             admin token, so `clear` and `play` work without owning
+                            the room (default $WB_ADMIN_TOKEN)";
+
+// How long to wait for the server to answer events already sent
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+struct Client {
+    http: reqwest::Client,
+    base: Url,
+    user: String,
+    // Proves `user` owns the rooms it is used with
+    owner_token: Option<String>,
+    token: Option<String>,
+}
+
+impl Client {
+    // `segments` as a path under the backend's URL, each one escaped
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut().unwrap().pop_if_empty().extend(segments);
+        url
+    }
+
+    async fn join(&self, room: &str) -> Result<Socket, String> {
+        self.join_as(room, &self.user).await
+    }
+
+    async fn join_as(&self, room: &str, user: &str) -> Result<Socket, String> {
+        let mut url = self.url(&["ws"]);
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme).unwrap();
+        url.query_pairs_mut().append_pair("room", room).append_pair("user", user);
+        if let Some(token) = self.owner_token.as_ref().filter(|_| user == self.user) {
+            url.query_pairs_mut().append_pair("owner_token", token);
+        }
+        let mut request = url.as_str().into_client_request().map_err(|e| format!("failed to join {:?}: {}", room, e))?;
+        // The admin token makes the connection an owner of the room, whoever owns it
+        if let Some(token) = &self.token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| "invalid admin token".to_string())?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        let (socket, _) = connect_async(request)
+            .await
+            .map_err(|e| format!("failed to join {:?}: {}", room, e))?;
+        Ok(socket)
+    }
+
+    async fn get(&self, url: Url) -> Result<Vec<u8>, String> {
+        let response = self.http.get(url.clone()).send().await.map_err(|e| format!("{}: {}", url, e))?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(|e| format!("{}: {}", url, e))?;
+        if !status.is_success() {
+            return Err(format!("{}: {} {}", url, status, String::from_utf8_lossy(&body).trim()));
+        }
+        Ok(body.to_vec())
+    }
+
+    async fn board(&self, room: &str) -> Result<BoardSnapshot, String> {
+        let body = self.get(self.url(&["rooms", room, "board.json"])).await?;
+        serde_json::from_slice(&body).map_err(|e| format!("unexpected board from the server: {}", e))
+    }
+}
+
+// Command-line arguments that are not options, and the options' values
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, Option<String>>,
+}
+
+// Options that take no value
+const FLAGS: [&str; 2] = ["--live", "--clear"];
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            positional: Vec::new(),
+            options: HashMap::new(),
+        };
+        let mut given = std::env::args().skip(1);
+        while let Some(arg) = given.next() {
+            if arg == "-h" || arg == "--help" {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            if !arg.starts_with("--") {
+                args.positional.push(arg);
+            } else if FLAGS.contains(&arg.as_str()) {
+                args.options.insert(arg, None);
+            } else {
+                let value = given.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                args.options.insert(arg, Some(value));
+            }
+        }
+        Ok(args)
+    }
+
+    fn value(&mut self, option: &str) -> Option<String> {
+        self.options.remove(option).flatten()
+    }
+
+    fn flag(&mut self, option: &str) -> bool {
+        self.options.remove(option).is_some()
+    }
+
+    // Exactly `names.len()` positional arguments, after the command, and no options left unused
+    fn finish<const N: usize>(self, names: [&str; N]) -> Result<[String; N], String> {
+        if let Some(option) = self.options.keys().next() {
+            return Err(format!("unknown option {}", option));
+        }
+        let given = self.positional.len().saturating_sub(1);
+        self.positional
+            .into_iter()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| format!("expected {} but got {} argument(s)", names.join(" "), given))
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let code = match run().await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("wb: {}", e);
+            1
+        }
+    };
+    exit(code);
+}
+
+async fn run() -> Result<i32, String> {
+    let mut args = Args::parse().map_err(|e| format!("{}\n\n{}", e, USAGE))?;
+    let url = args
+        .value("--url")
+        .or_else(|| std::env::var("WB_URL").ok())
+        .unwrap_or_else(|| "http://127.0.0.1:3000".to_string());
+    let mut base = Url::parse(&url).map_err(|e| format!("invalid URL {:?}: {}", url, e))?;
+    match base.scheme() {
+        "http" | "https" => {}
+        "ws" => base.set_scheme("http").unwrap(),
+        "wss" => base.set_scheme("https").unwrap(),
+        other => return Err(format!("invalid URL {:?}: unsupported scheme {}", url, other)),
+    }
+    let client = Client {
+        http: reqwest::Client::new(),
+        base,
+        user: args.value("--user").unwrap_or_else(|| "wb".to_string()),
+        owner_token: args
+            .value("--owner-token")
+            .or_else(|| std::env::var("WB_OWNER_TOKEN").ok())
+            .filter(|token| !token.is_empty()),
+        token: args
+            .value("--token")
+            .or_else(|| std::env::var("WB_ADMIN_TOKEN").ok())
+            .filter(|token| !token.is_empty()),
+    };
+
+    let command = args.positional.first().cloned().unwrap_or_default();
+    match command.as_str() {
+        "tail" => {
+            let live = args.flag("--live");
+            let [room] = args.finish(["ROOM"])?;
+            tail(&client, &room, live).await
+        }
+        "post" => {
+            let file = (args.positional.len() == 3).then(|| args.positional.pop().unwrap());
+            let [room] = args.finish(["ROOM"])?;
+            post(&client, &room, file).await
+        }
+        "dump" => {
+            let format = args.value("--format");
+            let width = args.value("--width");
+            let output = args.value("--output");
+            let [room] = args.finish(["ROOM"])?;
+            dump(&client, &room, format, width, output).await
+        }
+        "clear" => {
+            let [room] = args.finish(["ROOM"])?;
+            clear(&client, &room).await
+        }
+        "copy" => {
+            let first = args.flag("--clear");
+            let [from, to] = args.finish(["FROM", "TO"])?;
+            copy(&client, &from, &to, first).await
+        }
+        "play" => {
+            let speed = args.value("--speed");
+            let first = args.flag("--clear");
+            let [file, room] = args.finish(["RECORDING", "ROOM"])?;
+            play(&client, &file, &room, speed, first).await
+        }
+        "render" => {
+            let format = args.value("--format");
+            let width = args.value("--width");
+            let output = args.value("--output");
+            let [file] = args.finish(["RECORDING"])?;
+            render(&file, format, width, output)
+        }
+        "" => Err(format!("no command given\n\n{}", USAGE)),
+        other => Err(format!("unknown command {:?}\n\n{}", other, USAGE)),
+    }
+}
+
+async fn tail(client: &Client, room: &str, live: bool) -> Result<i32, String> {
+    let mut socket = client.join(room).await?;
+    // The board and history sent on joining end with the room's checkpoints
+    let mut joined = !live;
+    let mut stdout = std::io::stdout().lock();
+    while let Some(message) = socket.next().await {
+        let text = match message {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(close)) => {
+                return match close {
+                    Some(close) if !close.reason.is_empty() => Err(format!("disconnected: {}", close.reason)),
+                    _ => Ok(0),
+                };
+            }
+            Ok(_) => continue,
+            Err(e) => return Err(format!("connection lost: {}", e)),
+        };
+        if !joined {
+            joined = matches!(
+                serde_json::from_str::<Frame>(&text).map(|frame| frame.event),
+                Ok(WhiteboardEvent::Checkpoints { .. })
+            );
+            continue;
+        }
+        // Stop quietly once whoever reads the output goes away
+        if writeln!(stdout, "{}", text).and_then(|_| stdout.flush()).is_err() {
+            return Ok(0);
+        }
+    }
+    Ok(0)
+}
+
+async fn post(client: &Client, room: &str, file: Option<String>) -> Result<i32, String> {
+    let lines: Box<dyn tokio::io::AsyncRead + Unpin> = match &file {
+        Some(path) => Box::new(
+            tokio::fs::File::open(path)
+                .await
+                .map_err(|e| format!("failed to open {}: {}", path, e))?,
+        ),
+        None => Box::new(tokio::io::stdin()),
+    };
+    let name = file.unwrap_or_else(|| "stdin".to_string());
+    let frames = stream::unfold((BufReader::new(lines).lines(), 0), move |(mut lines, number)| {
+        let name = name.clone();
+        async move {
+            let number = number + 1;
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(e) => return Some((Err(format!("failed to read {}: {}", name, e)), (lines, number))),
+            };
+            let parsed = if line.trim().is_empty() {
+                Ok(None)
+            } else {
+                serde_json::from_str::<Frame>(&line)
+                    .map(|frame| Some((format!("{} line {}", name, number), frame)))
+                    .map_err(|e| format!("{} line {}: {}", name, number, e))
+            };
+            Some((parsed, (lines, number)))
+        }
+    })
+    .filter_map(|parsed| async move { parsed.transpose() });
+
+    let socket = client.join(room).await?;
+    let (sent, rejected) = send_frames(socket, Box::pin(frames)).await?;
+    eprintln!("wb: {} of {} events accepted", sent - rejected, sent);
+    Ok(if rejected > 0 { 1 } else { 0 })
+}
+
+// Send `frames`, each with a label to report it by, and wait until the server has answered
+// every one. Returns how many were sent and how many of those were rejected.
+async fn send_frames(
+    socket: Socket,
+    mut frames: impl Stream<Item = Result<(String, Frame), String>> + Unpin,
+) -> Result<(usize, usize), String> {
+    let (mut writer, mut reader) = socket.split();
+    // Label of each frame sent and not yet answered, by its `ref`
+    let mut pending = HashMap::new();
+    let (mut sent, mut rejected) = (0, 0);
+    let mut input_done = false;
+    // The server takes a `ref` it has seen from the same user before for a retry, so every run
+    // names its events differently
+    let run = format!("{:08x}", fastrand::u32(..));
+    while !input_done || !pending.is_empty() {
+        let wait = if input_done { REPLY_TIMEOUT } else { Duration::MAX };
+        tokio::select! {
+            next = frames.next(), if !input_done => match next {
+                None => input_done = true,
+                Some(Err(e)) => return Err(e),
+                Some(Ok((label, mut frame))) => {
+                    if frame.event.is_server_only() {
+                        continue;
+                    }
+                    let reference = frame.reference.get_or_insert_with(|| format!("wb-{}-{}", run, sent)).clone();
+                    // The server decides who sent it and where it goes in the room's order
+                    frame.user = None;
+                    frame.seq = None;
+                    let text = serde_json::to_string(&frame).unwrap();
+                    writer.send(Message::Text(text)).await.map_err(|e| format!("connection lost: {}", e))?;
+                    pending.insert(reference, label);
+                    sent += 1;
+                }
+            },
+            message = timeout(wait, reader.next()) => {
+                let text = match message {
+                    Ok(Some(Ok(Message::Text(text)))) => text,
+                    Ok(Some(Ok(Message::Close(_)))) | Ok(None) => return Err("the server closed the connection".to_string()),
+                    Ok(Some(Ok(_))) => continue,
+                    Ok(Some(Err(e))) => return Err(format!("connection lost: {}", e)),
+                    Err(_) => return Err(format!("no answer for {} event(s)", pending.len())),
+                };
+                let Ok(reply) = serde_json::from_str::<Frame>(&text) else { continue };
+                let Some(label) = reply.reference.and_then(|reference| pending.remove(&reference)) else { continue };
+                if let WhiteboardEvent::Reject { reason } = reply.event {
+                    eprintln!("wb: {}: rejected: {}", label, reason);
+                    rejected += 1;
+                }
+            }
+        }
+    }
+    let _ = writer.close().await;
+    Ok((sent, rejected))
+}
+
+async fn dump(
+    client: &Client,
+    room: &str,
+    format: Option<String>,
+    width: Option<String>,
+    output: Option<String>,
+) -> Result<i32, String> {
+    let format = output_format(format, output.as_deref());
+    let body = match format.as_str() {
+        "json" => pretty(&client.board(room).await?),
+        "svg" | "png" => {
+            let mut url = client.url(&["rooms", room, &format!("board.{}", format)]);
+            match (format.as_str(), width) {
+                ("png", Some(width)) => {
+                    url.query_pairs_mut().append_pair("width", &width);
+                }
+                ("svg", None) => {
+                    url.query_pairs_mut().append_pair("crop", "true");
+                }
+                (_, None) => {}
+                (_, Some(_)) => return Err("--width only applies to PNG".to_string()),
+            }
+            client.get(url).await?
+        }
+        other => return Err(format!("unknown format {:?}: expected json, svg or png", other)),
+    };
+    write_output(output, body)
+}
+
+// The format asked for, else the one `output`'s extension names, else JSON
+fn output_format(format: Option<String>, output: Option<&str>) -> String {
+    format
+        .or_else(|| {
+            let extension = output?.rsplit_once('.')?.1;
+            ["json", "svg", "png"].contains(&extension).then(|| extension.to_string())
+        })
+        .unwrap_or_else(|| "json".to_string())
+}
+
+fn pretty(board: &BoardSnapshot) -> Vec<u8> {
+    let mut json = serde_json::to_vec_pretty(board).unwrap();
+    json.push(b'\n');
+    json
+}
+
+// Write `body` to the file at `output`, or to stdout
+fn write_output(output: Option<String>, body: Vec<u8>) -> Result<i32, String> {
+    match output {
+        Some(path) => std::fs::write(&path, body).map_err(|e| format!("failed to write {}: {}", path, e))?,
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(&body).and_then(|_| stdout.flush());
+        }
+    }
+    Ok(0)
+}
+
+async fn clear(client: &Client, room: &str) -> Result<i32, String> {
+    // Operators can clear any room over the admin API; anyone else has to own it
+    if let Some(token) = &client.token {
+        let url = client.url(&["rooms", room, "clear"]);
+        let response = client
+            .http
+            .post(url.clone())
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(|e| format!("{}: {}", url, e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {} {}", url, status, body.trim()));
+        }
+        return Ok(0);
+    }
+    let socket = client.join(room).await?;
+    let frames = stream::iter([Ok(("clear".to_string(), Frame::server(WhiteboardEvent::ClearBoard)))]);
+    let (_, rejected) = send_frames(socket, frames).await?;
+    Ok(if rejected > 0 { 1 } else { 0 })
+}
+
+async fn copy(client: &Client, from: &str, to: &str, first: bool) -> Result<i32, String> {
+    let board = client.board(from).await?;
+    if first && clear(client, to).await? != 0 {
+        return Ok(1);
+    }
+    // Elements keep their ids, so copying again replaces rather than duplicates them
+    let frames = board.elements.into_iter().map(|frame| {
+        let label = format!("element {}", frame.id.as_deref().unwrap_or("?"));
+        Ok((label, frame))
+    });
+    let socket = client.join(to).await?;
+    let (sent, rejected) = send_frames(socket, stream::iter(frames)).await?;
+    eprintln!("wb: copied {} of {} elements from {:?} to {:?}", sent - rejected, sent, from, to);
+    Ok(if rejected > 0 { 1 } else { 0 })
+}
+
+fn read_recording(file: &str) -> Result<Recording, String> {
+    let text = std::fs::read_to_string(file).map_err(|e| format!("failed to read {}: {}", file, e))?;
+    Recording::parse(&text).map_err(|e| format!("{}: {}", file, e))
+}
+
+// Answers from the server to one of the connections a recording is played back over:
+// the `ref` of each event and why it was rejected, if it was
+type Reply = Result<(String, Option<String>), String>;
+
+// Connections playing back a recording, one per user in it
+struct Players<'a> {
+    client: &'a Client,
+    room: String,
+    writers: HashMap<String, SplitSink<Socket, Message>>,
+    replies: mpsc::UnboundedSender<Reply>,
+}
+
+impl Players<'_> {
+    // Join as `user`, passing on what the server answers their events with
+    async fn connect(&mut self, user: &str) -> Result<(), String> {
+        let (writer, mut reader) = self.client.join_as(&self.room, user).await?.split();
+        let replies = self.replies.clone();
+        let name = user.to_string();
+        tokio::spawn(async move {
+            while let Some(message) = reader.next().await {
+                let text = match message {
+                    Ok(Message::Text(text)) => text,
+                    Ok(Message::Close(_)) | Err(_) => break,
+                    Ok(_) => continue,
+                };
+                let Ok(reply) = serde_json::from_str::<Frame>(&text) else { continue };
+                let (Some(reference), event) = (reply.reference, reply.event) else { continue };
+                let reason = match event {
+                    WhiteboardEvent::Reject { reason } => Some(reason),
+                    WhiteboardEvent::Ack => None,
+                    _ => continue,
+                };
+                if replies.send(Ok((reference, reason))).is_err() {
+                    return;
+                }
+            }
+            let _ = replies.send(Err(format!("the server closed {}'s connection", name)));
+        });
+        self.writers.insert(user.to_string(), writer);
+        Ok(())
+    }
+
+    async fn send(&mut self, user: &str, frame: Frame) -> Result<(), String> {
+        if !self.writers.contains_key(user) {
+            self.connect(user).await?;
+        }
+        let text = serde_json::to_string(&frame).unwrap();
+        let writer = self.writers.get_mut(user).unwrap();
+        writer.send(Message::Text(text)).await.map_err(|e| format!("connection lost: {}", e))
+    }
+
+    async fn close(self) {
+        for (_, mut writer) in self.writers {
+            let _ = writer.close().await;
+        }
+    }
+}
+
+async fn play(client: &Client, file: &str, room: &str, speed: Option<String>, first: bool) -> Result<i32, String> {
+    let speed: f64 = match speed {
+        Some(speed) => speed
+            .parse()
+            .ok()
+            .filter(|speed: &f64| speed.is_finite() && *speed >= 0.0)
+            .ok_or_else(|| format!("invalid --speed {:?}: expected a number of at least 0", speed))?,
+        None => 1.0,
+    };
+    let recording = read_recording(file)?;
+    if first && clear(client, room).await? != 0 {
+        return Ok(1);
+    }
+
+    // What to send, when and as whom. The board recording started with and boards
+    // replaced wholesale are drawn by whoever runs the playback, so they join first
+    // and own the room if nobody else is in it.
+    let mut steps = Vec::new();
+    let element = |frame: &Frame| (format!("element {}", frame.id.as_deref().unwrap_or("?")), client.user.clone(), frame.clone());
+    steps.extend(recording.header.board.iter().map(|frame| (0, element(frame))));
+    for (n, record) in recording.records.iter().enumerate() {
+        let label = format!("record {}", n + 1);
+        let frame = &record.frame;
+        match (&frame.event, &record.sender) {
+            (WhiteboardEvent::ResetBoard { elements }, _) => {
+                steps.push((record.at, (label, client.user.clone(), Frame::server(WhiteboardEvent::ClearBoard))));
+                steps.extend(elements.iter().map(|frame| (record.at, element(frame))));
+            }
+            (WhiteboardEvent::ClearBoard, _) => {
+                steps.push((record.at, (label, client.user.clone(), frame.clone())));
+            }
+            // The other owner commands name connections and users of the recorded session
+            (event, Some(sender)) if !event.is_server_only() && !event.is_admin() => {
+                steps.push((record.at, (label, sender.clone(), frame.clone())));
+            }
+            _ => {}
+        }
+    }
+
+    let (replies, mut answers) = mpsc::unbounded_channel();
+    let mut players = Players {
+        client,
+        room: room.to_string(),
+        writers: HashMap::new(),
+        replies,
+    };
+    // Join before anyone else in the recording does, with or without a board to draw
+    players.connect(&client.user).await?;
+    let run = format!("{:08x}", fastrand::u32(..));
+    let started = Instant::now();
+    let mut pending = HashMap::new();
+    let (mut sent, mut rejected) = (0, 0);
+    let mut steps = steps.into_iter().peekable();
+    loop {
+        let due = match steps.peek() {
+            Some((at, _)) if speed > 0.0 => started + Duration::from_secs_f64(*at as f64 / 1000.0 / speed),
+            Some(_) => started,
+            None if pending.is_empty() => break,
+            None => Instant::now() + REPLY_TIMEOUT,
+        };
+        tokio::select! {
+            answer = answers.recv() => {
+                let (reference, reason) = answer.expect("players hold a sender")?;
+                let Some(label) = pending.remove(&reference) else { continue };
+                if let Some(reason) = reason {
+                    eprintln!("wb: {}: {}: rejected: {}", file, label, reason);
+                    rejected += 1;
+                }
+            }
+            _ = sleep_until(due) => {
+                let Some((_, (label, user, mut frame))) = steps.next() else {
+                    return Err(format!("no answer for {} event(s)", pending.len()));
+                };
+                let reference = format!("wb-{}-{}", run, sent);
+                frame.reference = Some(reference.clone());
+                frame.user = None;
+                frame.seq = None;
+                players.send(&user, frame).await?;
+                pending.insert(reference, label);
+                sent += 1;
+            }
+        }
+    }
+    players.close().await;
+    eprintln!("wb: played {} of {} events into {:?}", sent - rejected, sent, room);
+    Ok(if rejected > 0 { 1 } else { 0 })
+}
+
+fn render(file: &str, format: Option<String>, width: Option<String>, output: Option<String>) -> Result<i32, String> {
+    let recording = read_recording(file)?;
+    let board = recording.final_board();
+    let body = match output_format(format, output.as_deref()).as_str() {
+        "json" => {
+            let seq = recording.records.iter().filter_map(|record| record.frame.seq).max();
+            pretty(&BoardSnapshot {
+                seq: seq.unwrap_or(recording.header.seq),
+                elements: board,
+            })
+        }
+        "svg" if width.is_some() => return Err("--width only applies to PNG".to_string()),
+        "svg" => recording::svg(&board).into_bytes(),
+        "png" => {
+            let width = match width {
+                Some(width) => width.parse().map_err(|_| format!("invalid --width {:?}", width))?,
+                None => 800,
+            };
+            backend::init_font(std::env::var_os("WB_FONT").as_deref().map(Path::new));
+            recording::png(&board, width)?
+        }
+        other => return Err(format!("unknown format {:?}: expected json, svg or png", other)),
+    };
+    write_output(output, body)
+}
@@ -0,0 +1,32 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+
+use crate::AppState;
+
+// Checkpoints are made and restored over the socket; reading them is open, like the board
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/rooms/:room/checkpoints", get(list))
+        .route("/rooms/:room/checkpoints/:name", get(show))
+}
+
+async fn list(State(state): State<AppState>, Path(room): Path<String>) -> Response {
+    match state.existing_room(&room, |_, room| room.checkpoints()) {
+        Ok(checkpoints) => Json(checkpoints).into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+// A checkpoint with every element it holds
+async fn show(State(state): State<AppState>, Path((room, name)): Path<(String, String)>) -> Response {
+    match state.existing_room(&room, |_, room| room.checkpoint(&name).map(|checkpoint| Json(checkpoint).into_response())) {
+        Ok(Some(response)) => response,
+        Ok(None) => (StatusCode::NOT_FOUND, "no such checkpoint").into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
@@ -0,0 +1,300 @@
+use std::{env, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    origin::AllowedOrigins,
+    outbound::{QueueConfig, SlowClient},
+    store::Retention,
+    tls::TlsConfig,
+    webhook::{Delivery, Webhook},
+};
+
+// Runtime configuration for the server.
+// Everything is read from `WB_*` environment variables so the same binary
+// can be configured the same way locally, in containers and in CI.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub addr: SocketAddr,
+    pub log_format: LogFormat,
+    // Serve `wss://` directly when a certificate and key are configured
+    pub tls: Option<TlsConfig>,
+    // Address to accept relay links from other instances on
+    pub relay_listen: Option<SocketAddr>,
+    // Relay addresses of other instances to dial
+    pub relay_peers: Vec<String>,
+    // Shared by every instance in the mesh; links that cannot prove they know it are refused
+    pub relay_secret: Option<Arc<str>>,
+    // Persist room history here; rooms live in memory only when unset
+    pub data_dir: Option<PathBuf>,
+    // Compact a room's log once it holds this many events
+    pub compact_tail: usize,
+    // How often every room's log is compacted regardless of size
+    pub compact_interval: Duration,
+    // Older snapshot/log generations to keep per room; `None` keeps all
+    pub retain_segments: Option<usize>,
+    // Bearer token required by the admin HTTP API
+    pub admin_token: Option<Arc<str>>,
+    // Evict rooms from memory once they have had no connections for this long
+    pub room_idle: Duration,
+    // What eviction does with a room's history unless the room says otherwise
+    pub room_retention: Retention,
+    // Endpoints notified about events in every room
+    pub webhooks: Vec<Webhook>,
+    pub webhook_delivery: Delivery,
+    // Font text is drawn with in rendered boards; a system font is looked for when unset
+    pub font: Option<PathBuf>,
+    // Store uploaded images here; defaults to `.assets` under the data directory, which
+    // no room's directory can be named, and image uploads are disabled when neither is set
+    pub asset_dir: Option<PathBuf>,
+    // Largest image upload accepted, in bytes
+    pub asset_max_bytes: usize,
+    // Store templates added over HTTP here; defaults to `.templates` under the data
+    // directory, and they only last until restart when neither is set
+    pub template_dir: Option<PathBuf>,
+    // How many messages can wait for each client, and what happens to clients that fall behind
+    pub client_queue: QueueConfig,
+    // Keep session recordings here; defaults to `.recordings` under the data directory,
+    // and rooms cannot be recorded when neither is set
+    pub recording_dir: Option<PathBuf>,
+    // Web pages allowed to connect and call the HTTP API
+    pub allowed_origins: AllowedOrigins,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    // Human-readable, one event per line
+    Pretty,
+    // One JSON object per line, for log shipping and grepping incidents
+    Json,
+}
+
+// What every setting is when its variable is unset
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            log_format: LogFormat::Pretty,
+            tls: None,
+            relay_listen: None,
+            relay_peers: Vec::new(),
+            relay_secret: None,
+            data_dir: None,
+            compact_tail: 1000,
+            compact_interval: Duration::from_secs(300),
+            retain_segments: None,
+            admin_token: None,
+            room_idle: Duration::from_secs(600),
+            room_retention: Retention::Persist,
+            webhooks: Vec::new(),
+            webhook_delivery: Delivery {
+                batch_window: Duration::from_millis(1000),
+                batch_max: 100,
+                attempts: 5,
+                backoff: Duration::from_millis(1000),
+                queue_max: 1000,
+            },
+            font: None,
+            asset_dir: None,
+            asset_max_bytes: 5 * 1024 * 1024,
+            template_dir: None,
+            client_queue: QueueConfig {
+                capacity: 512,
+                policy: SlowClient::Coalesce,
+            },
+            recording_dir: None,
+            // Where `trunk serve` serves the frontend
+            allowed_origins: AllowedOrigins::Only(vec![
+                "http://127.0.0.1:8080".to_string(),
+                "http://localhost:8080".to_string(),
+            ]),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        let defaults = Config::default();
+        let addr = match env::var("WB_ADDR") {
+            Ok(addr) => addr
+                .parse()
+                .map_err(|e| format!("invalid WB_ADDR {:?}: {}", addr, e))?,
+            Err(_) => defaults.addr,
+        };
+
+        let log_format = match env::var("WB_LOG_FORMAT").as_deref() {
+            Ok("json") => LogFormat::Json,
+            Ok("pretty") => LogFormat::Pretty,
+            Err(_) => defaults.log_format,
+            Ok(other) => return Err(format!("invalid WB_LOG_FORMAT {:?}: expected \"pretty\" or \"json\"", other)),
+        };
+
+        let tls = match (env::var_os("WB_TLS_CERT"), env::var_os("WB_TLS_KEY")) {
+            (Some(cert), Some(key)) => {
+                let secs = parse_var("WB_TLS_RELOAD_SECS", 30)?;
+                Some(TlsConfig {
+                    cert: PathBuf::from(cert),
+                    key: PathBuf::from(key),
+                    reload_interval: Duration::from_secs(secs),
+                })
+            }
+            (None, None) => None,
+            _ => return Err("WB_TLS_CERT and WB_TLS_KEY must be set together".to_string()),
+        };
+
+        let relay_listen = match env::var("WB_RELAY_LISTEN") {
+            Ok(addr) => Some(
+                addr.parse()
+                    .map_err(|e| format!("invalid WB_RELAY_LISTEN {:?}: {}", addr, e))?,
+            ),
+            Err(_) => None,
+        };
+        let relay_peers: Vec<String> = env::var("WB_RELAY_PEERS")
+            .map(|peers| {
+                peers
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|peer| !peer.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let relay_secret = env::var("WB_RELAY_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .map(Arc::from);
+        if (relay_listen.is_some() || !relay_peers.is_empty()) && relay_secret.is_none() {
+            return Err("WB_RELAY_LISTEN and WB_RELAY_PEERS need WB_RELAY_SECRET to authenticate links with".to_string());
+        }
+
+        let data_dir = env::var_os("WB_DATA_DIR").map(PathBuf::from);
+        let compact_tail = parse_var("WB_COMPACT_TAIL", defaults.compact_tail)?;
+        let compact_interval = Duration::from_secs(parse_var("WB_COMPACT_SECS", defaults.compact_interval.as_secs())?);
+        let retain_segments = match env::var("WB_RETAIN_SEGMENTS").as_deref() {
+            Ok("all") => None,
+            Err(_) => defaults.retain_segments,
+            Ok(n) => Some(
+                n.parse()
+                    .map_err(|_| format!("invalid WB_RETAIN_SEGMENTS {:?}: expected a number or \"all\"", n))?,
+            ),
+        };
+        let admin_token = env::var("WB_ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty())
+            .map(Arc::from);
+
+        let room_idle = Duration::from_secs(parse_var("WB_ROOM_IDLE_SECS", defaults.room_idle.as_secs())?);
+        let room_retention = match env::var("WB_ROOM_RETENTION").as_deref() {
+            Ok("persist") => Retention::Persist,
+            Err(_) => defaults.room_retention,
+            Ok("discard") => Retention::Discard,
+            Ok(other) => {
+                return Err(format!(
+                    "invalid WB_ROOM_RETENTION {:?}: expected \"persist\" or \"discard\"",
+                    other
+                ))
+            }
+        };
+
+        let webhook_urls: Vec<String> = env::var("WB_WEBHOOK_URLS")
+            .map(|urls| {
+                urls.split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let webhooks = match env::var("WB_WEBHOOK_SECRET") {
+            Ok(secret) if !secret.is_empty() => webhook_urls
+                .into_iter()
+                .map(|url| Webhook {
+                    url,
+                    secret: secret.clone(),
+                })
+                .collect(),
+            _ if webhook_urls.is_empty() => Vec::new(),
+            _ => return Err("WB_WEBHOOK_URLS needs WB_WEBHOOK_SECRET to sign deliveries with".to_string()),
+        };
+        let webhook_delivery = Delivery {
+            batch_window: Duration::from_millis(parse_var("WB_WEBHOOK_BATCH_MS", defaults.webhook_delivery.batch_window.as_millis() as u64)?),
+            batch_max: parse_var("WB_WEBHOOK_BATCH_MAX", defaults.webhook_delivery.batch_max)?,
+            attempts: parse_var("WB_WEBHOOK_ATTEMPTS", defaults.webhook_delivery.attempts)?,
+            backoff: Duration::from_millis(parse_var("WB_WEBHOOK_BACKOFF_MS", defaults.webhook_delivery.backoff.as_millis() as u64)?),
+            queue_max: parse_var("WB_WEBHOOK_QUEUE_MAX", defaults.webhook_delivery.queue_max)?,
+        };
+        if webhook_delivery.queue_max == 0 {
+            return Err("WB_WEBHOOK_QUEUE_MAX must be at least 1".to_string());
+        }
+
+        let font = env::var_os("WB_FONT").map(PathBuf::from);
+        let asset_dir = env::var_os("WB_ASSET_DIR")
+            .map(PathBuf::from)
+            .or_else(|| data_dir.as_ref().map(|dir| dir.join(".assets")));
+        let asset_max_bytes = parse_var("WB_ASSET_MAX_BYTES", defaults.asset_max_bytes)?;
+        let template_dir = env::var_os("WB_TEMPLATE_DIR")
+            .map(PathBuf::from)
+            .or_else(|| data_dir.as_ref().map(|dir| dir.join(".templates")));
+        let recording_dir = env::var_os("WB_RECORDING_DIR")
+            .map(PathBuf::from)
+            .or_else(|| data_dir.as_ref().map(|dir| dir.join(".recordings")));
+        let allowed_origins = match env::var("WB_ALLOWED_ORIGINS") {
+            Ok(list) => AllowedOrigins::parse(&list).map_err(|e| format!("invalid WB_ALLOWED_ORIGINS: {}", e))?,
+            Err(_) => defaults.allowed_origins,
+        };
+        let client_queue = QueueConfig {
+            capacity: parse_var("WB_CLIENT_QUEUE", defaults.client_queue.capacity)?,
+            policy: match env::var("WB_SLOW_CLIENT").as_deref() {
+                Ok("coalesce") => SlowClient::Coalesce,
+                Err(_) => defaults.client_queue.policy,
+                Ok("drop-ephemeral") => SlowClient::DropEphemeral,
+                Ok("disconnect") => SlowClient::Disconnect,
+                Ok(other) => {
+                    return Err(format!(
+                        "invalid WB_SLOW_CLIENT {:?}: expected \"coalesce\", \"drop-ephemeral\" or \"disconnect\"",
+                        other
+                    ))
+                }
+            },
+        };
+
+        Ok(Config {
+            addr,
+            log_format,
+            tls,
+            relay_listen,
+            relay_peers,
+            relay_secret,
+            data_dir,
+            compact_tail,
+            compact_interval,
+            retain_segments,
+            admin_token,
+            room_idle,
+            room_retention,
+            webhooks,
+            webhook_delivery,
+            font,
+            asset_dir,
+            asset_max_bytes,
+            template_dir,
+            client_queue,
+            recording_dir,
+            allowed_origins,
+        })
+    }
+}
+
+// Read a positive number from the environment, falling back to `default` when unset
+fn parse_var<T>(name: &str, default: T) -> Result<T, String>
+where
+    T: std::str::FromStr + PartialOrd + Default,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<T>()
+            .ok()
+            .filter(|parsed| *parsed > T::default())
+            .ok_or_else(|| format!("invalid {} {:?}: expected a positive number", name, value)),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Point, WhiteboardEvent};
+
+// The board as a conflict-free replicated document.
+//
+// Every element is a last-writer-wins register: each write carries a `Stamp`
+// and a replica keeps whichever write has the highest one, so replicas that
+// have seen the same writes agree on the board whatever order they saw them in.
+// Deletes are writes of no value (tombstones), so a delete also wins over any
+// older write that turns up late.
+//
+// Freehand strokes grow one point at a time. Rewriting the whole stroke for
+// every point would make drawing quadratic, so points are kept as a grow-only
+// set next to the register instead, ordered by their stamps. A write to the
+// register shadows every point stamped before it.
+//
+// Replicas tell each other what they have seen with a state vector: the
+// highest stamp merged from each replica. A replica that sends its state
+// vector gets back a diff with just the writes it is missing.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Doc {
+    #[serde(skip)]
+    replica: String,
+    // Lamport clock; the counter of the latest stamp this replica has seen
+    #[serde(skip)]
+    clock: u64,
+    elements: HashMap<String, Register>,
+    state: StateVector,
+    // Local ops not yet handed out with `take_ops`
+    #[serde(skip)]
+    pending: Vec<Op>,
+    // Our own counter as of the last `live_update`
+    #[serde(skip)]
+    published: u64,
+}
+
+// Lamport counter plus the replica that wrote it, so concurrent writes still
+// have a total order every replica agrees on
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp(pub u64, pub String);
+
+// Highest stamp counter merged from each replica
+pub type StateVector = BTreeMap<String, u64>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op")]
+pub enum Op {
+    // Replace an element, or delete it when `value` is `None`
+    Set {
+        id: String,
+        stamp: Stamp,
+        // When the element was first written; orders elements for drawing
+        created: Stamp,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+        value: Option<WhiteboardEvent>,
+    },
+    // Add a point to a freehand stroke
+    Append {
+        id: String,
+        stamp: Stamp,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+        point: Point,
+    },
+}
+
+impl Op {
+    pub fn id(&self) -> &str {
+        match self {
+            Op::Set { id, .. } | Op::Append { id, .. } => id,
+        }
+    }
+
+    pub fn stamp(&self) -> &Stamp {
+        match self {
+            Op::Set { stamp, .. } | Op::Append { stamp, .. } => stamp,
+        }
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            Op::Set { user, .. } | Op::Append { user, .. } => user.as_deref(),
+        }
+    }
+}
+
+// A batch of ops sent to another replica. A replica whose state vector covers
+// `since` has everything up to `state` once it merges the ops; one that does
+// not has missed something in between and needs a diff.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Update {
+    pub since: StateVector,
+    pub state: StateVector,
+    pub ops: Vec<Op>,
+}
+
+impl Update {
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Register {
+    stamp: Stamp,
+    created: Stamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    value: Option<WhiteboardEvent>,
+    // Points appended to a stroke after `stamp`, sorted by stamp
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    points: Vec<(Stamp, Point, Option<String>)>,
+}
+
+impl Register {
+    // Registers only exist for points so far; the first write defines the element
+    fn is_unwritten(&self) -> bool {
+        self.stamp == Stamp::default()
+    }
+
+    fn current(&self) -> Option<WhiteboardEvent> {
+        match &self.value {
+            Some(WhiteboardEvent::DrawStroke { points }) => {
+                let mut points = points.clone();
+                points.extend(self.points.iter().map(|(_, point, _)| *point));
+                Some(WhiteboardEvent::DrawStroke { points })
+            }
+            value => value.clone(),
+        }
+    }
+}
+
+impl Doc {
+    pub fn new(replica: &str) -> Self {
+        Doc {
+            replica: replica.to_string(),
+            ..Doc::default()
+        }
+    }
+
+    // Take over a document restored from disk under this process's replica id
+    pub fn resume(mut self, replica: &str) -> Self {
+        self.replica = replica.to_string();
+        self.clock = self
+            .elements
+            .values()
+            .flat_map(|register| {
+                std::iter::once(register.stamp.0).chain(register.points.iter().map(|(stamp, _, _)| stamp.0))
+            })
+            .chain(self.state.values().copied())
+            .max()
+            .unwrap_or(0);
+        self.published = self.state.get(replica).copied().unwrap_or(0);
+        self
+    }
+
+    pub fn get(&self, id: &str) -> Option<WhiteboardEvent> {
+        self.elements.get(id).and_then(Register::current)
+    }
+
+    // Elements on the board in drawing order
+    pub fn elements(&self) -> Vec<(&str, WhiteboardEvent)> {
+        let mut elements: Vec<_> = self
+            .elements
+            .iter()
+            .filter_map(|(id, register)| Some((&register.created, id.as_str(), register.current()?)))
+            .collect();
+        elements.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        elements.into_iter().map(|(_, id, event)| (id, event)).collect()
+    }
+
+    pub fn state_vector(&self) -> &StateVector {
+        &self.state
+    }
+
+    // Insert or replace an element, returning its previous value.
+    // Replaced elements keep their place in the drawing order.
+    pub fn put(&mut self, id: &str, user: &str, event: WhiteboardEvent) -> Option<WhiteboardEvent> {
+        let before = self.get(id);
+        self.set(id, Some(user), Some(event));
+        before
+    }
+
+    pub fn remove(&mut self, id: &str, user: &str) -> Option<WhiteboardEvent> {
+        let before = self.get(id)?;
+        self.set(id, Some(user), None);
+        Some(before)
+    }
+
+    // Remove every element. Elements written concurrently on other replicas survive.
+    pub fn clear(&mut self, user: Option<&str>) {
+        let ids: Vec<String> = self
+            .elements
+            .iter()
+            .filter(|(_, register)| register.value.is_some())
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            self.set(&id, user, None);
+        }
+    }
+
+    // Extend a freehand stroke, starting it if needed
+    pub fn append_point(&mut self, id: &str, user: &str, point: Point) {
+        if !matches!(self.get(id), Some(WhiteboardEvent::DrawStroke { .. })) {
+            self.set(id, Some(user), Some(WhiteboardEvent::DrawStroke { points: vec![point] }));
+            return;
+        }
+        let op = Op::Append {
+            id: id.to_string(),
+            stamp: self.tick(),
+            user: Some(user.to_string()),
+            point,
+        };
+        self.apply(&op);
+        self.pending.push(op);
+    }
+
+    // Ops made locally since the last call
+    pub fn take_ops(&mut self) -> Vec<Op> {
+        std::mem::take(&mut self.pending)
+    }
+
+    // Forget local ops that never need sending, such as ones replayed from disk.
+    // Other replicas that lack them get them from diffs instead.
+    pub fn discard_ops(&mut self) {
+        self.pending.clear();
+        self.published = self.state.get(&self.replica).copied().unwrap_or(0);
+    }
+
+    // Wrap our own ops, in the order they were made, for the other replicas
+    pub fn live_update(&mut self, ops: Vec<Op>) -> Update {
+        let since = StateVector::from([(self.replica.clone(), self.published)]);
+        self.published = self.state.get(&self.replica).copied().unwrap_or(0);
+        let state = StateVector::from([(self.replica.clone(), self.published)]);
+        Update { since, state, ops }
+    }
+
+    // Everything this replica has that one at `since` is missing
+    pub fn diff(&self, since: &StateVector) -> Update {
+        let missing = |stamp: &Stamp| since.get(&stamp.1).is_none_or(|seen| stamp.0 > *seen);
+        let mut ops = Vec::new();
+        for (id, register) in &self.elements {
+            // The element's drawing position travels with its register too
+            if !register.is_unwritten() && (missing(&register.stamp) || missing(&register.created)) {
+                ops.push(Op::Set {
+                    id: id.clone(),
+                    stamp: register.stamp.clone(),
+                    created: register.created.clone(),
+                    user: register.user.clone(),
+                    value: register.value.clone(),
+                });
+            }
+            for (stamp, point, user) in &register.points {
+                if missing(stamp) {
+                    ops.push(Op::Append {
+                        id: id.clone(),
+                        stamp: stamp.clone(),
+                        user: user.clone(),
+                        point: *point,
+                    });
+                }
+            }
+        }
+        Update {
+            since: since.clone(),
+            state: self.state.clone(),
+            ops,
+        }
+    }
+
+    // Merge one op from another replica. Returns whether it changed the document.
+    pub fn merge(&mut self, op: &Op) -> bool {
+        self.clock = self.clock.max(op.stamp().0);
+        self.apply(op)
+    }
+
+    // Record that an update's ops have been merged. Returns false when the
+    // update skipped over something we never saw, so we need a diff.
+    pub fn advance(&mut self, update: &Update) -> bool {
+        let covered = update
+            .since
+            .iter()
+            .all(|(replica, seen)| self.state.get(replica).is_some_and(|ours| ours >= seen) || *seen == 0);
+        if covered {
+            for (replica, seen) in &update.state {
+                let ours = self.state.entry(replica.clone()).or_default();
+                *ours = (*ours).max(*seen);
+            }
+        }
+        covered
+    }
+
+    fn tick(&mut self) -> Stamp {
+        self.clock += 1;
+        self.state.insert(self.replica.clone(), self.clock);
+        Stamp(self.clock, self.replica.clone())
+    }
+
+    fn set(&mut self, id: &str, user: Option<&str>, value: Option<WhiteboardEvent>) {
+        let stamp = self.tick();
+        let created = match self.elements.get(id) {
+            Some(register) if !register.is_unwritten() => register.created.clone(),
+            _ => stamp.clone(),
+        };
+        let op = Op::Set {
+            id: id.to_string(),
+            stamp,
+            created,
+            user: user.map(String::from),
+            value,
+        };
+        self.apply(&op);
+        self.pending.push(op);
+    }
+
+    fn apply(&mut self, op: &Op) -> bool {
+        let register = self.elements.entry(op.id().to_string()).or_insert_with(|| Register {
+            stamp: Stamp::default(),
+            created: Stamp::default(),
+            user: None,
+            value: None,
+            points: Vec::new(),
+        });
+
+        match op {
+            Op::Set { stamp, created, user, value, .. } => {
+                // Whichever replica saw the element first decides where it is drawn
+                let created = if register.is_unwritten() {
+                    created.clone()
+                } else {
+                    register.created.clone().min(created.clone())
+                };
+                let changed = created != register.created;
+                register.created = created;
+                if *stamp <= register.stamp {
+                    return changed;
+                }
+                register.stamp = stamp.clone();
+                register.user = user.clone();
+                register.value = value.clone();
+                register.points.retain(|(point_stamp, _, _)| point_stamp > stamp);
+                true
+            }
+            Op::Append { stamp, user, point, .. } => {
+                if *stamp <= register.stamp {
+                    return false;
+                }
+                match register.points.binary_search_by(|(point_stamp, _, _)| point_stamp.cmp(stamp)) {
+                    Ok(_) => false,
+                    Err(index) => {
+                        register.points.insert(index, (stamp.clone(), *point, user.clone()));
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const IDS: [&str; 4] = ["a", "b", "c", "d"];
+
+    #[derive(Clone, Debug)]
+    enum Action {
+        Put { replica: usize, id: usize, x: i8 },
+        Remove { replica: usize, id: usize },
+        Append { replica: usize, id: usize, x: i8 },
+        Clear { replica: usize },
+        // Deliver the oldest update in flight between two replicas, as a link would
+        Deliver { from: usize, to: usize },
+        // Lose everything in flight between two replicas, as when a link drops
+        Drop { from: usize, to: usize },
+        // One replica asks another for a diff
+        Sync { from: usize, to: usize },
+    }
+
+    fn action(replicas: usize) -> impl Strategy<Value = Action> {
+        let r = 0..replicas;
+        let id = 0..IDS.len();
+        prop_oneof![
+            (r.clone(), id.clone(), any::<i8>()).prop_map(|(replica, id, x)| Action::Put { replica, id, x }),
+            (r.clone(), id.clone()).prop_map(|(replica, id)| Action::Remove { replica, id }),
+            (r.clone(), id, any::<i8>()).prop_map(|(replica, id, x)| Action::Append { replica, id, x }),
+            r.clone().prop_map(|replica| Action::Clear { replica }),
+            (r.clone(), r.clone()).prop_map(|(from, to)| Action::Deliver { from, to }),
+            (r.clone(), r.clone()).prop_map(|(from, to)| Action::Drop { from, to }),
+            (r.clone(), r).prop_map(|(from, to)| Action::Sync { from, to }),
+        ]
+    }
+
+    fn scenario() -> impl Strategy<Value = (usize, Vec<Action>)> {
+        (2..5usize).prop_flat_map(|replicas| (Just(replicas), prop::collection::vec(action(replicas), 0..120)))
+    }
+
+    fn line(x: i8) -> WhiteboardEvent {
+        WhiteboardEvent::DrawLine {
+            from: (x as f64, 0.0),
+            to: (0.0, x as f64),
+            color: "#000".to_string(),
+            width: 1.0,
+        }
+    }
+
+    // Merge an update the way a relay link does; false means a diff is needed
+    fn receive(doc: &mut Doc, update: &Update) -> bool {
+        for op in &update.ops {
+            doc.merge(op);
+        }
+        doc.advance(update)
+    }
+
+    fn sync(docs: &mut [Doc], from: usize, to: usize) {
+        let diff = docs[to].diff(docs[from].state_vector());
+        assert!(receive(&mut docs[from], &diff), "a diff always covers the state vector it answers");
+    }
+
+    fn board(doc: &Doc) -> Vec<(String, WhiteboardEvent)> {
+        doc.elements()
+            .into_iter()
+            .map(|(id, event)| (id.to_string(), event))
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn replicas_converge((replicas, actions) in scenario()) {
+            let mut docs: Vec<Doc> = (0..replicas).map(|r| Doc::new(&format!("r{}", r))).collect();
+            let mut links: HashMap<(usize, usize), VecDeque<Update>> = HashMap::new();
+
+            for action in actions {
+                let author = match action {
+                    Action::Put { replica, id, x } => {
+                        docs[replica].put(IDS[id], "u", line(x));
+                        Some(replica)
+                    }
+                    Action::Remove { replica, id } => {
+                        docs[replica].remove(IDS[id], "u");
+                        Some(replica)
+                    }
+                    Action::Append { replica, id, x } => {
+                        docs[replica].append_point(IDS[id], "u", (x as f64, 1.0));
+                        Some(replica)
+                    }
+                    Action::Clear { replica } => {
+                        docs[replica].clear(Some("u"));
+                        Some(replica)
+                    }
+                    Action::Deliver { from, to } => {
+                        if let Some(update) = links.get_mut(&(from, to)).and_then(VecDeque::pop_front) {
+                            if !receive(&mut docs[to], &update) {
+                                sync(&mut docs, to, from);
+                            }
+                        }
+                        None
+                    }
+                    Action::Drop { from, to } => {
+                        links.remove(&(from, to));
+                        None
+                    }
+                    Action::Sync { from, to } if from != to => {
+                        sync(&mut docs, from, to);
+                        None
+                    }
+                    Action::Sync { .. } => None,
+                };
+
+                if let Some(author) = author {
+                    let ops = docs[author].take_ops();
+                    if ops.is_empty() {
+                        continue;
+                    }
+                    let update = docs[author].live_update(ops);
+                    for to in (0..replicas).filter(|to| *to != author) {
+                        links.entry((author, to)).or_default().push_back(update.clone());
+                    }
+                }
+            }
+
+            // Anti-entropy with state-vector diffs only: if any replica ever
+            // claimed to have seen something it had not, it would never get it
+            for from in 0..replicas {
+                for to in 0..replicas {
+                    if from != to {
+                        sync(&mut docs, from, to);
+                    }
+                }
+            }
+
+            let expected = board(&docs[0]);
+            for doc in &docs[1..] {
+                prop_assert_eq!(&board(doc), &expected);
+                prop_assert_eq!(doc.state_vector(), docs[0].state_vector());
+            }
+        }
+
+        #[test]
+        fn merge_order_does_not_matter(ops in prop::collection::vec((0..3usize, 0..IDS.len(), any::<i8>(), any::<bool>()), 0..60), seed in any::<u64>()) {
+            // Make ops on separate replicas, then merge all of them in two different orders
+            let mut authors: Vec<Doc> = (0..3).map(|r| Doc::new(&format!("r{}", r))).collect();
+            let mut all = Vec::new();
+            for (replica, id, x, stroke) in ops {
+                if stroke {
+                    authors[replica].append_point(IDS[id], "u", (x as f64, 0.0));
+                } else {
+                    authors[replica].put(IDS[id], "u", line(x));
+                }
+                all.extend(authors[replica].take_ops());
+            }
+
+            let mut shuffled = all.clone();
+            let mut state = seed | 1;
+            for i in (1..shuffled.len()).rev() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                shuffled.swap(i, (state % (i as u64 + 1)) as usize);
+            }
+
+            let mut in_order = Doc::new("x");
+            let mut out_of_order = Doc::new("y");
+            for op in &all {
+                in_order.merge(op);
+            }
+            for op in shuffled.iter().chain(&shuffled) {
+                out_of_order.merge(op);
+            }
+            prop_assert_eq!(board(&in_order), board(&out_of_order));
+        }
+    }
+
+    #[test]
+    fn later_write_wins_and_delete_beats_older_writes() {
+        let mut a = Doc::new("a");
+        let mut b = Doc::new("b");
+        a.put("x", "alice", line(1));
+        let create = a.take_ops();
+        for op in &create {
+            b.merge(op);
+        }
+
+        // Concurrent: a deletes, b edits after seeing a's write, so b's stamp is higher
+        a.remove("x", "alice");
+        b.put("x", "bob", line(2));
+        let (from_a, from_b) = (a.take_ops(), b.take_ops());
+        for op in &from_b {
+            a.merge(op);
+        }
+        for op in &from_a {
+            b.merge(op);
+        }
+        assert_eq!(a.get("x"), b.get("x"));
+
+        // A delete made after seeing the edit wins everywhere
+        a.remove("x", "alice");
+        for op in a.take_ops() {
+            b.merge(&op);
+        }
+        assert_eq!(b.get("x"), None);
+    }
+
+    #[test]
+    fn strokes_keep_their_points_in_order() {
+        let mut a = Doc::new("a");
+        for x in 0..5 {
+            a.append_point("s", "alice", (x as f64, 0.0));
+        }
+        let ops = a.take_ops();
+        let mut b = Doc::new("b");
+        for op in ops.iter().rev() {
+            b.merge(op);
+        }
+        let points = (0..5).map(|x| (x as f64, 0.0)).collect();
+        assert_eq!(b.get("s"), Some(WhiteboardEvent::DrawStroke { points }));
+    }
+
+    #[test]
+    fn snapshots_resume_with_their_clock() {
+        let mut a = Doc::new("a");
+        a.put("x", "alice", line(1));
+        let saved = serde_json::to_string(&a).unwrap();
+        let mut restored = serde_json::from_str::<Doc>(&saved).unwrap().resume("a2");
+        restored.put("x", "alice", line(2));
+        for op in restored.take_ops() {
+            a.merge(&op);
+        }
+        assert_eq!(a.get("x"), Some(line(2)));
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    protocol::{Frame, WhiteboardEvent},
+    room::{board_frames, replay_entry, restore_board},
+    AppState,
+};
+
+// Query parameters accepted by `/rooms/<room>/diff`, e.g. `?from_checkpoint=before&to=120`.
+// Each side is a seq or a checkpoint's name; leaving out the `to` side compares with the board as it is now.
+#[derive(Deserialize, Debug)]
+pub struct DiffParams {
+    from: Option<u64>,
+    from_checkpoint: Option<String>,
+    to: Option<u64>,
+    to_checkpoint: Option<String>,
+}
+
+// How the board changed between two versions
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Diff {
+    // Seqs of the two versions
+    pub from: u64,
+    pub to: u64,
+    // Elements only in the later version, and only in the earlier one
+    pub added: Vec<Frame>,
+    pub removed: Vec<Frame>,
+    // Elements that are the same but somewhere else
+    pub moved: Vec<Changed>,
+    // Elements changed in any other way
+    pub modified: Vec<Changed>,
+    pub unchanged: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Changed {
+    pub id: String,
+    pub before: WhiteboardEvent,
+    pub after: WhiteboardEvent,
+}
+
+// Compare two boards, each given as its elements in drawing order. Elements are matched by id,
+// and listed in the drawing order of the version they come from.
+pub fn compare(from: u64, before: &[Frame], to: u64, after: &[Frame]) -> Diff {
+    let by_id = |frames: &[Frame]| -> HashMap<String, WhiteboardEvent> {
+        frames
+            .iter()
+            .filter_map(|frame| Some((frame.id.clone()?, frame.event.clone())))
+            .collect()
+    };
+    let (old, new) = (by_id(before), by_id(after));
+    let mut diff = Diff {
+        from,
+        to,
+        ..Diff::default()
+    };
+    diff.removed = before
+        .iter()
+        .filter(|frame| frame.id.as_ref().is_some_and(|id| !new.contains_key(id)))
+        .cloned()
+        .collect();
+    for frame in after {
+        let Some(id) = &frame.id else { continue };
+        match old.get(id) {
+            None => diff.added.push(frame.clone()),
+            Some(previous) if *previous == frame.event => diff.unchanged += 1,
+            Some(previous) => {
+                let changed = Changed {
+                    id: id.clone(),
+                    before: previous.clone(),
+                    after: frame.event.clone(),
+                };
+                match offset(previous, &frame.event) {
+                    Some(_) => diff.moved.push(changed),
+                    None => diff.modified.push(changed),
+                }
+            }
+        }
+    }
+    diff
+}
+
+// How far `after` is from `before`, if it is the same element only shifted
+fn offset(before: &WhiteboardEvent, after: &WhiteboardEvent) -> Option<(f64, f64)> {
+    let shift = |a: (f64, f64), b: (f64, f64)| (b.0 - a.0, b.1 - a.1);
+    // Every point must have moved by the same amount
+    let common = |shifts: &[(f64, f64)]| {
+        let first = *shifts.first()?;
+        let same = shifts.iter().all(|s| (s.0 - first.0).abs() < 1e-6 && (s.1 - first.1).abs() < 1e-6);
+        same.then_some(first)
+    };
+    let moved = match (before, after) {
+        (WhiteboardEvent::DrawStroke { points: a }, WhiteboardEvent::DrawStroke { points: b }) if a.len() == b.len() => {
+            common(&a.iter().zip(b).map(|(a, b)| shift(*a, *b)).collect::<Vec<_>>())
+        }
+        (
+            WhiteboardEvent::DrawLine { from: f1, to: t1, color: c1, width: w1 },
+            WhiteboardEvent::DrawLine { from: f2, to: t2, color: c2, width: w2 },
+        )
+        | (
+            WhiteboardEvent::DrawRect { from: f1, to: t1, color: c1, width: w1 },
+            WhiteboardEvent::DrawRect { from: f2, to: t2, color: c2, width: w2 },
+        ) if c1 == c2 && w1 == w2 => common(&[shift(*f1, *f2), shift(*t1, *t2)]),
+        (
+            WhiteboardEvent::DrawCircle { center: c1, radius: r1, color: k1, width: w1 },
+            WhiteboardEvent::DrawCircle { center: c2, radius: r2, color: k2, width: w2 },
+        ) if r1 == r2 && k1 == k2 && w1 == w2 => Some(shift(*c1, *c2)),
+        (
+            WhiteboardEvent::AddText { pos: p1, text: t1, color: c1, size: s1 },
+            WhiteboardEvent::AddText { pos: p2, text: t2, color: c2, size: s2 },
+        ) if t1 == t2 && c1 == c2 && s1 == s2 => Some(shift(*p1, *p2)),
+        (
+            WhiteboardEvent::AddImage { pos: p1, width: w1, height: h1, asset: a1 },
+            WhiteboardEvent::AddImage { pos: p2, width: w2, height: h2, asset: a2 },
+        ) if w1 == w2 && h1 == h2 && a1 == a2 => Some(shift(*p1, *p2)),
+        _ => None,
+    };
+    moved.filter(|(dx, dy)| *dx != 0.0 || *dy != 0.0)
+}
+
+// Reading the diff between versions is open, like the board itself
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/rooms/:room/diff", get(diff))
+}
+
+// One side of a diff
+enum Version {
+    Seq(u64),
+    Checkpoint(String),
+    Now,
+}
+
+impl Version {
+    fn pick(seq: Option<u64>, checkpoint: Option<String>, side: &str) -> Result<Self, (StatusCode, String)> {
+        match (seq, checkpoint) {
+            (Some(_), Some(_)) => Err((
+                StatusCode::BAD_REQUEST,
+                format!("give `{0}` or `{0}_checkpoint`, not both", side),
+            )),
+            (Some(seq), None) => Ok(Version::Seq(seq)),
+            (None, Some(name)) => Ok(Version::Checkpoint(name)),
+            (None, None) => Ok(Version::Now),
+        }
+    }
+}
+
+async fn diff(State(state): State<AppState>, Path(room): Path<String>, Query(params): Query<DiffParams>) -> Response {
+    if let Err(rejection) = state.existing_room(&room, |_, _| ()) {
+        return rejection.into_response();
+    }
+    let versions = Version::pick(params.from, params.from_checkpoint, "from").and_then(|from| {
+        if matches!(from, Version::Now) {
+            return Err((StatusCode::BAD_REQUEST, "a diff needs `from` or `from_checkpoint`".to_string()));
+        }
+        Ok((from, Version::pick(params.to, params.to_checkpoint, "to")?))
+    });
+    let (from, to) = match versions {
+        Ok(versions) => versions,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let before = match board_at(&state, &room, from).await {
+        Ok(board) => board,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let after = match board_at(&state, &room, to).await {
+        Ok(board) => board,
+        Err(rejection) => return rejection.into_response(),
+    };
+    Json(compare(before.0, &before.1, after.0, &after.1)).into_response()
+}
+
+// The room's board at `version`, with the seq it was at
+async fn board_at(state: &AppState, room: &str, version: Version) -> Result<(u64, Vec<Frame>), (StatusCode, String)> {
+    // Whatever the room has in memory; an older seq is left to the history below
+    let current = state.existing_room(room, |_, room_state| match &version {
+        Version::Now => Ok(Some((room_state.seq(), room_state.snapshot()))),
+        Version::Checkpoint(name) => match room_state.checkpoint(name) {
+            Some(checkpoint) => Ok(Some((checkpoint.seq, checkpoint.elements.clone()))),
+            None => Err((StatusCode::NOT_FOUND, format!("no checkpoint {:?}", name))),
+        },
+        Version::Seq(seq) if *seq == room_state.seq() => Ok(Some((*seq, room_state.snapshot()))),
+        Version::Seq(seq) if *seq > room_state.seq() => Err((
+            StatusCode::BAD_REQUEST,
+            format!("the room is only at seq {}", room_state.seq()),
+        )),
+        Version::Seq(_) => Ok(None),
+    })??;
+    if let Some(current) = current {
+        return Ok(current);
+    }
+    let Version::Seq(seq) = version else { unreachable!() };
+    // The room writes its log in the background; read it back only once it has caught up
+    if let Some(flushed) = state.existing_room(room, |_, room_state| room_state.flushed())? {
+        let _ = flushed.await;
+    }
+
+    // Older versions are rebuilt from the history on disk
+    let Some(store) = state.rooms.store().cloned() else {
+        return Err((StatusCode::NOT_FOUND, "room history is not persisted".to_string()));
+    };
+    let room = room.to_string();
+    let rebuilt = tokio::task::spawn_blocking(move || {
+        let history = store.history(&room).map_err(|e| {
+            error!(room, error = %e, "failed to read room history");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to read room history".to_string())
+        })?;
+        if seq < history.seq {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("history before seq {} is no longer kept", history.seq),
+            ));
+        }
+        let mut board = restore_board(history.doc, &history.elements);
+        for entry in history.entries.iter().take_while(|entry| entry.seq <= seq) {
+            replay_entry(&mut board, entry);
+        }
+        Ok((seq, board_frames(&board)))
+    });
+    rebuilt
+        .await
+        .unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(from: (f64, f64), to: (f64, f64), color: &str) -> WhiteboardEvent {
+        WhiteboardEvent::DrawLine {
+            from,
+            to,
+            color: color.to_string(),
+            width: 3.0,
+        }
+    }
+
+    fn frame(id: &str, event: WhiteboardEvent) -> Frame {
+        Frame::new(Some(id.to_string()), "alice", event)
+    }
+
+    fn frame_ids(frames: &[Frame]) -> Vec<&str> {
+        frames.iter().map(|frame| frame.id.as_deref().unwrap()).collect()
+    }
+
+    fn changed_ids(changes: &[Changed]) -> Vec<&str> {
+        changes.iter().map(|changed| changed.id.as_str()).collect()
+    }
+
+    #[test]
+    fn elements_are_sorted_into_added_removed_moved_and_modified() {
+        let before = [
+            frame("kept", line((0.0, 0.0), (10.0, 0.0), "#000000")),
+            frame("gone", line((0.0, 10.0), (10.0, 10.0), "#000000")),
+            frame("moved", line((0.0, 20.0), (10.0, 20.0), "#000000")),
+            frame("recoloured", line((0.0, 30.0), (10.0, 30.0), "#000000")),
+            frame("stretched", line((0.0, 40.0), (10.0, 40.0), "#000000")),
+        ];
+        let after = [
+            frame("new", line((0.0, 50.0), (10.0, 50.0), "#000000")),
+            frame("stretched", line((5.0, 45.0), (25.0, 45.0), "#000000")),
+            frame("recoloured", line((0.0, 30.0), (10.0, 30.0), "#ff0000")),
+            frame("moved", line((5.0, 25.0), (15.0, 25.0), "#000000")),
+            frame("kept", line((0.0, 0.0), (10.0, 0.0), "#000000")),
+        ];
+        let diff = compare(3, &before, 9, &after);
+
+        assert_eq!((diff.from, diff.to), (3, 9));
+        assert_eq!(frame_ids(&diff.added), ["new"]);
+        assert_eq!(frame_ids(&diff.removed), ["gone"]);
+        assert_eq!(changed_ids(&diff.moved), ["moved"]);
+        // Listed in the later version's drawing order
+        assert_eq!(changed_ids(&diff.modified), ["stretched", "recoloured"]);
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.moved[0].before, before[2].event);
+        assert_eq!(diff.moved[0].after, after[3].event);
+    }
+
+    #[test]
+    fn only_the_same_element_shifted_counts_as_moved() {
+        let stroke = |dx: f64, dy: f64| WhiteboardEvent::DrawStroke {
+            points: vec![(0.0 + dx, 0.0 + dy), (4.0 + dx, 2.0 + dy), (8.0 + dx, 0.0 + dy)],
+        };
+        assert_eq!(offset(&stroke(0.0, 0.0), &stroke(3.0, -2.0)), Some((3.0, -2.0)));
+        // A stroke with a point added is a different stroke
+        let longer = WhiteboardEvent::DrawStroke {
+            points: vec![(0.0, 0.0), (4.0, 2.0), (8.0, 0.0), (9.0, 1.0)],
+        };
+        assert_eq!(offset(&stroke(0.0, 0.0), &longer), None);
+
+        let circle = |x: f64, radius: f64| WhiteboardEvent::DrawCircle {
+            center: (x, 0.0),
+            radius,
+            color: "#000000".to_string(),
+            width: 2.0,
+        };
+        assert_eq!(offset(&circle(0.0, 5.0), &circle(7.0, 5.0)), Some((7.0, 0.0)));
+        assert_eq!(offset(&circle(0.0, 5.0), &circle(7.0, 6.0)), None);
+
+        // Not moving at all, or turning into something else, is not a move
+        assert_eq!(offset(&circle(0.0, 5.0), &circle(0.0, 5.0)), None);
+        assert_eq!(offset(&circle(0.0, 5.0), &stroke(0.0, 0.0)), None);
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::VecDeque;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use tracing::{debug, error};
+
+use crate::{
+    assets::Images,
+    pdf::{self, Layout, Paper, Region},
+    protocol::{BoardSnapshot, WhiteboardEvent},
+    render::{self, Bounds},
+    svg, AppState,
+};
+
+// Largest image side served, in pixels
+const MAX_SIZE: u32 = 4096;
+// Image width when neither width nor height is asked for
+const DEFAULT_WIDTH: u32 = 800;
+// Renders kept per room for its current board
+const MAX_CACHED: usize = 16;
+
+// Read-only views of a room's board. Anyone who can join a room can see its board,
+// so these are not behind the admin token.
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/rooms/:room/board.json", get(board_json))
+        .route("/rooms/:room/board.png", get(board_png))
+        .route("/rooms/:room/board.svg", get(board_svg))
+        .route("/rooms/:room/board.pdf", get(board_pdf))
+}
+
+// Query parameters accepted by `/rooms/<room>/board.png`, e.g. `?width=400&bbox=0,0,1200,800`.
+// `bbox` is the part of the board to show, in board coordinates; it defaults to
+// everything drawn. Given only one of `width`/`height`, the other follows the bbox.
+#[derive(Deserialize, Debug)]
+pub struct ImageParams {
+    width: Option<u32>,
+    height: Option<u32>,
+    bbox: Option<String>,
+}
+
+// Query parameters accepted by `/rooms/<room>/board.svg`, e.g. `?crop=true&download`.
+// Without `bbox` the image covers the web client's canvas and anything drawn outside it,
+// or with `crop=true` just what is drawn. `download` makes browsers save it as a file.
+#[derive(Deserialize, Debug)]
+pub struct SvgParams {
+    bbox: Option<String>,
+    #[serde(default)]
+    crop: bool,
+    download: Option<String>,
+}
+
+// Query parameters accepted by `/rooms/<room>/board.pdf`, e.g. `?paper=a3&layout=tile`.
+// `frames=Intro:0,0,600,400;Ideas:600,0,1200,400` exports each named region of the board
+// on pages of its own; otherwise the document covers `bbox`, or everything drawn.
+#[derive(Deserialize, Debug)]
+pub struct PdfParams {
+    #[serde(default)]
+    paper: Paper,
+    #[serde(default)]
+    layout: Layout,
+    bbox: Option<String>,
+    frames: Option<String>,
+    download: Option<String>,
+}
+
+// Renders of a room's board, dropped as soon as the board changes
+#[derive(Default)]
+pub struct RenderCache {
+    // The room's seq the renders were made at
+    seq: u64,
+    entries: VecDeque<(String, Bytes)>,
+}
+
+impl RenderCache {
+    pub fn get(&self, seq: u64, key: &str) -> Option<Bytes> {
+        if seq != self.seq {
+            return None;
+        }
+        self.entries
+            .iter()
+            .find(|(cached, _)| cached == key)
+            .map(|(_, render)| render.clone())
+    }
+
+    // Keep a render of the board as it was at `seq`, unless the board has moved on since
+    pub fn put(&mut self, seq: u64, key: String, render: Bytes) {
+        if seq < self.seq {
+            return;
+        }
+        if seq > self.seq {
+            self.seq = seq;
+            self.entries.clear();
+        }
+        self.entries.retain(|(cached, _)| *cached != key);
+        if self.entries.len() >= MAX_CACHED {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, render));
+    }
+}
+
+async fn board_json(State(state): State<AppState>, Path(room): Path<String>) -> Response {
+    let board = state.existing_room(&room, |_, room_state| BoardSnapshot {
+        seq: room_state.seq(),
+        elements: room_state.snapshot(),
+    });
+    match board {
+        Ok(board) => Json(board).into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn board_png(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Query(params): Query<ImageParams>,
+) -> Response {
+    let bbox = match parse_bbox(params.bbox.as_deref()) {
+        Ok(bbox) => bbox,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let valid = |size: Option<u32>| size.is_none_or(|size| (1..=MAX_SIZE).contains(&size));
+    if !valid(params.width) || !valid(params.height) {
+        return (StatusCode::BAD_REQUEST, format!("width and height must be 1 to {} pixels", MAX_SIZE)).into_response();
+    }
+
+    let key = format!("png:{:?}:{:?}:{:?}", params.width, params.height, params.bbox);
+    let rendered = cached_render(&state, &room, key, move |elements, images| {
+        let bounds = bbox.unwrap_or_else(|| Bounds::content(elements));
+        let (width, height) = image_size(bounds, params.width, params.height);
+        render::png(elements, images, bounds, width, height).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+    })
+    .await;
+    match rendered {
+        Ok(png) => ([(header::CONTENT_TYPE, "image/png"), (header::CACHE_CONTROL, "no-cache")], png).into_response(),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn board_svg(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Query(params): Query<SvgParams>,
+) -> Response {
+    let bbox = match parse_bbox(params.bbox.as_deref()) {
+        Ok(bbox) => bbox,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let key = format!("svg:{}:{:?}", params.crop, params.bbox);
+    let rendered = cached_render(&state, &room, key, move |elements, images| {
+        let bounds = match bbox {
+            Some(bbox) => bbox,
+            None if params.crop => Bounds::content(elements),
+            None => Bounds::canvas(elements),
+        };
+        Ok(svg::svg(elements, images, bounds).into_bytes())
+    })
+    .await;
+    match rendered {
+        Ok(svg) => {
+            let mut response = (
+                [(header::CONTENT_TYPE, "image/svg+xml"), (header::CACHE_CONTROL, "no-cache")],
+                svg,
+            )
+                .into_response();
+            if params.download.is_some() {
+                response.headers_mut().insert(header::CONTENT_DISPOSITION, attachment(&room, "svg"));
+            }
+            response
+        }
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn board_pdf(
+    State(state): State<AppState>,
+    Path(room): Path<String>,
+    Query(params): Query<PdfParams>,
+) -> Response {
+    let bbox = match parse_bbox(params.bbox.as_deref()) {
+        Ok(bbox) => bbox,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let frames = params
+        .frames
+        .as_deref()
+        .map(|frames| frames.split(';').filter(|frame| !frame.trim().is_empty()).map(Region::parse).collect())
+        .transpose();
+    let frames: Option<Vec<Region>> = match frames {
+        Ok(frames) => frames,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let key = format!("pdf:{:?}:{:?}:{:?}:{:?}", params.paper, params.layout, params.bbox, params.frames);
+    let title = room.clone();
+    let rendered = cached_render(&state, &room, key, move |elements, images| {
+        let regions = frames.unwrap_or_else(|| {
+            vec![Region {
+                name: title.clone(),
+                bounds: bbox.unwrap_or_else(|| Bounds::content(elements)),
+            }]
+        });
+        let pages = pdf::pages(params.paper, params.layout, regions).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        Ok(pdf::pdf(elements, images, &title, &pages))
+    })
+    .await;
+    match rendered {
+        Ok(pdf) => {
+            let mut response = (
+                [(header::CONTENT_TYPE, "application/pdf"), (header::CACHE_CONTROL, "no-cache")],
+                pdf,
+            )
+                .into_response();
+            if params.download.is_some() {
+                response.headers_mut().insert(header::CONTENT_DISPOSITION, attachment(&room, "pdf"));
+            }
+            response
+        }
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+// Parse the requested bounding box, if any
+fn parse_bbox(bbox: Option<&str>) -> Result<Option<Bounds>, (StatusCode, String)> {
+    bbox.map(Bounds::parse)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))
+}
+
+// Render the room's current board with `render`, or serve the render cached under `key`
+// if the board has not changed since
+async fn cached_render<F>(state: &AppState, room: &str, key: String, render: F) -> Result<Bytes, (StatusCode, String)>
+where
+    F: FnOnce(&[WhiteboardEvent], &Images) -> Result<Vec<u8>, (StatusCode, String)> + Send + 'static,
+{
+    let board = state.existing_room(room, |room_handle, room_state| {
+        let seq = room_state.seq();
+        match room_state.renders().get(seq, &key) {
+            Some(cached) => Err(cached),
+            None => {
+                let elements: Vec<_> = room_state.snapshot().into_iter().map(|frame| frame.event).collect();
+                Ok((room_handle.clone(), seq, elements))
+            }
+        }
+    })?;
+    let (room_handle, seq, elements) = match board {
+        Ok(board) => board,
+        Err(cached) => {
+            debug!(room, key, "served cached board render");
+            return Ok(cached);
+        }
+    };
+
+    // Rendering takes a while for big boards; keep it off the async workers and the room lock
+    let assets = state.assets.clone();
+    let rendered = tokio::task::spawn_blocking(move || {
+        let images = assets.map(|assets| assets.images(&elements)).unwrap_or_default();
+        render(&elements, &images)
+    })
+        .await
+        .unwrap_or_else(|e| Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())));
+    match rendered {
+        Ok(rendered) => {
+            let rendered = Bytes::from(rendered);
+            room_handle.lock().unwrap().renders().put(seq, key, rendered.clone());
+            Ok(rendered)
+        }
+        Err((status, e)) if status.is_server_error() => {
+            error!(room, key, error = %e, "failed to render board");
+            Err((status, "failed to render board".to_string()))
+        }
+        Err(rejection) => Err(rejection),
+    }
+}
+
+// `Content-Disposition` saving the download as `<room>.<extension>`
+fn attachment(room: &str, extension: &str) -> HeaderValue {
+    // Keep the file name to characters that need no quoting in any browser
+    let name: String = room
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    HeaderValue::from_str(&format!("attachment; filename=\"{}.{}\"", name, extension)).unwrap()
+}
+
+// Pixel size for showing `bounds`, filling in whichever side was not asked for
+fn image_size(bounds: Bounds, width: Option<u32>, height: Option<u32>) -> (u32, u32) {
+    let aspect = bounds.width() / bounds.height();
+    let fit = |size: f64| (size.round() as u32).clamp(1, MAX_SIZE);
+    match (width, height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, fit(width as f64 / aspect)),
+        (None, Some(height)) => (fit(height as f64 * aspect), height),
+        (None, None) => (DEFAULT_WIDTH, fit(DEFAULT_WIDTH as f64 / aspect)),
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::Instant,
+};
+
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, error::TrySendError},
+};
+use tracing::warn;
+
+use crate::{
+    crdt::{StateVector, Update},
+    protocol::Frame,
+};
+
+// How many messages a tap can fall behind by before it is cut off
+pub const TAP_CAPACITY: usize = 4096;
+
+// A message as a tap gets it, with when it was published
+pub type Tapped = (Instant, String);
+
+// Fan-out of serialized events to every connection in a room.
+// The default `LocalHub` only reaches sockets in this process;
+// `relay::RelayHub` also forwards to other backend instances.
+pub trait Hub: Send + Sync + 'static {
+    // Subscribe to everything published in `room`, wherever it was published
+    fn subscribe(&self, room: &str) -> broadcast::Receiver<String>;
+
+    // Publish a message to every subscriber of `room`
+    fn publish(&self, room: &str, msg: String);
+
+    // Everything published in `room` from now on, each with when it was published, for
+    // recording it. Unlike a subscriber, a tap never skips a message: one that falls
+    // `TAP_CAPACITY` messages behind is closed instead, so it ends rather than has gaps.
+    fn tap(&self, room: &str) -> mpsc::Receiver<Tapped>;
+
+    // Publish a change to the room's board. `msgs` go to this instance's
+    // subscribers; other instances get `update` instead, merge it into their
+    // copy of the board and tell their own subscribers what changed.
+    fn publish_change(&self, room: &str, msgs: Vec<String>, _update: Update) {
+        for msg in msgs {
+            self.publish(room, msg);
+        }
+    }
+
+    // Ask other instances for whatever changes to `room` this one is missing
+    fn sync(&self, _room: &str, _state: &StateVector) {}
+
+    // Forget `room` if nobody is subscribed to it any more, e.g. once it has been evicted
+    fn close(&self, _room: &str) {}
+}
+
+// Publish the frames a room produced, along with the board changes behind them
+pub fn publish_frames(hub: &dyn Hub, room: &str, frames: Vec<Frame>, update: Update) {
+    let (changes, others): (Vec<_>, Vec<_>) = frames.into_iter().partition(|frame| frame.event.changes_board());
+    for frame in others {
+        hub.publish(room, serde_json::to_string(&frame).unwrap());
+    }
+    if !changes.is_empty() || !update.is_empty() {
+        let msgs = changes.iter().map(|frame| serde_json::to_string(frame).unwrap()).collect();
+        hub.publish_change(room, msgs, update);
+    }
+}
+
+// In-process hub: one broadcast channel per room
+pub struct LocalHub {
+    rooms: Mutex<HashMap<String, broadcast::Sender<String>>>,
+    capacity: usize,
+    taps: Mutex<HashMap<String, Vec<mpsc::Sender<Tapped>>>>,
+}
+
+impl LocalHub {
+    pub fn new(capacity: usize) -> Self {
+        LocalHub {
+            rooms: Mutex::new(HashMap::new()),
+            capacity,
+            taps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn channel(&self, room: &str) -> broadcast::Sender<String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms
+            .entry(room.to_string())
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .clone()
+    }
+}
+
+impl Hub for LocalHub {
+    fn subscribe(&self, room: &str) -> broadcast::Receiver<String> {
+        self.channel(room).subscribe()
+    }
+
+    fn publish(&self, room: &str, msg: String) {
+        let mut taps = self.taps.lock().unwrap();
+        if let Some(room_taps) = taps.get_mut(room) {
+            let now = Instant::now();
+            room_taps.retain(|tap| match tap.try_send((now, msg.clone())) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(room, "tap fell too far behind, closed it");
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+            if room_taps.is_empty() {
+                taps.remove(room);
+            }
+        }
+        drop(taps);
+        // Nobody listening is not an error
+        let _ = self.channel(room).send(msg);
+    }
+
+    fn tap(&self, room: &str) -> mpsc::Receiver<Tapped> {
+        let (tx, rx) = mpsc::channel(TAP_CAPACITY);
+        self.taps.lock().unwrap().entry(room.to_string()).or_default().push(tx);
+        rx
+    }
+
+    fn close(&self, room: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.get(room).is_some_and(|channel| channel.receiver_count() == 0) {
+            rooms.remove(room);
+        }
+    }
+}
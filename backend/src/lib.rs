@@ -0,0 +1,143 @@
+// The whiteboard server as a library, so it can also be started in-process, e.g. by tests
+use axum::{http::StatusCode, middleware, routing::get, Router};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+mod admin;
+mod assets;
+mod checkpoints;
+pub mod config;
+mod crdt;
+mod diff;
+mod export;
+mod hub;
+mod metrics;
+pub mod origin;
+mod outbound;
+mod owner;
+mod pdf;
+pub mod protocol;
+pub mod recording;
+mod relay;
+mod render;
+mod replay;
+mod room;
+mod socket;
+mod store;
+mod svg;
+pub mod telemetry;
+mod templates;
+pub mod tls;
+mod webhook;
+
+use config::Config;
+use assets::Assets;
+use hub::{Hub, LocalHub};
+use outbound::QueueConfig;
+use owner::OwnerKey;
+use recording::Recordings;
+use relay::RelayHub;
+use room::{Room, Rooms};
+use store::Store;
+use templates::Templates;
+use webhook::Webhooks;
+
+// Shared state handed to every request handler
+#[derive(Clone)]
+struct AppState {
+    hub: Arc<dyn Hub>,
+    rooms: Arc<Rooms>,
+    // Bearer token for the admin HTTP API; the API is disabled without one
+    admin_token: Option<Arc<str>>,
+    // Uploaded images; uploads are refused without somewhere to keep them
+    assets: Option<Assets>,
+    // Layouts new boards can start from
+    templates: Arc<Templates>,
+    client_queue: QueueConfig,
+    recordings: Arc<Recordings>,
+}
+
+
+impl AppState {
+    // Run `f` on a room for the read-only HTTP API. Reading a room that was never used does
+    // not create it: that is a 404.
+    fn existing_room<R>(
+        &self,
+        room: &str,
+        f: impl FnOnce(&Arc<Mutex<Room>>, &mut Room) -> R,
+    ) -> Result<R, (StatusCode, String)> {
+        if room.is_empty() || room.len() > 64 {
+            return Err((StatusCode::BAD_REQUEST, "room names must be 1 to 64 bytes".to_string()));
+        }
+        self.rooms
+            .with_existing(room, f)
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "no such room".to_string()))
+    }
+}
+
+pub use render::init_font;
+
+// Build the server's routes and start its background work (compaction, eviction, the relay).
+// Must be served with `into_make_service_with_connect_info::<SocketAddr>()`.
+pub async fn router(config: &Config) -> Result<Router, String> {
+    let store = config.data_dir.clone().map(|dir| {
+        info!(dir = %dir.display(), "persisting rooms");
+        Store::new(dir, config.compact_tail, config.retain_segments)
+    });
+    let assets = config.asset_dir.clone().map(|dir| {
+        info!(dir = %dir.display(), "storing uploaded images");
+        Assets::new(dir)
+    });
+    let templates = Arc::new(Templates::load(config.template_dir.clone()));
+    if let Some(dir) = &config.recording_dir {
+        info!(dir = %dir.display(), "keeping session recordings");
+    }
+    let recordings = Arc::new(Recordings::new(config.recording_dir.clone()));
+    let hooks = Webhooks::new(config.webhooks.clone(), config.webhook_delivery);
+    let owner_key = OwnerKey::load(config)?;
+    let rooms = Arc::new(Rooms::new(store, config.room_retention, hooks, owner_key));
+    rooms.spawn_compactor(config.compact_interval);
+
+    // Room-scoped fan-out, relayed to other instances when configured
+    let local = LocalHub::new(100);
+    let hub: Arc<dyn Hub> = if config.relay_listen.is_some() || !config.relay_peers.is_empty() {
+        let Some(secret) = &config.relay_secret else {
+            return Err("the relay needs a secret to authenticate links with".to_string());
+        };
+        let relay = RelayHub::new(local, rooms.clone(), secret);
+        if let Some(addr) = config.relay_listen {
+            if let Err(e) = relay.listen(addr).await {
+                return Err(format!("failed to bind relay listener on {}: {}", addr, e));
+            }
+        }
+        for peer in &config.relay_peers {
+            relay.connect(peer.clone());
+        }
+        relay
+    } else {
+        Arc::new(local)
+    };
+    rooms.spawn_reaper(config.room_idle, hub.clone());
+
+    Ok(Router::new()
+        .route("/ws", get(socket::ws_handler))
+        .route("/ws/replay", get(replay::replay_handler))
+        .merge(admin::routes())
+        .merge(export::routes())
+        .merge(assets::routes(config.asset_max_bytes))
+        .merge(templates::routes())
+        .merge(checkpoints::routes())
+        .merge(diff::routes())
+        .merge(recording::routes())
+        .merge(metrics::routes())
+        .with_state(AppState {
+            hub,
+            rooms,
+            admin_token: config.admin_token.clone(),
+            assets,
+            templates,
+            client_queue: config.client_queue,
+            recordings,
+        })
+        .layer(middleware::from_fn_with_state(Arc::new(config.allowed_origins.clone()), origin::check)))
+}
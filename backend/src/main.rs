@@ -1,77 +1,61 @@
-use axum::{
-    extract::ws::{WebSocketUpgrade, WebSocket, Message},
-    extract::State,
-    response::IntoResponse,
-    routing::get,
-    Router,
-};
-use futures_util::{StreamExt, SinkExt};
-use std::{
-    net::SocketAddr,
-    sync::Arc,
-};
-use tokio::sync::broadcast;
-use serde::{Serialize, Deserialize};
+use axum_server::{accept::NoDelayAcceptor, tls_rustls::RustlsAcceptor};
+use std::net::SocketAddr;
+use tracing::{error, info};
 
-// Type alias for the broadcast channel sender
-// We'll use this to broadcast messages to all clients
-// Each client gets a receiver (subscriber)
-type Tx = broadcast::Sender<String>;
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(tag = "type")]
-pub enum WhiteboardEvent {
-    DrawFreehand { x: f64, y: f64, dragging: bool },
-    DrawLine { from: (f64, f64), to: (f64, f64), color: String, width: f64 },
-    DrawRect { from: (f64, f64), to: (f64, f64), color: String, width: f64 },
-    DrawCircle { center: (f64, f64), radius: f64, color: String, width: f64 },
-    AddText { pos: (f64, f64), text: String, color: String, size: f64 },
-    Pan { dx: f64, dy: f64 },
-    Zoom { factor: f64 },
-}
+use backend::{config::Config, telemetry, tls};
 
 #[tokio::main]
 async fn main() {
-    // Create a broadcast channel for drawing events
-    let (tx, _rx) = broadcast::channel::<String>(100);
-    let app = Router::new()
-        .route("/ws", get(ws_handler))
-        .with_state(Arc::new(tx));
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    println!("Backend running at ws://{}", addr);
-    axum::serve(
-        tokio::net::TcpListener::bind(addr).await.unwrap(),
-        app
-    )
-    .await
-    .unwrap();
-}
-
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    State(tx): State<Arc<Tx>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, tx))
-}
-
-async fn handle_socket(socket: WebSocket, tx: Arc<Tx>) {
-    let mut rx = tx.subscribe();
-    let (mut sender, mut receiver) = socket.split();
-
-    // Task to forward broadcast messages to this client
-    let send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg)).await.is_err() {
-                break;
-            }
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("configuration error: {}", e);
+            std::process::exit(2);
         }
-    });
-
-    // Receive messages from this client and broadcast them
-    while let Some(Ok(Message::Text(msg))) = receiver.next().await {
-        let _ = tx.send(msg);
+    };
+    telemetry::init(config.log_format);
+    backend::init_font(config.font.as_deref());
+
+    let app = match backend::router(&config).await {
+        Ok(app) => app,
+        Err(e) => {
+            error!(error = %e, "failed to start");
+            std::process::exit(1);
+        }
+    };
+
+    // Both listeners send every event as it comes rather than waiting to fill packets
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let result = match config.tls {
+        Some(tls) => {
+            let rustls_config = match tls::load(&tls).await {
+                Ok(rustls_config) => rustls_config,
+                Err(e) => {
+                    error!(cert = %tls.cert.display(), key = %tls.key.display(), error = %e, "failed to load TLS certificate");
+                    std::process::exit(1);
+                }
+            };
+            tls::spawn_reloader(rustls_config.clone(), tls);
+            info!(addr = %config.addr, "backend running at wss://{}", config.addr);
+            let acceptor = RustlsAcceptor::new(rustls_config).acceptor(NoDelayAcceptor::new());
+            axum_server::bind(config.addr)
+                .acceptor(acceptor)
+                .serve(service)
+                .await
+        }
+        None => {
+            let listener = match tokio::net::TcpListener::bind(config.addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(addr = %config.addr, error = %e, "failed to bind");
+                    std::process::exit(1);
+                }
+            };
+            info!(addr = %config.addr, "backend running at ws://{}", config.addr);
+            axum::serve(listener, service).tcp_nodelay(true).await
+        }
+    };
+    if let Err(e) = result {
+        error!(error = %e, "server error");
     }
-
-    send_task.abort();
 }
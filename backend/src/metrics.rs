@@ -0,0 +1,78 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+
+use crate::{outbound, AppState};
+
+// Process-wide counters, served at `GET /metrics` in the Prometheus text format
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Idle rooms evicted from memory, by what happened to their history
+pub static ROOMS_EVICTED_PERSISTED: Counter = Counter::new();
+pub static ROOMS_EVICTED_DISCARDED: Counter = Counter::new();
+// Messages slow clients never got because their queue was full, and clients disconnected for it
+pub static CLIENT_MESSAGES_SHED: Counter = Counter::new();
+pub static CLIENTS_DISCONNECTED_SLOW: Counter = Counter::new();
+// Requests refused because the page they came from is not allowed
+pub static ORIGINS_REJECTED: Counter = Counter::new();
+// Webhook notifications dropped because their endpoint fell too far behind
+pub static WEBHOOK_NOTIFICATIONS_DROPPED: Counter = Counter::new();
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/metrics", get(metrics))
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP wb_rooms_loaded Rooms currently held in memory");
+    let _ = writeln!(out, "# TYPE wb_rooms_loaded gauge");
+    let _ = writeln!(out, "wb_rooms_loaded {}", state.rooms.len());
+    let _ = writeln!(out, "# HELP wb_rooms_evicted_total Idle rooms evicted from memory");
+    let _ = writeln!(out, "# TYPE wb_rooms_evicted_total counter");
+    let _ = writeln!(out, "wb_rooms_evicted_total{{retention=\"persist\"}} {}", ROOMS_EVICTED_PERSISTED.get());
+    let _ = writeln!(out, "wb_rooms_evicted_total{{retention=\"discard\"}} {}", ROOMS_EVICTED_DISCARDED.get());
+    let _ = writeln!(out, "# HELP wb_client_queue_depth Messages waiting to be sent to each connection");
+    let _ = writeln!(out, "# TYPE wb_client_queue_depth gauge");
+    for (conn, room, depth) in outbound::depths() {
+        let _ = writeln!(out, "wb_client_queue_depth{{conn=\"{}\",room=\"{}\"}} {}", conn, label(&room), depth);
+    }
+    let _ = writeln!(out, "# HELP wb_client_messages_shed_total Messages dropped or coalesced away for slow clients");
+    let _ = writeln!(out, "# TYPE wb_client_messages_shed_total counter");
+    let _ = writeln!(out, "wb_client_messages_shed_total {}", CLIENT_MESSAGES_SHED.get());
+    let _ = writeln!(out, "# HELP wb_clients_disconnected_slow_total Clients disconnected for falling too far behind");
+    let _ = writeln!(out, "# TYPE wb_clients_disconnected_slow_total counter");
+    let _ = writeln!(out, "wb_clients_disconnected_slow_total {}", CLIENTS_DISCONNECTED_SLOW.get());
+    let _ = writeln!(out, "# HELP wb_origins_rejected_total Requests refused for coming from a disallowed origin");
+    let _ = writeln!(out, "# TYPE wb_origins_rejected_total counter");
+    let _ = writeln!(out, "wb_origins_rejected_total {}", ORIGINS_REJECTED.get());
+    let _ = writeln!(out, "# HELP wb_webhook_notifications_dropped_total Webhook notifications dropped for endpoints too far behind");
+    let _ = writeln!(out, "# TYPE wb_webhook_notifications_dropped_total counter");
+    let _ = writeln!(out, "wb_webhook_notifications_dropped_total {}", WEBHOOK_NOTIFICATIONS_DROPPED.get());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
+
+// A label value with the characters the text format reserves escaped
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,91 @@
+// Which web pages may use the backend. Browsers say which page a request comes from in
+// `Origin`, on WebSocket upgrades as well as cross-origin HTTP requests; requests from pages
+// not on the allow-list are refused, so no other site can connect its visitors to a board.
+// Requests without `Origin` come from tools like `wb` and curl rather than a page, and pass.
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+use crate::metrics::ORIGINS_REJECTED;
+
+// Methods and request headers pages may use, and how long browsers may cache that answer
+const ALLOW_METHODS: &str = "GET, POST, DELETE";
+const ALLOW_HEADERS: &str = "authorization, content-type";
+const MAX_AGE_SECS: &str = "600";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AllowedOrigins {
+    Any,
+    // Normalised origins, e.g. `https://board.example.com`
+    Only(Vec<String>),
+}
+
+impl AllowedOrigins {
+    // A comma-separated list of origins, or `*` for any
+    pub fn parse(list: &str) -> Result<Self, String> {
+        if list.trim() == "*" {
+            return Ok(AllowedOrigins::Any);
+        }
+        list.split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                let url = reqwest::Url::parse(origin).map_err(|e| format!("{:?}: {}", origin, e))?;
+                let bare = url.path() == "/" && url.query().is_none() && url.fragment().is_none();
+                if !matches!(url.scheme(), "http" | "https") || url.host().is_none() || !bare {
+                    return Err(format!("{:?}: expected scheme://host[:port]", origin));
+                }
+                Ok(url.origin().ascii_serialization())
+            })
+            .collect::<Result<_, _>>()
+            .map(AllowedOrigins::Only)
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigins::Any => true,
+            AllowedOrigins::Only(origins) => origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+        }
+    }
+}
+
+// Refuse requests from pages that are not allowed, and give allowed ones the CORS headers
+// browsers need to read the answers
+pub async fn check(
+    State(allowed): State<Arc<AllowedOrigins>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(origin) = request.headers().get(header::ORIGIN).cloned() else {
+        return next.run(request).await;
+    };
+    if !origin.to_str().is_ok_and(|origin| allowed.allows(origin)) {
+        warn!(%peer, origin = ?origin, method = %request.method(), path = %request.uri().path(), "rejected request from a disallowed origin");
+        ORIGINS_REJECTED.inc();
+        return (StatusCode::FORBIDDEN, "origin not allowed").into_response();
+    }
+
+    let preflight = request.method() == Method::OPTIONS && request.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    let mut response = if preflight {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static(ALLOW_METHODS));
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static(ALLOW_HEADERS));
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(MAX_AGE_SECS));
+        response
+    } else {
+        next.run(request).await
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    // Answers differ by origin, so caches must keep them apart
+    headers.append(header::VARY, HeaderValue::from_static("origin"));
+    response
+}
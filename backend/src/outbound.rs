@@ -0,0 +1,397 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{Arc, LazyLock, Mutex, Weak},
+};
+
+use axum::extract::ws::CloseFrame;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::{
+    metrics,
+    protocol::{Frame, WhiteboardEvent},
+};
+
+// Close code sent to clients disconnected for falling too far behind
+pub const CLOSE_SLOW: u16 = 4002;
+
+// What a connection's outbound queue does once it is full. No change to the board is ever
+// lost, so whatever cannot make room disconnects the client, which then starts
+// again from a fresh snapshot when it reconnects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowClient {
+    // Fold queued messages that newer ones make redundant into those
+    Coalesce,
+    // Drop view changes, which only matter while they are fresh
+    DropEphemeral,
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    // Most messages queued for one client
+    pub capacity: usize,
+    pub policy: SlowClient,
+}
+
+// Every connection's queue by connection, for metrics
+static QUEUES: LazyLock<Mutex<BTreeMap<u64, Registered>>> = LazyLock::new(Default::default);
+
+struct Registered {
+    room: String,
+    queue: Weak<Outbound>,
+}
+
+// Messages waiting to be written to one client's socket
+pub struct Outbound {
+    conn: u64,
+    config: QueueConfig,
+    queue: Mutex<Queue>,
+    ready: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Queued>,
+    // Set once the connection is to be closed, with the close frame to send, if any
+    closing: Option<Option<CloseFrame<'static>>>,
+}
+
+struct Queued {
+    text: String,
+    // The message parsed, once a policy needed to look at it; `None` inside if it is not a frame
+    frame: Option<Option<Frame>>,
+}
+
+impl Queued {
+    fn frame(&mut self) -> Option<&Frame> {
+        self.frame
+            .get_or_insert_with(|| serde_json::from_str(&self.text).ok())
+            .as_ref()
+    }
+}
+
+// What the writer should do next
+pub enum Next {
+    Send(String),
+    Close(Option<CloseFrame<'static>>),
+}
+
+impl Outbound {
+    pub fn new(conn: u64, room: &str, config: QueueConfig) -> Arc<Self> {
+        let outbound = Arc::new(Outbound {
+            conn,
+            config,
+            queue: Mutex::new(Queue::default()),
+            ready: Notify::new(),
+        });
+        QUEUES
+            .lock()
+            .unwrap()
+            .insert(
+                conn,
+                Registered {
+                    room: room.to_string(),
+                    queue: Arc::downgrade(&outbound),
+                },
+            );
+        outbound
+    }
+
+    // Queue a message for the client. Returns false once the connection is closing.
+    pub fn push(&self, text: String) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closing.is_some() {
+            return false;
+        }
+        let mut message = Queued { text, frame: None };
+        if queue.messages.len() >= self.config.capacity {
+            let before = queue.messages.len();
+            let keep = match self.config.policy {
+                SlowClient::Coalesce => {
+                    coalesce(&mut queue.messages);
+                    true
+                }
+                SlowClient::DropEphemeral => {
+                    queue.messages.retain_mut(|queued| !is_ephemeral(queued.frame()));
+                    !is_ephemeral(message.frame())
+                }
+                SlowClient::Disconnect => true,
+            };
+            let freed = before - queue.messages.len();
+            if freed > 0 || !keep {
+                let shed = freed + usize::from(!keep);
+                metrics::CLIENT_MESSAGES_SHED.add(shed as u64);
+                debug!(conn = self.conn, shed, policy = ?self.config.policy, "shed messages for a slow client");
+            }
+            if !keep {
+                return true;
+            }
+            if queue.messages.len() >= self.config.capacity {
+                warn!(conn = self.conn, queued = queue.messages.len(), "disconnecting client that fell too far behind");
+                metrics::CLIENTS_DISCONNECTED_SLOW.inc();
+                queue.messages.clear();
+                queue.closing = Some(Some(CloseFrame {
+                    code: CLOSE_SLOW,
+                    reason: "too far behind the room".into(),
+                }));
+                self.ready.notify_one();
+                return false;
+            }
+        }
+        queue.messages.push_back(message);
+        self.ready.notify_one();
+        true
+    }
+
+    // Close the connection ahead of anything still queued, sending `frame` if given
+    pub fn close(&self, frame: Option<CloseFrame<'static>>) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closing.is_none() {
+            queue.closing = Some(frame);
+        }
+        self.ready.notify_one();
+    }
+
+    // Wait for the next thing to write
+    pub async fn next(&self) -> Next {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(frame) = queue.closing.take() {
+                    // Keep refusing messages once closed
+                    queue.closing = Some(None);
+                    return Next::Close(frame);
+                }
+                if let Some(message) = queue.messages.pop_front() {
+                    return Next::Send(message.text);
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.queue.lock().unwrap().messages.len()
+    }
+}
+
+impl Drop for Outbound {
+    fn drop(&mut self) {
+        QUEUES.lock().unwrap().remove(&self.conn);
+    }
+}
+
+// Queue depth of every connection, with its room, for metrics
+pub fn depths() -> Vec<(u64, String, usize)> {
+    let queues: Vec<_> = QUEUES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(conn, registered)| (*conn, registered.room.clone(), registered.queue.clone()))
+        .collect();
+    queues
+        .into_iter()
+        .filter_map(|(conn, room, queue)| Some((conn, room, queue.upgrade()?.depth())))
+        .collect()
+}
+
+// View changes: a client that missed some only sees someone else's view a little late
+fn is_ephemeral(frame: Option<&Frame>) -> bool {
+    frame.is_some_and(|frame| matches!(frame.event, WhiteboardEvent::Pan { .. } | WhiteboardEvent::Zoom { .. }))
+}
+
+// Drop queued messages that a newer queued message makes redundant, and fold each user's
+// pans and zooms into their newest one. The client ends up with the same board and room
+// state as if it had seen everything.
+fn coalesce(messages: &mut VecDeque<Queued>) {
+    // What newer messages, already seen walking back from the newest, make redundant
+    let mut replaced = HashSet::new();
+    let mut board_reset = false;
+    let mut room_state = false;
+    let mut checkpoints = false;
+    // Index of each user's newest pan and zoom, and what older ones add to them
+    let mut pans: HashMap<String, (usize, f64, f64)> = HashMap::new();
+    let mut zooms: HashMap<String, (usize, f64)> = HashMap::new();
+    let mut redundant = vec![false; messages.len()];
+
+    for index in (0..messages.len()).rev() {
+        let Some(frame) = messages[index].frame() else { continue };
+        let user = frame.user.clone().unwrap_or_default();
+        let element = frame.id.as_deref();
+        redundant[index] = match &frame.event {
+            WhiteboardEvent::RoomState { .. } => std::mem::replace(&mut room_state, true),
+            WhiteboardEvent::Checkpoints { .. } => std::mem::replace(&mut checkpoints, true),
+            WhiteboardEvent::ClearBoard | WhiteboardEvent::ResetBoard { .. } => std::mem::replace(&mut board_reset, true),
+            event if event.is_element() || matches!(event, WhiteboardEvent::DeleteElement | WhiteboardEvent::DrawFreehand { .. }) => {
+                let Some(id) = element else { continue };
+                let superseded = board_reset || replaced.contains(id);
+                // A point added to a stroke does not stand in for what came before it
+                if !matches!(event, WhiteboardEvent::DrawFreehand { .. }) {
+                    replaced.insert(id.to_string());
+                }
+                superseded
+            }
+            WhiteboardEvent::Pan { dx, dy } => match pans.get_mut(&user) {
+                Some((_, x, y)) => {
+                    *x += dx;
+                    *y += dy;
+                    true
+                }
+                None => {
+                    pans.insert(user, (index, 0.0, 0.0));
+                    false
+                }
+            },
+            WhiteboardEvent::Zoom { factor } => match zooms.get_mut(&user) {
+                Some((_, total)) => {
+                    *total *= factor;
+                    true
+                }
+                None => {
+                    zooms.insert(user, (index, 1.0));
+                    false
+                }
+            },
+            _ => false,
+        };
+    }
+
+    // Fold the dropped pans and zooms into the ones kept
+    for (index, extra_x, extra_y) in pans.into_values() {
+        if extra_x == 0.0 && extra_y == 0.0 {
+            continue;
+        }
+        fold(&mut messages[index], |event| {
+            if let WhiteboardEvent::Pan { dx, dy } = event {
+                *dx += extra_x;
+                *dy += extra_y;
+            }
+        });
+    }
+    for (index, extra) in zooms.into_values() {
+        if extra == 1.0 {
+            continue;
+        }
+        fold(&mut messages[index], |event| {
+            if let WhiteboardEvent::Zoom { factor } = event {
+                *factor *= extra;
+            }
+        });
+    }
+
+    let mut index = 0;
+    messages.retain(|_| {
+        index += 1;
+        !redundant[index - 1]
+    });
+}
+
+// Change a queued frame's event and write it out again
+fn fold(message: &mut Queued, change: impl FnOnce(&mut WhiteboardEvent)) {
+    let Some(Some(frame)) = &mut message.frame else { return };
+    change(&mut frame.event);
+    message.text = serde_json::to_string(frame).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(frame: Frame) -> Queued {
+        Queued {
+            text: serde_json::to_string(&frame).unwrap(),
+            frame: None,
+        }
+    }
+
+    fn line(id: &str, x: f64) -> Frame {
+        let event = WhiteboardEvent::DrawLine {
+            from: (x, 0.0),
+            to: (10.0, 10.0),
+            color: "#2563eb".to_string(),
+            width: 3.0,
+        };
+        Frame::new(Some(id.to_string()), "alice", event)
+    }
+
+    fn view(user: &str, event: WhiteboardEvent) -> Frame {
+        Frame::new(None, user, event)
+    }
+
+    // Coalesce `frames` as a full queue would, returning what is left to send
+    fn coalesced(frames: Vec<Frame>) -> Vec<Frame> {
+        let mut messages: VecDeque<Queued> = frames.into_iter().map(queued).collect();
+        coalesce(&mut messages);
+        messages.iter().map(|message| serde_json::from_str(&message.text).unwrap()).collect()
+    }
+
+    #[test]
+    fn pans_and_zooms_fold_into_each_users_newest() {
+        let pan = |user: &str, dx: f64, dy: f64| view(user, WhiteboardEvent::Pan { dx, dy });
+        let zoom = |user: &str, factor: f64| view(user, WhiteboardEvent::Zoom { factor });
+        let left = coalesced(vec![
+            pan("alice", 1.0, 2.0),
+            zoom("alice", 2.0),
+            pan("bob", 5.0, 5.0),
+            pan("alice", 3.0, 4.0),
+            zoom("alice", 1.5),
+        ]);
+        let events: Vec<_> = left.iter().map(|frame| (frame.user.as_deref().unwrap(), &frame.event)).collect();
+        assert_eq!(
+            events,
+            [
+                ("bob", &WhiteboardEvent::Pan { dx: 5.0, dy: 5.0 }),
+                ("alice", &WhiteboardEvent::Pan { dx: 4.0, dy: 6.0 }),
+                ("alice", &WhiteboardEvent::Zoom { factor: 3.0 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn newer_element_frames_replace_older_ones() {
+        let left = coalesced(vec![line("a", 1.0), line("b", 2.0), line("a", 3.0)]);
+        assert_eq!(left, [line("b", 2.0), line("a", 3.0)]);
+    }
+
+    #[test]
+    fn stroke_points_are_all_kept() {
+        let point = |x: f64| {
+            Frame::new(
+                Some("stroke".to_string()),
+                "alice",
+                WhiteboardEvent::DrawFreehand { x, y: 0.0, dragging: true },
+            )
+        };
+        let points = vec![point(1.0), point(2.0), point(3.0)];
+        assert_eq!(coalesced(points.clone()), points);
+    }
+
+    #[test]
+    fn clearing_the_board_drops_what_came_before() {
+        let clear = view("alice", WhiteboardEvent::ClearBoard);
+        let left = coalesced(vec![line("a", 1.0), line("b", 2.0), clear.clone(), line("c", 3.0)]);
+        assert_eq!(left, [clear, line("c", 3.0)]);
+    }
+
+    #[tokio::test]
+    async fn clients_still_behind_after_coalescing_are_disconnected() {
+        let config = QueueConfig {
+            capacity: 2,
+            policy: SlowClient::Coalesce,
+        };
+        let outbound = Outbound::new(u64::MAX, "slow", config);
+        let text = |frame: Frame| serde_json::to_string(&frame).unwrap();
+        assert!(outbound.push(text(line("a", 1.0))));
+        assert!(outbound.push(text(line("a", 2.0))));
+        // Full, but the first line is redundant
+        assert!(outbound.push(text(line("b", 3.0))));
+        // Full of lines that all still matter
+        assert!(!outbound.push(text(line("c", 4.0))));
+        match outbound.next().await {
+            Next::Close(Some(frame)) => assert_eq!(frame.code, CLOSE_SLOW),
+            _ => panic!("the client was not disconnected"),
+        }
+        assert!(!outbound.push(text(line("d", 5.0))));
+    }
+}
@@ -0,0 +1,71 @@
+// Owner tokens: what a connection shows to act as one of a room's owners.
+//
+// Rooms list their owners by user id, which clients pick for themselves, so taking a
+// user's word for it would let anyone reconnect as an owner. Instead the server hands
+// each owner a token when the room becomes theirs, and a connection only counts as an
+// owner's when it comes back with it. A token is a MAC of the room and the user, so it
+// needs no storing, and every instance holding the same key accepts it.
+use std::{fs, io, path::Path, sync::Arc};
+
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use tracing::info;
+
+use crate::{admin::constant_time_eq, config::Config, store::{hex, write_atomic}};
+
+const KEY_FILE: &str = ".owner-key";
+
+#[derive(Clone)]
+pub struct OwnerKey(Arc<hmac::Key>);
+
+impl OwnerKey {
+    // Linked instances derive the key from the relay secret, so they all accept the same
+    // tokens. A single instance keeps a key of its own in the data directory, so tokens
+    // outlive restarts, or makes one up when rooms are not persisted either.
+    pub fn load(config: &Config) -> Result<Self, String> {
+        let key = if let Some(secret) = &config.relay_secret {
+            let secret = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+            hmac::sign(&secret, b"owner tokens").as_ref().to_vec()
+        } else if let Some(dir) = &config.data_dir {
+            stored_key(dir).map_err(|e| format!("failed to read or create the owner key in {}: {}", dir.display(), e))?
+        } else {
+            random_key()?
+        };
+        Ok(OwnerKey(Arc::new(hmac::Key::new(hmac::HMAC_SHA256, &key))))
+    }
+
+    // The token proving `user` owns `room`
+    pub fn token(&self, room: &str, user: &str) -> String {
+        let mut message = room.as_bytes().to_vec();
+        message.push(0);
+        message.extend_from_slice(user.as_bytes());
+        hex(hmac::sign(&self.0, &message).as_ref())
+    }
+
+    pub fn verify(&self, room: &str, user: &str, token: &str) -> bool {
+        constant_time_eq(self.token(room, user).as_bytes(), token.as_bytes())
+    }
+}
+
+fn stored_key(dir: &Path) -> io::Result<Vec<u8>> {
+    let path = dir.join(KEY_FILE);
+    match fs::read(&path) {
+        Ok(key) if !key.is_empty() => return Ok(key),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let key = random_key().map_err(io::Error::other)?;
+    fs::create_dir_all(dir)?;
+    write_atomic(&path, &key)?;
+    info!(path = %path.display(), "created owner key");
+    Ok(key)
+}
+
+fn random_key() -> Result<Vec<u8>, String> {
+    let mut key = vec![0; 32];
+    SystemRandom::new().fill(&mut key).map_err(|_| "no randomness for the owner key".to_string())?;
+    Ok(key)
+}
@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+
+use pdf_writer::{
+    types::{LineCapStyle, LineJoinStyle},
+    Content, Filter, Finish, Name, Pdf, Rect, Ref, Str, TextStr,
+};
+use serde::Deserialize;
+use tiny_skia::Pixmap;
+
+use crate::{
+    assets::{Images, Kind},
+    protocol::WhiteboardEvent,
+    render::{parse_color, Bounds, DOT_RADIUS, IMAGE_DASH, IMAGE_OUTLINE, STROKE_COLOR},
+};
+
+// Points per board pixel when tiling: board pixels are CSS pixels, 96 to the inch
+const TILE_SCALE: f64 = 72.0 / 96.0;
+// Blank space around every page's content, in points
+const MARGIN: f64 = 36.0;
+// Most pages a tiled export may have
+pub const MAX_PAGES: usize = 100;
+const FONT: Name = Name(b"F1");
+// Size of page headings, in points
+const HEADING_SIZE: f32 = 10.0;
+// Where a cubic Bézier's control points go to approximate a quarter circle
+const KAPPA: f64 = 0.552_284_75;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Paper {
+    #[default]
+    A4,
+    A3,
+    Letter,
+    Legal,
+}
+
+impl Paper {
+    // Portrait width and height in points
+    fn size(self) -> (f64, f64) {
+        match self {
+            Paper::A4 => (595.28, 841.89),
+            Paper::A3 => (841.89, 1190.55),
+            Paper::Letter => (612.0, 792.0),
+            Paper::Legal => (612.0, 1008.0),
+        }
+    }
+
+    // Turned to match the shape of what goes on it
+    fn oriented(self, region: Bounds) -> (f64, f64) {
+        let (short, long) = self.size();
+        if region.width() > region.height() {
+            (long, short)
+        } else {
+            (short, long)
+        }
+    }
+}
+
+// How a region of the board is laid out on paper
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    // Scaled to fit a single page
+    #[default]
+    Fit,
+    // At print size, cut into as many pages as it takes
+    Tile,
+}
+
+// A part of the board printed on pages of its own
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub name: String,
+    pub bounds: Bounds,
+}
+
+impl Region {
+    // Parse `name:x0,y0,x1,y1`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (name, bbox) = value
+            .rsplit_once(':')
+            .ok_or_else(|| format!("invalid frame {:?}: expected name:x0,y0,x1,y1", value))?;
+        Ok(Region {
+            name: name.trim().to_string(),
+            bounds: Bounds::parse(bbox)?,
+        })
+    }
+}
+
+// One page of the document: the part of the board it shows, fitted to the paper,
+// and the heading printed above it
+pub struct Page {
+    region: Bounds,
+    size: (f64, f64),
+    heading: String,
+}
+
+// The pages for `regions`, in order, laid out on `paper`.
+// Fails when there are no regions, or when that takes more than `MAX_PAGES` pages.
+pub fn pages(paper: Paper, layout: Layout, regions: Vec<Region>) -> Result<Vec<Page>, String> {
+    if regions.is_empty() {
+        return Err("a PDF needs at least one frame: name:x0,y0,x1,y1".to_string());
+    }
+    let mut pages = Vec::new();
+    for region in regions {
+        match layout {
+            Layout::Fit => pages.push(Page {
+                size: paper.oriented(region.bounds),
+                region: region.bounds,
+                heading: region.name,
+            }),
+            Layout::Tile => tile(&mut pages, paper, region)?,
+        }
+    }
+    if pages.len() > MAX_PAGES {
+        return Err(too_many_pages());
+    }
+    Ok(pages)
+}
+
+fn too_many_pages() -> String {
+    format!("the export would take more than {} pages; fit it to one page or use bigger paper", MAX_PAGES)
+}
+
+// `region` cut into page-sized tiles at print size, left to right and top to bottom
+fn tile(pages: &mut Vec<Page>, paper: Paper, region: Region) -> Result<(), String> {
+    let bounds = region.bounds;
+    let size = paper.oriented(bounds);
+    let tile_width = (size.0 - 2.0 * MARGIN) / TILE_SCALE;
+    let tile_height = (size.1 - 2.0 * MARGIN) / TILE_SCALE;
+    let columns = (bounds.width() / tile_width).ceil().max(1.0);
+    let rows = (bounds.height() / tile_height).ceil().max(1.0);
+    // Checked before counting them out, since a huge region makes for a huge count
+    if pages.len() as f64 + columns * rows > MAX_PAGES as f64 {
+        return Err(too_many_pages());
+    }
+    let (columns, rows) = (columns as usize, rows as usize);
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let x0 = bounds.x0 + column as f64 * tile_width;
+            let y0 = bounds.y0 + row as f64 * tile_height;
+            pages.push(Page {
+                region: Bounds {
+                    x0,
+                    y0,
+                    x1: x0 + tile_width,
+                    y1: y0 + tile_height,
+                },
+                size,
+                heading: format!("{} — row {}, column {}", region.name, row + 1, column + 1),
+            });
+        }
+    }
+    Ok(())
+}
+
+// A PDF with `pages`, each showing its region of the board with `elements` drawn in order.
+// Image elements show their PNG or JPEG from `images`; other formats are outlined.
+pub fn pdf(elements: &[WhiteboardEvent], images: &Images, title: &str, pages: &[Page]) -> Vec<u8> {
+    let mut pdf = Pdf::new();
+    let catalog_id = Ref::new(1);
+    let tree_id = Ref::new(2);
+    let font_id = Ref::new(3);
+    let info_id = Ref::new(4);
+    // Every page takes two ids: the page and its content stream
+    let page_ids: Vec<(Ref, Ref)> = (0..pages.len() as i32)
+        .map(|i| (Ref::new(5 + 2 * i), Ref::new(6 + 2 * i)))
+        .collect();
+
+    pdf.catalog(catalog_id).pages(tree_id);
+    pdf.pages(tree_id)
+        .kids(page_ids.iter().map(|(page_id, _)| *page_id))
+        .count(pages.len() as i32);
+    pdf.document_info(info_id).title(TextStr(title));
+    // One of the standard fonts every reader has, so nothing needs embedding
+    pdf.type1_font(font_id)
+        .base_font(Name(b"Helvetica"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+
+    // Each image once, however often it is placed, named for the pages to draw it by
+    let mut next_id = 5 + 2 * pages.len() as i32;
+    let mut embedded: HashMap<&str, (String, Ref)> = HashMap::new();
+    for element in elements {
+        let WhiteboardEvent::AddImage { asset, .. } = element else { continue };
+        let Some(image) = images.get(asset).filter(|_| !embedded.contains_key(asset.as_str())) else { continue };
+        // The image and, for a PNG, its alpha channel
+        let (image_id, mask_id) = (Ref::new(next_id), Ref::new(next_id + 1));
+        if embed_image(&mut pdf, image_id, mask_id, image) {
+            embedded.insert(asset, (format!("Im{}", embedded.len()), image_id));
+            next_id += 2;
+        }
+    }
+
+    for (page, (page_id, content_id)) in pages.iter().zip(&page_ids) {
+        let (width, height) = page.size;
+        let mut writer = pdf.page(*page_id);
+        writer
+            .parent(tree_id)
+            .media_box(Rect::new(0.0, 0.0, width as f32, height as f32))
+            .contents(*content_id);
+        let mut resources = writer.resources();
+        resources.fonts().pair(FONT, font_id);
+        let mut xobjects = resources.x_objects();
+        for (name, image_id) in embedded.values() {
+            xobjects.pair(Name(name.as_bytes()), *image_id);
+        }
+        xobjects.finish();
+        resources.finish();
+        writer.finish();
+        pdf.stream(*content_id, &page_content(elements, &embedded, page));
+    }
+    pdf.finish()
+}
+
+fn page_content(elements: &[WhiteboardEvent], embedded: &HashMap<&str, (String, Ref)>, page: &Page) -> Vec<u8> {
+    let (width, height) = page.size;
+    let mut content = Content::new();
+
+    // Heading in the top margin
+    content
+        .begin_text()
+        .set_font(FONT, HEADING_SIZE)
+        .set_fill_gray(0.4)
+        .next_line(MARGIN as f32, (height - MARGIN / 2.0 - HEADING_SIZE as f64 / 2.0) as f32)
+        .show(Str(&win_ansi(&page.heading)))
+        .end_text();
+
+    // Fit the region into the space inside the margins, centred
+    let area = (width - 2.0 * MARGIN, height - 2.0 * MARGIN);
+    let region = page.region;
+    let scale = (area.0 / region.width()).min(area.1 / region.height());
+    let left = MARGIN + (area.0 - region.width() * scale) / 2.0;
+    let top = height - MARGIN - (area.1 - region.height() * scale) / 2.0;
+
+    content.save_state();
+    content
+        .rect(
+            left as f32,
+            (top - region.height() * scale) as f32,
+            (region.width() * scale) as f32,
+            (region.height() * scale) as f32,
+        )
+        .clip_nonzero()
+        .end_path();
+    // Board coordinates from here on, with y pointing down as on the canvas
+    content.transform([
+        scale as f32,
+        0.0,
+        0.0,
+        -scale as f32,
+        (left - region.x0 * scale) as f32,
+        (top + region.y0 * scale) as f32,
+    ]);
+    for element in elements {
+        draw(&mut content, element, embedded);
+    }
+    content.restore_state();
+    content.finish()
+}
+
+// Mirrors `draw_event` in the web client's renderer, except that freehand strokes
+// are drawn as paths like in SVG exports
+fn draw(content: &mut Content, element: &WhiteboardEvent, embedded: &HashMap<&str, (String, Ref)>) {
+    match element {
+        WhiteboardEvent::DrawFreehand { x, y, .. } => draw_stroke(content, &[(*x, *y)]),
+        WhiteboardEvent::DrawStroke { points } => draw_stroke(content, points),
+        WhiteboardEvent::DrawLine { from, to, color, width } => {
+            set_stroke(content, color, *width);
+            content
+                .move_to(from.0 as f32, from.1 as f32)
+                .line_to(to.0 as f32, to.1 as f32)
+                .stroke();
+        }
+        WhiteboardEvent::DrawRect { from, to, color, width } => {
+            set_stroke(content, color, *width);
+            content
+                .rect(
+                    from.0 as f32,
+                    from.1 as f32,
+                    (to.0 - from.0) as f32,
+                    (to.1 - from.1) as f32,
+                )
+                .stroke();
+        }
+        WhiteboardEvent::DrawCircle { center, radius, color, width } => {
+            set_stroke(content, color, *width);
+            circle(content, *center, *radius);
+            content.stroke();
+        }
+        WhiteboardEvent::AddText { pos, text, color, size } => {
+            let (r, g, b, _) = parse_color(color);
+            content
+                .begin_text()
+                .set_font(FONT, *size as f32)
+                .set_fill_rgb(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+                // Flip the glyphs back upright inside the board's y-down coordinates
+                .set_text_matrix([1.0, 0.0, 0.0, -1.0, pos.0 as f32, pos.1 as f32])
+                .show(Str(&win_ansi(text)))
+                .end_text();
+        }
+        WhiteboardEvent::AddImage { pos, width, height, asset } if embedded.contains_key(asset.as_str()) => {
+            let (name, _) = &embedded[asset.as_str()];
+            // Images fill the unit square from the bottom up; stretch it over the element, upright
+            content
+                .save_state()
+                .transform([*width as f32, 0.0, 0.0, -*height as f32, pos.0 as f32, (pos.1 + height) as f32])
+                .x_object(Name(name.as_bytes()))
+                .restore_state();
+        }
+        WhiteboardEvent::AddImage { pos, width, height, .. } => {
+            set_stroke(content, IMAGE_OUTLINE, 1.0);
+            content
+                .set_dash_pattern(IMAGE_DASH.map(|n| n as f32), 0.0)
+                .rect(pos.0 as f32, pos.1 as f32, *width as f32, *height as f32)
+                .stroke()
+                .set_dash_pattern([], 0.0);
+        }
+        _ => {}
+    }
+}
+
+fn draw_stroke(content: &mut Content, points: &[(f64, f64)]) {
+    let Some(((x, y), rest)) = points.split_first() else { return };
+    set_stroke(content, STROKE_COLOR, DOT_RADIUS * 2.0);
+    content.set_line_join(LineJoinStyle::RoundJoin);
+    content.move_to(*x as f32, *y as f32);
+    if rest.is_empty() {
+        // A lone point still shows as a dot with round caps
+        content.line_to(*x as f32, *y as f32);
+    }
+    for (x, y) in rest {
+        content.line_to(*x as f32, *y as f32);
+    }
+    content.stroke();
+}
+
+// Write `image` as an image XObject, with its alpha channel as a soft mask at `mask_id` if
+// it has one. PNGs are decoded and stored compressed; JPEGs go in as they are. Returns
+// false for anything that cannot be embedded.
+fn embed_image(pdf: &mut Pdf, image_id: Ref, mask_id: Ref, (kind, data): &(Kind, Vec<u8>)) -> bool {
+    match kind {
+        Kind::Png => {
+            let Ok(pixmap) = Pixmap::decode_png(data) else { return false };
+            let mut rgb = Vec::with_capacity(pixmap.pixels().len() * 3);
+            let mut alpha = Vec::with_capacity(pixmap.pixels().len());
+            for pixel in pixmap.pixels() {
+                let pixel = pixel.demultiply();
+                rgb.extend([pixel.red(), pixel.green(), pixel.blue()]);
+                alpha.push(pixel.alpha());
+            }
+            let (width, height) = (pixmap.width() as i32, pixmap.height() as i32);
+            let opaque = alpha.iter().all(|a| *a == 255);
+            let rgb = miniz_oxide::deflate::compress_to_vec_zlib(&rgb, 6);
+            let mut image = pdf.image_xobject(image_id, &rgb);
+            image.width(width).height(height).bits_per_component(8);
+            image.filter(Filter::FlateDecode);
+            image.color_space().device_rgb();
+            if !opaque {
+                image.s_mask(mask_id);
+            }
+            image.finish();
+            if !opaque {
+                let alpha = miniz_oxide::deflate::compress_to_vec_zlib(&alpha, 6);
+                let mut mask = pdf.image_xobject(mask_id, &alpha);
+                mask.width(width).height(height).bits_per_component(8);
+                mask.filter(Filter::FlateDecode);
+                mask.color_space().device_gray();
+            }
+            true
+        }
+        Kind::Jpeg => {
+            // CMYK JPEGs disagree about whether their ink is inverted; outline them instead
+            let Some((width, height, components @ (1 | 3))) = jpeg_size(data) else { return false };
+            let mut image = pdf.image_xobject(image_id, data);
+            image.width(width).height(height).bits_per_component(8);
+            image.filter(Filter::DctDecode);
+            if components == 1 {
+                image.color_space().device_gray();
+            } else {
+                image.color_space().device_rgb();
+            }
+            true
+        }
+        Kind::Gif | Kind::Webp => false,
+    }
+}
+
+// A baseline or progressive JPEG's width, height and number of colour components,
+// read from its start-of-frame segment
+fn jpeg_size(data: &[u8]) -> Option<(i32, i32, u8)> {
+    let mut at = 2;
+    while at + 4 <= data.len() {
+        if data[at] != 0xff {
+            return None;
+        }
+        let marker = data[at + 1];
+        let length = u16::from_be_bytes([data[at + 2], data[at + 3]]) as usize;
+        // Start of frame, of any kind; C4, C8 and CC in the same range are tables, not frames
+        if matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let frame = data.get(at + 4..at + 10)?;
+            let height = u16::from_be_bytes([frame[1], frame[2]]) as i32;
+            let width = u16::from_be_bytes([frame[3], frame[4]]) as i32;
+            return (width > 0 && height > 0).then_some((width, height, frame[5]));
+        }
+        at += 2 + length;
+    }
+    None
+}
+
+// PDF has no transparency without extra resources, so colours are drawn opaque
+fn set_stroke(content: &mut Content, color: &str, width: f64) {
+    let (r, g, b, _) = parse_color(color);
+    content
+        .set_stroke_rgb(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+        .set_line_width(width as f32)
+        .set_line_cap(LineCapStyle::RoundCap)
+        .set_line_join(LineJoinStyle::MiterJoin);
+}
+
+// A circle as four Bézier quarter arcs
+fn circle(content: &mut Content, (cx, cy): (f64, f64), r: f64) {
+    let k = r * KAPPA;
+    let p = |x: f64, y: f64| ((cx + x) as f32, (cy + y) as f32);
+    let (x, y) = p(r, 0.0);
+    content.move_to(x, y);
+    for [(x1, y1), (x2, y2), (x3, y3)] in [
+        [p(r, k), p(k, r), p(0.0, r)],
+        [p(-k, r), p(-r, k), p(-r, 0.0)],
+        [p(-r, -k), p(-k, -r), p(0.0, -r)],
+        [p(k, -r), p(r, -k), p(r, 0.0)],
+    ] {
+        content.cubic_to(x1, y1, x2, y2, x3, y3);
+    }
+    content.close_path();
+}
+
+// Encode `text` for the standard fonts, which cover Latin-1 only
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            code @ (0x20..=0x7e | 0xa0..=0xff) => code as u8,
+            0x2014 => 0x97,
+            0x2013 => 0x96,
+            _ => b'?',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jpeg_sizes_come_from_the_frame_header() {
+        // An APP0 segment to skip, then a baseline frame 32 wide, 16 high, in 3 components
+        let jpeg = [0xff, 0xd8, 0xff, 0xe0, 0, 4, 0, 0, 0xff, 0xc0, 0, 17, 8, 0, 16, 0, 32, 3];
+        assert_eq!(jpeg_size(&jpeg), Some((32, 16, 3)));
+        assert_eq!(jpeg_size(&jpeg[..14]), None);
+        // A Huffman table shares the range but is not a frame
+        let jpeg = [0xff, 0xd8, 0xff, 0xc4, 0, 2, 0xff, 0xc2, 0, 17, 8, 0, 1, 0, 1, 1];
+        assert_eq!(jpeg_size(&jpeg), Some((1, 1, 1)));
+    }
+
+    fn region(name: &str, bbox: &str) -> Region {
+        Region {
+            name: name.to_string(),
+            bounds: Bounds::parse(bbox).unwrap(),
+        }
+    }
+
+    fn pages_for(layout: Layout, bbox: &str) -> Result<usize, String> {
+        pages(Paper::A4, layout, vec![region("board", bbox)]).map(|pages| pages.len())
+    }
+
+    #[test]
+    fn frames_parse_with_their_names() {
+        let frame = Region::parse(" Ideas : 600,0,1200,400").unwrap();
+        assert_eq!(frame.name, "Ideas");
+        assert_eq!(frame.bounds, Bounds::parse("600,0,1200,400").unwrap());
+        // Names may hold colons of their own
+        assert_eq!(Region::parse("a:b:0,0,1,1").unwrap().name, "a:b");
+        assert!(Region::parse("0,0,1,1").is_err());
+        assert!(Region::parse("Ideas:0,0,1").is_err());
+    }
+
+    #[test]
+    fn fitted_regions_take_a_page_each_turned_to_suit() {
+        let regions = vec![region("wide", "0,0,1200,800"), region("tall", "0,0,400,900")];
+        let pages = pages(Paper::A4, Layout::Fit, regions).unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].size, (841.89, 595.28));
+        assert_eq!(pages[1].size, (595.28, 841.89));
+        assert_eq!(pages[1].heading, "tall");
+    }
+
+    #[test]
+    fn tiles_cover_the_region_row_by_row() {
+        // A landscape A4 page holds (841.89 - 72) x (595.28 - 72) points inside its margins,
+        // 1026.5 x 697.7 board pixels at print size
+        let pages = pages(Paper::A4, Layout::Tile, vec![region("board", "0,0,2000,1000")]).unwrap();
+        assert_eq!(pages.len(), 4);
+        let headings: Vec<&str> = pages.iter().map(|page| page.heading.as_str()).collect();
+        assert_eq!(
+            headings,
+            [
+                "board — row 1, column 1",
+                "board — row 1, column 2",
+                "board — row 2, column 1",
+                "board — row 2, column 2",
+            ]
+        );
+        assert_eq!(pages[1].region.x0, pages[0].region.x1);
+        assert_eq!(pages[2].region.y0, pages[0].region.y1);
+        assert!(pages[3].region.x1 >= 2000.0 && pages[3].region.y1 >= 1000.0);
+
+        // Something smaller than a page still gets one
+        assert_eq!(pages_for(Layout::Tile, "0,0,10,10"), Ok(1));
+    }
+
+    #[test]
+    fn exports_are_capped_at_max_pages() {
+        // 10 x 10 tiles, just at the limit
+        assert_eq!(pages_for(Layout::Tile, "0,0,10265,6977"), Ok(MAX_PAGES));
+        assert!(pages_for(Layout::Tile, "0,0,10265,7000").is_err());
+        // Huge regions are refused before their tiles are counted out
+        assert!(pages_for(Layout::Tile, "0,0,1e15,1e15").is_err());
+        // Fitting never takes more than a page per region
+        assert_eq!(pages_for(Layout::Fit, "0,0,1e15,1e15"), Ok(1));
+        let many = (0..=MAX_PAGES).map(|n| region(&n.to_string(), "0,0,10,10")).collect();
+        assert!(pages(Paper::A4, Layout::Fit, many).is_err());
+    }
+
+    #[test]
+    fn an_empty_frame_list_is_refused() {
+        assert!(pages(Paper::A4, Layout::Fit, Vec::new()).is_err());
+        assert!(pages(Paper::A4, Layout::Tile, Vec::new()).is_err());
+    }
+}
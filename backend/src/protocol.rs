@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+
+pub type Point = (f64, f64);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum WhiteboardEvent {
+    // One point of a freehand stroke; `dragging: false` starts a new stroke
+    DrawFreehand { x: f64, y: f64, dragging: bool },
+    // A whole freehand stroke, used when an existing stroke is sent again
+    DrawStroke { points: Vec<Point> },
+    DrawLine { from: Point, to: Point, color: String, width: f64 },
+    DrawRect { from: Point, to: Point, color: String, width: f64 },
+    DrawCircle { center: Point, radius: f64, color: String, width: f64 },
+    AddText { pos: Point, text: String, color: String, size: f64 },
+    // An uploaded image, named by its asset id, with `pos` its top-left corner
+    AddImage { pos: Point, width: f64, height: f64, asset: String },
+    Pan { dx: f64, dy: f64 },
+    Zoom { factor: f64 },
+    // Remove the element named by the frame's `id`
+    DeleteElement,
+    // Revert or re-apply the sender's own last change
+    Undo,
+    Redo,
+    // Mark the board as it is now under `name`, replacing any checkpoint of that name
+    CreateCheckpoint { name: String },
+    // Put the board back the way it was at checkpoint `name`
+    RestoreCheckpoint { name: String },
+    // A chat message to everyone in the room; the server sets `ts`, in Unix milliseconds
+    ChatMessage {
+        text: String,
+        #[serde(default)]
+        ts: u64,
+    },
+
+    // Room administration, owners only
+    ClearBoard,
+    // Make the board read-only for everyone but owners
+    LockRoom { locked: bool },
+    Kick { conn: u64 },
+    Mute { conn: u64, muted: bool },
+    // Make user `to` an owner in place of the sender
+    TransferOwnership { to: String },
+
+    // Sent by the server only
+    // Tells a client who it is, right after it connects: the frame's `user` is its user id,
+    // and `seq` is where the room's order stands. Owners get the token that proves it,
+    // to connect with as `owner_token` next time.
+    Welcome {
+        conn: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner_token: Option<String>,
+    },
+    // Sent to a user's connections when the room is handed to them, as in `Welcome`
+    OwnerToken { token: String },
+    // The event named by `ref` was applied, as number `seq` in the room's order
+    // when it changed the board
+    Ack,
+    // The event named by `ref` was not applied
+    Reject { reason: String },
+    // The whole board, replacing whatever the client has, after a checkpoint was restored
+    // or a restore undone
+    ResetBoard { elements: Vec<Frame> },
+    // The room's checkpoints, oldest first, sent as clients join and whenever one is made
+    Checkpoints { checkpoints: Vec<CheckpointInfo> },
+    // The room's latest chat messages, oldest first, sent to clients as they join
+    ChatHistory { messages: Vec<Frame> },
+    // Who administers the room, whether it is locked and who is connected
+    RoomState { owners: Vec<String>, locked: bool, connections: Vec<Connection> },
+    // A replay covers the board changes after `from` up to `to`; the board as it was
+    // at `from` follows, then the changes themselves
+    ReplayStart { from: u64, to: u64 },
+    // Every change in the replay has been sent
+    ReplayEnd,
+}
+
+// A client connected to a room
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Connection {
+    pub conn: u64,
+    pub user: String,
+    pub muted: bool,
+    // Whether the connection proved it owns the room
+    #[serde(default)]
+    pub owner: bool,
+}
+
+// A room's board as `GET /rooms/<room>/board.json` serves it: one frame per element, in drawing order
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BoardSnapshot {
+    // Where the room's order stood
+    pub seq: u64,
+    pub elements: Vec<Frame>,
+}
+
+// A checkpoint as clients see it, without its elements
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CheckpointInfo {
+    pub name: String,
+    pub seq: u64,
+    pub ts: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub elements: usize,
+}
+
+impl WhiteboardEvent {
+    // Commands only room owners may send
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            WhiteboardEvent::ClearBoard
+                | WhiteboardEvent::LockRoom { .. }
+                | WhiteboardEvent::Kick { .. }
+                | WhiteboardEvent::Mute { .. }
+                | WhiteboardEvent::TransferOwnership { .. }
+        )
+    }
+
+    pub fn is_server_only(&self) -> bool {
+        matches!(
+            self,
+            WhiteboardEvent::Welcome { .. }
+                | WhiteboardEvent::OwnerToken { .. }
+                | WhiteboardEvent::RoomState { .. }
+                | WhiteboardEvent::ChatHistory { .. }
+                | WhiteboardEvent::ResetBoard { .. }
+                | WhiteboardEvent::Checkpoints { .. }
+                | WhiteboardEvent::Ack
+                | WhiteboardEvent::Reject { .. }
+                | WhiteboardEvent::ReplayStart { .. }
+                | WhiteboardEvent::ReplayEnd
+        )
+    }
+
+    // Events that create or replace a board element
+    pub fn is_element(&self) -> bool {
+        matches!(
+            self,
+            WhiteboardEvent::DrawStroke { .. }
+                | WhiteboardEvent::DrawLine { .. }
+                | WhiteboardEvent::DrawRect { .. }
+                | WhiteboardEvent::DrawCircle { .. }
+                | WhiteboardEvent::AddText { .. }
+                | WhiteboardEvent::AddImage { .. }
+        )
+    }
+
+    // Events that change what is on the board
+    pub fn changes_board(&self) -> bool {
+        self.is_element()
+            || matches!(
+                self,
+                WhiteboardEvent::DrawFreehand { .. }
+                    | WhiteboardEvent::DeleteElement
+                    | WhiteboardEvent::ClearBoard
+                    | WhiteboardEvent::ResetBoard { .. }
+            )
+    }
+}
+
+// Envelope around every event on the wire, e.g.
+// `{"type":"DrawLine","id":"3f2a.17","user":"alice","from":[0,0],...}`.
+// `id` names the element the event creates or changes; the server fills it in
+// when a client leaves it out. `user` is always set by the server.
+// `seq` is the frame's place in the room's order of board changes, and `ref`
+// is a client's own name for an event it sent, echoed back in the ack.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Frame {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(flatten)]
+    pub event: WhiteboardEvent,
+}
+
+impl Frame {
+    // A message from the server itself rather than relayed for a user
+    pub fn server(event: WhiteboardEvent) -> Self {
+        Frame {
+            id: None,
+            user: None,
+            seq: None,
+            reference: None,
+            event,
+        }
+    }
+
+    pub fn new(id: Option<String>, user: &str, event: WhiteboardEvent) -> Self {
+        Frame {
+            id,
+            user: Some(user.to_string()),
+            ..Frame::server(event)
+        }
+    }
+
+    // The answer to a client's event named `reference`
+    pub fn reply(reference: String, seq: Option<u64>, event: WhiteboardEvent) -> Self {
+        Frame {
+            seq,
+            reference: Some(reference),
+            ..Frame::server(event)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Events are flattened into the frame, so a field of the event's own named like one of
+    // the frame's would be taken by the frame and the event would no longer parse
+    #[test]
+    fn events_round_trip() {
+        let welcome = Frame {
+            user: Some("alice".to_string()),
+            ..Frame::server(WhiteboardEvent::Welcome {
+                conn: 7,
+                owner_token: Some("0123abcd".to_string()),
+            })
+        };
+        let transfer = Frame::new(None, "alice", WhiteboardEvent::TransferOwnership { to: "bob".to_string() });
+        for frame in [welcome, transfer] {
+            let text = serde_json::to_string(&frame).unwrap();
+            let parsed: Frame = serde_json::from_str(&text).unwrap();
+            assert_eq!(parsed, frame);
+        }
+    }
+}
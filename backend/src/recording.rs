@@ -0,0 +1,441 @@
+// Session recordings: everything a room sends its clients, with when and on whose behalf,
+// kept as a portable file that can be played back against a server or rendered offline.
+//
+// A recording is JSON lines: a `Header` holding the board when recording started,
+// then one `Record` per frame the room published.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    admin::authorize,
+    assets::Images,
+    crdt::Doc,
+    hub::Tapped,
+    protocol::{Frame, WhiteboardEvent},
+    render::{self, Bounds},
+    room::{apply_to_board, board_frames},
+    svg, AppState,
+};
+
+// Bumped whenever a change to the format would trip up older readers
+pub const VERSION: u32 = 1;
+pub const EXTENSION: &str = "wbrec";
+
+// Largest rendered image side, in pixels
+const MAX_SIZE: u32 = 4096;
+
+// First line of a recording
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Header {
+    pub version: u32,
+    pub room: String,
+    // When recording started, in milliseconds since the Unix epoch
+    pub started: u64,
+    // The room's seq when recording started
+    pub seq: u64,
+    // The board at that point, one frame per element in drawing order
+    pub board: Vec<Frame>,
+}
+
+// Every line after the header
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    // Milliseconds since recording started
+    pub at: u64,
+    // User the frame was sent on behalf of; none for the server's own messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    pub frame: Frame,
+}
+
+pub struct Recording {
+    pub header: Header,
+    pub records: Vec<Record>,
+}
+
+impl Recording {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        let (_, first) = lines.next().ok_or("empty recording")?;
+        let header: Header = serde_json::from_str(first).map_err(|e| format!("line 1: {}", e))?;
+        if header.version != VERSION {
+            return Err(format!("unsupported recording version {} (expected {})", header.version, VERSION));
+        }
+        let records = lines
+            .map(|(n, line)| serde_json::from_str(line).map_err(|e| format!("line {}: {}", n + 1, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Recording { header, records })
+    }
+
+    // The board once every recorded frame has been applied to the one recording started
+    // with. Only the file is consulted, so it comes out the same every time.
+    pub fn final_board(&self) -> Vec<Frame> {
+        let mut board = Doc::new("playback");
+        for element in &self.header.board {
+            apply_to_board(&mut board, element);
+        }
+        for record in &self.records {
+            match &record.frame.event {
+                WhiteboardEvent::ResetBoard { elements } => {
+                    board.clear(None);
+                    for element in elements {
+                        apply_to_board(&mut board, element);
+                    }
+                }
+                _ => apply_to_board(&mut board, &record.frame),
+            }
+        }
+        board_frames(&board)
+    }
+}
+
+// `board` as SVG, cropped to what is drawn. A recording holds no images, so they are outlined.
+pub fn svg(board: &[Frame]) -> String {
+    let elements: Vec<_> = board.iter().map(|frame| frame.event.clone()).collect();
+    svg::svg(&elements, &Images::new(), Bounds::content(&elements))
+}
+
+// `board` as a PNG `width` pixels wide, cropped to what is drawn
+pub fn png(board: &[Frame], width: u32) -> Result<Vec<u8>, String> {
+    if !(1..=MAX_SIZE).contains(&width) {
+        return Err(format!("width must be 1 to {} pixels", MAX_SIZE));
+    }
+    let elements: Vec<_> = board.iter().map(|frame| frame.event.clone()).collect();
+    let bounds = Bounds::content(&elements);
+    let height = ((width as f64 * bounds.height() / bounds.width()).round() as u32).clamp(1, MAX_SIZE);
+    render::png(&elements, &Images::new(), bounds, width, height)
+}
+
+// Rooms being recorded, each by a task writing to a file of its own
+pub struct Recordings {
+    // Where recordings are kept; recording is disabled without it
+    dir: Option<PathBuf>,
+    active: Mutex<HashMap<String, Active>>,
+}
+
+struct Active {
+    file: String,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Recordings {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Recordings {
+            dir,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Start writing `tap` to a new file after `header`, returning the file's name.
+    // `start` is when the header's board was taken, which records are timed from.
+    fn start(
+        &self,
+        header: Header,
+        start: Instant,
+        tap: mpsc::Receiver<Tapped>,
+    ) -> Result<String, (StatusCode, String)> {
+        let dir = self.dir.as_ref().ok_or((StatusCode::NOT_FOUND, "recording disabled".to_string()))?;
+        let mut active = self.active.lock().unwrap();
+        // A recording whose writer gave up is over, and does not stand in the way of a new one
+        if active.get(&header.room).is_some_and(|recording| !recording.task.is_finished()) {
+            return Err((StatusCode::CONFLICT, "room is already being recorded".to_string()));
+        }
+        // Keep the file name to characters that are safe on every filesystem
+        let name: String = header
+            .room
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_') { c } else { '_' })
+            .collect();
+        let file = format!("{}-{}.{}", name, header.started, EXTENSION);
+        let path = dir.join(&file);
+        let room = header.room.clone();
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn({
+            let room = room.clone();
+            async move {
+                if let Err(e) = write(path, header, start, tap, stopped).await {
+                    error!(room, error = %e, "failed to write recording");
+                }
+            }
+        });
+        active.insert(room, Active { file: file.clone(), stop, task });
+        Ok(file)
+    }
+
+    // Stop recording `room` once everything published so far is written,
+    // returning the file it went to
+    async fn stop(&self, room: &str) -> Option<String> {
+        let Active { file, stop, task } = self.active.lock().unwrap().remove(room)?;
+        let _ = stop.send(());
+        let _ = task.await;
+        Some(file)
+    }
+
+    fn path(&self, file: &str) -> Option<PathBuf> {
+        let valid = !file.starts_with('.') && !file.contains(['/', '\\']) && file.ends_with(&format!(".{}", EXTENSION));
+        valid.then(|| self.dir.as_ref().map(|dir| dir.join(file))).flatten()
+    }
+}
+
+async fn write(
+    path: PathBuf,
+    header: Header,
+    start: Instant,
+    mut tap: mpsc::Receiver<Tapped>,
+    mut stopped: oneshot::Receiver<()>,
+) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut out = BufWriter::new(tokio::fs::File::create(&path).await?);
+    out.write_all(&line(&header)).await?;
+    out.flush().await?;
+    let mut stopping = false;
+    while !stopping {
+        let msg = tokio::select! {
+            msg = tap.recv() => msg,
+            _ = &mut stopped => {
+                stopping = true;
+                tap.try_recv().ok()
+            }
+        };
+        let Some(msg) = msg else {
+            if stopping {
+                break;
+            }
+            // The hub closes a tap that falls too far behind rather than leave gaps in it
+            return Err(std::io::Error::other("fell too far behind the room, recording cut short"));
+        };
+        // Write out whatever else is waiting before flushing
+        let mut batch = vec![msg];
+        while let Ok(msg) = tap.try_recv() {
+            batch.push(msg);
+        }
+        for (published, msg) in batch {
+            let at = published.saturating_duration_since(start).as_millis() as u64;
+            let Ok(frame) = serde_json::from_str::<Frame>(&msg) else {
+                warn!(path = %path.display(), "left an unreadable frame out of the recording");
+                continue;
+            };
+            let sender = frame.user.clone();
+            out.write_all(&line(&Record { at, sender, frame })).await?;
+        }
+        out.flush().await?;
+    }
+    Ok(())
+}
+
+fn line<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut line = serde_json::to_vec(value).unwrap();
+    line.push(b'\n');
+    line
+}
+
+// Starting and stopping recordings, and fetching them, all behind the admin token
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/rooms/:room/recording", post(set_recording))
+        .route("/recordings", get(list))
+        .route("/recordings/:file", get(download))
+}
+
+#[derive(Deserialize)]
+struct RecordingBody {
+    recording: bool,
+}
+
+#[derive(Serialize)]
+struct RecordingFile {
+    file: String,
+}
+
+#[derive(Serialize)]
+struct RecordingInfo {
+    file: String,
+    bytes: u64,
+}
+
+async fn set_recording(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+    Json(body): Json<RecordingBody>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    if room.is_empty() || room.len() > 64 {
+        return (StatusCode::BAD_REQUEST, "room names must be 1 to 64 bytes").into_response();
+    }
+
+    if !body.recording {
+        return match state.recordings.stop(&room).await {
+            Some(file) => {
+                info!(room, file, "stopped recording");
+                Json(RecordingFile { file }).into_response()
+            }
+            None => (StatusCode::NOT_FOUND, "room is not being recorded").into_response(),
+        };
+    }
+
+    // Tap the room under its lock so the header's board and the first record meet exactly
+    let (header, start, tap) = state.rooms.with(&room, |_, room_state| {
+        let header = Header {
+            version: VERSION,
+            room: room.clone(),
+            started: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            seq: room_state.seq(),
+            board: room_state.snapshot(),
+        };
+        (header, Instant::now(), state.hub.tap(&room))
+    });
+    match state.recordings.start(header, start, tap) {
+        Ok(file) => {
+            info!(room, file, "started recording");
+            (StatusCode::CREATED, Json(RecordingFile { file })).into_response()
+        }
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+async fn list(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    let Some(dir) = &state.recordings.dir else {
+        return (StatusCode::NOT_FOUND, "recording disabled").into_response();
+    };
+    let mut recordings = Vec::new();
+    if let Ok(mut entries) = tokio::fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let file = entry.file_name().to_string_lossy().into_owned();
+            if state.recordings.path(&file).is_none() {
+                continue;
+            }
+            let bytes = entry.metadata().await.map(|meta| meta.len()).unwrap_or_default();
+            recordings.push(RecordingInfo { file, bytes });
+        }
+    }
+    recordings.sort_by(|a, b| a.file.cmp(&b.file));
+    Json(recordings).into_response()
+}
+
+async fn download(State(state): State<AppState>, headers: HeaderMap, Path(file): Path<String>) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    let Some(path) = state.recordings.path(&file) else {
+        return (StatusCode::NOT_FOUND, "no such recording").into_response();
+    };
+    match tokio::fs::read(&path).await {
+        Ok(body) => ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND, "no such recording").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hub::{Hub, LocalHub, TAP_CAPACITY};
+
+    fn header(started: u64) -> Header {
+        Header {
+            version: VERSION,
+            room: "broken".to_string(),
+            started,
+            seq: 0,
+            board: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_recordings_do_not_block_new_ones() {
+        // Nothing can be written under a file
+        let file = std::env::temp_dir().join(format!("wb-recording-{}-file", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+        let recordings = Recordings::new(Some(file.join("recordings")));
+
+        let (_tap, receiver) = mpsc::channel(1);
+        recordings.start(header(1), Instant::now(), receiver).unwrap();
+        for _ in 0..100 {
+            if recordings.active.lock().unwrap()["broken"].task.is_finished() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let (_tap, receiver) = mpsc::channel(1);
+        assert_eq!(recordings.start(header(2), Instant::now(), receiver).unwrap(), "broken-2.wbrec");
+
+        // While one is being written, it is
+        let dir = std::env::temp_dir().join(format!("wb-recording-{}-ok", std::process::id()));
+        let recordings = Recordings::new(Some(dir.clone()));
+        let (_live, receiver) = mpsc::channel(1);
+        recordings.start(header(3), Instant::now(), receiver).unwrap();
+        let (_tap, receiver) = mpsc::channel(1);
+        assert_eq!(recordings.start(header(4), Instant::now(), receiver).unwrap_err().0, StatusCode::CONFLICT);
+        recordings.stop("broken").await.unwrap();
+        let _ = std::fs::remove_file(&file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn records_are_timed_from_when_they_were_published() {
+        let dir = std::env::temp_dir().join(format!("wb-recording-{}-timed", std::process::id()));
+        let recordings = Recordings::new(Some(dir.clone()));
+        let (tap, receiver) = mpsc::channel(8);
+        let start = Instant::now();
+        let file = recordings.start(header(1), start, receiver).unwrap();
+        // Both are waiting by the time the writer gets to them
+        for millis in [100, 300] {
+            let frame = Frame::server(WhiteboardEvent::ClearBoard);
+            let published = start + std::time::Duration::from_millis(millis);
+            tap.send((published, serde_json::to_string(&frame).unwrap())).await.unwrap();
+        }
+        recordings.stop("broken").await.unwrap();
+        let recording = Recording::parse(&std::fs::read_to_string(dir.join(file)).unwrap()).unwrap();
+        let at: Vec<u64> = recording.records.iter().map(|record| record.at).collect();
+        assert_eq!(at, [100, 300]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn recordings_that_fall_behind_end() {
+        let dir = std::env::temp_dir().join(format!("wb-recording-{}-behind", std::process::id()));
+        let recordings = Recordings::new(Some(dir.clone()));
+        let hub = LocalHub::new(1);
+        recordings.start(header(1), Instant::now(), hub.tap("broken")).unwrap();
+        // The writer does not get to run in between on this runtime
+        let frame = serde_json::to_string(&Frame::server(WhiteboardEvent::ClearBoard)).unwrap();
+        for _ in 0..=TAP_CAPACITY {
+            hub.publish("broken", frame.clone());
+        }
+        for _ in 0..100 {
+            if recordings.active.lock().unwrap()["broken"].task.is_finished() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(recordings.active.lock().unwrap()["broken"].task.is_finished());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
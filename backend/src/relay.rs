@@ -0,0 +1,326 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+// How long a new link has to prove it knows the relay secret
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+use crate::{
+    crdt::{StateVector, Update},
+    hub::{Hub, LocalHub, Tapped},
+};
+
+// This instance's copy of every room, as seen by the relay
+pub trait Replica: Send + Sync + 'static {
+    // Rooms to bring up to date when a link comes up
+    fn rooms(&self) -> Vec<String>;
+
+    fn state_vector(&self, room: &str) -> StateVector;
+
+    // Whatever this instance has that one at `since` is missing
+    fn diff(&self, room: &str, since: &StateVector) -> Update;
+
+    // Merge board changes from another instance, handing messages for local
+    // subscribers to `deliver`. Returns false when a diff is needed to catch up.
+    fn merge(&self, room: &str, update: Update, deliver: &mut dyn FnMut(String)) -> bool;
+
+    // Look at a message published on another instance before it is delivered locally
+    fn observe(&self, room: &str, msg: &str);
+}
+
+// Frames exchanged between instances, one JSON object per line
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RelayFrame {
+    // A message for every subscriber of a room
+    Publish { room: String, msg: String },
+    // Changes to a room's board
+    Update { room: String, update: Update },
+    // The sender's state vector for a room, answered with an update holding
+    // whatever the sender is missing
+    Sync { room: String, state: StateVector },
+    // First frame each end sends: a fresh challenge for the other end
+    Hello { nonce: Vec<u8> },
+    // Answer to the other end's challenge: the HMAC of its nonce, keyed with the relay secret
+    Proof { mac: Vec<u8> },
+}
+
+impl RelayFrame {
+    fn line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+// Hub that keeps rooms in sync across backend instances over plain TCP.
+//
+// Every instance delivers its own publications locally and writes them to all
+// of its peer links; frames read from a link are only delivered locally, never
+// forwarded again. Instances therefore need to form a full mesh: each one
+// either listens for or dials every other one.
+//
+// Board changes travel as CRDT updates rather than client messages. When a
+// link comes up, and whenever an update shows a gap, the two ends swap state
+// vectors and send each other the changes the other is missing.
+//
+// Before anything else, both ends of a link prove they know the relay secret,
+// so nobody who can merely reach the relay port can join the mesh.
+pub struct RelayHub {
+    local: LocalHub,
+    replica: Arc<dyn Replica>,
+    key: hmac::Key,
+    // Serialized frames published on this instance, fanned out to every link
+    outgoing: broadcast::Sender<Arc<str>>,
+}
+
+impl RelayHub {
+    pub fn new(local: LocalHub, replica: Arc<dyn Replica>, secret: &str) -> Arc<Self> {
+        let (outgoing, _) = broadcast::channel(1024);
+        Arc::new(RelayHub {
+            local,
+            replica,
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            outgoing,
+        })
+    }
+
+    // Accept links from peers that dial this instance
+    pub async fn listen(self: &Arc<Self>, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(%addr, "relay listening");
+
+        let hub = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let span = info_span!("relay", peer = %peer);
+                        tokio::spawn(hub.clone().run_link(stream, false).instrument(span));
+                    }
+                    Err(e) => warn!(error = %e, "failed to accept relay link"),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Keep a link to `peer` open, reconnecting with backoff whenever it drops
+    pub fn connect(self: &Arc<Self>, peer: String) {
+        let hub = self.clone();
+        let span = info_span!("relay", peer = %peer);
+        tokio::spawn(
+            async move {
+                let mut backoff = Duration::from_millis(250);
+                loop {
+                    match TcpStream::connect(&peer).await {
+                        Ok(stream) => {
+                            backoff = Duration::from_millis(250);
+                            hub.clone().run_link(stream, true).await;
+                        }
+                        Err(e) => debug!(error = %e, "relay peer unreachable"),
+                    }
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(10));
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    // `dialed` tells which end of the link this instance is
+    async fn run_link(self: Arc<Self>, stream: TcpStream, dialed: bool) {
+        let _ = stream.set_nodelay(true);
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.handshake(&mut lines, &mut writer, dialed)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!(error = %e, "rejected relay link");
+                return;
+            }
+            Err(_) => {
+                warn!("rejected relay link: handshake timed out");
+                return;
+            }
+        }
+        info!("relay link up");
+        let mut outgoing = self.outgoing.subscribe();
+
+        // Catch up on everything that happened while the link was down
+        for room in self.replica.rooms() {
+            let state = self.replica.state_vector(&room);
+            if let Err(e) = write_frame(&mut writer, &RelayFrame::Sync { room, state }).await {
+                warn!(error = %e, "relay write error");
+                return;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                line = lines.next_line() => match line {
+                    Ok(Some(line)) => match serde_json::from_str::<RelayFrame>(&line) {
+                        Ok(frame) => {
+                            if let Some(reply) = self.receive(frame) {
+                                if let Err(e) = write_frame(&mut writer, &reply).await {
+                                    warn!(error = %e, "relay write error");
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) => warn!(error = %e, "rejected malformed relay frame"),
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!(error = %e, "relay read error");
+                        break;
+                    }
+                },
+                frame = outgoing.recv() => match frame {
+                    Ok(frame) => {
+                        if let Err(e) = writer.write_all(frame.as_bytes()).await {
+                            warn!(error = %e, "relay write error");
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "relay link lagged, frames to this peer were dropped");
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+        info!("relay link down");
+    }
+
+    // Swap challenges with the other end and check its answer. Each answer also names the end
+    // that gave it, so a peer cannot pass our own answer back to us as its own.
+    async fn handshake(
+        &self,
+        lines: &mut Lines<BufReader<OwnedReadHalf>>,
+        writer: &mut (impl AsyncWrite + Unpin),
+        dialed: bool,
+    ) -> Result<(), String> {
+        let mut nonce = vec![0; 32];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| "no randomness for a challenge".to_string())?;
+        write_frame(writer, &RelayFrame::Hello { nonce: nonce.clone() }).await.map_err(|e| e.to_string())?;
+
+        let mut next = async || -> Result<RelayFrame, String> {
+            let line = lines.next_line().await.map_err(|e| e.to_string())?.ok_or("link closed")?;
+            serde_json::from_str(&line).map_err(|e| format!("malformed handshake: {}", e))
+        };
+        let RelayFrame::Hello { nonce: theirs } = next().await? else {
+            return Err("peer did not start with a challenge".to_string());
+        };
+        let mac = hmac::sign(&self.key, &challenge(dialed, &theirs)).as_ref().to_vec();
+        write_frame(writer, &RelayFrame::Proof { mac }).await.map_err(|e| e.to_string())?;
+        let RelayFrame::Proof { mac } = next().await? else {
+            return Err("peer did not answer the challenge".to_string());
+        };
+        hmac::verify(&self.key, &challenge(!dialed, &nonce), &mac).map_err(|_| "peer does not know the relay secret".to_string())
+    }
+
+    // Handle a frame from a peer, returning what to send back to it
+    fn receive(&self, frame: RelayFrame) -> Option<RelayFrame> {
+        match frame {
+            RelayFrame::Publish { room, msg } => {
+                self.replica.observe(&room, &msg);
+                self.local.publish(&room, msg);
+                None
+            }
+            RelayFrame::Update { room, update } => {
+                let caught_up = self
+                    .replica
+                    .merge(&room, update, &mut |msg| self.local.publish(&room, msg));
+                if caught_up {
+                    return None;
+                }
+                debug!(%room, "relay update skipped changes, asking for a diff");
+                let state = self.replica.state_vector(&room);
+                Some(RelayFrame::Sync { room, state })
+            }
+            RelayFrame::Sync { room, state } => {
+                let update = self.replica.diff(&room, &state);
+                let ahead = update
+                    .state
+                    .iter()
+                    .any(|(replica, seen)| state.get(replica).is_none_or(|theirs| seen > theirs));
+                (!update.is_empty() || ahead).then_some(RelayFrame::Update { room, update })
+            }
+            RelayFrame::Hello { .. } | RelayFrame::Proof { .. } => {
+                warn!("ignored handshake frame on an established relay link");
+                None
+            }
+        }
+    }
+
+    // Send a frame to every link
+    fn broadcast(&self, frame: &RelayFrame) {
+        // No links up is not an error; peers catch up when they connect
+        let _ = self.outgoing.send(frame.line().into());
+    }
+}
+
+// What the end that dialed (or was dialed) signs to answer `nonce`
+fn challenge(dialed: bool, nonce: &[u8]) -> Vec<u8> {
+    let role: &[u8] = if dialed { b"dialer:" } else { b"listener:" };
+    [role, nonce].concat()
+}
+
+async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), frame: &RelayFrame) -> io::Result<()> {
+    writer.write_all(frame.line().as_bytes()).await
+}
+
+impl Hub for RelayHub {
+    fn subscribe(&self, room: &str) -> broadcast::Receiver<String> {
+        self.local.subscribe(room)
+    }
+
+    fn publish(&self, room: &str, msg: String) {
+        self.broadcast(&RelayFrame::Publish {
+            room: room.to_string(),
+            msg: msg.clone(),
+        });
+        self.local.publish(room, msg);
+    }
+
+    fn tap(&self, room: &str) -> mpsc::Receiver<Tapped> {
+        self.local.tap(room)
+    }
+
+    fn publish_change(&self, room: &str, msgs: Vec<String>, update: Update) {
+        if !update.is_empty() {
+            self.broadcast(&RelayFrame::Update {
+                room: room.to_string(),
+                update,
+            });
+        }
+        for msg in msgs {
+            self.local.publish(room, msg);
+        }
+    }
+
+    fn sync(&self, room: &str, state: &StateVector) {
+        self.broadcast(&RelayFrame::Sync {
+            room: room.to_string(),
+            state: state.clone(),
+        });
+    }
+
+    fn close(&self, room: &str) {
+        self.local.close(room);
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use ab_glyph::{Font, FontArc, OutlineCurve};
+use tiny_skia::{
+    Color, FillRule, FilterQuality, LineCap, Paint, PathBuilder, Pixmap, PixmapPaint, Rect, Stroke, StrokeDash, Transform,
+};
+use tracing::{info, warn};
+
+use crate::{
+    assets::{Images, Kind},
+    protocol::WhiteboardEvent,
+};
+
+// Colour and radius of freehand dots, as drawn by the web client
+pub const STROKE_COLOR: &str = "#2563eb";
+pub const DOT_RADIUS: f64 = 2.0;
+// An image that cannot be drawn, because it is missing or its format cannot be embedded,
+// shows as a dashed outline of where it goes, like in the web client while one loads
+pub const IMAGE_OUTLINE: &str = "#cbd5e1";
+pub const IMAGE_DASH: [f64; 2] = [6.0, 4.0];
+// Space left around the board's content when no bounding box is asked for
+const PADDING: f64 = 20.0;
+// What an empty board shows: the web client's canvas
+const EMPTY: Bounds = Bounds {
+    x0: 0.0,
+    y0: 0.0,
+    x1: 1200.0,
+    y1: 800.0,
+};
+
+// Fonts tried for text when `WB_FONT` is unset
+const SYSTEM_FONTS: [&str; 4] = [
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "/System/Library/Fonts/Supplemental/Arial.ttf",
+];
+
+static FONT: OnceLock<Option<FontArc>> = OnceLock::new();
+
+// Load the font text is rendered with: `path` if given, else the first system font found.
+// Without one, text is left out of rendered boards.
+pub fn init_font(path: Option<&Path>) {
+    let candidates: Vec<PathBuf> = match path {
+        Some(path) => vec![path.to_path_buf()],
+        None => SYSTEM_FONTS.iter().map(PathBuf::from).collect(),
+    };
+    let font = candidates.iter().find_map(|candidate| {
+        let data = std::fs::read(candidate).ok()?;
+        match FontArc::try_from_vec(data) {
+            Ok(font) => {
+                info!(font = %candidate.display(), "loaded font for rendering boards");
+                Some(font)
+            }
+            Err(e) => {
+                warn!(font = %candidate.display(), error = %e, "failed to parse font");
+                None
+            }
+        }
+    });
+    if font.is_none() {
+        warn!("no font found for rendering boards; text will be left out (set WB_FONT)");
+    }
+    let _ = FONT.set(font);
+}
+
+fn font() -> Option<&'static FontArc> {
+    FONT.get_or_init(|| None).as_ref()
+}
+
+// A rectangle of the board, in board coordinates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub x0: f64,
+    pub y0: f64,
+    pub x1: f64,
+    pub y1: f64,
+}
+
+impl Bounds {
+    // Parse `x0,y0,x1,y1`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let numbers: Vec<f64> = value
+            .split(',')
+            .map(|n| n.trim().parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid bounding box {:?}: expected x0,y0,x1,y1", value))?;
+        let [x0, y0, x1, y1] = numbers[..] else {
+            return Err(format!("invalid bounding box {:?}: expected x0,y0,x1,y1", value));
+        };
+        if !numbers.iter().all(|n| n.is_finite()) || x1 <= x0 || y1 <= y0 {
+            return Err(format!("invalid bounding box {:?}: must have a positive width and height", value));
+        }
+        Ok(Bounds { x0, y0, x1, y1 })
+    }
+
+    // Everything drawn on the board with some padding around it, or the canvas
+    // the web client starts with when the board is empty
+    pub fn content(elements: &[WhiteboardEvent]) -> Self {
+        let mut bounds: Option<Bounds> = None;
+        for element in elements {
+            if let Some(extent) = extent(element) {
+                bounds = Some(match bounds {
+                    Some(bounds) => bounds.union(extent),
+                    None => extent,
+                });
+            }
+        }
+        match bounds {
+            Some(bounds) => Bounds {
+                x0: bounds.x0 - PADDING,
+                y0: bounds.y0 - PADDING,
+                x1: bounds.x1 + PADDING,
+                y1: bounds.y1 + PADDING,
+            },
+            None => EMPTY,
+        }
+    }
+
+    // The web client's canvas, grown to take in anything drawn outside it
+    pub fn canvas(elements: &[WhiteboardEvent]) -> Self {
+        EMPTY.union(Bounds::content(elements))
+    }
+
+    pub fn width(&self) -> f64 {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> f64 {
+        self.y1 - self.y0
+    }
+
+    fn union(self, other: Bounds) -> Bounds {
+        Bounds {
+            x0: self.x0.min(other.x0),
+            y0: self.y0.min(other.y0),
+            x1: self.x1.max(other.x1),
+            y1: self.y1.max(other.y1),
+        }
+    }
+
+    fn around(points: impl IntoIterator<Item = (f64, f64)>, margin: f64) -> Option<Bounds> {
+        points
+            .into_iter()
+            .map(|(x, y)| Bounds {
+                x0: x - margin,
+                y0: y - margin,
+                x1: x + margin,
+                y1: y + margin,
+            })
+            .reduce(Bounds::union)
+    }
+}
+
+// The area an element covers, strokes included
+fn extent(element: &WhiteboardEvent) -> Option<Bounds> {
+    match element {
+        WhiteboardEvent::DrawFreehand { x, y, .. } => Bounds::around([(*x, *y)], DOT_RADIUS),
+        WhiteboardEvent::DrawStroke { points } => Bounds::around(points.iter().copied(), DOT_RADIUS),
+        WhiteboardEvent::DrawLine { from, to, width, .. } | WhiteboardEvent::DrawRect { from, to, width, .. } => {
+            Bounds::around([*from, *to], width / 2.0)
+        }
+        WhiteboardEvent::DrawCircle { center, radius, width, .. } => Bounds::around([*center], radius + width / 2.0),
+        WhiteboardEvent::AddText { pos, text, size, .. } => Some(Bounds {
+            x0: pos.0,
+            // Text sits on its baseline, with descenders below it
+            y0: pos.1 - size,
+            x1: pos.0 + text_width(text, *size),
+            y1: pos.1 + size * 0.25,
+        }),
+        WhiteboardEvent::AddImage { pos, width, height, .. } => Some(Bounds {
+            x0: pos.0,
+            y0: pos.1,
+            x1: pos.0 + width,
+            y1: pos.1 + height,
+        }),
+        _ => None,
+    }
+}
+
+// Width of `text` set at `size` pixels, estimated when there is no font
+pub fn text_width(text: &str, size: f64) -> f64 {
+    let Some(font) = font() else {
+        return text.chars().count() as f64 * size * 0.6;
+    };
+    let scale = size as f32 / font.units_per_em().unwrap_or(1000.0);
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let glyph = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern_unscaled(previous, glyph) * scale;
+        }
+        width += font.h_advance_unscaled(glyph) * scale;
+        previous = Some(glyph);
+    }
+    width as f64
+}
+
+// Parse a CSS hex colour (`#rgb`, `#rrggbb` or `#rrggbbaa`), falling back to black
+// like the canvas does for colours it cannot read
+pub fn parse_color(color: &str) -> (u8, u8, u8, u8) {
+    let hex = color.trim().strip_prefix('#').unwrap_or_default();
+    let digits: Option<Vec<u8>> = hex.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect();
+    match (hex.len(), digits.as_deref()) {
+        (3, Some(&[r, g, b])) => (r * 17, g * 17, b * 17, 255),
+        (6, Some(&[r1, r2, g1, g2, b1, b2])) => (r1 << 4 | r2, g1 << 4 | g2, b1 << 4 | b2, 255),
+        (8, Some(&[r1, r2, g1, g2, b1, b2, a1, a2])) => (r1 << 4 | r2, g1 << 4 | g2, b1 << 4 | b2, a1 << 4 | a2),
+        _ => (0, 0, 0, 255),
+    }
+}
+
+// Render `elements` in drawing order to a `width`×`height` PNG showing `bounds`.
+// The bounds are scaled to fit, keeping their aspect ratio, and centred on a white background.
+// Image elements show their PNG from `images`; other formats are outlined.
+pub fn png(
+    elements: &[WhiteboardEvent],
+    images: &Images,
+    bounds: Bounds,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, String> {
+    let mut pixmap = Pixmap::new(width, height).ok_or_else(|| format!("cannot render {}x{} pixels", width, height))?;
+    pixmap.fill(Color::WHITE);
+
+    let scale = (width as f64 / bounds.width()).min(height as f64 / bounds.height());
+    let transform = Transform::from_row(
+        scale as f32,
+        0.0,
+        0.0,
+        scale as f32,
+        ((width as f64 - bounds.width() * scale) / 2.0 - bounds.x0 * scale) as f32,
+        ((height as f64 - bounds.height() * scale) / 2.0 - bounds.y0 * scale) as f32,
+    );
+    for element in elements {
+        draw(&mut pixmap, element, images, transform);
+    }
+    pixmap.encode_png().map_err(|e| e.to_string())
+}
+
+fn paint(color: &str) -> Paint<'static> {
+    let (r, g, b, a) = parse_color(color);
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(r, g, b, a);
+    paint.anti_alias = true;
+    paint
+}
+
+fn stroke(width: f64) -> Stroke {
+    Stroke {
+        width: width as f32,
+        line_cap: LineCap::Round,
+        ..Stroke::default()
+    }
+}
+
+// Mirrors `draw_event` in the web client's renderer
+fn draw(pixmap: &mut Pixmap, element: &WhiteboardEvent, images: &Images, transform: Transform) {
+    match element {
+        WhiteboardEvent::DrawFreehand { x, y, .. } => draw_dot(pixmap, *x, *y, transform),
+        WhiteboardEvent::DrawStroke { points } => {
+            for (x, y) in points {
+                draw_dot(pixmap, *x, *y, transform);
+            }
+        }
+        WhiteboardEvent::DrawLine { from, to, color, width } => {
+            let mut path = PathBuilder::new();
+            path.move_to(from.0 as f32, from.1 as f32);
+            path.line_to(to.0 as f32, to.1 as f32);
+            if let Some(path) = path.finish() {
+                pixmap.stroke_path(&path, &paint(color), &stroke(*width), transform, None);
+            }
+        }
+        WhiteboardEvent::DrawRect { from, to, color, width } => {
+            let rect = Rect::from_ltrb(
+                from.0.min(to.0) as f32,
+                from.1.min(to.1) as f32,
+                from.0.max(to.0) as f32,
+                from.1.max(to.1) as f32,
+            );
+            if let Some(rect) = rect {
+                let path = PathBuilder::from_rect(rect);
+                pixmap.stroke_path(&path, &paint(color), &stroke(*width), transform, None);
+            }
+        }
+        WhiteboardEvent::DrawCircle { center, radius, color, width } => {
+            if let Some(path) = PathBuilder::from_circle(center.0 as f32, center.1 as f32, *radius as f32) {
+                pixmap.stroke_path(&path, &paint(color), &stroke(*width), transform, None);
+            }
+        }
+        WhiteboardEvent::AddText { pos, text, color, size } => draw_text(pixmap, *pos, text, color, *size, transform),
+        WhiteboardEvent::AddImage { pos, width, height, asset } => {
+            let image = match images.get(asset) {
+                Some((Kind::Png, data)) => Pixmap::decode_png(data).ok(),
+                _ => None,
+            };
+            if let Some(image) = image {
+                let transform = transform
+                    .pre_translate(pos.0 as f32, pos.1 as f32)
+                    .pre_scale(*width as f32 / image.width() as f32, *height as f32 / image.height() as f32);
+                let paint = PixmapPaint {
+                    quality: FilterQuality::Bilinear,
+                    ..PixmapPaint::default()
+                };
+                pixmap.draw_pixmap(0, 0, image.as_ref(), &paint, transform, None);
+                return;
+            }
+            let rect = Rect::from_xywh(pos.0 as f32, pos.1 as f32, *width as f32, *height as f32);
+            if let Some(rect) = rect {
+                let path = PathBuilder::from_rect(rect);
+                let stroke = Stroke {
+                    dash: StrokeDash::new(IMAGE_DASH.iter().map(|n| *n as f32).collect(), 0.0),
+                    ..stroke(1.0)
+                };
+                pixmap.stroke_path(&path, &paint(IMAGE_OUTLINE), &stroke, transform, None);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn draw_dot(pixmap: &mut Pixmap, x: f64, y: f64, transform: Transform) {
+    if let Some(path) = PathBuilder::from_circle(x as f32, y as f32, DOT_RADIUS as f32) {
+        pixmap.fill_path(&path, &paint(STROKE_COLOR), FillRule::Winding, transform, None);
+    }
+}
+
+// Fill the glyph outlines of `text` with its left end on the baseline at `pos`
+fn draw_text(pixmap: &mut Pixmap, pos: (f64, f64), text: &str, color: &str, size: f64, transform: Transform) {
+    let Some(font) = font() else { return };
+    let scale = size as f32 / font.units_per_em().unwrap_or(1000.0);
+    let (x, y) = (pos.0 as f32, pos.1 as f32);
+
+    let mut path = PathBuilder::new();
+    let mut pen = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let glyph = font.glyph_id(c);
+        if let Some(previous) = previous {
+            pen += font.kern_unscaled(previous, glyph) * scale;
+        }
+        previous = Some(glyph);
+        if let Some(outline) = font.outline(glyph) {
+            // Font units point up; the board's y axis points down
+            let point = |p: ab_glyph::Point| (x + pen + p.x * scale, y - p.y * scale);
+            let mut last = None;
+            for curve in &outline.curves {
+                let (start, end) = match curve {
+                    OutlineCurve::Line(a, b) => (*a, *b),
+                    OutlineCurve::Quad(a, _, c) => (*a, *c),
+                    OutlineCurve::Cubic(a, _, _, d) => (*a, *d),
+                };
+                if last != Some(start) {
+                    let (sx, sy) = point(start);
+                    path.move_to(sx, sy);
+                }
+                match curve {
+                    OutlineCurve::Line(_, b) => {
+                        let (bx, by) = point(*b);
+                        path.line_to(bx, by);
+                    }
+                    OutlineCurve::Quad(_, b, c) => {
+                        let ((bx, by), (cx, cy)) = (point(*b), point(*c));
+                        path.quad_to(bx, by, cx, cy);
+                    }
+                    OutlineCurve::Cubic(_, b, c, d) => {
+                        let ((bx, by), (cx, cy), (dx, dy)) = (point(*b), point(*c), point(*d));
+                        path.cubic_to(bx, by, cx, cy, dx, dy);
+                    }
+                }
+                last = Some(end);
+            }
+        }
+        pen += font.h_advance_unscaled(glyph) * scale;
+    }
+    if let Some(path) = path.finish() {
+        pixmap.fill_path(&path, &paint(color), FillRule::Winding, transform, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounding_boxes_parse_and_reject_bad_input() {
+        assert_eq!(
+            Bounds::parse(" 0, -10.5,1200 ,800").unwrap(),
+            Bounds { x0: 0.0, y0: -10.5, x1: 1200.0, y1: 800.0 }
+        );
+        for bad in ["", "0,0,10", "0,0,10,10,10", "0,0,ten,10", "10,0,0,10", "0,0,0,10", "0,0,inf,10", "0,0,NaN,10"] {
+            assert!(Bounds::parse(bad).is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn content_bounds_take_in_strokes_and_padding() {
+        assert_eq!(Bounds::content(&[]), EMPTY);
+        let elements = [
+            WhiteboardEvent::DrawLine {
+                from: (100.0, 100.0),
+                to: (200.0, 150.0),
+                color: "#000000".to_string(),
+                width: 10.0,
+            },
+            WhiteboardEvent::DrawCircle {
+                center: (300.0, 300.0),
+                radius: 50.0,
+                color: "#000000".to_string(),
+                width: 2.0,
+            },
+            WhiteboardEvent::DrawFreehand { x: 50.0, y: 400.0, dragging: false },
+        ];
+        assert_eq!(
+            Bounds::content(&elements),
+            Bounds {
+                x0: 50.0 - DOT_RADIUS - PADDING,
+                y0: 95.0 - PADDING,
+                x1: 351.0 + PADDING,
+                y1: 400.0 + DOT_RADIUS + PADDING,
+            }
+        );
+        // The canvas only ever grows to take in what is drawn outside it
+        assert_eq!(Bounds::canvas(&elements), EMPTY);
+    }
+
+    #[test]
+    fn colours_parse_like_the_canvas() {
+        assert_eq!(parse_color("#2563eb"), (0x25, 0x63, 0xeb, 255));
+        assert_eq!(parse_color(" #F0a "), (0xff, 0x00, 0xaa, 255));
+        assert_eq!(parse_color("#11223380"), (0x11, 0x22, 0x33, 0x80));
+        for unreadable in ["red", "2563eb", "#12345", "#ggg", ""] {
+            assert_eq!(parse_color(unreadable), (0, 0, 0, 255), "{:?}", unreadable);
+        }
+    }
+}
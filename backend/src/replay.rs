@@ -0,0 +1,165 @@
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::{ConnectInfo, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    protocol::{Frame, WhiteboardEvent},
+    room::{board_frames, replay_entry, restore_board},
+    socket::default_room,
+    store::{History, LogEntry},
+    AppState,
+};
+
+// Longest pause between two replayed changes, however long the room sat still
+const MAX_GAP: Duration = Duration::from_secs(2);
+
+// Query parameters accepted on `/ws/replay`, e.g. `/ws/replay?room=retro&from=120&speed=4`.
+// `from`/`to` are seqs and `since`/`until` Unix times in milliseconds; without them
+// the replay covers all of the room's history still on disk.
+#[derive(Deserialize, Debug)]
+pub struct ReplayParams {
+    #[serde(default = "default_room")]
+    room: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    since: Option<u64>,
+    until: Option<u64>,
+    // Playback speed relative to how the changes were made
+    #[serde(default = "default_speed")]
+    speed: f64,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+// A room's history cut down to the requested range
+struct Replay {
+    from: u64,
+    to: u64,
+    // The board as it was at `from`
+    board: Vec<Frame>,
+    entries: Vec<LogEntry>,
+}
+
+impl Replay {
+    fn new(history: History, params: &ReplayParams) -> Self {
+        let mut board = restore_board(history.doc, &history.elements);
+        let mut from = history.seq;
+        let mut entries = history.entries.into_iter().peekable();
+
+        // Everything before the range only shapes the board the replay starts from
+        while let Some(entry) = entries.next_if(|entry| {
+            params.from.is_some_and(|from| entry.seq <= from) || params.since.is_some_and(|since| entry.ts < since)
+        }) {
+            replay_entry(&mut board, &entry);
+            from = entry.seq;
+        }
+        let entries: Vec<LogEntry> = entries
+            .take_while(|entry| {
+                params.to.is_none_or(|to| entry.seq <= to) && params.until.is_none_or(|until| entry.ts <= until)
+            })
+            .collect();
+
+        Replay {
+            from,
+            to: entries.last().map_or(from, |entry| entry.seq),
+            board: board_frames(&board),
+            entries,
+        }
+    }
+}
+
+// Read-only connection that plays a room's history back instead of joining it
+pub async fn replay_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<ReplayParams>,
+    State(state): State<AppState>,
+) -> Response {
+    if params.room.is_empty() || params.room.len() > 64 {
+        warn!(%peer, room = %params.room, "rejected replay with invalid room name");
+        return (StatusCode::BAD_REQUEST, "room names must be 1 to 64 bytes").into_response();
+    }
+    if !(params.speed.is_finite() && params.speed > 0.0) {
+        return (StatusCode::BAD_REQUEST, "speed must be a positive number").into_response();
+    }
+    let Some(store) = state.rooms.store().cloned() else {
+        return (StatusCode::NOT_FOUND, "room history is not persisted").into_response();
+    };
+    let room = params.room.clone();
+    // Reading every generation kept can take a while; keep it off the async workers
+    let history = tokio::task::spawn_blocking(move || store.history(&room))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    let history = match history {
+        Ok(history) => history,
+        Err(e) => {
+            error!(room = %params.room, error = %e, "failed to read room history");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "failed to read room history").into_response();
+        }
+    };
+
+    let replay = Replay::new(history, &params);
+    let span = info_span!("replay", %peer, room = %params.room);
+    ws.on_upgrade(move |socket| play(socket, replay, params.speed).instrument(span))
+        .into_response()
+}
+
+async fn play(socket: WebSocket, replay: Replay, speed: f64) {
+    info!(from = replay.from, to = replay.to, speed, "replay started");
+    let (mut sender, mut receiver) = socket.split();
+
+    let send = async move {
+        let start = Frame::server(WhiteboardEvent::ReplayStart {
+            from: replay.from,
+            to: replay.to,
+        });
+        for frame in std::iter::once(start).chain(replay.board) {
+            sender.send(Message::Text(serde_json::to_string(&frame).unwrap())).await?;
+        }
+
+        let mut last_ts = None;
+        for entry in replay.entries {
+            if let Some(last_ts) = last_ts {
+                let gap = Duration::from_millis(entry.ts.saturating_sub(last_ts)).div_f64(speed);
+                tokio::time::sleep(gap.min(MAX_GAP)).await;
+            }
+            last_ts = Some(entry.ts);
+            let frame = Frame {
+                seq: Some(entry.seq),
+                ..entry.frame
+            };
+            sender.send(Message::Text(serde_json::to_string(&frame).unwrap())).await?;
+        }
+
+        let end = Frame::server(WhiteboardEvent::ReplayEnd);
+        sender.send(Message::Text(serde_json::to_string(&end).unwrap())).await?;
+        sender.close().await
+    };
+
+    // Nothing a replay client sends is applied; only watch for it hanging up
+    let hang_up = async move {
+        while let Some(Ok(msg)) = receiver.next().await {
+            if matches!(msg, Message::Close(_)) {
+                break;
+            }
+        }
+    };
+
+    tokio::select! {
+        result = send => match result {
+            Ok(()) => info!("replay finished"),
+            Err(e) => warn!(error = %e, "failed to send replay to client"),
+        },
+        _ = hang_up => info!("replay client left"),
+    }
+}
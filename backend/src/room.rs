@@ -0,0 +1,1192 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap, VecDeque},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+
+use crate::{
+    assets,
+    crdt::{Doc, Op, StateVector, Update},
+    export::RenderCache,
+    hub::Hub,
+    metrics,
+    owner::OwnerKey,
+    protocol::{CheckpointInfo, Connection, Frame, WhiteboardEvent},
+    relay::Replica,
+    store::{self, Checkpoint, Finished, LogEntry, LogWriter, Retention, RoomSettings, Store},
+    webhook::{HookEvent, Webhook, Webhooks},
+};
+
+// How many of a user's changes can be undone per room
+const HISTORY_LIMIT: usize = 200;
+
+// How many of a user's recent event refs are remembered,
+// so a retried event is acknowledged again rather than applied twice
+const APPLIED_LIMIT: usize = 256;
+
+// How many of a room's latest chat messages are kept and sent to clients as they join
+const CHAT_HISTORY: usize = 100;
+
+// Longest chat message accepted, in bytes
+const MAX_CHAT_BYTES: usize = 2000;
+
+// How many checkpoints a room keeps; the oldest goes to make room for a new one
+const MAX_CHECKPOINTS: usize = 50;
+
+// Longest checkpoint name accepted, in bytes
+const MAX_CHECKPOINT_NAME: usize = 100;
+
+// Largest width or height an image can be placed at, in board pixels
+const MAX_IMAGE_SIZE: f64 = 10_000.0;
+
+// Random per-process prefix for server-assigned element ids,
+// so ids never collide between relayed instances
+static INSTANCE_ID: LazyLock<String> = LazyLock::new(|| {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    format!("{:08x}", hasher.finish() as u32)
+});
+
+pub fn instance_id() -> &'static str {
+    &INSTANCE_ID
+}
+
+// All rooms known to this instance
+pub struct Rooms {
+    rooms: Mutex<HashMap<String, Arc<Mutex<Room>>>>,
+    // Where room history is persisted, if anywhere
+    store: Option<Store>,
+    // What eviction does with rooms that have no retention setting of their own
+    retention: Retention,
+    hooks: Webhooks,
+    owner_key: OwnerKey,
+    // Log writers of evicted rooms that may still be writing; a room loads again only once
+    // its old writer has finished
+    closing: Mutex<HashMap<String, Arc<Finished>>>,
+}
+
+impl Rooms {
+    pub fn new(store: Option<Store>, retention: Retention, hooks: Webhooks, owner_key: OwnerKey) -> Self {
+        Rooms {
+            rooms: Mutex::new(HashMap::new()),
+            store,
+            retention,
+            hooks,
+            owner_key,
+            closing: Mutex::default(),
+        }
+    }
+
+    // Run `f` on a room under its lock, loading the room the first time it is used.
+    // `f` also gets the room's handle, for callers that keep it to lock again later.
+    // Every change to a room goes through here or `with_existing`: a room the reaper
+    // evicted between the lookup and the lock is looked up again, so nothing lands on a
+    // room that has been dropped, or writes to a log a newly loaded room also holds open.
+    pub fn with<R>(&self, name: &str, f: impl FnOnce(&Arc<Mutex<Room>>, &mut Room) -> R) -> R {
+        self.locked(name, f, |name| Some(self.get(name))).unwrap()
+    }
+
+    // Like `with`, but only for a room that already exists, in memory or on disk
+    pub fn with_existing<R>(&self, name: &str, f: impl FnOnce(&Arc<Mutex<Room>>, &mut Room) -> R) -> Option<R> {
+        self.locked(name, f, |name| self.find(name))
+    }
+
+    fn locked<R>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&Arc<Mutex<Room>>, &mut Room) -> R,
+        lookup: impl Fn(&str) -> Option<Arc<Mutex<Room>>>,
+    ) -> Option<R> {
+        loop {
+            let handle = lookup(name)?;
+            let mut room = handle.lock().unwrap();
+            if room.is_evicted() {
+                continue;
+            }
+            return Some(f(&handle, &mut room));
+        }
+    }
+
+    // Get a room, loading it from disk the first time it is used
+    fn get(&self, name: &str) -> Arc<Mutex<Room>> {
+        if let Some(room) = self.rooms.lock().unwrap().get(name) {
+            return room.clone();
+        }
+        self.insert(name)
+    }
+
+    // Get a room only if it already exists, in memory or on disk
+    fn find(&self, name: &str) -> Option<Arc<Mutex<Room>>> {
+        if let Some(room) = self.rooms.lock().unwrap().get(name) {
+            return Some(room.clone());
+        }
+        let store = self.store.as_ref()?;
+        self.settle(name);
+        if !store.has_room(name) {
+            return None;
+        }
+        Some(self.insert(name))
+    }
+
+    // Add a room to the map and load it outside the map lock. The room is locked until it
+    // has loaded, so anyone else after it waits for this load rather than starting another.
+    fn insert(&self, name: &str) -> Arc<Mutex<Room>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get(name) {
+            return room.clone();
+        }
+        let handle = Arc::new(Mutex::new(Room::new(name, self.hooks.clone(), self.owner_key.clone())));
+        rooms.insert(name.to_string(), handle.clone());
+        let mut room = handle.lock().unwrap();
+        drop(rooms);
+        self.load(&mut room);
+        drop(room);
+        handle
+    }
+
+    // Wait for the log writer of an evicted room of this name to finish
+    fn settle(&self, name: &str) {
+        let closing = self.closing.lock().unwrap().get(name).cloned();
+        if let Some(closing) = closing {
+            closing.wait();
+        }
+    }
+
+    pub fn all(&self) -> Vec<Arc<Mutex<Room>>> {
+        self.rooms.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn store(&self) -> Option<&Store> {
+        self.store.as_ref()
+    }
+
+    // The token proving `user` owns `room`
+    pub fn owner_token(&self, room: &str, user: &str) -> String {
+        self.owner_key.token(room, user)
+    }
+
+    // Number of rooms held in memory
+    pub fn len(&self) -> usize {
+        self.rooms.lock().unwrap().len()
+    }
+
+    // Periodically compact every room, so idle boards get a fresh snapshot too
+    pub fn spawn_compactor(self: &Arc<Self>, every: Duration) {
+        let rooms = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                // Only queues the snapshot; the room's log writer does the disk work
+                for room in rooms.all() {
+                    room.lock().unwrap().compact();
+                }
+            }
+        });
+    }
+
+    // Periodically evict rooms nobody has been connected to for `idle`
+    pub fn spawn_reaper(self: &Arc<Self>, idle: Duration, hub: Arc<dyn Hub>) {
+        let rooms = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((idle / 4).max(Duration::from_secs(1)));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                rooms.evict_idle(idle, &*hub);
+            }
+        });
+    }
+
+    fn evict_idle(&self, idle: Duration, hub: &dyn Hub) {
+        // Holding the map lock keeps anyone from looking up a room while it is evicted
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut closing = self.closing.lock().unwrap();
+        closing.retain(|_, finished| !finished.is_done());
+        rooms.retain(|name, room| {
+            // A room someone holds, e.g. while it loads, is in use
+            let Ok(mut room) = room.try_lock() else { return true };
+            let Some(idle_for) = room.idle_for().filter(|idle_for| *idle_for >= idle) else {
+                return true;
+            };
+            let (retention, finished) = room.evict(self.retention);
+            if let Some(finished) = finished {
+                closing.insert(name.clone(), finished);
+            }
+            hub.close(name);
+            match retention {
+                Retention::Persist => metrics::ROOMS_EVICTED_PERSISTED.inc(),
+                Retention::Discard => metrics::ROOMS_EVICTED_DISCARDED.inc(),
+            }
+            info!(room = %name, ?retention, idle_secs = idle_for.as_secs(), "evicted idle room");
+            false
+        });
+    }
+
+    fn load(&self, room: &mut Room) {
+        let name = room.name.clone();
+        let Some(store) = &self.store else {
+            room.notify(HookEvent::RoomCreated);
+            return;
+        };
+
+        self.settle(&name);
+        match store.open_room(&name) {
+            Ok((loaded, log)) => {
+                room.board = restore_board(loaded.doc, &loaded.elements);
+                for entry in &loaded.tail {
+                    replay_entry(&mut room.board, entry);
+                }
+                room.board.discard_ops();
+                room.seq = loaded.seq;
+                room.settings = loaded.settings;
+                let skip = loaded.chat.len().saturating_sub(CHAT_HISTORY);
+                room.chat = loaded.chat.into_iter().skip(skip).collect();
+                room.checkpoints = loaded.checkpoints;
+                room.log = Some(LogWriter::spawn(&name, log));
+                info!(room = %name, seq = room.seq, replayed = loaded.tail.len(), "loaded room");
+                // Nobody has ever joined or drawn on it
+                if room.seq == 0 && room.settings.owners.is_empty() {
+                    room.notify(HookEvent::RoomCreated);
+                }
+            }
+            // Serve the room from memory rather than risk writing over history we could not read
+            Err(e) => error!(room = %name, error = %e, "failed to load room history, not persisting it"),
+        }
+    }
+
+}
+
+// Keeps this instance's rooms in sync with other instances over the relay
+impl Replica for Rooms {
+    fn rooms(&self) -> Vec<String> {
+        self.rooms.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn state_vector(&self, room: &str) -> StateVector {
+        self.with(room, |_, room| room.board.state_vector().clone())
+    }
+
+    fn diff(&self, room: &str, since: &StateVector) -> Update {
+        self.with(room, |_, room| room.board.diff(since))
+    }
+
+    fn merge(&self, room: &str, update: Update, deliver: &mut dyn FnMut(String)) -> bool {
+        self.with(room, |_, room| room.merge(update, deliver))
+    }
+
+    fn observe(&self, room: &str, msg: &str) {
+        match serde_json::from_str::<Frame>(msg) {
+            Ok(frame) => self.with(room, |_, room| room.observe(&frame)),
+            Err(e) => warn!(%room, error = %e, "ignored malformed relayed frame"),
+        }
+    }
+}
+
+// One user's change to one element, with enough state to revert it
+#[derive(Clone, Debug)]
+struct Change {
+    id: String,
+    before: Option<WhiteboardEvent>,
+    after: Option<WhiteboardEvent>,
+}
+
+// What one undo step reverts
+#[derive(Clone, Debug)]
+enum Edit {
+    Element(Change),
+    // Restoring a checkpoint, which may change any number of elements at once
+    Restore(Vec<Change>),
+}
+
+#[derive(Default)]
+struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+// A connection currently in the room
+struct Member {
+    user: String,
+    muted: bool,
+    // Whether it showed its user's owner token, or was given it
+    proved: bool,
+    // Whether it came with the admin token, which makes it an owner whoever its user is
+    admin: bool,
+    // Fired to disconnect the client
+    kick: Option<oneshot::Sender<()>>,
+    // Messages for this connection alone
+    inbox: mpsc::UnboundedSender<String>,
+}
+
+// What a new connection gets from the room
+pub struct Joined {
+    // Fires when the connection is kicked
+    pub kicked: oneshot::Receiver<()>,
+    pub inbox: mpsc::UnboundedReceiver<String>,
+    // The token proving the connection's user owns the room, if it does
+    pub owner_token: Option<String>,
+}
+
+pub struct Room {
+    name: String,
+    board: Doc,
+    settings: RoomSettings,
+    members: BTreeMap<u64, Member>,
+    // Undo/redo stacks per user
+    history: HashMap<String, History>,
+    // The freehand stroke each user is currently drawing
+    open_strokes: HashMap<String, String>,
+    next_id: u64,
+    // Number of changes applied to the board so far
+    seq: u64,
+    log: Option<LogWriter>,
+    // Our own board changes not yet sent to other instances
+    outbox: Vec<Op>,
+    // Refs of each user's recently applied events, with the seq they were given
+    applied: HashMap<String, VecDeque<(String, Option<u64>)>>,
+    // Latest chat messages, oldest first
+    chat: VecDeque<Frame>,
+    // Named copies of the board to go back to, oldest first
+    checkpoints: Vec<Checkpoint>,
+    // When the last connection left, or the room was loaded; `None` while anyone is connected
+    idle_since: Option<Instant>,
+    // Set once the room is dropped from memory; a stale handle must look the room up again
+    evicted: bool,
+    hooks: Webhooks,
+    renders: RenderCache,
+    owner_key: OwnerKey,
+}
+
+// What became of an event a client sent
+pub struct Applied {
+    // Frames to publish to the room
+    pub frames: Vec<Frame>,
+    // Where the event landed in the room's order, if it changed the board
+    pub seq: Option<u64>,
+}
+
+impl Room {
+    fn new(name: &str, hooks: Webhooks, owner_key: OwnerKey) -> Self {
+        Room {
+            name: name.to_string(),
+            board: Doc::new(instance_id()),
+            settings: RoomSettings::default(),
+            members: BTreeMap::new(),
+            history: HashMap::new(),
+            open_strokes: HashMap::new(),
+            next_id: 0,
+            seq: 0,
+            log: None,
+            outbox: Vec::new(),
+            applied: HashMap::new(),
+            chat: VecDeque::new(),
+            checkpoints: Vec::new(),
+            idle_since: Some(Instant::now()),
+            evicted: false,
+            hooks,
+            renders: RenderCache::default(),
+            owner_key,
+        }
+    }
+
+    // Number of board changes applied so far; every change gets the next one
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // Every element on the board, for clients joining the room
+    pub fn snapshot(&self) -> Vec<Frame> {
+        board_frames(&self.board)
+    }
+
+    // The room's latest chat messages, for clients joining the room
+    pub fn chat_history(&self) -> Frame {
+        Frame::server(WhiteboardEvent::ChatHistory {
+            messages: self.chat.iter().cloned().collect(),
+        })
+    }
+
+    // The room's checkpoints as clients list them
+    pub fn checkpoints(&self) -> Vec<CheckpointInfo> {
+        self.checkpoints
+            .iter()
+            .map(|checkpoint| CheckpointInfo {
+                name: checkpoint.name.clone(),
+                seq: checkpoint.seq,
+                ts: checkpoint.ts,
+                user: checkpoint.user.clone(),
+                elements: checkpoint.elements.len(),
+            })
+            .collect()
+    }
+
+    pub fn checkpoints_frame(&self) -> Frame {
+        Frame::server(WhiteboardEvent::Checkpoints {
+            checkpoints: self.checkpoints(),
+        })
+    }
+
+    pub fn checkpoint(&self, name: &str) -> Option<&Checkpoint> {
+        self.checkpoints.iter().find(|checkpoint| checkpoint.name == name)
+    }
+
+    // Images of the board made since it last changed
+    pub fn renders(&mut self) -> &mut RenderCache {
+        &mut self.renders
+    }
+
+    // Fold the room's log into a fresh snapshot, if anything happened since the last one.
+    // The snapshot is written by the room's log writer, from a copy of the board.
+    pub fn compact(&mut self) {
+        let Some(log) = self.log.as_mut().filter(|log| log.has_tail()) else { return };
+        log.compact(self.seq, self.board.clone());
+    }
+
+    // Resolves once every change made so far is on disk; at once if the room is not persisted
+    pub fn flushed(&self) -> Option<oneshot::Receiver<()>> {
+        self.log.as_ref().map(LogWriter::flushed)
+    }
+
+    // How long the room has had no connections
+    pub fn idle_for(&self) -> Option<Duration> {
+        self.idle_since.map(|since| since.elapsed())
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        self.evicted
+    }
+
+    // Let go of the room's history before it is dropped from memory: keep it on disk
+    // as a fresh snapshot, or delete it, as its retention setting says.
+    // Returns what was done, and the log writer's handle to wait on before the room is
+    // loaded again. Without a store there is nothing to keep, so it is discarded.
+    fn evict(&mut self, default: Retention) -> (Retention, Option<Arc<Finished>>) {
+        self.evicted = true;
+        if self.log.is_none() {
+            return (Retention::Discard, None);
+        }
+        match self.settings.retention.unwrap_or(default) {
+            Retention::Persist => {
+                self.compact();
+                (Retention::Persist, self.log.take().map(LogWriter::close))
+            }
+            Retention::Discard => (Retention::Discard, self.log.take().map(LogWriter::destroy)),
+        }
+    }
+
+    // Choose what eviction does with this room's history; `None` follows the server default
+    pub fn set_retention(&mut self, retention: Option<Retention>) {
+        self.settings.retention = retention;
+        self.save_settings();
+    }
+
+    // Notify the room's own webhooks as well as the global ones about the next event;
+    // a webhook registered under an existing URL replaces it
+    pub fn add_webhook(&mut self, hook: Webhook) {
+        for replaced in self.settings.webhooks.iter().filter(|existing| existing.url == hook.url && **existing != hook) {
+            self.hooks.stop(&self.name, replaced);
+        }
+        self.settings.webhooks.retain(|existing| existing.url != hook.url);
+        self.settings.webhooks.push(hook);
+        self.save_settings();
+    }
+
+    // Returns false if no webhook was registered under `url`
+    pub fn remove_webhook(&mut self, url: &str) -> bool {
+        let Some(at) = self.settings.webhooks.iter().position(|hook| hook.url == url) else {
+            return false;
+        };
+        let hook = self.settings.webhooks.remove(at);
+        self.hooks.stop(&self.name, &hook);
+        self.save_settings();
+        true
+    }
+
+    pub fn webhook_urls(&self) -> Vec<String> {
+        self.settings.webhooks.iter().map(|hook| hook.url.clone()).collect()
+    }
+
+    fn notify(&self, event: HookEvent) {
+        self.hooks.notify(&self.name, &self.settings.webhooks, event);
+    }
+
+    // Register a new connection. The first user in a room without owners becomes its owner;
+    // after that a connection only acts for an owner with the token they were given, or with
+    // the admin token (`admin`).
+    pub fn join(&mut self, conn: u64, user: &str, owner_token: Option<&str>, admin: bool) -> Joined {
+        let first = self.settings.owners.is_empty();
+        if first {
+            self.settings.owners.push(user.to_string());
+            self.save_settings();
+        }
+        let proved = first || owner_token.is_some_and(|token| self.owner_key.verify(&self.name, user, token));
+        self.idle_since = None;
+        let (kick, kicked) = oneshot::channel();
+        let (inbox, inbox_rx) = mpsc::unbounded_channel();
+        self.members.insert(
+            conn,
+            Member {
+                user: user.to_string(),
+                muted: false,
+                proved,
+                admin,
+                kick: Some(kick),
+                inbox,
+            },
+        );
+        self.notify(HookEvent::UserJoined {
+            conn,
+            user: user.to_string(),
+        });
+        let owner_token = (self.owns(conn) && self.is_owner(user)).then(|| self.owner_key.token(&self.name, user));
+        Joined {
+            kicked,
+            inbox: inbox_rx,
+            owner_token,
+        }
+    }
+
+    pub fn leave(&mut self, conn: u64) {
+        if let Some(member) = self.members.remove(&conn) {
+            self.open_strokes.remove(&member.user);
+            self.notify(HookEvent::UserLeft { conn, user: member.user });
+        }
+        if self.members.is_empty() {
+            self.idle_since = Some(Instant::now());
+        }
+    }
+
+    // Whether `user` is listed as one of the room's owners
+    pub fn is_owner(&self, user: &str) -> bool {
+        self.settings.owners.iter().any(|owner| owner == user)
+    }
+
+    // Whether connection `conn` may act as an owner
+    fn owns(&self, conn: u64) -> bool {
+        self.members
+            .get(&conn)
+            .is_some_and(|member| member.admin || (member.proved && self.is_owner(&member.user)))
+    }
+
+    // Hand `user`'s connections the token for the room they now own
+    fn grant(&mut self, user: &str) {
+        let token = self.owner_key.token(&self.name, user);
+        let frame = Frame::new(None, user, WhiteboardEvent::OwnerToken { token });
+        let msg = serde_json::to_string(&frame).unwrap();
+        for member in self.members.values_mut().filter(|member| member.user == user) {
+            member.proved = true;
+            let _ = member.inbox.send(msg.clone());
+        }
+    }
+
+    // Owners, lock and connections, as broadcast to clients
+    pub fn state_frame(&self) -> Frame {
+        Frame::server(WhiteboardEvent::RoomState {
+            owners: self.settings.owners.clone(),
+            locked: self.settings.locked,
+            connections: self
+                .members
+                .iter()
+                .map(|(conn, member)| Connection {
+                    conn: *conn,
+                    user: member.user.clone(),
+                    muted: member.muted,
+                    owner: self.owns(*conn),
+                })
+                .collect(),
+        })
+    }
+
+    // Apply an event sent by one of our own clients.
+    // Returns what to publish to the room, or why the event was rejected.
+    // An event whose `ref` was already applied is only acknowledged again.
+    pub fn apply_local(&mut self, conn: u64, user: &str, frame: Frame) -> Result<Applied, String> {
+        let reference = frame.reference.clone();
+        if let Some(reference) = &reference {
+            let seen = self.applied.get(user).and_then(|applied| applied.iter().find(|(r, _)| r == reference));
+            if let Some((_, seq)) = seen {
+                return Ok(Applied {
+                    frames: Vec::new(),
+                    seq: *seq,
+                });
+            }
+        }
+
+        let frames = self.apply_frame(conn, user, frame)?;
+        let seq = frames.iter().filter_map(|frame| frame.seq).max();
+        if let Some(reference) = reference {
+            let applied = self.applied.entry(user.to_string()).or_default();
+            applied.push_back((reference, seq));
+            if applied.len() > APPLIED_LIMIT {
+                applied.pop_front();
+            }
+        }
+        Ok(Applied { frames, seq })
+    }
+
+    fn apply_frame(&mut self, conn: u64, user: &str, frame: Frame) -> Result<Vec<Frame>, String> {
+        let event = &frame.event;
+        if event.is_server_only() {
+            return Err("clients cannot send server messages".to_string());
+        }
+        if event.is_admin() {
+            if !self.owns(conn) {
+                return Err("only room owners can administer the room".to_string());
+            }
+            return self.administer(Some(user), frame.event);
+        }
+
+        let edits = event.changes_board()
+            || matches!(
+                event,
+                WhiteboardEvent::Undo
+                    | WhiteboardEvent::Redo
+                    | WhiteboardEvent::CreateCheckpoint { .. }
+                    | WhiteboardEvent::RestoreCheckpoint { .. }
+            );
+        // Muting silences chat as well; a locked board can still be talked about
+        let speaks = matches!(event, WhiteboardEvent::ChatMessage { .. });
+        if (edits || speaks) && self.members.get(&conn).is_some_and(|member| member.muted) {
+            return Err("connection is muted".to_string());
+        }
+        if edits && self.settings.locked && !self.owns(conn) {
+            return Err("room is locked".to_string());
+        }
+
+        let mut frames = self.apply_event(user, frame)?;
+        for frame in &mut frames {
+            self.commit(frame);
+        }
+        Ok(frames)
+    }
+
+    // Apply an administrative command, from an owner's connection or the HTTP API (`by: None`)
+    pub fn administer(&mut self, by: Option<&str>, event: WhiteboardEvent) -> Result<Vec<Frame>, String> {
+        match event {
+            WhiteboardEvent::ClearBoard => {
+                self.board.clear(by);
+                // Nothing left to undo, and nobody should be able to undo the clear piecemeal
+                self.history.clear();
+                self.open_strokes.clear();
+                let mut frame = Frame {
+                    user: by.map(String::from),
+                    ..Frame::server(WhiteboardEvent::ClearBoard)
+                };
+                self.commit(&mut frame);
+                self.notify(HookEvent::BoardCleared { user: by.map(String::from) });
+                Ok(vec![frame])
+            }
+            WhiteboardEvent::LockRoom { locked } => {
+                self.settings.locked = locked;
+                self.save_settings();
+                Ok(vec![self.state_frame()])
+            }
+            WhiteboardEvent::Kick { conn } => {
+                let member = self
+                    .members
+                    .get_mut(&conn)
+                    .ok_or_else(|| format!("no connection {}", conn))?;
+                if let Some(kick) = member.kick.take() {
+                    let _ = kick.send(());
+                }
+                // The room state goes out once the connection has actually left
+                Ok(Vec::new())
+            }
+            WhiteboardEvent::Mute { conn, muted } => {
+                let member = self
+                    .members
+                    .get_mut(&conn)
+                    .ok_or_else(|| format!("no connection {}", conn))?;
+                member.muted = muted;
+                Ok(vec![self.state_frame()])
+            }
+            WhiteboardEvent::TransferOwnership { to: user } => {
+                if user.is_empty() {
+                    return Err("ownership needs a user".to_string());
+                }
+                match by {
+                    Some(by) => self.settings.owners.retain(|owner| owner != by),
+                    None => self.settings.owners.clear(),
+                }
+                if !self.is_owner(&user) {
+                    self.settings.owners.push(user.clone());
+                }
+                self.save_settings();
+                self.grant(&user);
+                Ok(vec![self.state_frame()])
+            }
+            event => Err(format!("{:?} is not an administrative command", event)),
+        }
+    }
+
+    // Board changes made here since the last call, for other instances
+    pub fn take_update(&mut self) -> Update {
+        let ops = std::mem::take(&mut self.outbox);
+        if ops.is_empty() {
+            return Update::default();
+        }
+        self.board.live_update(ops)
+    }
+
+    pub fn state_vector(&self) -> &StateVector {
+        self.board.state_vector()
+    }
+
+    // Merge board changes made on another instance, handing the frames that
+    // tell our clients about them to `deliver`. Returns false when the update
+    // does not follow on from what we have, so we need a diff.
+    pub fn merge(&mut self, update: Update, deliver: &mut dyn FnMut(String)) -> bool {
+        for op in &update.ops {
+            let id = op.id();
+            let before = self.board.get(id);
+            if !self.board.merge(op) {
+                continue;
+            }
+            let after = self.board.get(id);
+            self.commit_op(op);
+            if let Some(mut frame) = change_frame(op, before, after) {
+                frame.seq = Some(self.seq);
+                deliver(serde_json::to_string(&frame).unwrap());
+            }
+        }
+        self.board.advance(&update)
+    }
+
+    // Put a template's elements on the board, each as a change of its own.
+    // Only an empty board is filled, so a template never lands on anyone's work.
+    pub fn fill(&mut self, template: &str, elements: Vec<WhiteboardEvent>) -> Result<Vec<Frame>, String> {
+        if !self.board.elements().is_empty() {
+            return Err("the room's board is not empty".to_string());
+        }
+        let user = format!("template:{}", template);
+        let mut frames = Vec::new();
+        for event in elements {
+            let id = self.element_id(None)?;
+            self.board.put(&id, &user, event.clone());
+            self.notify(HookEvent::ElementAdded {
+                id: id.clone(),
+                user: user.clone(),
+                element: event.clone(),
+            });
+            let mut frame = Frame::new(Some(id), &user, event);
+            self.commit(&mut frame);
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    // Mirror room settings and chat another instance published
+    pub fn observe(&mut self, frame: &Frame) {
+        match &frame.event {
+            // Only the names travel; owners connected here still need their token
+            WhiteboardEvent::RoomState { owners, locked, .. } => {
+                self.settings.owners = owners.clone();
+                self.settings.locked = *locked;
+                self.save_settings();
+            }
+            WhiteboardEvent::ChatMessage { .. } => self.record_chat(frame.clone()),
+            _ => {}
+        }
+    }
+
+    // Keep a chat message, dropping the oldest once there are more than `CHAT_HISTORY`
+    fn record_chat(&mut self, message: Frame) {
+        self.chat.push_back(message);
+        if self.chat.len() > CHAT_HISTORY {
+            self.chat.pop_front();
+        }
+        let Some(log) = &mut self.log else { return };
+        match log.chat_lines() {
+            // Rewriting only now and then keeps the file from growing without bound
+            lines if lines >= 2 * CHAT_HISTORY => log.rewrite_chat(self.chat.iter().cloned().collect()),
+            _ => log.append_chat(self.chat.back().unwrap().clone()),
+        }
+    }
+
+    fn save_settings(&self) {
+        if let Some(log) = &self.log {
+            log.save_settings(self.settings.clone());
+        }
+    }
+
+    fn apply_event(&mut self, user: &str, frame: Frame) -> Result<Vec<Frame>, String> {
+        let Frame { id, event, .. } = frame;
+        if !matches!(event, WhiteboardEvent::DrawFreehand { .. }) {
+            self.open_strokes.remove(user);
+        }
+
+        match event {
+            WhiteboardEvent::DrawFreehand { x, y, dragging } => {
+                let continuing = self.open_strokes.get(user).filter(|_| dragging).cloned();
+                let id = match continuing {
+                    Some(id) => id,
+                    None => {
+                        let id = self.element_id(id)?;
+                        self.open_strokes.insert(user.to_string(), id.clone());
+                        id
+                    }
+                };
+
+                let before = self.board.get(&id);
+                self.board.append_point(&id, user, (x, y));
+                let after = self.board.get(&id);
+                if let (None, Some(element)) = (&before, &after) {
+                    self.notify(HookEvent::ElementAdded {
+                        id: id.clone(),
+                        user: user.to_string(),
+                        element: element.clone(),
+                    });
+                }
+                let history = self.history.entry(user.to_string()).or_default();
+                match history.undo.last_mut() {
+                    // Every point of a stroke is part of the same undoable change
+                    Some(Edit::Element(change)) if before.is_some() && change.id == id => change.after = after,
+                    _ => record(history, Edit::Element(Change { id: id.clone(), before, after })),
+                }
+                Ok(vec![Frame::new(Some(id), user, WhiteboardEvent::DrawFreehand { x, y, dragging })])
+            }
+            event if event.is_element() => {
+                if let WhiteboardEvent::AddImage { width, height, asset, .. } = &event {
+                    check_image(*width, *height, asset)?;
+                }
+                let id = self.element_id(id)?;
+                let before = self.board.put(&id, user, event.clone());
+                if before.is_none() {
+                    self.notify(HookEvent::ElementAdded {
+                        id: id.clone(),
+                        user: user.to_string(),
+                        element: event.clone(),
+                    });
+                }
+                self.record(user, Change { id: id.clone(), before, after: Some(event.clone()) });
+                Ok(vec![Frame::new(Some(id), user, event)])
+            }
+            WhiteboardEvent::DeleteElement => {
+                let id = id.ok_or("DeleteElement needs an element id")?;
+                let before = self
+                    .board
+                    .remove(&id, user)
+                    .ok_or_else(|| format!("no element {:?}", id))?;
+                self.record(user, Change { id: id.clone(), before: Some(before), after: None });
+                Ok(vec![Frame::new(Some(id), user, WhiteboardEvent::DeleteElement)])
+            }
+            WhiteboardEvent::ChatMessage { text, .. } => {
+                if text.trim().is_empty() || text.len() > MAX_CHAT_BYTES {
+                    return Err(format!("chat messages must be 1 to {} bytes", MAX_CHAT_BYTES));
+                }
+                let ts = store::now_millis();
+                let frame = Frame::new(None, user, WhiteboardEvent::ChatMessage { text, ts });
+                self.record_chat(frame.clone());
+                Ok(vec![frame])
+            }
+            WhiteboardEvent::CreateCheckpoint { name } => Ok(vec![self.create_checkpoint(user, &name)?]),
+            WhiteboardEvent::RestoreCheckpoint { name } => Ok(self.restore_checkpoint(user, &name)?.into_iter().collect()),
+            WhiteboardEvent::Undo => Ok(self.undo(user).into_iter().collect()),
+            WhiteboardEvent::Redo => Ok(self.redo(user).into_iter().collect()),
+            // View changes are relayed as they are
+            event => Ok(vec![Frame::new(id, user, event)]),
+        }
+    }
+
+    fn undo(&mut self, user: &str) -> Option<Frame> {
+        let history = self.history.get_mut(user)?;
+        while let Some(edit) = history.undo.pop() {
+            let Some(frame) = step(&mut self.board, user, &edit, true) else { continue };
+            history.redo.push(edit);
+            return Some(frame);
+        }
+        None
+    }
+
+    fn redo(&mut self, user: &str) -> Option<Frame> {
+        let history = self.history.get_mut(user)?;
+        while let Some(edit) = history.redo.pop() {
+            let Some(frame) = step(&mut self.board, user, &edit, false) else { continue };
+            history.undo.push(edit);
+            return Some(frame);
+        }
+        None
+    }
+
+    // Save the board as it is now under `name`, replacing any checkpoint of that name.
+    // Returns the room's new list of checkpoints.
+    fn create_checkpoint(&mut self, user: &str, name: &str) -> Result<Frame, String> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > MAX_CHECKPOINT_NAME {
+            return Err(format!("checkpoint names must be 1 to {} bytes", MAX_CHECKPOINT_NAME));
+        }
+        self.checkpoints.retain(|checkpoint| checkpoint.name != name);
+        if self.checkpoints.len() >= MAX_CHECKPOINTS {
+            self.checkpoints.remove(0);
+        }
+        self.checkpoints.push(Checkpoint {
+            name: name.to_string(),
+            seq: self.seq,
+            ts: store::now_millis(),
+            user: Some(user.to_string()),
+            elements: board_frames(&self.board),
+        });
+        if let Some(log) = &self.log {
+            log.save_checkpoints(self.checkpoints.clone());
+        }
+        info!(room = %self.name, checkpoint = name, "created checkpoint");
+        Ok(self.checkpoints_frame())
+    }
+
+    // Put the board back the way it was at checkpoint `name`, as one change the user can undo.
+    // Returns the frame resetting every client's board, or nothing if the board already matches.
+    fn restore_checkpoint(&mut self, user: &str, name: &str) -> Result<Option<Frame>, String> {
+        let elements = self
+            .checkpoint(name)
+            .ok_or_else(|| format!("no checkpoint {:?}", name))?
+            .elements
+            .clone();
+        let kept: HashMap<&str, &WhiteboardEvent> = elements
+            .iter()
+            .filter_map(|frame| Some((frame.id.as_deref()?, &frame.event)))
+            .collect();
+
+        let mut changes: Vec<Change> = self
+            .board
+            .elements()
+            .into_iter()
+            .filter(|(id, _)| !kept.contains_key(id))
+            .map(|(id, event)| Change {
+                id: id.to_string(),
+                before: Some(event),
+                after: None,
+            })
+            .collect();
+        for (id, event) in kept {
+            let before = self.board.get(id);
+            if before.as_ref() != Some(event) {
+                changes.push(Change {
+                    id: id.to_string(),
+                    before,
+                    after: Some(event.clone()),
+                });
+            }
+        }
+        if changes.is_empty() {
+            return Ok(None);
+        }
+
+        for change in &changes {
+            set_element(&mut self.board, user, &change.id, change.after.clone());
+        }
+        self.open_strokes.clear();
+        record(self.history.entry(user.to_string()).or_default(), Edit::Restore(changes));
+        info!(room = %self.name, checkpoint = name, user, "restored checkpoint");
+        Ok(Some(reset_frame(&self.board, user)))
+    }
+
+    // Give a frame that changed the board its place in the room's order and
+    // persist it with the ops it made, queueing the ops for other instances
+    fn commit(&mut self, frame: &mut Frame) {
+        if !frame.event.changes_board() {
+            return;
+        }
+        let ops = self.board.take_ops();
+        self.outbox.extend(ops.iter().cloned());
+        self.append(frame.clone(), ops);
+        frame.seq = Some(self.seq);
+    }
+
+    // Persist an op merged from another instance
+    fn commit_op(&mut self, op: &Op) {
+        self.append(op_frame(op), vec![op.clone()]);
+    }
+
+    fn append(&mut self, frame: Frame, ops: Vec<Op>) {
+        self.seq += 1;
+        let Some(log) = &mut self.log else { return };
+
+        let entry = LogEntry {
+            seq: self.seq,
+            ts: store::now_millis(),
+            frame,
+            ops,
+        };
+        log.append(entry);
+        if log.needs_compaction() {
+            self.compact();
+        }
+    }
+
+    fn record(&mut self, user: &str, change: Change) {
+        record(self.history.entry(user.to_string()).or_default(), Edit::Element(change));
+    }
+
+    fn element_id(&mut self, requested: Option<String>) -> Result<String, String> {
+        match requested {
+            Some(id) if id.is_empty() || id.len() > 64 => Err(format!("invalid element id {:?}", id)),
+            Some(id) => Ok(id),
+            None => {
+                self.next_id += 1;
+                Ok(format!("{}.{}", instance_id(), self.next_id))
+            }
+        }
+    }
+}
+
+// Images must name an uploaded asset and have a size that can be drawn
+pub fn check_image(width: f64, height: f64, asset: &str) -> Result<(), String> {
+    if !assets::is_asset_id(asset) {
+        return Err(format!("invalid asset id {:?}", asset));
+    }
+    let valid = |length: f64| length.is_finite() && length > 0.0 && length <= MAX_IMAGE_SIZE;
+    if !valid(width) || !valid(height) {
+        return Err(format!("image sizes must be between 0 and {}", MAX_IMAGE_SIZE));
+    }
+    Ok(())
+}
+
+fn record(history: &mut History, edit: Edit) {
+    history.redo.clear();
+    history.undo.push(edit);
+    if history.undo.len() > HISTORY_LIMIT {
+        history.undo.remove(0);
+    }
+}
+
+// One frame per element on the board, in drawing order
+pub fn board_frames(board: &Doc) -> Vec<Frame> {
+    board
+        .elements()
+        .into_iter()
+        .map(|(id, event)| Frame {
+            id: Some(id.to_string()),
+            ..Frame::server(event)
+        })
+        .collect()
+}
+
+// The board a snapshot holds
+pub fn restore_board(doc: Doc, elements: &[Frame]) -> Doc {
+    let mut board = doc.resume(instance_id());
+    // Snapshots written before the board was a CRDT only hold frames
+    for element in elements {
+        apply_to_board(&mut board, element);
+    }
+    board
+}
+
+// Apply a logged event to a board restored from the snapshot before it
+pub fn replay_entry(board: &mut Doc, entry: &LogEntry) {
+    if entry.ops.is_empty() {
+        apply_to_board(board, &entry.frame);
+    }
+    for op in &entry.ops {
+        board.merge(op);
+    }
+}
+
+// Replay a frame from a log written before ops were persisted, or from a recording
+pub fn apply_to_board(board: &mut Doc, frame: &Frame) {
+    let user = frame.user.as_deref().unwrap_or_default();
+    if frame.event == WhiteboardEvent::ClearBoard {
+        board.clear(frame.user.as_deref());
+        return;
+    }
+    let Some(id) = &frame.id else { return };
+    match &frame.event {
+        WhiteboardEvent::DrawFreehand { x, y, .. } => board.append_point(id, user, (*x, *y)),
+        WhiteboardEvent::DeleteElement => {
+            board.remove(id, user);
+        }
+        event if event.is_element() => {
+            board.put(id, user, event.clone());
+        }
+        _ => {}
+    }
+}
+
+// Put an element back into the given state and build the frame telling peers about it
+fn set_element(board: &mut Doc, user: &str, id: &str, value: Option<WhiteboardEvent>) -> Frame {
+    match value {
+        Some(event) => {
+            board.put(id, user, event.clone());
+            Frame::new(Some(id.to_string()), user, event)
+        }
+        None => {
+            board.remove(id, user);
+            Frame::new(Some(id.to_string()), user, WhiteboardEvent::DeleteElement)
+        }
+    }
+}
+
+// Take what an edit changed back to how it was before it (`undo`), or forward again.
+// Elements someone else changed since are left alone, so their work is never clobbered;
+// returns nothing when that leaves nothing to do, or else the frame telling peers.
+fn step(board: &mut Doc, user: &str, edit: &Edit, undo: bool) -> Option<Frame> {
+    let sides = |change: &Change| match undo {
+        true => (change.after.clone(), change.before.clone()),
+        false => (change.before.clone(), change.after.clone()),
+    };
+    match edit {
+        Edit::Element(change) => {
+            let (from, to) = sides(change);
+            (board.get(&change.id) == from).then(|| set_element(board, user, &change.id, to))
+        }
+        Edit::Restore(changes) => {
+            let mut stepped = false;
+            for change in changes {
+                let (from, to) = sides(change);
+                if board.get(&change.id) == from {
+                    set_element(board, user, &change.id, to);
+                    stepped = true;
+                }
+            }
+            stepped.then(|| reset_frame(board, user))
+        }
+    }
+}
+
+// The frame replacing every client's board with this one
+fn reset_frame(board: &Doc, user: &str) -> Frame {
+    Frame::new(None, user, WhiteboardEvent::ResetBoard { elements: board_frames(board) })
+}
+
+// The frame an op stands for in the room log
+fn op_frame(op: &Op) -> Frame {
+    let event = match op {
+        Op::Set { value: Some(event), .. } => event.clone(),
+        Op::Set { value: None, .. } => WhiteboardEvent::DeleteElement,
+        Op::Append { point: (x, y), .. } => WhiteboardEvent::DrawFreehand { x: *x, y: *y, dragging: true },
+    };
+    Frame {
+        id: Some(op.id().to_string()),
+        user: op.user().map(String::from),
+        ..Frame::server(event)
+    }
+}
+
+// What clients need to hear about a merged op, if anything: a point added to
+// the end of a stroke is sent as just that point, anything else as the whole element
+fn change_frame(op: &Op, before: Option<WhiteboardEvent>, after: Option<WhiteboardEvent>) -> Option<Frame> {
+    if before == after {
+        return None;
+    }
+    let appended = match (&before, &after, op) {
+        (
+            Some(WhiteboardEvent::DrawStroke { points: old }),
+            Some(WhiteboardEvent::DrawStroke { points: new }),
+            Op::Append { point, .. },
+        ) => new.len() == old.len() + 1 && new.starts_with(old) && new.last() == Some(point),
+        _ => false,
+    };
+    if appended || after.is_none() {
+        return Some(op_frame(op));
+    }
+    Some(Frame {
+        id: Some(op.id().to_string()),
+        user: op.user().map(String::from),
+        ..Frame::server(after?)
+    })
+}
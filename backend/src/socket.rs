@@ -0,0 +1,235 @@
+use axum::{
+    extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    admin::has_admin_token,
+    hub::publish_frames,
+    outbound::{Next, Outbound},
+    protocol::{Frame, WhiteboardEvent},
+    AppState,
+};
+
+// Close code sent to clients removed by a room owner
+const CLOSE_KICKED: u16 = 4001;
+
+// Counter used to tell connections apart in logs and admin commands
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+// Query parameters accepted on `/ws`, e.g. `/ws?room=retro&user=alice`.
+// `owner_token` is the token an owner was welcomed with before.
+#[derive(Deserialize)]
+pub struct ConnectParams {
+    #[serde(default = "default_room")]
+    room: String,
+    user: Option<String>,
+    owner_token: Option<String>,
+}
+
+pub fn default_room() -> String {
+    "default".to_string()
+}
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<ConnectParams>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if params.room.is_empty() || params.room.len() > 64 {
+        warn!(%peer, room = %params.room, "rejected connection with invalid room name");
+        return (StatusCode::BAD_REQUEST, "room names must be 1 to 64 bytes").into_response();
+    }
+
+    let conn_id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+    let user = params.user.unwrap_or_else(|| format!("anon-{}", conn_id));
+    // Operators act as owners in every room; only tools can set the header, browsers cannot
+    let admin = state.admin_token.as_deref().is_some_and(|token| has_admin_token(token, &headers));
+    let span = info_span!("conn", conn = conn_id, %peer, room = %params.room, user = %user, admin);
+    ws.on_upgrade(move |socket| {
+        handle_socket(socket, state, params.room, conn_id, user, params.owner_token, admin).instrument(span)
+    })
+    .into_response()
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    room: String,
+    conn: u64,
+    user: String,
+    owner_token: Option<String>,
+    admin: bool,
+) {
+    info!("client connected");
+    // Take the snapshot and subscribe under the room lock so no change falls in between
+    let (room_state, welcome, snapshot, mut rx, mut kicked, mut inbox) = state.rooms.with(&room, |room_handle, room_state| {
+        let joined = room_state.join(conn, &user, owner_token.as_deref(), admin);
+        let welcome = Frame {
+            seq: Some(room_state.seq()),
+            ..Frame::new(None, &user, WhiteboardEvent::Welcome { conn, owner_token: joined.owner_token })
+        };
+        let rx = state.hub.subscribe(&room);
+        state.hub.publish(&room, serde_json::to_string(&room_state.state_frame()).unwrap());
+        // Pick up anything other instances have that this one missed
+        state.hub.sync(&room, room_state.state_vector());
+        let mut snapshot = room_state.snapshot();
+        snapshot.push(room_state.chat_history());
+        snapshot.push(room_state.checkpoints_frame());
+        (room_handle.clone(), welcome, snapshot, rx, joined.kicked, joined.inbox)
+    });
+    let (mut sender, mut receiver) = socket.split();
+    // Everything for this client waits here, so a slow client only ever holds itself up
+    let outbound = Outbound::new(conn, &room, state.client_queue);
+
+    // Task to move the room's messages into the queue as they are published
+    let pump_task = tokio::spawn({
+        let outbound = outbound.clone();
+        async move {
+            loop {
+                tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Ok(msg) => {
+                            if !outbound.push(msg) {
+                                break;
+                            }
+                        }
+                        // Only happens if this task is starved; keep going with the newest messages
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!(skipped, "client lagged behind the broadcast channel");
+                        }
+                        Err(RecvError::Closed) => {
+                            outbound.close(None);
+                            break;
+                        }
+                    },
+                    Some(msg) = inbox.recv() => {
+                        if !outbound.push(msg) {
+                            break;
+                        }
+                    }
+                    Ok(()) = &mut kicked => {
+                        info!("client kicked");
+                        outbound.close(Some(CloseFrame {
+                            code: CLOSE_KICKED,
+                            reason: "kicked by a room owner".into(),
+                        }));
+                        break;
+                    }
+                }
+            }
+        }
+        .in_current_span()
+    });
+
+    // Task to bring this client up to date, then write out whatever is queued for it
+    let mut send_task = tokio::spawn({
+        let outbound = outbound.clone();
+        async move {
+            for frame in std::iter::once(welcome).chain(snapshot) {
+                let msg = serde_json::to_string(&frame).unwrap();
+                if let Err(e) = sender.send(Message::Text(msg)).await {
+                    warn!(error = %e, "failed to send snapshot to client");
+                    return;
+                }
+            }
+
+            loop {
+                match outbound.next().await {
+                    Next::Send(msg) => {
+                        if let Err(e) = sender.send(Message::Text(msg)).await {
+                            warn!(error = %e, "failed to send to client");
+                            break;
+                        }
+                    }
+                    Next::Close(frame) => {
+                        if frame.is_some() {
+                            let _ = sender.send(Message::Close(frame)).await;
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        .in_current_span()
+    });
+
+    // Receive messages from this client and publish the valid ones to the room,
+    // until either side hangs up
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => msg,
+            _ = &mut send_task => break,
+        };
+        let Some(msg) = msg else { break };
+
+        match msg {
+            Ok(Message::Text(msg)) => {
+                let frame = match serde_json::from_str::<Frame>(&msg) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        warn!(error = %e, "rejected malformed event");
+                        if let Some(reference) = malformed_ref(&msg) {
+                            let reason = format!("malformed event: {}", e);
+                            let reply = Frame::reply(reference, None, WhiteboardEvent::Reject { reason });
+                            outbound.push(serde_json::to_string(&reply).unwrap());
+                        }
+                        continue;
+                    }
+                };
+
+                // Publish while holding the room so peers see changes in the order they were applied
+                let reference = frame.reference.clone();
+                let mut room_state = room_state.lock().unwrap();
+                let (reply, seq) = match room_state.apply_local(conn, &user, frame) {
+                    Ok(applied) => {
+                        publish_frames(&*state.hub, &room, applied.frames, room_state.take_update());
+                        (WhiteboardEvent::Ack, applied.seq)
+                    }
+                    Err(reason) => {
+                        warn!(%reason, "rejected event");
+                        (WhiteboardEvent::Reject { reason }, None)
+                    }
+                };
+                // Clients that do not name their events get no replies
+                if let Some(reference) = reference {
+                    outbound.push(serde_json::to_string(&Frame::reply(reference, seq, reply)).unwrap());
+                }
+            }
+            Ok(Message::Binary(_)) => warn!("rejected binary frame"),
+            Ok(Message::Close(_)) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!(error = %e, "websocket error");
+                break;
+            }
+        }
+    }
+
+    pump_task.abort();
+    send_task.abort();
+    {
+        let mut room_state = room_state.lock().unwrap();
+        room_state.leave(conn);
+        state.hub.publish(&room, serde_json::to_string(&room_state.state_frame()).unwrap());
+    }
+    info!("client disconnected");
+}
+
+// The `ref` of a message that is not a valid frame, so the rejection can still name it
+fn malformed_ref(msg: &str) -> Option<String> {
+    let value = serde_json::from_str::<serde_json::Value>(msg).ok()?;
+    value.get("ref")?.as_str().map(String::from)
+}
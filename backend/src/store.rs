@@ -0,0 +1,712 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+use crate::{
+    crdt::{Doc, Op},
+    protocol::Frame,
+    webhook::Webhook,
+};
+
+// On-disk room history.
+//
+// Each room gets a directory holding generations of
+//   snapshot-<seq>.json  the board's CRDT document after event <seq>
+//   log-<seq>.jsonl      the events applied after that snapshot, one per line
+// Compaction writes a new snapshot and starts a new log, so loading a room
+// only ever reads one snapshot plus a short tail, however old the board is.
+// Older generations are kept for history or pruned, depending on `retain`.
+// Chat lives apart from the board in chat.jsonl, and only the latest messages are kept.
+// Named checkpoints of the board are kept in checkpoints.json.
+#[derive(Clone, Debug)]
+pub struct Store {
+    dir: PathBuf,
+    // Compact once the current log holds this many events
+    compact_tail: usize,
+    // How many older generations to keep; `None` keeps all of them
+    retain: Option<usize>,
+}
+
+// One line of a room log
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub seq: u64,
+    // Unix time in milliseconds
+    pub ts: u64,
+    #[serde(flatten)]
+    pub frame: Frame,
+    // The CRDT ops the frame made, which is what replaying the log applies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ops: Vec<Op>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    seq: u64,
+    #[serde(default)]
+    doc: Doc,
+    // Plain elements, as written before the board was a CRDT
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    elements: Vec<Frame>,
+}
+
+// Per-room settings that outlive the connections, stored next to the history
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoomSettings {
+    #[serde(default)]
+    pub owners: Vec<String>,
+    #[serde(default)]
+    pub locked: bool,
+    // What happens to the room when it is evicted for being idle; `None` follows the server default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    // Endpoints notified about this room's events, on top of the global ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<Webhook>,
+}
+
+// The board as it was when someone marked it, to return to later
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub name: String,
+    // Where the room's order stood, and when, in Unix milliseconds
+    pub seq: u64,
+    pub ts: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    // Every element on the board, in drawing order
+    pub elements: Vec<Frame>,
+}
+
+// Whether an idle room's history is kept when it is evicted from memory
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Retention {
+    // Keep the history on disk, so the room comes back as it was next time it is used
+    #[default]
+    Persist,
+    // Delete the history, so the room starts empty next time
+    Discard,
+}
+
+// What a room looked like on disk when it was opened
+pub struct Loaded {
+    pub seq: u64,
+    pub doc: Doc,
+    pub elements: Vec<Frame>,
+    pub tail: Vec<LogEntry>,
+    pub settings: RoomSettings,
+    // Chat messages, oldest first
+    pub chat: Vec<Frame>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl Store {
+    pub fn new(dir: PathBuf, compact_tail: usize, retain: Option<usize>) -> Self {
+        Store {
+            dir,
+            compact_tail,
+            retain,
+        }
+    }
+
+    // Whether `room` has ever been persisted
+    pub fn has_room(&self, room: &str) -> bool {
+        self.dir.join(encode_room(room)).is_dir()
+    }
+
+    pub fn open_room(&self, room: &str) -> io::Result<(Loaded, RoomLog)> {
+        let dir = self.dir.join(encode_room(room));
+        fs::create_dir_all(&dir)?;
+
+        let base = generations(&dir)?.last().copied().unwrap_or(0);
+        let snapshot = read_snapshot(&dir, base)?;
+        // Cut off anything a crash left half-written, so new lines start on a line of their own
+        let (tail, good): (Vec<LogEntry>, _) = read_log(&log_path(&dir, base))?;
+        truncate(&log_path(&dir, base), good)?;
+        let (chat, good): (Vec<Frame>, _) = read_log(&dir.join("chat.jsonl"))?;
+        truncate(&dir.join("chat.jsonl"), good)?;
+        let settings = match fs::read(dir.join("settings.json")) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => RoomSettings::default(),
+            Err(e) => return Err(e),
+        };
+        let checkpoints = match fs::read(dir.join("checkpoints.json")) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(&dir, base))?;
+        let chat_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join("chat.jsonl"))?;
+        let log = RoomLog {
+            dir,
+            file,
+            chat_file,
+            chat_lines: chat.len(),
+            tail: tail.len(),
+            compact_tail: self.compact_tail,
+            retain: self.retain,
+        };
+        let seq = tail.last().map_or(snapshot.seq, |entry| entry.seq);
+        Ok((
+            Loaded {
+                seq,
+                doc: snapshot.doc,
+                elements: snapshot.elements,
+                tail,
+                settings,
+                chat,
+                checkpoints,
+            },
+            log,
+        ))
+    }
+
+    // All of a room's history still on disk: the oldest snapshot kept and every event since.
+    // A room that was never persisted has an empty history.
+    pub fn history(&self, room: &str) -> io::Result<History> {
+        let dir = self.dir.join(encode_room(room));
+        let generations = match generations(&dir) {
+            Ok(generations) => generations,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let base = generations.first().copied().unwrap_or(0);
+        let snapshot = read_snapshot(&dir, base)?;
+        let mut entries = Vec::new();
+        for generation in &generations {
+            entries.extend(read_log::<LogEntry>(&log_path(&dir, *generation))?.0);
+        }
+        Ok(History {
+            seq: snapshot.seq,
+            doc: snapshot.doc,
+            elements: snapshot.elements,
+            entries,
+        })
+    }
+}
+
+// A room's history as far back as it goes
+pub struct History {
+    // Where the history starts, and the board at that point
+    pub seq: u64,
+    pub doc: Doc,
+    pub elements: Vec<Frame>,
+    // Every event after `seq`, in order
+    pub entries: Vec<LogEntry>,
+}
+
+// The open log of one room
+pub struct RoomLog {
+    dir: PathBuf,
+    file: File,
+    chat_file: File,
+    // Chat messages in the chat file, including ones no longer kept in memory
+    chat_lines: usize,
+    // Events written since the last snapshot
+    tail: usize,
+    compact_tail: usize,
+    retain: Option<usize>,
+}
+
+impl RoomLog {
+    pub fn append(&mut self, entry: &LogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.tail += 1;
+        Ok(())
+    }
+
+    pub fn append_chat(&mut self, message: &Frame) -> io::Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        self.chat_file.write_all(&line)?;
+        self.chat_lines += 1;
+        Ok(())
+    }
+
+    // Replace the chat file with just `messages`, dropping older ones
+    pub fn rewrite_chat<'a>(&mut self, messages: impl IntoIterator<Item = &'a Frame>) -> io::Result<()> {
+        let mut contents = Vec::new();
+        let mut lines = 0;
+        for message in messages {
+            serde_json::to_writer(&mut contents, message)?;
+            contents.push(b'\n');
+            lines += 1;
+        }
+        let path = self.dir.join("chat.jsonl");
+        write_atomic(&path, &contents)?;
+        self.chat_file = OpenOptions::new().append(true).open(path)?;
+        self.chat_lines = lines;
+        Ok(())
+    }
+
+    pub fn save_settings(&self, settings: &RoomSettings) -> io::Result<()> {
+        write_atomic(&self.dir.join("settings.json"), &serde_json::to_vec(settings)?)
+    }
+
+    pub fn save_checkpoints(&self, checkpoints: &[Checkpoint]) -> io::Result<()> {
+        write_atomic(&self.dir.join("checkpoints.json"), &serde_json::to_vec(checkpoints)?)
+    }
+
+    // Delete the room's history and settings for good
+    pub fn destroy(self) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_dir_all(&self.dir)
+    }
+
+    // Fold everything up to `seq` into a snapshot of `doc` and start a new log
+    pub fn compact(&mut self, seq: u64, doc: Doc) -> io::Result<()> {
+        let snapshot = serde_json::to_vec(&Snapshot {
+            seq,
+            doc,
+            elements: Vec::new(),
+        })?;
+        write_atomic(&snapshot_path(&self.dir, seq), &snapshot)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(&self.dir, seq))?;
+        self.tail = 0;
+        self.prune()
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        let Some(retain) = self.retain else { return Ok(()) };
+        let generations = generations(&self.dir)?;
+        // The newest generation is the live one
+        let old = generations.len().saturating_sub(retain + 1);
+        for base in &generations[..old] {
+            for path in [snapshot_path(&self.dir, *base), log_path(&self.dir, *base)] {
+                match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A room's log, written by a thread of its own so the room never waits on the disk.
+// Writes are made in the order they are queued; failures can only be logged, as
+// whoever made the change has moved on. The thread ends once the writer is dropped
+// and everything queued has been written.
+pub struct LogWriter {
+    queue: mpsc::Sender<LogWrite>,
+    // The log's counters as they will be once everything queued is written
+    tail: usize,
+    chat_lines: usize,
+    compact_tail: usize,
+    finished: Arc<Finished>,
+}
+
+enum LogWrite {
+    Entry(LogEntry),
+    Chat(Frame),
+    RewriteChat(Vec<Frame>),
+    Settings(RoomSettings),
+    Checkpoints(Vec<Checkpoint>),
+    Compact(u64, Doc),
+    Destroy,
+    Flush(oneshot::Sender<()>),
+}
+
+impl LogWriter {
+    pub fn spawn(room: &str, log: RoomLog) -> Self {
+        let (queue, writes) = mpsc::channel();
+        let finished = Arc::new(Finished::default());
+        let writer = LogWriter {
+            queue,
+            tail: log.tail,
+            chat_lines: log.chat_lines,
+            compact_tail: log.compact_tail,
+            finished: finished.clone(),
+        };
+        let room = room.to_string();
+        let mut log = Some(log);
+        thread::Builder::new()
+            .name("room-log".to_string())
+            .spawn(move || {
+                for write in writes {
+                    log_write(&room, &mut log, write);
+                }
+                finished.set();
+            })
+            .expect("failed to start room log writer");
+        writer
+    }
+
+    pub fn append(&mut self, entry: LogEntry) {
+        self.tail += 1;
+        self.send(LogWrite::Entry(entry));
+    }
+
+    pub fn append_chat(&mut self, message: Frame) {
+        self.chat_lines += 1;
+        self.send(LogWrite::Chat(message));
+    }
+
+    pub fn chat_lines(&self) -> usize {
+        self.chat_lines
+    }
+
+    pub fn rewrite_chat(&mut self, messages: Vec<Frame>) {
+        self.chat_lines = messages.len();
+        self.send(LogWrite::RewriteChat(messages));
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.tail >= self.compact_tail
+    }
+
+    pub fn has_tail(&self) -> bool {
+        self.tail > 0
+    }
+
+    pub fn save_settings(&self, settings: RoomSettings) {
+        self.send(LogWrite::Settings(settings));
+    }
+
+    pub fn save_checkpoints(&self, checkpoints: Vec<Checkpoint>) {
+        self.send(LogWrite::Checkpoints(checkpoints));
+    }
+
+    // Snapshot `doc` as of `seq`; it is serialised and written off the caller's thread
+    pub fn compact(&mut self, seq: u64, doc: Doc) {
+        self.tail = 0;
+        self.send(LogWrite::Compact(seq, doc));
+    }
+
+    // Delete the room's history for good, once what is queued before it is written
+    pub fn destroy(self) -> Arc<Finished> {
+        self.send(LogWrite::Destroy);
+        self.finished.clone()
+    }
+
+    // Stop writing once what is queued is written, which the returned handle waits for
+    pub fn close(self) -> Arc<Finished> {
+        self.finished.clone()
+    }
+
+    // Resolves once everything queued so far is on disk, e.g. before reading history back
+    pub fn flushed(&self) -> oneshot::Receiver<()> {
+        let (done, flushed) = oneshot::channel();
+        self.send(LogWrite::Flush(done));
+        flushed
+    }
+
+    fn send(&self, write: LogWrite) {
+        // The thread only ends once the writer is dropped
+        let _ = self.queue.send(write);
+    }
+}
+
+fn log_write(room: &str, log: &mut Option<RoomLog>, write: LogWrite) {
+    if let LogWrite::Flush(done) = write {
+        let _ = done.send(());
+        return;
+    }
+    // A destroyed log has nothing left to write to
+    let Some(open) = log else { return };
+    let (result, what) = match write {
+        LogWrite::Entry(entry) => (open.append(&entry), "append to room log"),
+        LogWrite::Chat(message) => (open.append_chat(&message), "save chat message"),
+        LogWrite::RewriteChat(messages) => (open.rewrite_chat(&messages), "save chat message"),
+        LogWrite::Settings(settings) => (open.save_settings(&settings), "save room settings"),
+        LogWrite::Checkpoints(checkpoints) => (open.save_checkpoints(&checkpoints), "save checkpoints"),
+        LogWrite::Compact(seq, doc) => match open.compact(seq, doc) {
+            Ok(()) => {
+                info!(room, seq, "compacted room history");
+                return;
+            }
+            Err(e) => (Err(e), "compact room history"),
+        },
+        LogWrite::Destroy => (log.take().unwrap().destroy(), "delete room history"),
+        LogWrite::Flush(_) => unreachable!(),
+    };
+    if let Err(e) = result {
+        error!(room, error = %e, "failed to {}", what);
+    }
+}
+
+// Set once a room's log writer has written everything and stopped
+#[derive(Default)]
+pub struct Finished {
+    done: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Finished {
+    fn set(&self) {
+        *self.done.lock().unwrap() = true;
+        self.changed.notify_all();
+    }
+
+    pub fn is_done(&self) -> bool {
+        *self.done.lock().unwrap()
+    }
+
+    // Block until the writer has stopped
+    pub fn wait(&self) {
+        let mut done = self.done.lock().unwrap();
+        while !*done {
+            done = self.changed.wait(done).unwrap();
+        }
+    }
+}
+
+// Write to a temporary file first so a crash never leaves a half-written file behind.
+// Every write gets a temporary file of its own, so concurrent writes of the same file
+// cannot mix their contents; the last rename wins.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let tmp = path.with_extension(format!("{}-{}.tmp", process::id(), WRITES.fetch_add(1, Ordering::Relaxed)));
+    let written = File::create(&tmp).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    let result = written.and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// Lowercase hex, as used for content hashes and signatures
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn read_snapshot(dir: &Path, seq: u64) -> io::Result<Snapshot> {
+    match fs::read(snapshot_path(dir, seq)) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        // Generation 0 starts from an empty board
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Snapshot {
+            seq,
+            doc: Doc::default(),
+            elements: Vec::new(),
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+// The entries of a log, and how many bytes of it they take up
+fn read_log<T: DeserializeOwned>(path: &Path) -> io::Result<(Vec<T>, u64)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    let mut good = 0;
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        // A crash mid-write can leave a torn last line; everything before it is good
+        let parsed = match line.last() {
+            Some(b'\n') => serde_json::from_slice(&line).map_err(|e| e.to_string()),
+            _ => Err("line ends early".to_string()),
+        };
+        match parsed {
+            Ok(entry) => {
+                entries.push(entry);
+                good += line.len() as u64;
+            }
+            Err(e) => {
+                warn!(log = %path.display(), error = %e, "ignoring unreadable end of room log");
+                break;
+            }
+        }
+    }
+    Ok((entries, good))
+}
+
+// Cut a log back to its first `len` bytes, if it is any longer
+fn truncate(path: &Path, len: u64) -> io::Result<()> {
+    let file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if file.metadata()?.len() > len {
+        file.set_len(len)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+// Sequence numbers of the generations in a room directory, oldest first
+fn generations(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut bases = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        let base = name
+            .strip_prefix("snapshot-")
+            .and_then(|rest| rest.strip_suffix(".json"))
+            .or_else(|| name.strip_prefix("log-").and_then(|rest| rest.strip_suffix(".jsonl")));
+        if let Some(base) = base.and_then(|base| base.parse().ok()) {
+            bases.push(base);
+        }
+    }
+    bases.sort_unstable();
+    bases.dedup();
+    Ok(bases)
+}
+
+// Zero-padded so directory listings sort in sequence order
+fn snapshot_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("snapshot-{:020}.json", seq))
+}
+
+fn log_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("log-{:020}.jsonl", seq))
+}
+
+// Room names come from URLs; keep them from escaping the data directory
+fn encode_room(room: &str) -> String {
+    let mut encoded = String::new();
+    for byte in room.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::WhiteboardEvent;
+
+    fn entry(seq: u64) -> LogEntry {
+        let event = WhiteboardEvent::DrawLine {
+            from: (0.0, 0.0),
+            to: (seq as f64, 0.0),
+            color: "#2563eb".to_string(),
+            width: 3.0,
+        };
+        LogEntry {
+            seq,
+            ts: 0,
+            frame: Frame::new(Some(format!("l{}", seq)), "alice", event),
+            ops: Vec::new(),
+        }
+    }
+
+    // A crash can leave half a line at the end of the log; the next line written after
+    // reopening must not be glued onto it and lost with it
+    #[test]
+    fn torn_log_tails_are_cut_off_on_open() {
+        let dir = std::env::temp_dir().join(format!("wb-store-{}-torn", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = Store::new(dir.clone(), 1000, None);
+        let (_, mut log) = store.open_room("torn").unwrap();
+        log.append(&entry(1)).unwrap();
+        log.append(&entry(2)).unwrap();
+        drop(log);
+
+        let path = log_path(&dir.join(encode_room("torn")), 0);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"seq":3,"ts":0,"type":"DrawL"#).unwrap();
+        drop(file);
+
+        let (loaded, mut log) = store.open_room("torn").unwrap();
+        assert_eq!(loaded.seq, 2);
+        log.append(&entry(3)).unwrap();
+        drop(log);
+
+        let (loaded, _) = store.open_room("torn").unwrap();
+        let seqs: Vec<u64> = loaded.tail.iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [1, 2, 3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The writer's thread must keep queued writes in order, compaction included, and
+    // have them all on disk once it reports it has finished
+    #[test]
+    fn log_writer_writes_everything_in_order() {
+        let dir = std::env::temp_dir().join(format!("wb-store-{}-writer", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = Store::new(dir.clone(), 2, None);
+        let (_, log) = store.open_room("writer").unwrap();
+        let mut writer = LogWriter::spawn("writer", log);
+        writer.append(entry(1));
+        writer.append(entry(2));
+        assert!(writer.needs_compaction());
+        writer.compact(2, Doc::new("test"));
+        assert!(!writer.has_tail());
+        writer.append(entry(3));
+        writer.close().wait();
+
+        let (loaded, log) = store.open_room("writer").unwrap();
+        assert_eq!(loaded.seq, 3);
+        assert_eq!(loaded.tail.len(), 1);
+        LogWriter::spawn("writer", log).destroy().wait();
+        assert!(!store.has_room("writer"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Concurrent uploads of the same image write the same file at once
+    #[test]
+    fn concurrent_atomic_writes_do_not_collide() {
+        let dir = std::env::temp_dir().join(format!("wb-store-{}-atomic", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("asset");
+        let contents: Vec<Vec<u8>> = (0..8u8).map(|n| vec![n; 64 * 1024]).collect();
+        std::thread::scope(|scope| {
+            for contents in &contents {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        write_atomic(path, contents).unwrap();
+                    }
+                });
+            }
+        });
+        assert!(contents.contains(&fs::read(&path).unwrap()));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "temporary files were left behind");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use std::fmt::Write;
+
+use crate::{
+    assets::Images,
+    protocol::WhiteboardEvent,
+    render::{parse_color, Bounds, DOT_RADIUS, IMAGE_DASH, IMAGE_OUTLINE, STROKE_COLOR},
+};
+
+// The web client's font stack for text
+const FONT_FAMILY: &str = "Inter, -apple-system, system-ui, sans-serif";
+
+// A standalone SVG document showing `bounds` of the board, one pixel per board unit,
+// with `elements` drawn in order on a white background. Image elements embed their
+// image from `images`, so the file needs nothing else to show them.
+pub fn svg(elements: &[WhiteboardEvent], images: &Images, bounds: Bounds) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="{x} {y} {w} {h}">"#,
+        x = bounds.x0,
+        y = bounds.y0,
+        w = bounds.width(),
+        h = bounds.height(),
+    );
+    let _ = writeln!(
+        out,
+        r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#ffffff"/>"##,
+        bounds.x0,
+        bounds.y0,
+        bounds.width(),
+        bounds.height()
+    );
+    for element in elements {
+        element_svg(&mut out, element, images);
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+fn element_svg(out: &mut String, element: &WhiteboardEvent, images: &Images) {
+    let _ = match element {
+        WhiteboardEvent::DrawFreehand { x, y, .. } => stroke_svg(out, &[(*x, *y)]),
+        WhiteboardEvent::DrawStroke { points } => stroke_svg(out, points),
+        WhiteboardEvent::DrawLine { from, to, color, width } => writeln!(
+            out,
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="{}"{} stroke-width="{}" stroke-linecap="round"/>"#,
+            from.0,
+            from.1,
+            to.0,
+            to.1,
+            hex(color),
+            opacity(color),
+            width
+        ),
+        WhiteboardEvent::DrawRect { from, to, color, width } => writeln!(
+            out,
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="{}"{} stroke-width="{}"/>"#,
+            from.0.min(to.0),
+            from.1.min(to.1),
+            (to.0 - from.0).abs(),
+            (to.1 - from.1).abs(),
+            hex(color),
+            opacity(color),
+            width
+        ),
+        WhiteboardEvent::DrawCircle { center, radius, color, width } => writeln!(
+            out,
+            r#"<circle cx="{}" cy="{}" r="{}" fill="none" stroke="{}"{} stroke-width="{}"/>"#,
+            center.0,
+            center.1,
+            radius,
+            hex(color),
+            opacity(color),
+            width
+        ),
+        WhiteboardEvent::AddText { pos, text, color, size } => writeln!(
+            out,
+            r#"<text x="{}" y="{}" font-family="{}" font-size="{}" fill="{}"{} xml:space="preserve">{}</text>"#,
+            pos.0,
+            pos.1,
+            FONT_FAMILY,
+            size,
+            hex(color),
+            opacity(color),
+            escape(text)
+        ),
+        WhiteboardEvent::AddImage { pos, width, height, asset } if images.contains_key(asset) => {
+            let (kind, data) = &images[asset];
+            writeln!(
+                out,
+                r#"<image x="{}" y="{}" width="{}" height="{}" preserveAspectRatio="none" href="data:{};base64,{}"/>"#,
+                pos.0,
+                pos.1,
+                width,
+                height,
+                kind.mime(),
+                base64(data)
+            )
+        }
+        WhiteboardEvent::AddImage { pos, width, height, .. } => writeln!(
+            out,
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="{}" stroke-width="1" stroke-dasharray="{} {}"/>"#,
+            pos.0,
+            pos.1,
+            width,
+            height,
+            IMAGE_OUTLINE,
+            IMAGE_DASH[0],
+            IMAGE_DASH[1]
+        ),
+        _ => Ok(()),
+    };
+}
+
+// A freehand stroke as one path through its points. The canvas draws a dot at every
+// point; a round-capped path as wide as the dots looks the same and scales cleanly.
+fn stroke_svg(out: &mut String, points: &[(f64, f64)]) -> std::fmt::Result {
+    let Some(((x, y), rest)) = points.split_first() else { return Ok(()) };
+    let mut d = format!("M{} {}", x, y);
+    if rest.is_empty() {
+        // A lone point still shows as a dot with round caps
+        let _ = write!(d, " L{} {}", x, y);
+    }
+    for (x, y) in rest {
+        let _ = write!(d, " L{} {}", x, y);
+    }
+    writeln!(
+        out,
+        r#"<path d="{}" fill="none" stroke="{}" stroke-width="{}" stroke-linecap="round" stroke-linejoin="round"/>"#,
+        d,
+        STROKE_COLOR,
+        DOT_RADIUS * 2.0
+    )
+}
+
+// Colours are written back out normalised, so nothing a client sent reaches the markup as is
+fn hex(color: &str) -> String {
+    let (r, g, b, _) = parse_color(color);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+fn opacity(color: &str) -> String {
+    match parse_color(color) {
+        (_, _, _, 255) => String::new(),
+        (_, _, _, a) => format!(r#" opacity="{:.3}""#, a as f64 / 255.0),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Not allowed in XML at all
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Standard base64 with padding, for data URIs
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_pads_to_whole_groups() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar\xff"), "Zm9vYmFy/w==");
+    }
+
+    #[test]
+    fn colours_are_written_back_normalised() {
+        assert_eq!(hex("#ABC"), "#aabbcc");
+        assert_eq!(hex("\"/><script>"), "#000000");
+        assert_eq!(opacity("#2563eb"), "");
+        assert_eq!(opacity("#2563eb80"), r#" opacity="0.502""#);
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(escape(r#"<b>"Tom" & 'Jerry'</b>"#), "&lt;b&gt;&quot;Tom&quot; &amp; &apos;Jerry&apos;&lt;/b&gt;");
+        // Control characters are not allowed in XML, apart from whitespace
+        assert_eq!(escape("a\u{0}b\u{1b}c\td\ne"), "abc\td\ne");
+    }
+
+    #[test]
+    fn text_elements_cannot_break_out_of_the_markup() {
+        let text = WhiteboardEvent::AddText {
+            pos: (1.0, 2.0),
+            text: "</text><script>alert(1)</script>".to_string(),
+            color: "red\" onload=\"alert(1)".to_string(),
+            size: 16.0,
+        };
+        let svg = svg(&[text], &Images::new(), Bounds::parse("0,0,100,100").unwrap());
+        assert!(!svg.contains("<script>"), "{}", svg);
+        assert!(!svg.contains("onload"), "{}", svg);
+        assert!(svg.contains(r##"fill="#000000""##), "{}", svg);
+    }
+}
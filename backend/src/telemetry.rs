@@ -0,0 +1,19 @@
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogFormat;
+
+// Install the global tracing subscriber.
+// Verbosity comes from `RUST_LOG` (defaults to `info`), the output format from config.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
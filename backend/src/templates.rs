@@ -0,0 +1,418 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{admin::authorize, hub::publish_frames, protocol::WhiteboardEvent, room, store, AppState};
+
+// Templates every server has. Stored templates of the same name take their place,
+// and deleting those brings the built-in ones back.
+const BUILT_IN: [(&str, &str); 3] = [
+    ("retro", include_str!("../templates/retro.json")),
+    ("kanban", include_str!("../templates/kanban.json")),
+    ("architecture", include_str!("../templates/architecture.json")),
+];
+
+// Most elements a template may hold
+const MAX_ELEMENTS: usize = 1000;
+
+// A named set of elements new boards can start from. Text may hold placeholders like
+// `{{title}}`, filled in when a room is created from the template.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Template {
+    #[serde(default)]
+    pub description: String,
+    pub elements: Vec<WhiteboardEvent>,
+}
+
+// What the template list shows about each template
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub name: String,
+    pub description: String,
+    pub built_in: bool,
+    // Placeholders its text holds, besides `room`
+    pub placeholders: Vec<String>,
+    pub elements: usize,
+}
+
+// What deleting a template did
+pub enum Removal {
+    Deleted,
+    // A stored template was deleted and the built-in one it replaced is back
+    Restored,
+    // Built-in templates cannot be deleted
+    BuiltIn,
+    Missing,
+}
+
+pub struct Templates {
+    built_in: BTreeMap<String, Template>,
+    // Templates added over HTTP, including ones replacing built-in templates
+    stored: RwLock<BTreeMap<String, Template>>,
+    // Where stored templates are kept; they last until restart when unset
+    dir: Option<PathBuf>,
+}
+
+impl Templates {
+    // The built-in templates, and every template stored in `dir`
+    pub fn load(dir: Option<PathBuf>) -> Self {
+        let built_in = BUILT_IN
+            .iter()
+            .map(|(name, json)| (name.to_string(), serde_json::from_str(json).expect("built-in templates are valid")))
+            .collect();
+        let stored = match &dir {
+            Some(dir) => read_dir(dir).unwrap_or_else(|e| {
+                error!(dir = %dir.display(), error = %e, "failed to read stored templates");
+                BTreeMap::new()
+            }),
+            None => BTreeMap::new(),
+        };
+        info!(stored = stored.len(), "loaded board templates");
+        Templates {
+            built_in,
+            stored: RwLock::new(stored),
+            dir,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Summary> {
+        let stored = self.stored.read().unwrap();
+        let mut all = self.built_in.clone();
+        all.extend(stored.iter().map(|(name, template)| (name.clone(), template.clone())));
+        all.into_iter()
+            .map(|(name, template)| Summary {
+                built_in: self.built_in.contains_key(&name),
+                description: template.description.clone(),
+                placeholders: placeholders(&template),
+                elements: template.elements.len(),
+                name,
+            })
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Template> {
+        let stored = self.stored.read().unwrap();
+        stored.get(name).or_else(|| self.built_in.get(name)).cloned()
+    }
+
+    // Add or replace a template. Returns true if there was none by that name.
+    pub fn put(&self, name: &str, template: Template) -> io::Result<bool> {
+        let mut stored = self.stored.write().unwrap();
+        if let Some(dir) = &self.dir {
+            fs::create_dir_all(dir)?;
+            store::write_atomic(&dir.join(format!("{}.json", name)), &serde_json::to_vec_pretty(&template)?)?;
+        }
+        let existed = stored.insert(name.to_string(), template).is_some() || self.built_in.contains_key(name);
+        Ok(!existed)
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<Removal> {
+        let mut stored = self.stored.write().unwrap();
+        if !stored.contains_key(name) {
+            return Ok(if self.built_in.contains_key(name) { Removal::BuiltIn } else { Removal::Missing });
+        }
+        if let Some(dir) = &self.dir {
+            match fs::remove_file(dir.join(format!("{}.json", name))) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        stored.remove(name);
+        Ok(if self.built_in.contains_key(name) { Removal::Restored } else { Removal::Deleted })
+    }
+}
+
+fn read_dir(dir: &std::path::Path) -> io::Result<BTreeMap<String, Template>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut templates = BTreeMap::new();
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()?.strip_suffix(".json")) else { continue };
+        if check_name(name).is_err() {
+            continue;
+        }
+        match fs::read(&path).map(|bytes| serde_json::from_slice::<Template>(&bytes)) {
+            Ok(Ok(template)) => {
+                templates.insert(name.to_string(), template);
+            }
+            Ok(Err(e)) => warn!(path = %path.display(), error = %e, "skipping unreadable template"),
+            Err(e) => warn!(path = %path.display(), error = %e, "skipping unreadable template"),
+        }
+    }
+    Ok(templates)
+}
+
+// Template names end up in file names, so they are kept plain
+fn check_name(name: &str) -> Result<(), String> {
+    let plain = name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if name.is_empty() || name.len() > 64 || !plain {
+        return Err("template names must be 1 to 64 letters, digits, '-' or '_'".to_string());
+    }
+    Ok(())
+}
+
+fn check(template: &Template) -> Result<(), String> {
+    if template.elements.len() > MAX_ELEMENTS {
+        return Err(format!("templates hold at most {} elements", MAX_ELEMENTS));
+    }
+    for element in &template.elements {
+        if !element.is_element() {
+            return Err(format!("{:?} is not a board element", element));
+        }
+        if let WhiteboardEvent::AddImage { width, height, asset, .. } = element {
+            room::check_image(*width, *height, asset)?;
+        }
+    }
+    Ok(())
+}
+
+// The template's elements with their placeholders filled in from `values`;
+// placeholders without a value are left as they are, to be typed over
+pub fn instantiate(template: &Template, values: &HashMap<String, String>) -> Vec<WhiteboardEvent> {
+    template
+        .elements
+        .iter()
+        .map(|element| match element {
+            WhiteboardEvent::AddText { pos, text, color, size } => WhiteboardEvent::AddText {
+                pos: *pos,
+                text: replace_placeholders(text, |name| values.get(name).cloned()),
+                color: color.clone(),
+                size: *size,
+            },
+            element => element.clone(),
+        })
+        .collect()
+}
+
+fn placeholders(template: &Template) -> Vec<String> {
+    let mut names = Vec::new();
+    for element in &template.elements {
+        if let WhiteboardEvent::AddText { text, .. } = element {
+            replace_placeholders(text, |name| {
+                if name != "room" && !names.iter().any(|known| known == name) {
+                    names.push(name.to_string());
+                }
+                None
+            });
+        }
+    }
+    names
+}
+
+// `text` with every `{{name}}` that `value` has a value for replaced by it
+fn replace_placeholders(text: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut filled = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else { break };
+        let end = start + 2 + len + 2;
+        filled.push_str(&rest[..start]);
+        match value(rest[start + 2..end - 2].trim()) {
+            Some(value) => filled.push_str(&value),
+            None => filled.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+// Listing and reading templates is open, for the web client's picker, and so is creating
+// a room from one; changing templates needs the admin token
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/templates", get(list))
+        .route("/templates/:name", get(show).put(put).delete(delete))
+        .route("/rooms/:room/template", post(create_room))
+}
+
+#[derive(Deserialize)]
+struct CreateBody {
+    template: String,
+    // Placeholder values; `room` defaults to the room's name
+    #[serde(default)]
+    values: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct Created {
+    elements: usize,
+    seq: u64,
+}
+
+async fn list(State(state): State<AppState>) -> Response {
+    Json(state.templates.list()).into_response()
+}
+
+async fn show(State(state): State<AppState>, Path(name): Path<String>) -> Response {
+    match state.templates.get(&name) {
+        Some(template) => Json(template).into_response(),
+        None => (StatusCode::NOT_FOUND, "no such template").into_response(),
+    }
+}
+
+async fn put(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(template): Json<Template>,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    if let Err(reason) = check_name(&name).and_then(|()| check(&template)) {
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    match state.templates.put(&name, template) {
+        Ok(created) => {
+            info!(template = name, created, "template saved over HTTP");
+            if created {
+                StatusCode::CREATED.into_response()
+            } else {
+                StatusCode::NO_CONTENT.into_response()
+            }
+        }
+        Err(e) => {
+            error!(template = name, error = %e, "failed to save template");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to save template").into_response()
+        }
+    }
+}
+
+async fn delete(State(state): State<AppState>, headers: HeaderMap, Path(name): Path<String>) -> Response {
+    if let Err(rejection) = authorize(&state, &headers) {
+        return rejection.into_response();
+    }
+    match state.templates.remove(&name) {
+        Ok(Removal::Deleted | Removal::Restored) => {
+            info!(template = name, "template deleted over HTTP");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(Removal::BuiltIn) => (StatusCode::BAD_REQUEST, "built-in templates cannot be deleted").into_response(),
+        Ok(Removal::Missing) => (StatusCode::NOT_FOUND, "no such template").into_response(),
+        Err(e) => {
+            error!(template = name, error = %e, "failed to delete template");
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to delete template").into_response()
+        }
+    }
+}
+
+async fn create_room(State(state): State<AppState>, Path(room): Path<String>, Json(body): Json<CreateBody>) -> Response {
+    if room.is_empty() || room.len() > 64 {
+        return (StatusCode::BAD_REQUEST, "room names must be 1 to 64 bytes").into_response();
+    }
+    let Some(template) = state.templates.get(&body.template) else {
+        return (StatusCode::NOT_FOUND, "no such template").into_response();
+    };
+    let mut values = body.values;
+    values.entry("room".to_string()).or_insert_with(|| room.clone());
+    let elements = instantiate(&template, &values);
+
+    state.rooms.with(&room, |_, room_state| match room_state.fill(&body.template, elements) {
+        Ok(frames) => {
+            let created = Created {
+                elements: frames.len(),
+                seq: room_state.seq(),
+            };
+            publish_frames(&*state.hub, &room, frames, room_state.take_update());
+            info!(room, template = body.template, "room created from template");
+            (StatusCode::CREATED, Json(created)).into_response()
+        }
+        Err(reason) => (StatusCode::CONFLICT, reason).into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(text: &str, values: &[(&str, &str)]) -> String {
+        replace_placeholders(text, |name| {
+            values.iter().find(|(known, _)| *known == name).map(|(_, value)| value.to_string())
+        })
+    }
+
+    fn text(text: &str) -> WhiteboardEvent {
+        WhiteboardEvent::AddText {
+            pos: (0.0, 0.0),
+            text: text.to_string(),
+            color: "#000000".to_string(),
+            size: 16.0,
+        }
+    }
+
+    #[test]
+    fn placeholders_are_filled_by_trimmed_name() {
+        let values = [("title", "Sprint 12"), ("room", "retro")];
+        assert_eq!(fill("{{title}} in {{ room }}", &values), "Sprint 12 in retro");
+        assert_eq!(fill("{{title}}{{title}}", &values), "Sprint 12Sprint 12");
+        assert_eq!(fill("no placeholders", &values), "no placeholders");
+        // Values are not searched for placeholders in turn
+        assert_eq!(fill("{{a}}", &[("a", "{{b}}"), ("b", "no")]), "{{b}}");
+    }
+
+    #[test]
+    fn placeholders_without_a_value_are_left_as_written() {
+        assert_eq!(fill("Owner: {{ owner }}", &[]), "Owner: {{ owner }}");
+        assert_eq!(fill("{{}}", &[]), "{{}}");
+    }
+
+    #[test]
+    fn unterminated_and_nested_braces_are_left_alone() {
+        let values = [("a", "1"), ("b", "2")];
+        assert_eq!(fill("{{a}} and {{b", &values), "1 and {{b");
+        assert_eq!(fill("{{a", &values), "{{a");
+        assert_eq!(fill("}}a{{", &values), "}}a{{");
+        // A placeholder runs to the first `}}`, so `a {{b` is the name here and has no value
+        assert_eq!(fill("{{a {{b}} }}", &values), "{{a {{b}} }}");
+        assert_eq!(fill("{{{a}}}", &values), "{{{a}}}");
+    }
+
+    #[test]
+    fn only_text_is_filled_in() {
+        let line = WhiteboardEvent::DrawLine {
+            from: (0.0, 0.0),
+            to: (1.0, 1.0),
+            color: "#000000".to_string(),
+            width: 2.0,
+        };
+        let template = Template {
+            description: String::new(),
+            elements: vec![text("{{title}} / {{room}}"), line.clone(), text("{{owner}} {{title}}")],
+        };
+        let values = HashMap::from([("title".to_string(), "Plan".to_string())]);
+        assert_eq!(
+            instantiate(&template, &values),
+            [text("Plan / {{room}}"), line, text("{{owner}} Plan")]
+        );
+        // Listed once each, in order, leaving out the room's name
+        assert_eq!(placeholders(&template), ["title", "owner"]);
+    }
+
+    #[test]
+    fn template_names_are_plain() {
+        assert!(check_name("retro-2_b").is_ok());
+        assert!(check_name(&"t".repeat(64)).is_ok());
+        for name in ["", &"t".repeat(65), "two words", "../retro", "retro.json", "café"] {
+            assert!(check_name(name).is_err(), "{:?} was accepted", name);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{info, warn};
+
+// Certificate and private key locations for native TLS termination
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // How often the files are checked for changes
+    pub reload_interval: Duration,
+}
+
+pub async fn load(tls: &TlsConfig) -> io::Result<RustlsConfig> {
+    // rustls needs a process-wide crypto provider; ignore the error if one is already installed
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(&tls.cert, &tls.key).await
+}
+
+// Watch the certificate and key for changes and swap them into the running server.
+// Existing connections keep their session; new handshakes pick up the new certificate.
+pub fn spawn_reloader(config: RustlsConfig, tls: TlsConfig) {
+    tokio::spawn(async move {
+        let mut last_seen = modified(&tls.cert, &tls.key).await;
+        let mut interval = tokio::time::interval(tls.reload_interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            let current = modified(&tls.cert, &tls.key).await;
+            if current == last_seen {
+                continue;
+            }
+
+            match config.reload_from_pem_file(&tls.cert, &tls.key).await {
+                Ok(()) => {
+                    info!(cert = %tls.cert.display(), "reloaded TLS certificate");
+                    last_seen = current;
+                }
+                // Probably caught a half-written file; keep serving the old certificate and retry
+                Err(e) => warn!(cert = %tls.cert.display(), error = %e, "failed to reload TLS certificate"),
+            }
+        }
+    });
+}
+
+async fn modified(cert: &Path, key: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: &Path| {
+        let path = path.to_owned();
+        async move { tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok() }
+    };
+    (mtime(cert).await, mtime(key).await)
+}
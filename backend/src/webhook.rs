@@ -0,0 +1,253 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, time::Instant};
+use tracing::{debug, info_span, warn, Instrument};
+
+use crate::{metrics, protocol::WhiteboardEvent, store};
+
+// Header carrying `sha256=<hex HMAC-SHA256 of the body>`
+pub const SIGNATURE_HEADER: &str = "X-Whiteboard-Signature";
+
+// An endpoint notified about room events, signing with its own secret
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: String,
+}
+
+// What happened in a room
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HookEvent {
+    RoomCreated,
+    UserJoined { conn: u64, user: String },
+    UserLeft { conn: u64, user: String },
+    ElementAdded { id: String, user: String, element: WhiteboardEvent },
+    // `user` is unset when the board was cleared over the admin API
+    BoardCleared { user: Option<String> },
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Notification {
+    pub room: String,
+    // Unix time in milliseconds
+    pub ts: u64,
+    #[serde(flatten)]
+    pub event: HookEvent,
+}
+
+// The body of one delivery
+#[derive(Serialize)]
+struct Batch<'a> {
+    events: &'a [Notification],
+}
+
+// Delivery timing, from config
+#[derive(Clone, Copy, Debug)]
+pub struct Delivery {
+    // How long a batch stays open for more events once its first event is queued
+    pub batch_window: Duration,
+    pub batch_max: usize,
+    // Attempts per batch before it is dropped
+    pub attempts: u32,
+    // Wait before the first retry; doubled for every retry after it, up to `MAX_BACKOFF`
+    pub backoff: Duration,
+    // Most notifications waiting for one endpoint; the oldest go to make room for new ones
+    pub queue_max: usize,
+}
+
+// How long an endpoint's delivery task waits for more notifications before it ends
+const IDLE: Duration = Duration::from_secs(300);
+
+// Longest wait between retries, however many attempts are configured
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// Sends room events to webhooks. Cheap to clone; every room holds one.
+//
+// Each endpoint gets its own queue and delivery task, so a slow or failing
+// endpoint only delays its own notifications. Events are delivered in order
+// in batches of up to `batch_max`, and a failed batch is retried with
+// exponential backoff before it is given up on. A room's own webhooks are
+// endpoints of their own, so removing one from a room stops only that room's deliveries.
+#[derive(Clone)]
+pub struct Webhooks {
+    // Endpoints notified about every room
+    global: Arc<[Webhook]>,
+    endpoints: Arc<Mutex<HashMap<Target, Arc<Endpoint>>>>,
+    client: reqwest::Client,
+    delivery: Delivery,
+}
+
+// A webhook, and the room it was registered for unless it is a global one
+type Target = (Option<String>, Webhook);
+
+// Notifications waiting for one endpoint's delivery task
+#[derive(Default)]
+struct Endpoint {
+    queue: Mutex<VecDeque<Notification>>,
+    ready: Notify,
+    stopped: AtomicBool,
+}
+
+impl Webhooks {
+    pub fn new(global: Vec<Webhook>, delivery: Delivery) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("failed to build webhook HTTP client");
+        Webhooks {
+            global: global.into(),
+            endpoints: Arc::default(),
+            client,
+            delivery,
+        }
+    }
+
+    // Queue `event` for the global webhooks and the room's own
+    pub fn notify(&self, room: &str, room_hooks: &[Webhook], event: HookEvent) {
+        if self.global.is_empty() && room_hooks.is_empty() {
+            return;
+        }
+        let notification = Notification {
+            room: room.to_string(),
+            ts: store::now_millis(),
+            event,
+        };
+        let global = self.global.iter().map(|hook| (None, hook.clone()));
+        let own = room_hooks.iter().map(|hook| (Some(room.to_string()), hook.clone()));
+        // Queued under the map lock, so an idle task cannot end with a notification left behind
+        let mut endpoints = self.endpoints.lock().unwrap();
+        for target in global.chain(own) {
+            let endpoint = endpoints.entry(target.clone()).or_insert_with(|| {
+                let endpoint = Arc::new(Endpoint::default());
+                let span = info_span!("webhook", url = %target.1.url, room = target.0.as_deref());
+                tokio::spawn(deliver(self.clone(), target.clone(), endpoint.clone()).instrument(span));
+                endpoint
+            });
+            let mut queue = endpoint.queue.lock().unwrap();
+            if queue.len() >= self.delivery.queue_max {
+                queue.pop_front();
+                metrics::WEBHOOK_NOTIFICATIONS_DROPPED.inc();
+                warn!(url = %target.1.url, queued = queue.len(), "webhook endpoint is behind, dropped its oldest notification");
+            }
+            queue.push_back(notification.clone());
+            endpoint.ready.notify_one();
+        }
+    }
+
+    // Stop delivering `room`'s notifications to `hook`, dropping any still queued
+    pub fn stop(&self, room: &str, hook: &Webhook) {
+        let target = (Some(room.to_string()), hook.clone());
+        if let Some(endpoint) = self.endpoints.lock().unwrap().remove(&target) {
+            endpoint.stopped.store(true, Ordering::Relaxed);
+            endpoint.ready.notify_one();
+        }
+    }
+
+    // The endpoint's next notification, or none once it is stopped or has been idle for
+    // `IDLE`, in which case it is forgotten and made anew when it is next notified
+    async fn next(&self, target: &Target, endpoint: &Arc<Endpoint>, wait: Duration) -> Option<Notification> {
+        loop {
+            if endpoint.stopped.load(Ordering::Relaxed) {
+                return None;
+            }
+            if let Some(notification) = endpoint.queue.lock().unwrap().pop_front() {
+                return Some(notification);
+            }
+            if tokio::time::timeout(wait, endpoint.ready.notified()).await.is_err() {
+                let mut endpoints = self.endpoints.lock().unwrap();
+                if endpoint.queue.lock().unwrap().is_empty() {
+                    // Unless it was stopped and the webhook registered again since
+                    if endpoints.get(target).is_some_and(|current| Arc::ptr_eq(current, endpoint)) {
+                        endpoints.remove(target);
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+async fn deliver(hooks: Webhooks, target: Target, endpoint: Arc<Endpoint>) {
+    let (client, delivery, hook) = (&hooks.client, hooks.delivery, &target.1);
+    let key = hmac::Key::new(hmac::HMAC_SHA256, hook.secret.as_bytes());
+    while let Some(first) = hooks.next(&target, &endpoint, IDLE).await {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + delivery.batch_window;
+        while batch.len() < delivery.batch_max {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let next = endpoint.queue.lock().unwrap().pop_front();
+            match next {
+                Some(notification) => batch.push(notification),
+                None if wait.is_zero() => break,
+                None => {
+                    let _ = tokio::time::timeout(wait, endpoint.ready.notified()).await;
+                    continue;
+                }
+            }
+        }
+
+        let body = serde_json::to_vec(&Batch { events: &batch }).unwrap();
+        let signature = format!("sha256={}", store::hex(hmac::sign(&key, &body).as_ref()));
+        let mut backoff = delivery.backoff;
+        for attempt in 1..=delivery.attempts {
+            if endpoint.stopped.load(Ordering::Relaxed) {
+                return;
+            }
+            let result = client
+                .post(&hook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => {
+                    debug!(events = batch.len(), attempt, "delivered webhook batch");
+                    break;
+                }
+                Err(e) if attempt < delivery.attempts => {
+                    warn!(error = %e, attempt, retry_in_ms = backoff.as_millis() as u64, "webhook delivery failed");
+                    tokio::time::sleep(backoff).await;
+                    backoff = next_backoff(backoff);
+                }
+                Err(e) => warn!(error = %e, attempt, events = batch.len(), "giving up on webhook batch"),
+            }
+        }
+    }
+    debug!("webhook delivery task ended");
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+    backoff.saturating_mul(2).min(MAX_BACKOFF.max(backoff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_a_cap() {
+        let mut backoff = Duration::from_secs(1);
+        let waits: Vec<u64> = (0..12)
+            .map(|_| {
+                backoff = next_backoff(backoff);
+                backoff.as_secs()
+            })
+            .collect();
+        assert_eq!(waits, [2, 4, 8, 16, 32, 64, 128, 256, 300, 300, 300, 300]);
+        // A configured first wait above the cap is kept rather than shortened, and never overflows
+        assert_eq!(next_backoff(Duration::from_secs(600)), Duration::from_secs(600));
+        assert_eq!(next_backoff(Duration::MAX), Duration::MAX);
+    }
+}
@@ -0,0 +1,238 @@
+// Drives the `wb` command-line client against a server started in-process.
+
+use std::{net::SocketAddr, process::Stdio, time::Duration};
+
+use backend::{config::Config, protocol::BoardSnapshot};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+    time::timeout,
+};
+
+async fn start(config: Config) -> SocketAddr {
+    let app = backend::router(&config).await.unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+fn wb(addr: SocketAddr, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_wb"));
+    command
+        .arg("--url")
+        .arg(format!("http://{}", addr))
+        .args(args)
+        .env_remove("WB_URL")
+        .env_remove("WB_ADMIN_TOKEN")
+        .env_remove("WB_OWNER_TOKEN")
+        .kill_on_drop(true);
+    command
+}
+
+// Run `wb` with `input` on stdin, returning its exit code, stdout and stderr
+async fn run(addr: SocketAddr, args: &[&str], input: &str) -> (i32, String, String) {
+    let mut child = wb(addr, args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input.as_bytes()).await.unwrap();
+    drop(stdin);
+    let output = timeout(Duration::from_secs(10), child.wait_with_output()).await.unwrap().unwrap();
+    (
+        output.status.code().unwrap_or(-1),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+async fn board(addr: SocketAddr, room: &str) -> BoardSnapshot {
+    let (code, stdout, stderr) = run(addr, &["dump", room], "").await;
+    assert_eq!(code, 0, "{}", stderr);
+    serde_json::from_str(&stdout).unwrap()
+}
+
+const EVENTS: &str = r##"{"type":"DrawLine","id":"l1","from":[0,0],"to":[100,50],"color":"#2563eb","width":3}
+{"type":"DrawRect","id":"r1","from":[10,10],"to":[60,40],"color":"#16a34a","width":2}
+
+{"type":"AddText","id":"t1","pos":[20,80],"text":"hello","color":"#1e293b","size":18}
+"##;
+
+#[tokio::test]
+async fn posts_dumps_and_copies_boards() {
+    let addr = start(Config {
+        admin_token: Some("secret".into()),
+        ..Config::default()
+    })
+    .await;
+    let (code, _, stderr) = run(addr, &["post", "source"], EVENTS).await;
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stderr.contains("3 of 3 events accepted"), "{}", stderr);
+
+    let source = board(addr, "source").await;
+    let ids: Vec<_> = source.elements.iter().map(|frame| frame.id.clone().unwrap()).collect();
+    assert_eq!(ids, ["l1", "r1", "t1"]);
+    assert_eq!(source.seq, 3);
+
+    let (code, _, stderr) = run(addr, &["copy", "source", "target"], "").await;
+    assert_eq!(code, 0, "{}", stderr);
+    let target = board(addr, "target").await;
+    let events = |board: &BoardSnapshot| board.elements.iter().map(|frame| frame.event.clone()).collect::<Vec<_>>();
+    assert_eq!(events(&target), events(&source));
+
+    // Owning the room takes the token its owner was given, not just their name
+    let (code, _, stderr) = run(addr, &["clear", "target"], "").await;
+    assert_eq!(code, 1, "{}", stderr);
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/rooms/target/owner", addr))
+        .bearer_auth("secret")
+        .header("content-type", "application/json")
+        .body(r#"{"user":"wb"}"#)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    let token = body["owner_token"].as_str().unwrap();
+    let (code, _, stderr) = run(addr, &["--owner-token", token, "clear", "target"], "").await;
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(board(addr, "target").await.elements.is_empty());
+    run(addr, &["post", "target"], EVENTS).await;
+    let (code, _, stderr) = run(addr, &["--token", "secret", "clear", "target"], "").await;
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(board(addr, "target").await.elements.is_empty());
+
+    let (code, svg, _) = run(addr, &["dump", "source", "--format", "svg"], "").await;
+    assert_eq!(code, 0);
+    assert!(svg.contains("<svg") && svg.contains(">hello</text>"), "{}", svg);
+}
+
+#[tokio::test]
+async fn rejected_and_malformed_events_fail_the_command() {
+    let addr = start(Config::default()).await;
+    let input = r#"{"type":"ChatMessage","text":"   "}
+{"type":"ChatMessage","text":"hi"}
+"#;
+    let (code, _, stderr) = run(addr, &["post", "chat"], input).await;
+    assert_eq!(code, 1);
+    assert!(stderr.contains("stdin line 1: rejected: chat messages must be"), "{}", stderr);
+    assert!(stderr.contains("1 of 2 events accepted"), "{}", stderr);
+
+    let (code, _, stderr) = run(addr, &["post", "chat"], "{\"type\":\"Teleport\"}\n").await;
+    assert_eq!(code, 1);
+    assert!(stderr.contains("stdin line 1: unknown variant `Teleport`"), "{}", stderr);
+}
+
+#[tokio::test]
+async fn tail_prints_live_events_as_json_lines() {
+    let addr = start(Config::default()).await;
+    let (code, _, _) = run(addr, &["post", "tailed"], EVENTS).await;
+    assert_eq!(code, 0);
+
+    let mut tail = wb(addr, &["tail", "tailed", "--live"]).stdout(Stdio::piped()).spawn().unwrap();
+    let mut lines = BufReader::new(tail.stdout.take().unwrap()).lines();
+    // Give `tail` time to join before posting
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let input = r##"{"type":"DrawCircle","id":"c1","center":[5,5],"radius":3,"color":"#e11d48","width":1}"##;
+    let (code, _, _) = run(addr, &["post", "tailed"], input).await;
+    assert_eq!(code, 0);
+
+    // The board posted before `tail` joined is left out
+    loop {
+        let line = timeout(Duration::from_secs(5), lines.next_line()).await.unwrap().unwrap().unwrap();
+        let frame: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_ne!(frame["id"], "l1");
+        if frame["type"] == "DrawCircle" {
+            assert_eq!(frame["id"], "c1");
+            assert_eq!(frame["user"], "wb");
+            break;
+        }
+    }
+}
+
+// Start or stop recording `room`, returning the recording's file name
+async fn set_recording(addr: SocketAddr, room: &str, on: bool) -> String {
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/rooms/{}/recording", addr, room))
+        .bearer_auth("secret")
+        .header("content-type", "application/json")
+        .body(format!("{{\"recording\":{}}}", on))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "{}", response.status());
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    body["file"].as_str().unwrap().to_string()
+}
+
+fn elements(board: &BoardSnapshot) -> serde_json::Value {
+    serde_json::to_value(&board.elements).unwrap()
+}
+
+#[tokio::test]
+async fn recordings_play_back_to_the_same_board() {
+    let dir = std::env::temp_dir().join(format!("wb-recordings-{:016x}", fastrand::u64(..)));
+    let addr = start(Config {
+        admin_token: Some("secret".into()),
+        recording_dir: Some(dir.clone()),
+        ..Config::default()
+    })
+    .await;
+    // What is already on the board goes in the recording's header
+    let (code, _, _) = run(addr, &["post", "session"], EVENTS).await;
+    assert_eq!(code, 0);
+
+    let file = set_recording(addr, "session", true).await;
+    let session = r##"{"type":"DrawRect","id":"r1","from":[30,30],"to":[90,70],"color":"#16a34a","width":2}
+{"type":"DeleteElement","id":"l1"}
+{"type":"DrawFreehand","id":"s1","x":1,"y":2,"dragging":false}
+{"type":"DrawFreehand","id":"s1","x":3,"y":4,"dragging":true}
+{"type":"DrawFreehand","id":"s1","x":5,"y":6,"dragging":true}
+{"type":"ChatMessage","text":"looks good"}
+"##;
+    let (code, _, stderr) = run(addr, &["post", "session"], session).await;
+    assert_eq!(code, 0, "{}", stderr);
+    let from_alice = r##"{"type":"AddText","id":"t2","pos":[0,100],"text":"from alice","color":"#1e293b","size":12}"##;
+    let (code, _, _) = run(addr, &["--user", "alice", "post", "session"], from_alice).await;
+    assert_eq!(code, 0);
+    assert_eq!(set_recording(addr, "session", false).await, file);
+
+    let downloaded = reqwest::Client::new()
+        .get(format!("http://{}/recordings/{}", addr, file))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let path = dir.join("downloaded.wbrec");
+    std::fs::write(&path, &downloaded).unwrap();
+    let path = path.to_str().unwrap();
+    let recorded = board(addr, "session").await;
+
+    // Rendering needs no server, and always comes out the same
+    for _ in 0..2 {
+        let (code, stdout, stderr) = run(addr, &["render", path], "").await;
+        assert_eq!(code, 0, "{}", stderr);
+        let rendered: BoardSnapshot = serde_json::from_str(&stdout).unwrap();
+        assert_eq!(elements(&rendered), elements(&recorded));
+        assert_eq!(rendered.seq, recorded.seq);
+    }
+
+    // Played back into an empty room, the recording draws the same board
+    let (code, _, stderr) = run(addr, &["play", path, "replayed", "--speed", "0"], "").await;
+    assert_eq!(code, 0, "{}", stderr);
+    let replayed = board(addr, "replayed").await;
+    let ids: Vec<_> = replayed.elements.iter().map(|frame| frame.id.clone().unwrap()).collect();
+    assert_eq!(ids, ["r1", "t1", "s1", "t2"]);
+    assert_eq!(elements(&replayed), elements(&recorded));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,153 @@
+// Runs two backend instances linked over the TCP relay and checks that rooms
+// stay in sync across them.
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const SECRET: &str = "relay-test-secret";
+
+// Kills the backend process when the test ends, pass or fail
+struct Instance {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn spawn_instance(relay_env: &[(&str, String)]) -> Instance {
+    let addr = free_addr();
+    let child = Command::new(env!("CARGO_BIN_EXE_backend"))
+        .env("WB_ADDR", addr.to_string())
+        .env("RUST_LOG", "warn")
+        .envs(relay_env.iter().map(|(k, v)| (*k, v.as_str())))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Instance { child, addr }
+}
+
+async fn connect(instance: &Instance, room: &str) -> Client {
+    let url = format!("ws://{}/ws?room={}", instance.addr, room);
+    for _ in 0..50 {
+        if let Ok((client, _)) = connect_async(&url).await {
+            return client;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("backend at {} did not come up", instance.addr);
+}
+
+// Next drawing event received, skipping room bookkeeping messages
+async fn next_drawing(client: &mut Client, wait: Duration) -> Option<String> {
+    loop {
+        match timeout(wait, client.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) if text.contains("DrawLine") => return Some(text),
+            Ok(Some(Ok(_))) => continue,
+            _ => return None,
+        }
+    }
+}
+
+fn line_event(x: f64) -> String {
+    format!(
+        r##"{{"type":"DrawLine","from":[{x},0.0],"to":[10.0,10.0],"color":"#2563eb","width":3.0}}"##
+    )
+}
+
+#[tokio::test]
+async fn rooms_are_relayed_between_instances() {
+    let relay_addr = free_addr();
+    let a = spawn_instance(&[("WB_RELAY_LISTEN", relay_addr.to_string()), ("WB_RELAY_SECRET", SECRET.to_string())]);
+    let b = spawn_instance(&[("WB_RELAY_PEERS", relay_addr.to_string()), ("WB_RELAY_SECRET", SECRET.to_string())]);
+
+    let mut on_a = connect(&a, "shared").await;
+    let mut on_b = connect(&b, "shared").await;
+    let mut other_room = connect(&b, "other").await;
+
+    // The relay link comes up asynchronously, so keep drawing until B sees A's events
+    let mut received = None;
+    for attempt in 0..50 {
+        on_a.send(Message::Text(line_event(attempt as f64))).await.unwrap();
+        if let Some(text) = next_drawing(&mut on_b, Duration::from_millis(200)).await {
+            received = Some(text);
+            break;
+        }
+    }
+    let received = received.expect("event drawn on instance A never reached instance B");
+    assert!(received.contains("DrawLine"));
+
+    // And the other direction, now that the link is known to be up
+    on_b.send(Message::Text(line_event(1234.5))).await.unwrap();
+    let mut seen_on_a = false;
+    while let Some(text) = next_drawing(&mut on_a, Duration::from_secs(2)).await {
+        if text.contains("1234.5") {
+            seen_on_a = true;
+            break;
+        }
+    }
+    assert!(seen_on_a, "event drawn on instance B never reached instance A");
+
+    // Rooms stay isolated across the relay
+    assert_eq!(next_drawing(&mut other_room, Duration::from_millis(300)).await, None);
+}
+
+#[tokio::test]
+async fn instances_catch_up_on_changes_made_before_they_linked() {
+    let relay_addr = free_addr();
+    let a = spawn_instance(&[("WB_RELAY_LISTEN", relay_addr.to_string()), ("WB_RELAY_SECRET", SECRET.to_string())]);
+    let mut on_a = connect(&a, "early").await;
+    on_a.send(Message::Text(line_event(4321.5))).await.unwrap();
+    assert!(next_drawing(&mut on_a, Duration::from_secs(2)).await.is_some());
+
+    // B only links up now, so the line can only reach it through a state-vector diff
+    let b = spawn_instance(&[("WB_RELAY_PEERS", relay_addr.to_string()), ("WB_RELAY_SECRET", SECRET.to_string())]);
+    let mut on_b = connect(&b, "early").await;
+    let received = next_drawing(&mut on_b, Duration::from_secs(5)).await;
+    assert!(
+        received.is_some_and(|text| text.contains("4321.5")),
+        "instance B never caught up with a change made on A before they linked"
+    );
+}
+
+#[tokio::test]
+async fn links_without_the_secret_are_refused() {
+    let relay_addr = free_addr();
+    let a = spawn_instance(&[("WB_RELAY_LISTEN", relay_addr.to_string()), ("WB_RELAY_SECRET", SECRET.to_string())]);
+    let b = spawn_instance(&[("WB_RELAY_PEERS", relay_addr.to_string()), ("WB_RELAY_SECRET", "guessed".to_string())]);
+    let mut on_a = connect(&a, "guarded").await;
+    let mut on_b = connect(&b, "guarded").await;
+
+    // Keep drawing on both for as long as a working link would take to come up
+    for attempt in 0..10 {
+        on_a.send(Message::Text(line_event(attempt as f64))).await.unwrap();
+        on_b.send(Message::Text(line_event(100.0 + attempt as f64))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    // A's lines start left of x = 100 and B's right of it
+    let x = |text: &str| serde_json::from_str::<serde_json::Value>(text).unwrap()["from"][0].as_f64().unwrap();
+    while let Some(text) = next_drawing(&mut on_b, Duration::from_millis(300)).await {
+        assert!(x(&text) >= 100.0, "an event from A crossed a link with the wrong secret: {}", text);
+    }
+    while let Some(text) = next_drawing(&mut on_a, Duration::from_millis(300)).await {
+        assert!(x(&text) < 100.0, "an event from B crossed a link with the wrong secret: {}", text);
+    }
+}
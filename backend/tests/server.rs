@@ -0,0 +1,555 @@
+// Starts the server in-process on an ephemeral port and drives it with several
+// WebSocket clients, the way browsers use it.
+
+use std::{net::SocketAddr, time::Duration};
+
+use backend::{config::Config, origin::AllowedOrigins};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Error, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// How long to wait for a message that should arrive, and for one that should not
+const WAIT: Duration = Duration::from_secs(2);
+const QUIET: Duration = Duration::from_millis(300);
+
+async fn start(config: Config) -> SocketAddr {
+    let app = backend::router(&config).await.unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+// Join `room` as `user`, returning the connection id the server gave us
+async fn join(addr: SocketAddr, room: &str, user: &str) -> (Client, u64) {
+    let (client, welcome) = join_with(addr, room, user, "").await;
+    (client, welcome["conn"].as_u64().unwrap())
+}
+
+// Join with more query parameters, e.g. `&owner_token=...`, returning the welcome
+async fn join_with(addr: SocketAddr, room: &str, user: &str, query: &str) -> (Client, Value) {
+    let (mut client, _) = connect_async(format!("ws://{}/ws?room={}&user={}{}", addr, room, user, query))
+        .await
+        .unwrap();
+    let welcome = next_of(&mut client, "Welcome", WAIT).await.expect("no welcome");
+    assert_eq!(welcome["user"], user);
+    (client, welcome)
+}
+
+async fn send(client: &mut Client, frame: Value) {
+    client.send(Message::Text(frame.to_string())).await.unwrap();
+}
+
+async fn next_frame(client: &mut Client, wait: Duration) -> Option<Value> {
+    loop {
+        match timeout(wait, client.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => return Some(serde_json::from_str(&text).unwrap()),
+            Ok(Some(Ok(_))) => continue,
+            _ => return None,
+        }
+    }
+}
+
+// Next message of type `kind`, skipping any others
+async fn next_of(client: &mut Client, kind: &str, wait: Duration) -> Option<Value> {
+    loop {
+        let frame = next_frame(client, wait).await?;
+        if frame["type"] == kind {
+            return Some(frame);
+        }
+    }
+}
+
+// Next message that changes the board, skipping room bookkeeping
+async fn next_drawing(client: &mut Client, wait: Duration) -> Option<Value> {
+    loop {
+        let frame = next_frame(client, wait).await?;
+        let kind = frame["type"].as_str().unwrap_or_default();
+        if kind.starts_with("Draw") || kind.starts_with("Add") || kind == "DeleteElement" || kind == "ClearBoard" {
+            return Some(frame);
+        }
+    }
+}
+
+// Reply to the event sent with `ref` set to `reference`
+async fn reply_to(client: &mut Client, reference: &str) -> Value {
+    loop {
+        let frame = next_frame(client, WAIT).await.expect("no reply");
+        if frame["ref"] == reference {
+            return frame;
+        }
+    }
+}
+
+fn line(id: &str, x: f64) -> Value {
+    json!({"type": "DrawLine", "id": id, "ref": id, "from": [x, 0.0], "to": [10.0, 10.0], "color": "#2563eb", "width": 3.0})
+}
+
+#[tokio::test]
+async fn events_are_relayed_to_everyone_in_the_room() {
+    let addr = start(Config::default()).await;
+    let (mut alice, _) = join(addr, "relay", "alice").await;
+    let (mut bob, _) = join(addr, "relay", "bob").await;
+
+    send(&mut alice, line("l1", 1.5)).await;
+    let ack = reply_to(&mut alice, "l1").await;
+    assert_eq!(ack["type"], "Ack");
+    assert_eq!(ack["seq"], 1);
+
+    let seen = next_drawing(&mut bob, WAIT).await.expect("bob never saw alice's line");
+    assert_eq!(seen["type"], "DrawLine");
+    assert_eq!(seen["id"], "l1");
+    assert_eq!(seen["user"], "alice");
+    assert_eq!(seen["seq"], 1);
+    assert_eq!(seen["from"], json!([1.5, 0.0]));
+
+    // The user on a frame is always the sender's, whatever the client claims
+    let mut forged = line("l2", 2.5);
+    forged["user"] = json!("mallory");
+    send(&mut bob, forged).await;
+    // Senders see their own events too, in the room's order
+    assert_eq!(next_drawing(&mut alice, WAIT).await.unwrap()["id"], "l1");
+    let seen = next_drawing(&mut alice, WAIT).await.expect("alice never saw bob's line");
+    assert_eq!(seen["id"], "l2");
+    assert_eq!(seen["user"], "bob");
+}
+
+#[tokio::test]
+async fn rooms_are_isolated() {
+    let addr = start(Config::default()).await;
+    let (mut alice, _) = join(addr, "first", "alice").await;
+    let (mut bob, _) = join(addr, "second", "bob").await;
+    let (mut carol, _) = join(addr, "first", "carol").await;
+
+    send(&mut alice, line("l1", 1.0)).await;
+    assert!(next_drawing(&mut carol, WAIT).await.is_some());
+    assert_eq!(next_drawing(&mut bob, QUIET).await, None);
+
+    // Nor does a late joiner of another room see the board
+    let (mut dave, _) = join(addr, "second", "dave").await;
+    assert_eq!(next_drawing(&mut dave, QUIET).await, None);
+}
+
+#[tokio::test]
+async fn invalid_events_are_rejected_and_not_relayed() {
+    let addr = start(Config::default()).await;
+    let (mut owner, _) = join(addr, "strict", "owner").await;
+    let (mut guest, _) = join(addr, "strict", "guest").await;
+
+    let rejections = [
+        (json!({"type": "Teleport", "ref": "r1"}), "malformed event"),
+        (json!({"type": "Ack", "ref": "r2"}), "clients cannot send server messages"),
+        (json!({"type": "ClearBoard", "ref": "r3"}), "only room owners can administer the room"),
+        (line(&"x".repeat(65), 0.0), "invalid element id"),
+        (json!({"type": "ChatMessage", "ref": "r5", "text": "   "}), "chat messages must be"),
+        (json!({"type": "CreateCheckpoint", "ref": "r6", "name": ""}), "checkpoint names must be"),
+    ];
+    for (event, reason) in rejections {
+        let reference = event["ref"].as_str().unwrap().to_string();
+        send(&mut guest, event).await;
+        let reply = reply_to(&mut guest, &reference).await;
+        assert_eq!(reply["type"], "Reject", "{} was not rejected", reference);
+        let given = reply["reason"].as_str().unwrap();
+        assert!(given.starts_with(reason), "{} was rejected with {:?}", reference, given);
+    }
+    assert_eq!(next_drawing(&mut owner, QUIET).await, None);
+
+    // A locked room only takes drawing from its owners
+    send(&mut owner, json!({"type": "LockRoom", "locked": true, "ref": "lock"})).await;
+    assert_eq!(reply_to(&mut owner, "lock").await["type"], "Ack");
+    send(&mut guest, line("l1", 1.0)).await;
+    assert_eq!(reply_to(&mut guest, "l1").await["reason"], "room is locked");
+    assert_eq!(next_drawing(&mut owner, QUIET).await, None);
+
+    // Room names are checked before the upgrade
+    let long = "r".repeat(65);
+    assert!(connect_async(format!("ws://{}/ws?room={}", addr, long)).await.is_err());
+}
+
+#[tokio::test]
+async fn owners_are_known_by_their_token() {
+    let addr = start(Config {
+        admin_token: Some("secret".into()),
+        ..Config::default()
+    })
+    .await;
+    let (_first, welcome) = join_with(addr, "owned", "alice", "").await;
+    let token = welcome["owner_token"].as_str().expect("the first user was not made owner").to_string();
+
+    // Anyone can say they are alice, but only alice has her token
+    let (mut impostor, welcome) = join_with(addr, "owned", "alice", "&owner_token=0123abcd").await;
+    assert_eq!(welcome["owner_token"], Value::Null);
+    send(&mut impostor, json!({"type": "LockRoom", "locked": true, "ref": "l1"})).await;
+    assert_eq!(reply_to(&mut impostor, "l1").await["reason"], "only room owners can administer the room");
+    let (mut alice, welcome) = join_with(addr, "owned", "alice", &format!("&owner_token={}", token)).await;
+    assert_eq!(welcome["owner_token"], token.as_str());
+    let state = next_of(&mut alice, "RoomState", WAIT).await.unwrap();
+    let owners: Vec<bool> = state["connections"].as_array().unwrap().iter().map(|c| c["owner"] == true).collect();
+    assert_eq!(owners, [true, false, true]);
+
+    // Handing the room over gives the new owner a token of their own
+    let (mut bob, _) = join(addr, "owned", "bob").await;
+    send(&mut alice, json!({"type": "TransferOwnership", "to": "bob", "ref": "t1"})).await;
+    assert_eq!(reply_to(&mut alice, "t1").await["type"], "Ack");
+    let granted = next_of(&mut bob, "OwnerToken", WAIT).await.expect("bob was never given a token");
+    assert_ne!(granted["token"], token.as_str());
+    send(&mut bob, json!({"type": "LockRoom", "locked": true, "ref": "l2"})).await;
+    assert_eq!(reply_to(&mut bob, "l2").await["type"], "Ack");
+    send(&mut alice, json!({"type": "LockRoom", "locked": false, "ref": "l3"})).await;
+    assert_eq!(reply_to(&mut alice, "l3").await["type"], "Reject");
+
+    // Operators act as owners whoever they connect as
+    let mut request = format!("ws://{}/ws?room=owned&user=ops", addr).into_client_request().unwrap();
+    request.headers_mut().insert("authorization", HeaderValue::from_static("Bearer secret"));
+    let (mut ops, _) = connect_async(request).await.unwrap();
+    send(&mut ops, json!({"type": "LockRoom", "locked": false, "ref": "l4"})).await;
+    assert_eq!(reply_to(&mut ops, "l4").await["type"], "Ack");
+}
+
+#[tokio::test]
+async fn admin_commands_tell_bad_requests_from_missing_connections() {
+    let addr = start(Config {
+        admin_token: Some("secret".into()),
+        ..Config::default()
+    })
+    .await;
+    let (_alice, conn) = join(addr, "admin", "alice").await;
+    let http = reqwest::Client::new();
+    let post = |path: String, body: &'static str| {
+        http.post(format!("http://{}/rooms/admin/{}", addr, path))
+            .bearer_auth("secret")
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+    };
+    assert_eq!(post(format!("connections/{}/mute", conn), r#"{"muted":true}"#).await.unwrap().status(), 204);
+    assert_eq!(post(format!("connections/{}/kick", conn + 1), "").await.unwrap().status(), 404);
+    assert_eq!(post("owner".to_string(), r#"{"user":""}"#).await.unwrap().status(), 400);
+}
+
+#[tokio::test]
+async fn admin_reads_do_not_create_rooms() {
+    let dir = std::env::temp_dir().join(format!("wb-server-{}-admin", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let addr = start(Config {
+        data_dir: Some(dir.clone()),
+        admin_token: Some("secret".into()),
+        ..Config::default()
+    })
+    .await;
+    let http = reqwest::Client::new();
+    let request = |method: reqwest::Method, room: String, path: &'static str| {
+        http.request(method, format!("http://{}/rooms/{}{}", addr, room, path))
+            .bearer_auth("secret")
+            .header("content-type", "application/json")
+            .body(r#"{"locked":true,"retention":null}"#)
+            .send()
+    };
+
+    for path in ["", "/webhooks"] {
+        let status = request(reqwest::Method::GET, "nowhere".to_string(), path).await.unwrap().status();
+        assert_eq!(status, 404, "{}", path);
+    }
+    let status = request(reqwest::Method::DELETE, "nowhere".to_string(), "/webhooks?url=http://x").await.unwrap().status();
+    assert_eq!(status, 404);
+    for path in ["/lock", "/retention"] {
+        let status = request(reqwest::Method::POST, "r".repeat(65), path).await.unwrap().status();
+        assert_eq!(status, 400, "{}", path);
+    }
+    let rooms = std::fs::read_dir(&dir).unwrap().filter(|entry| !entry.as_ref().unwrap().file_name().to_string_lossy().starts_with('.'));
+    assert_eq!(rooms.count(), 0, "a room was created on disk");
+
+    assert_eq!(request(reqwest::Method::POST, "somewhere".to_string(), "/lock").await.unwrap().status(), 204);
+    assert_eq!(request(reqwest::Method::GET, "somewhere".to_string(), "").await.unwrap().status(), 200);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn late_joiners_get_the_current_board() {
+    let addr = start(Config::default()).await;
+    let (mut alice, _) = join(addr, "late", "alice").await;
+    send(&mut alice, line("kept", 1.0)).await;
+    send(&mut alice, line("deleted", 2.0)).await;
+    send(&mut alice, line("moved", 3.0)).await;
+    // Sent again under the same ref it would only count as a retry
+    let mut moved = line("moved", 4.0);
+    moved["ref"] = json!("move");
+    send(&mut alice, moved).await;
+    send(&mut alice, json!({"type": "DeleteElement", "id": "deleted", "ref": "delete"})).await;
+    send(&mut alice, json!({"type": "ChatMessage", "text": "hello", "ref": "chat"})).await;
+    assert_eq!(reply_to(&mut alice, "chat").await["type"], "Ack");
+
+    // The board arrives ahead of the chat history, one frame per element in drawing order
+    let (mut bob, _) = join(addr, "late", "bob").await;
+    let mut board = Vec::new();
+    let history = loop {
+        let frame = next_frame(&mut bob, WAIT).await.expect("snapshot ended early");
+        match frame["type"].as_str() {
+            Some("ChatHistory") => break frame,
+            Some("RoomState") => continue,
+            _ => board.push(frame),
+        }
+    };
+    let ids: Vec<&str> = board.iter().map(|frame| frame["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, ["kept", "moved"]);
+    assert_eq!(board[1]["from"], json!([4.0, 0.0]));
+    assert_eq!(history["messages"][0]["text"], "hello");
+
+    // And then live events
+    send(&mut alice, line("after", 5.0)).await;
+    assert_eq!(next_drawing(&mut bob, WAIT).await.unwrap()["id"], "after");
+}
+
+#[tokio::test]
+async fn exports_outline_where_images_go() {
+    let addr = start(Config::default()).await;
+    let (mut alice, _) = join(addr, "pictures", "alice").await;
+    let asset = "a".repeat(64);
+    send(
+        &mut alice,
+        json!({"type": "AddImage", "ref": "image", "pos": [100.0, 50.0], "width": 200.0, "height": 100.0, "asset": asset}),
+    )
+    .await;
+    assert_eq!(reply_to(&mut alice, "image").await["type"], "Ack");
+
+    // Cropped to the image, with the 20px padding around it
+    let board = format!("http://{}/rooms/pictures/board", addr);
+    let svg = reqwest::get(format!("{}.svg?crop=true", board)).await.unwrap().text().await.unwrap();
+    assert!(svg.contains(r#"viewBox="80 30 240 140""#), "{}", svg);
+    assert!(svg.contains(r##"<rect x="100" y="50" width="200" height="100" fill="none" stroke="#cbd5e1""##), "{}", svg);
+
+    let png = reqwest::get(format!("{}.png?width=240", board)).await.unwrap().bytes().await.unwrap();
+    let png = tiny_skia::Pixmap::decode_png(&png).unwrap();
+    assert_eq!((png.width(), png.height()), (240, 140));
+    assert!(png.pixels().iter().any(|pixel| pixel.red() < 255), "the image's outline is missing");
+
+    let pdf = reqwest::get(format!("{}.pdf", board)).await.unwrap().bytes().await.unwrap();
+    let pdf = String::from_utf8_lossy(&pdf);
+    assert!(pdf.contains("[6 4] 0 d\n100 50 200 100 re"), "{}", pdf);
+
+    for frames in ["", ";", " ; "] {
+        let response = reqwest::get(format!("{}.pdf?frames={}", board, frames)).await.unwrap();
+        assert_eq!(response.status(), 400, "frames={:?}", frames);
+    }
+}
+
+#[tokio::test]
+async fn exports_embed_stored_images() {
+    let dir = std::env::temp_dir().join(format!("wb-server-{}-images", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let addr = start(Config {
+        asset_dir: Some(dir.clone()),
+        ..Config::default()
+    })
+    .await;
+    let mut red = tiny_skia::Pixmap::new(4, 2).unwrap();
+    red.fill(tiny_skia::Color::from_rgba8(255, 0, 0, 255));
+    let http = reqwest::Client::new();
+    let upload = http.post(format!("http://{}/assets", addr)).body(red.encode_png().unwrap()).send().await.unwrap();
+    let upload: Value = serde_json::from_str(&upload.text().await.unwrap()).unwrap();
+    let asset = upload["asset"].as_str().unwrap().to_string();
+
+    let (mut alice, _) = join(addr, "photos", "alice").await;
+    send(
+        &mut alice,
+        json!({"type": "AddImage", "ref": "image", "pos": [100.0, 50.0], "width": 200.0, "height": 100.0, "asset": asset}),
+    )
+    .await;
+    assert_eq!(reply_to(&mut alice, "image").await["type"], "Ack");
+
+    let board = format!("http://{}/rooms/photos/board", addr);
+    let svg = reqwest::get(format!("{}.svg?crop=true", board)).await.unwrap().text().await.unwrap();
+    let image = r#"<image x="100" y="50" width="200" height="100" preserveAspectRatio="none" href="data:image/png;base64,"#;
+    assert!(svg.contains(image), "{}", svg);
+
+    let png = reqwest::get(format!("{}.png?width=240", board)).await.unwrap().bytes().await.unwrap();
+    let png = tiny_skia::Pixmap::decode_png(&png).unwrap();
+    let middle = png.pixel(120, 70).unwrap();
+    assert_eq!((middle.red(), middle.green(), middle.blue()), (255, 0, 0));
+
+    let pdf = reqwest::get(format!("{}.pdf", board)).await.unwrap().bytes().await.unwrap();
+    let pdf = String::from_utf8_lossy(&pdf);
+    assert!(pdf.contains("/Subtype /Image"), "{}", pdf);
+    assert!(pdf.contains("200 0 0 -100 100 150 cm\n/Im0 Do"), "{}", pdf);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn reading_a_room_does_not_create_it() {
+    let dir = std::env::temp_dir().join(format!("wb-server-{}-unknown", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let addr = start(Config {
+        data_dir: Some(dir.clone()),
+        ..Config::default()
+    })
+    .await;
+
+    let status = |path: String| async move { reqwest::get(format!("http://{}{}", addr, path)).await.unwrap().status() };
+    for path in ["board.json", "board.svg", "checkpoints", "checkpoints/first", "diff?from=0"] {
+        assert_eq!(status(format!("/rooms/nowhere/{}", path)).await, 404, "{}", path);
+        assert_eq!(status(format!("/rooms/{}/{}", "r".repeat(65), path)).await, 400, "{}", path);
+    }
+    let rooms = std::fs::read_dir(&dir).unwrap().filter(|entry| !entry.as_ref().unwrap().file_name().to_string_lossy().starts_with('.'));
+    assert_eq!(rooms.count(), 0, "a room was created on disk");
+
+    let (_alice, _) = join(addr, "somewhere", "alice").await;
+    assert_eq!(status("/rooms/somewhere/board.json".to_string()).await, 200);
+    assert_eq!(status("/rooms/somewhere/checkpoints".to_string()).await, 200);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn undo_leaves_other_peoples_changes_alone() {
+    let addr = start(Config::default()).await;
+    let (mut alice, _) = join(addr, "undo", "alice").await;
+    let (mut bob, _) = join(addr, "undo", "bob").await;
+    send(&mut alice, line("own", 1.0)).await;
+    send(&mut alice, line("shared", 2.0)).await;
+    assert_eq!(reply_to(&mut alice, "shared").await["type"], "Ack");
+    let mut moved = line("shared", 3.0);
+    moved["ref"] = json!("move");
+    send(&mut bob, moved).await;
+    for id in ["own", "shared", "shared"] {
+        assert_eq!(next_drawing(&mut bob, WAIT).await.expect("bob missed a line")["id"], id);
+    }
+
+    // Bob has since moved the shared line, so alice's undo passes over it to her line before
+    send(&mut alice, json!({"type": "Undo", "ref": "u1"})).await;
+    assert_eq!(reply_to(&mut alice, "u1").await["type"], "Ack");
+    let undone = next_drawing(&mut bob, WAIT).await.expect("bob never saw the undo");
+    assert_eq!(undone["type"], "DeleteElement");
+    assert_eq!(undone["id"], "own");
+    assert_eq!(undone["user"], "alice");
+
+    // And then there is nothing left for her to undo
+    send(&mut alice, json!({"type": "Undo", "ref": "u2"})).await;
+    let reply = reply_to(&mut alice, "u2").await;
+    assert_eq!(reply["type"], "Ack");
+    assert_eq!(reply["seq"], Value::Null);
+    assert_eq!(next_drawing(&mut bob, QUIET).await, None);
+
+    // Bob's line is where he put it
+    let (mut carol, _) = join(addr, "undo", "carol").await;
+    let shared = next_drawing(&mut carol, WAIT).await.expect("the shared line is gone");
+    assert_eq!(shared["id"], "shared");
+    assert_eq!(shared["from"], json!([3.0, 0.0]));
+    assert_eq!(next_drawing(&mut carol, QUIET).await, None);
+}
+
+#[tokio::test]
+async fn new_edits_drop_what_could_be_redone() {
+    let addr = start(Config::default()).await;
+    let (mut alice, _) = join(addr, "redo", "alice").await;
+    let (mut bob, _) = join(addr, "redo", "bob").await;
+    send(&mut alice, line("first", 1.0)).await;
+    send(&mut alice, json!({"type": "Undo", "ref": "u1"})).await;
+    send(&mut alice, json!({"type": "Redo", "ref": "r1"})).await;
+    assert_eq!(reply_to(&mut alice, "r1").await["type"], "Ack");
+    assert_eq!(next_drawing(&mut bob, WAIT).await.unwrap()["type"], "DrawLine");
+    assert_eq!(next_drawing(&mut bob, WAIT).await.unwrap()["type"], "DeleteElement");
+    let redone = next_drawing(&mut bob, WAIT).await.expect("bob never saw the redo");
+    assert_eq!(redone["type"], "DrawLine");
+    assert_eq!(redone["id"], "first");
+
+    // Undone and then drawn over, the line can no longer be redone
+    send(&mut alice, json!({"type": "Undo", "ref": "u2"})).await;
+    send(&mut alice, line("second", 2.0)).await;
+    send(&mut alice, json!({"type": "Redo", "ref": "r2"})).await;
+    let reply = reply_to(&mut alice, "r2").await;
+    assert_eq!(reply["type"], "Ack");
+    assert_eq!(reply["seq"], Value::Null);
+    assert_eq!(next_drawing(&mut bob, WAIT).await.unwrap()["type"], "DeleteElement");
+    assert_eq!(next_drawing(&mut bob, WAIT).await.unwrap()["id"], "second");
+    assert_eq!(next_drawing(&mut bob, QUIET).await, None);
+}
+
+#[tokio::test]
+async fn disconnected_clients_are_cleaned_up() {
+    let addr = start(Config::default()).await;
+    let (mut alice, _) = join(addr, "cleanup", "alice").await;
+    let (mut bob, bob_conn) = join(addr, "cleanup", "bob").await;
+    let metrics = format!("http://{}/metrics", addr);
+    let queue = format!("wb_client_queue_depth{{conn=\"{}\",room=\"cleanup\"}}", bob_conn);
+    let body = reqwest::get(&metrics).await.unwrap().text().await.unwrap();
+    assert!(body.contains(&queue), "no queue for bob in:\n{}", body);
+
+    bob.close(None).await.unwrap();
+    // Everyone left is told who is still there
+    let connections = loop {
+        let state = next_of(&mut alice, "RoomState", WAIT).await.expect("bob's leaving was never announced");
+        let connections = state["connections"].as_array().unwrap().clone();
+        if connections.iter().all(|connection| connection["user"] != "bob") {
+            break connections;
+        }
+    };
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0]["user"], "alice");
+
+    let body = reqwest::get(&metrics).await.unwrap().text().await.unwrap();
+    assert!(!body.contains(&queue), "bob's queue outlived the connection:\n{}", body);
+
+    // Coming back is a new connection, brought up to date like any other
+    send(&mut alice, line("l1", 1.0)).await;
+    let (mut bob, conn) = join(addr, "cleanup", "bob").await;
+    assert_ne!(conn, bob_conn);
+    assert_eq!(next_drawing(&mut bob, WAIT).await.unwrap()["id"], "l1");
+}
+
+#[tokio::test]
+async fn only_allowed_origins_get_in() {
+    let addr = start(Config {
+        allowed_origins: AllowedOrigins::parse("https://board.example, http://localhost:8080/").unwrap(),
+        ..Config::default()
+    })
+    .await;
+    let connect = |origin: &'static str| async move {
+        let mut request = format!("ws://{}/ws?room=origins", addr).into_client_request().unwrap();
+        request.headers_mut().insert("origin", HeaderValue::from_static(origin));
+        connect_async(request).await
+    };
+    assert!(connect("https://board.example").await.is_ok());
+    assert!(connect("http://localhost:8080").await.is_ok());
+    match connect("https://evil.example").await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), 403),
+        other => panic!("connected from a disallowed origin: {:?}", other.map(|_| ())),
+    }
+    // Tools that are not browsers send no origin
+    assert!(connect_async(format!("ws://{}/ws?room=origins", addr)).await.is_ok());
+
+    let http = reqwest::Client::new();
+    let board = format!("http://{}/rooms/origins/board.json", addr);
+    let response = http.get(&board).header("origin", "https://board.example").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["access-control-allow-origin"], "https://board.example");
+    assert_eq!(response.headers()["vary"], "origin");
+    let response = http.get(&board).header("origin", "https://evil.example").send().await.unwrap();
+    assert_eq!(response.status(), 403);
+    assert!(!response.headers().contains_key("access-control-allow-origin"));
+
+    // Browsers ask before uploading or calling the admin API from another origin
+    let response = http
+        .request(reqwest::Method::OPTIONS, format!("http://{}/assets", addr))
+        .header("origin", "https://board.example")
+        .header("access-control-request-method", "POST")
+        .header("access-control-request-headers", "content-type")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    assert_eq!(response.headers()["access-control-allow-origin"], "https://board.example");
+    assert!(response.headers()["access-control-allow-methods"].to_str().unwrap().contains("POST"));
+
+    let body = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+    assert!(body.contains("wb_origins_rejected_total 2"), "{}", body);
+}
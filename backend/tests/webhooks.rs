@@ -0,0 +1,203 @@
+// Runs a backend instance against a local HTTP stand-in for a webhook endpoint
+// and checks what gets delivered to it.
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use futures_util::SinkExt;
+use ring::hmac;
+use serde_json::Value;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+const SECRET: &str = "s3cret";
+
+// Kills the backend process when the test ends, pass or fail
+struct Instance {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn spawn_instance(env: &[(&str, String)]) -> Instance {
+    let addr = free_addr();
+    let child = Command::new(env!("CARGO_BIN_EXE_backend"))
+        .env("WB_ADDR", addr.to_string())
+        .env("RUST_LOG", "warn")
+        .envs(env.iter().map(|(k, v)| (*k, v.as_str())))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Instance { child, addr }
+}
+
+// One request the stand-in received
+#[derive(Clone, Debug)]
+struct Request {
+    signature: String,
+    body: Bytes,
+    accepted: bool,
+}
+
+#[derive(Clone, Default)]
+struct Endpoint {
+    requests: Arc<Mutex<Vec<Request>>>,
+    // Answer this many requests with a server error before accepting any
+    failures: usize,
+}
+
+async fn receive(State(endpoint): State<Endpoint>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let mut requests = endpoint.requests.lock().unwrap();
+    let accepted = requests.len() >= endpoint.failures;
+    requests.push(Request {
+        signature: headers
+            .get("x-whiteboard-signature")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+        body,
+        accepted,
+    });
+    if accepted {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+// Serve the stand-in on an ephemeral port; returns the webhook URL
+async fn stand_in(endpoint: Endpoint) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new().route("/hook", post(receive)).with_state(endpoint);
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{}/hook", addr)
+}
+
+// Join `room` as `user`, draw a line, clear the board and leave
+async fn session(instance: &Instance, room: &str, user: &str) {
+    let url = format!("ws://{}/ws?room={}&user={}", instance.addr, room, user);
+    let mut client = None;
+    for _ in 0..50 {
+        if let Ok((connected, _)) = connect_async(&url).await {
+            client = Some(connected);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let mut client = client.expect("backend did not come up");
+    let line = r##"{"type":"DrawLine","from":[0.0,0.0],"to":[10.0,10.0],"color":"#2563eb","width":3.0}"##;
+    client.send(Message::Text(line.into())).await.unwrap();
+    client.send(Message::Text(r#"{"type":"ClearBoard"}"#.into())).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client.close(None).await.unwrap();
+}
+
+// Event types in every accepted delivery, in order, once `last` has arrived
+async fn delivered_until(endpoint: &Endpoint, last: &str) -> Vec<(String, Value)> {
+    for _ in 0..100 {
+        let events: Vec<(String, Value)> = endpoint
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.accepted)
+            .flat_map(|request| {
+                let batch: Value = serde_json::from_slice(&request.body).unwrap();
+                batch["events"].as_array().unwrap().clone()
+            })
+            .map(|event| (event["type"].as_str().unwrap().to_string(), event))
+            .collect();
+        if events.iter().any(|(kind, _)| kind == last) {
+            return events;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("no {} event was delivered", last);
+}
+
+fn sign(body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+    let tag = hmac::sign(&key, body);
+    let hex: String = tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+#[tokio::test]
+async fn room_events_are_delivered_signed_and_batched() {
+    let endpoint = Endpoint::default();
+    let url = stand_in(endpoint.clone()).await;
+    let instance = spawn_instance(&[
+        ("WB_WEBHOOK_URLS", url),
+        ("WB_WEBHOOK_SECRET", SECRET.to_string()),
+        ("WB_WEBHOOK_BATCH_MS", "500".to_string()),
+    ]);
+
+    session(&instance, "hooks", "alice").await;
+    let events = delivered_until(&endpoint, "user_left").await;
+
+    let kinds: Vec<&str> = events.iter().map(|(kind, _)| kind.as_str()).collect();
+    assert_eq!(
+        kinds,
+        ["room_created", "user_joined", "element_added", "board_cleared", "user_left"]
+    );
+    assert!(events.iter().all(|(_, event)| event["room"] == "hooks"));
+    assert_eq!(events[1].1["user"], "alice");
+    assert_eq!(events[2].1["element"]["type"], "DrawLine");
+
+    let requests = endpoint.requests.lock().unwrap().clone();
+    assert!(requests.len() < events.len(), "events were not batched: {} requests", requests.len());
+    for request in &requests {
+        assert_eq!(request.signature, sign(&request.body));
+    }
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried() {
+    let endpoint = Endpoint {
+        failures: 2,
+        ..Endpoint::default()
+    };
+    let url = stand_in(endpoint.clone()).await;
+    let instance = spawn_instance(&[
+        ("WB_WEBHOOK_URLS", url),
+        ("WB_WEBHOOK_SECRET", SECRET.to_string()),
+        ("WB_WEBHOOK_BATCH_MS", "50".to_string()),
+        ("WB_WEBHOOK_BACKOFF_MS", "100".to_string()),
+    ]);
+
+    session(&instance, "retries", "bob").await;
+    let events = delivered_until(&endpoint, "user_left").await;
+
+    // Every event still arrives exactly once, the first batch on its third attempt
+    let kinds: Vec<&str> = events.iter().map(|(kind, _)| kind.as_str()).collect();
+    assert_eq!(
+        kinds,
+        ["room_created", "user_joined", "element_added", "board_cleared", "user_left"]
+    );
+    let requests = endpoint.requests.lock().unwrap().clone();
+    assert!(!requests[0].accepted && !requests[1].accepted && requests[2].accepted);
+    assert_eq!(requests[0].body, requests[2].body);
+    assert_eq!(requests[1].body, requests[2].body);
+}
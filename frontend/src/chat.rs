@@ -0,0 +1,152 @@
+use std::rc::Rc;
+
+use wasm_bindgen::JsValue;
+use web_sys::{HtmlInputElement, KeyboardEvent};
+use yew::prelude::*;
+
+use crate::{presence_color, Frame, WhiteboardEvent};
+
+// Most messages the panel holds; older ones scroll away for good
+const MAX_LINES: usize = 200;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatLine {
+    pub user: String,
+    pub text: String,
+    // Unix milliseconds, as stamped by the backend
+    pub ts: u64,
+}
+
+impl ChatLine {
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        match &frame.event {
+            WhiteboardEvent::ChatMessage { text, ts } => Some(ChatLine {
+                user: frame.user.clone().unwrap_or_default(),
+                text: text.clone(),
+                ts: *ts,
+            }),
+            _ => None,
+        }
+    }
+
+    // Local time the message was sent, as `HH:MM`
+    fn time(&self) -> String {
+        let date = js_sys::Date::new(&JsValue::from_f64(self.ts as f64));
+        format!("{:02}:{:02}", date.get_hours(), date.get_minutes())
+    }
+}
+
+// The room's chat as this client has seen it
+#[derive(Clone, Default, PartialEq)]
+pub struct ChatLog {
+    lines: Vec<ChatLine>,
+    open: bool,
+    // Messages from others that arrived while the panel was collapsed
+    unread: usize,
+}
+
+pub enum ChatAction {
+    // What the room said before this client joined, which counts as read
+    History(Vec<ChatLine>),
+    // A new message, and whether this client sent it
+    Message(ChatLine, bool),
+    Toggle,
+}
+
+impl Reducible for ChatLog {
+    type Action = ChatAction;
+
+    fn reduce(self: Rc<Self>, action: ChatAction) -> Rc<Self> {
+        let mut log = Rc::unwrap_or_clone(self);
+        match action {
+            ChatAction::History(lines) => log.lines = lines,
+            ChatAction::Message(line, mine) => {
+                if !mine && !log.open {
+                    log.unread += 1;
+                }
+                log.lines.push(line);
+            }
+            ChatAction::Toggle => {
+                log.open = !log.open;
+                log.unread = 0;
+            }
+        }
+        let excess = log.lines.len().saturating_sub(MAX_LINES);
+        log.lines.drain(..excess);
+        Rc::new(log)
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct ChatProps {
+    pub log: UseReducerHandle<ChatLog>,
+    // Whether this client may send messages
+    pub can_send: bool,
+    pub on_send: Callback<String>,
+}
+
+// Collapsible chat panel; collapsed it shows how many messages are unread
+#[function_component(ChatPanel)]
+pub fn chat_panel(props: &ChatProps) -> Html {
+    let log = &props.log;
+    let messages_ref = use_node_ref();
+
+    // Keep the newest message in view
+    {
+        let messages_ref = messages_ref.clone();
+        use_effect_with((log.lines.len(), log.open), move |_| {
+            if let Some(messages) = messages_ref.cast::<web_sys::Element>() {
+                messages.set_scroll_top(messages.scroll_height());
+            }
+        });
+    }
+
+    let toggle = {
+        let log = log.clone();
+        Callback::from(move |_: MouseEvent| log.dispatch(ChatAction::Toggle))
+    };
+    let onkeydown = {
+        let on_send = props.on_send.clone();
+        Callback::from(move |e: KeyboardEvent| {
+            if e.key() != "Enter" {
+                return;
+            }
+            let input: HtmlInputElement = e.target_unchecked_into();
+            let text = input.value();
+            if !text.trim().is_empty() {
+                on_send.emit(text);
+                input.set_value("");
+            }
+        })
+    };
+
+    html! {
+        <aside class={classes!("chat-panel", log.open.then_some("open"))}>
+            <button class="chat-toggle" onclick={toggle} title={if log.open { "Hide chat" } else { "Show chat" }}>
+                {"💬 Chat"}
+                if log.unread > 0 {
+                    <span class="chat-unread">{log.unread}</span>
+                }
+            </button>
+            if log.open {
+                <ul class="chat-messages" ref={messages_ref}>
+                    {for log.lines.iter().map(|line| html! {
+                        <li class="chat-message">
+                            <span class="chat-author" style={format!("color: {}", presence_color(&line.user))}>{&line.user}</span>
+                            <span class="chat-time">{line.time()}</span>
+                            <p class="chat-text">{&line.text}</p>
+                        </li>
+                    })}
+                </ul>
+                <input
+                    class="chat-input"
+                    type="text"
+                    maxlength="2000"
+                    disabled={!props.can_send}
+                    placeholder={if props.can_send { "Message the room…" } else { "Muted by a room owner" }}
+                    onkeydown={onkeydown}
+                />
+            }
+        </aside>
+    }
+}
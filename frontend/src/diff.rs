@@ -0,0 +1,167 @@
+use std::cell::RefCell;
+
+use serde::Deserialize;
+use web_sys::CanvasRenderingContext2d;
+
+use crate::{backend_http_url, render, Frame, WhiteboardEvent};
+
+// Overlay colours: added, removed, changed in place, and where moved elements were
+const ADDED: (&str, &str) = ("#16a34a", "rgba(22, 163, 74, 0.12)");
+const REMOVED: (&str, &str) = ("#dc2626", "rgba(220, 38, 38, 0.12)");
+const MODIFIED: (&str, &str) = ("#d97706", "rgba(217, 119, 6, 0.10)");
+const GHOST: &str = "#64748b";
+
+// Space left between an element and its highlight, in board pixels
+const PADDING: f64 = 6.0;
+
+thread_local! {
+    // The diff drawn over the board, while the overlay is on
+    static SHOWN: RefCell<Option<Diff>> = const { RefCell::new(None) };
+}
+
+// How the board changed between two versions, as the backend compares them
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Diff {
+    pub added: Vec<Frame>,
+    pub removed: Vec<Frame>,
+    pub moved: Vec<Changed>,
+    pub modified: Vec<Changed>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Changed {
+    pub before: WhiteboardEvent,
+    pub after: WhiteboardEvent,
+}
+
+impl Diff {
+    // e.g. "2 added, 1 removed, 1 moved, 3 changed"
+    pub fn summary(&self) -> String {
+        let counts = [
+            (self.added.len(), "added"),
+            (self.removed.len(), "removed"),
+            (self.moved.len(), "moved"),
+            (self.modified.len(), "changed"),
+        ];
+        let parts: Vec<String> = counts
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, what)| format!("{} {}", count, what))
+            .collect();
+        if parts.is_empty() {
+            "no changes".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+// How the room's board changed since checkpoint `name`
+pub async fn since_checkpoint(room: &str, name: &str) -> Result<Diff, String> {
+    let path = format!(
+        "/rooms/{}/diff?from_checkpoint={}",
+        String::from(js_sys::encode_uri_component(room)),
+        String::from(js_sys::encode_uri_component(name))
+    );
+    let response = gloo_net::http::Request::get(&backend_http_url(&path, &[]))
+        .send()
+        .await
+        .map_err(|_| "the server could not be reached".to_string())?;
+    if !response.ok() {
+        return Err(response.text().await.unwrap_or_else(|_| format!("error {}", response.status())));
+    }
+    response.json::<Diff>().await.map_err(|e| e.to_string())
+}
+
+// Draw `diff` over the board from now on, or stop with `None`
+pub fn show(diff: Option<Diff>) {
+    SHOWN.with(|shown| *shown.borrow_mut() = diff);
+}
+
+// Draw the diff being shown, if any, in board coordinates on top of the board:
+// removed elements as red ghosts, moved ones as grey ghosts where they were,
+// and boxes around what was added (green), changed (amber) or moved
+pub fn draw_overlay(ctx: &CanvasRenderingContext2d) {
+    SHOWN.with(|shown| {
+        let Some(diff) = &*shown.borrow() else { return };
+        for frame in &diff.removed {
+            ghost(ctx, &frame.event);
+            highlight(ctx, &frame.event, REMOVED, true);
+        }
+        for changed in &diff.moved {
+            ghost(ctx, &changed.before);
+            outline(ctx, &changed.before, GHOST);
+            highlight(ctx, &changed.after, MODIFIED, false);
+        }
+        for changed in &diff.modified {
+            highlight(ctx, &changed.after, MODIFIED, false);
+        }
+        for frame in &diff.added {
+            highlight(ctx, &frame.event, ADDED, false);
+        }
+    });
+}
+
+// The element drawn faintly, as it was
+fn ghost(ctx: &CanvasRenderingContext2d, event: &WhiteboardEvent) {
+    ctx.save();
+    ctx.set_global_alpha(0.35);
+    render::draw_event(ctx, event);
+    ctx.restore();
+}
+
+// A dashed outline around where the element is
+fn outline(ctx: &CanvasRenderingContext2d, event: &WhiteboardEvent, color: &str) {
+    let Some((x0, y0, x1, y1)) = bounds(ctx, event) else { return };
+    ctx.save();
+    ctx.set_stroke_style_str(color);
+    ctx.set_line_width(1.5);
+    ctx.set_line_dash(&js_sys::Array::of2(&6.0.into(), &4.0.into())).unwrap();
+    ctx.stroke_rect(x0 - PADDING, y0 - PADDING, x1 - x0 + 2.0 * PADDING, y1 - y0 + 2.0 * PADDING);
+    ctx.restore();
+}
+
+// A tinted box around the element
+fn highlight(ctx: &CanvasRenderingContext2d, event: &WhiteboardEvent, (stroke, fill): (&str, &str), dashed: bool) {
+    let Some((x0, y0, x1, y1)) = bounds(ctx, event) else { return };
+    let (x, y, w, h) = (x0 - PADDING, y0 - PADDING, x1 - x0 + 2.0 * PADDING, y1 - y0 + 2.0 * PADDING);
+    ctx.save();
+    ctx.set_fill_style_str(fill);
+    ctx.fill_rect(x, y, w, h);
+    ctx.set_stroke_style_str(stroke);
+    ctx.set_line_width(2.0);
+    if dashed {
+        ctx.set_line_dash(&js_sys::Array::of2(&6.0.into(), &4.0.into())).unwrap();
+    }
+    ctx.stroke_rect(x, y, w, h);
+    ctx.restore();
+}
+
+// The box an element covers, as (x0, y0, x1, y1)
+fn bounds(ctx: &CanvasRenderingContext2d, event: &WhiteboardEvent) -> Option<(f64, f64, f64, f64)> {
+    let span = |points: &mut dyn Iterator<Item = (f64, f64)>| {
+        points.fold(None, |bounds: Option<(f64, f64, f64, f64)>, (x, y)| {
+            Some(match bounds {
+                None => (x, y, x, y),
+                Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+            })
+        })
+    };
+    match event {
+        WhiteboardEvent::DrawStroke { points } => span(&mut points.iter().copied()),
+        WhiteboardEvent::DrawLine { from, to, .. } | WhiteboardEvent::DrawRect { from, to, .. } => {
+            span(&mut [*from, *to].into_iter())
+        }
+        WhiteboardEvent::DrawCircle { center, radius, .. } => {
+            Some((center.0 - radius, center.1 - radius, center.0 + radius, center.1 + radius))
+        }
+        // Text is drawn from its baseline
+        WhiteboardEvent::AddText { pos, text, size, .. } => {
+            ctx.set_font(&format!("{}px 'Inter', -apple-system, system-ui, sans-serif", size));
+            let width = ctx.measure_text(text).map(|metrics| metrics.width()).unwrap_or(0.0);
+            Some((pos.0, pos.1 - size, pos.0 + width, pos.1 + size * 0.25))
+        }
+        WhiteboardEvent::AddImage { pos, width, height, .. } => Some((pos.0, pos.1, pos.0 + width, pos.1 + height)),
+        _ => None,
+    }
+}
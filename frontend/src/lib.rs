@@ -1,25 +1,138 @@
 use yew::prelude::*;
-use gloo_net::websocket::{futures::WebSocket, Message};
+use gloo_net::websocket::{futures::WebSocket, Message, WebSocketError};
 use wasm_bindgen::JsCast;
 use wasm_bindgen::prelude::wasm_bindgen;
-use web_sys::{HtmlCanvasElement, CanvasRenderingContext2d, MouseEvent, HtmlInputElement, HtmlElement};
+use web_sys::{HtmlCanvasElement, CanvasRenderingContext2d, MouseEvent, DragEvent, File, HtmlInputElement, HtmlElement, HtmlSelectElement, ImageBitmap, UrlSearchParams};
 use serde::{Serialize, Deserialize};
-use futures_util::sink::SinkExt;
+use futures_util::lock::Mutex;
 use futures_util::stream::{StreamExt, SplitSink};
 use wasm_bindgen_futures::spawn_local;
-use std::rc::Rc;
+use gloo_timers::callback::{Interval, Timeout};
 use std::cell::RefCell;
+use std::rc::Rc;
+
+// Write half of the backend connection, shared by every event handler.
+// An async mutex keeps concurrent sends from different handlers in order.
+type WsWriter = Rc<Mutex<SplitSink<WebSocket, Message>>>;
+
+mod chat;
+mod diff;
+mod outbox;
+mod render;
+mod replay;
+mod templates;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+use chat::{ChatAction, ChatLine, ChatLog, ChatPanel};
+use outbox::Outbox;
+use templates::TemplatePicker;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(tag = "type")]
 enum WhiteboardEvent {
     DrawFreehand { x: f64, y: f64, dragging: bool },
+    DrawStroke { points: Vec<(f64, f64)> },
     DrawLine { from: (f64, f64), to: (f64, f64), color: String, width: f64 },
     DrawRect { from: (f64, f64), to: (f64, f64), color: String, width: f64 },
     DrawCircle { center: (f64, f64), radius: f64, color: String, width: f64 },
     AddText { pos: (f64, f64), text: String, color: String, size: f64 },
+    AddImage { pos: (f64, f64), width: f64, height: f64, asset: String },
     Pan { dx: f64, dy: f64 },
     Zoom { factor: f64 },
+    DeleteElement,
+    Undo,
+    Redo,
+    CreateCheckpoint { name: String },
+    RestoreCheckpoint { name: String },
+    ChatMessage {
+        text: String,
+        #[serde(default)]
+        ts: u64,
+    },
+    ClearBoard,
+    LockRoom { locked: bool },
+    Kick { conn: u64 },
+    Mute { conn: u64, muted: bool },
+    TransferOwnership { to: String },
+    Welcome {
+        conn: u64,
+        #[serde(default)]
+        owner_token: Option<String>,
+    },
+    OwnerToken { token: String },
+    Ack,
+    Reject { reason: String },
+    ChatHistory { messages: Vec<Frame> },
+    ResetBoard { elements: Vec<Frame> },
+    Checkpoints { checkpoints: Vec<CheckpointInfo> },
+    RoomState { owners: Vec<String>, locked: bool, connections: Vec<Connection> },
+    ReplayStart { from: u64, to: u64 },
+    ReplayEnd,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Connection {
+    conn: u64,
+    user: String,
+    muted: bool,
+    #[serde(default)]
+    owner: bool,
+}
+
+// A named copy of the board the room can go back to
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct CheckpointInfo {
+    name: String,
+    seq: u64,
+    ts: u64,
+    #[serde(default)]
+    user: Option<String>,
+    elements: usize,
+}
+
+// Close code the backend uses when a room owner kicks this client
+const CLOSE_KICKED: u16 = 4001;
+// Close code the backend uses when this client fell too far behind the room
+const CLOSE_SLOW: u16 = 4002;
+
+// Who administers the room and who is in it, as last announced by the backend
+#[derive(Clone, Debug, Default, PartialEq)]
+struct RoomInfo {
+    owners: Vec<String>,
+    locked: bool,
+    connections: Vec<Connection>,
+}
+
+impl WhiteboardEvent {
+    // Events that create or replace a board element
+    fn is_element(&self) -> bool {
+        matches!(
+            self,
+            WhiteboardEvent::DrawStroke { .. }
+                | WhiteboardEvent::DrawLine { .. }
+                | WhiteboardEvent::DrawRect { .. }
+                | WhiteboardEvent::DrawCircle { .. }
+                | WhiteboardEvent::AddText { .. }
+                | WhiteboardEvent::AddImage { .. }
+        )
+    }
+}
+
+// An event as relayed by the backend, tagged with the id of the element it touches
+// and the `user` who sent it.
+// Board changes carry their place in the room's order as `seq`; acks and rejections
+// carry the `ref` this client sent the event under.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Frame {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    seq: Option<u64>,
+    #[serde(default, rename = "ref")]
+    reference: Option<String>,
+    #[serde(flatten)]
+    event: WhiteboardEvent,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -30,7 +143,6 @@ enum Tool {
     Circle,
     Text,
     Pan,
-    Zoom,
 }
 
 impl Tool {
@@ -42,7 +154,6 @@ impl Tool {
             Tool::Circle => "⭕",
             Tool::Text => "🅰️",
             Tool::Pan => "👆",
-            Tool::Zoom => "🔍",
         }
     }
 
@@ -54,16 +165,167 @@ impl Tool {
             Tool::Circle => "crosshair",
             Tool::Text => "text",
             Tool::Pan => "grab",
-            Tool::Zoom => "zoom-in",
         }
     }
 }
 
+// Query parameters of the page URL, e.g. `?room=retro&user=alice`
+fn page_params() -> UrlSearchParams {
+    let search = web_sys::window().unwrap().location().search().unwrap_or_default();
+    UrlSearchParams::new_with_str(&search).unwrap()
+}
+
+// Backend WebSocket endpoint `path` on port 3000 of the host serving the page,
+// passing on the page parameters called `names`.
+// Browsers refuse plain `ws://` from HTTPS pages, so the scheme follows the page's.
+fn backend_url(path: &str, names: &[&str]) -> String {
+    backend_endpoint(("wss", "ws"), path, names)
+}
+
+// Backend HTTP endpoint `path`, like `backend_url`
+fn backend_http_url(path: &str, names: &[&str]) -> String {
+    backend_endpoint(("https", "http"), path, names)
+}
+
+fn backend_endpoint((secure, plain): (&str, &str), path: &str, names: &[&str]) -> String {
+    let location = web_sys::window().unwrap().location();
+    let scheme = if location.protocol().as_deref() == Ok("https:") { secure } else { plain };
+    let host = location
+        .hostname()
+        .ok()
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "127.0.0.1".to_string());
+
+    let page_params = page_params();
+    let params = UrlSearchParams::new().unwrap();
+    for name in names {
+        if let Some(value) = page_params.get(name) {
+            params.append(name, &value);
+        }
+    }
+    format!("{}://{}:3000{}?{}", scheme, host, path, String::from(params.to_string()))
+}
+
+// Colours that tell people apart in the room
+const PRESENCE_COLORS: [&str; 8] = ["#e11d48", "#ea580c", "#ca8a04", "#16a34a", "#0891b2", "#2563eb", "#7c3aed", "#c026d3"];
+
+// The colour `user` shows in, the same for them in every client
+fn presence_color(user: &str) -> &'static str {
+    let hash = user.bytes().fold(2166136261u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(16777619));
+    PRESENCE_COLORS[hash as usize % PRESENCE_COLORS.len()]
+}
+
+// The room this page shows
+fn room_name() -> String {
+    page_params().get("room").unwrap_or_else(|| "default".to_string())
+}
+
+// The board's room and user name come from the page URL. Owners connect with the token
+// the backend gave them, from the page URL when it has one or else as kept in the browser.
+fn ws_url() -> String {
+    let url = backend_url("/ws", &["room", "user"]);
+    let token = page_params().get("owner_token").or_else(|| {
+        let user = page_params().get("user")?;
+        local_storage()?.get_item(&owner_token_key(&user)).ok().flatten()
+    });
+    match token {
+        Some(token) => format!("{}&owner_token={}", url, String::from(js_sys::encode_uri_component(&token))),
+        None => url,
+    }
+}
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+// Where the owner token for this room and `user` is kept
+fn owner_token_key(user: &str) -> String {
+    format!("wb-owner-token:{}:{}", room_name(), user)
+}
+
+fn keep_owner_token(user: &str, token: &str) {
+    if let Some(storage) = local_storage() {
+        let _ = storage.set_item(&owner_token_key(user), token);
+    }
+}
+
+// How often unacknowledged events are checked for a retry
+const RETRY_INTERVAL_MS: u32 = 1000;
+// How long a notice about a rolled back change stays up
+const NOTICE_MS: u32 = 4000;
+
+// Drop whatever this client drew ahead of the backend by drawing the confirmed board again,
+// and tell the user why
+fn roll_back(canvas_ref: &NodeRef, board: &render::Board, pan: (f64, f64), zoom: f64, notice: &UseStateHandle<Option<String>>, reason: String) {
+    if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+        render::redraw(&canvas, board, pan, zoom);
+    }
+    show_notice(notice, reason);
+}
+
+fn show_notice(notice: &UseStateHandle<Option<String>>, text: String) {
+    notice.set(Some(text));
+    let notice = notice.clone();
+    Timeout::new(NOTICE_MS, move || notice.set(None)).forget();
+}
+
+// Image types the backend stores
+const IMAGE_TYPES: &str = "image/png,image/jpeg,image/gif,image/webp";
+// Longest side of a newly placed image, in board pixels
+const IMAGE_PLACE_SIZE: f64 = 400.0;
+
+// What the backend answers an upload with
+#[derive(Deserialize)]
+struct Uploaded {
+    asset: String,
+}
+
+// Upload `file` and place it on the board centred on `center`, at its own size
+// but no bigger than `IMAGE_PLACE_SIZE` across
+fn place_image(file: File, center: (f64, f64), ws: Rc<RefCell<Outbox>>, notice: UseStateHandle<Option<String>>) {
+    spawn_local(async move {
+        match upload_image(&file).await {
+            Ok((asset, (width, height))) => {
+                let scale = (IMAGE_PLACE_SIZE / width.max(height)).min(1.0);
+                let (width, height) = (width * scale, height * scale);
+                let pos = (center.0 - width / 2.0, center.1 - height / 2.0);
+                ws.borrow_mut().send(&WhiteboardEvent::AddImage { pos, width, height, asset });
+            }
+            Err(reason) => show_notice(&notice, format!("Image not added: {}", reason)),
+        }
+    });
+}
+
+// Store `file` on the backend, returning its asset id and its size in pixels
+async fn upload_image(file: &File) -> Result<(String, (f64, f64)), String> {
+    // Decoding it first turns away anything the browser could not show anyway
+    let bitmap = web_sys::window().unwrap().create_image_bitmap_with_blob(file).map_err(|_| "not an image".to_string())?;
+    let bitmap: ImageBitmap = wasm_bindgen_futures::JsFuture::from(bitmap)
+        .await
+        .map_err(|_| "not an image".to_string())?
+        .unchecked_into();
+    let size = (bitmap.width() as f64, bitmap.height() as f64);
+    bitmap.close();
+
+    let response = gloo_net::http::Request::post(&backend_http_url("/assets", &[]))
+        .header("Content-Type", &file.type_())
+        .body(file.clone())
+        .map_err(|e| e.to_string())?
+        .send()
+        .await
+        .map_err(|_| "the server could not be reached".to_string())?;
+    if !response.ok() {
+        return Err(response.text().await.unwrap_or_else(|_| format!("error {}", response.status())));
+    }
+    let uploaded: Uploaded = response.json().await.map_err(|e| e.to_string())?;
+    Ok((uploaded.asset, size))
+}
+
 #[function_component(App)]
 fn app() -> Html {
     let canvas_ref = use_node_ref();
     let drawing = use_state(|| false);
-    let ws = use_mut_ref(|| None::<Rc<RefCell<SplitSink<WebSocket, Message>>>>);
+    let ws = use_mut_ref(Outbox::default);
     let last_pos = use_mut_ref(|| (0.0, 0.0));
     let tool = use_state(|| Tool::Freehand);
     let color = use_state(|| "#2563eb".to_string());
@@ -74,101 +336,146 @@ fn app() -> Html {
     let zoom = use_state(|| 1.0);
     let pan_start = use_mut_ref(|| None::<(f64, f64)>);
     let connection_status = use_state(|| "connecting".to_string());
+    let board = use_mut_ref(render::Board::default);
+    let me = use_state(|| None::<(u64, String)>);
+    let room_info = use_state(RoomInfo::default);
+    let notice = use_state(|| None::<String>);
+    // Whether exports cover only what is drawn rather than the whole canvas
+    let crop_export = use_state(|| false);
+    // Paper size and layout for PDF exports
+    let pdf_options = use_state(|| ("a4".to_string(), "fit".to_string()));
+    let chat = use_reducer(ChatLog::default);
+    // Whether the dialog for starting a new board is open
+    let new_board = use_state(|| false);
+    let checkpoints = use_state(Vec::<CheckpointInfo>::new);
+    // Checkpoint picked for restoring; the newest when none was picked
+    let chosen_checkpoint = use_state(|| None::<String>);
+    // Checkpoint the board is being compared with, while the diff overlay is on
+    let comparing = use_state(|| None::<String>);
 
     // Connect to backend WebSocket
     {
         let ws = ws.clone();
         let canvas_ref = canvas_ref.clone();
+        let board = board.clone();
         let pan = pan.clone();
         let zoom = zoom.clone();
         let connection_status = connection_status.clone();
+        let me = me.clone();
+        let room_info = room_info.clone();
+        let notice = notice.clone();
+        let chat = chat.clone();
+        let checkpoints = checkpoints.clone();
         use_effect_with((), move |_| {
             let pan = pan.clone();
             let zoom = zoom.clone();
             let connection_status = connection_status.clone();
             spawn_local(async move {
-                let ws_url = "ws://127.0.0.1:3000/ws";
-                match WebSocket::open(ws_url) {
+                match WebSocket::open(&ws_url()) {
                     Ok(socket) => {
                         connection_status.set("connected".to_string());
                         let (write, read) = socket.split();
                         
                         // Spawn a task to handle incoming messages
                         let canvas_ref = canvas_ref.clone();
+                        let board = board.clone();
                         let pan = pan.clone();
                         let zoom = zoom.clone();
                         let connection_status = connection_status.clone();
+                        let reader_ws = ws.clone();
                         spawn_local(async move {
+                            let ws = reader_ws;
                             let mut read = read;
+                            // Who this connection is, once the backend has said
+                            let mut own_user = None;
                             while let Some(msg) = read.next().await {
                                 match msg {
                                     Ok(Message::Text(txt)) => {
-                                        if let Ok(event) = serde_json::from_str::<WhiteboardEvent>(&txt) {
-                                            if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
-                                                let ctx = canvas
-                                                    .get_context("2d")
-                                                    .unwrap()
-                                                    .unwrap()
-                                                    .dyn_into::<CanvasRenderingContext2d>()
-                                                    .unwrap();
-                                                
-                                                ctx.save();
-                                                ctx.translate(pan.0, pan.1).unwrap();
-                                                ctx.scale(*zoom, *zoom).unwrap();
-                                                
-                                                match event {
-                                                    WhiteboardEvent::DrawFreehand { x, y, .. } => {
-                                                        ctx.set_fill_style_str("#2563eb");
-                                                        ctx.begin_path();
-                                                        ctx.arc(x, y, 2.0, 0.0, std::f64::consts::PI * 2.0).unwrap();
-                                                        ctx.fill();
+                                        if let Ok(frame) = serde_json::from_str::<Frame>(&txt) {
+                                            match frame.event {
+                                                WhiteboardEvent::Welcome { conn, owner_token } => {
+                                                    let user = frame.user.clone().unwrap_or_default();
+                                                    if let Some(token) = owner_token {
+                                                        keep_owner_token(&user, &token);
                                                     }
-                                                    WhiteboardEvent::DrawLine { from, to, color, width } => {
-                                                        ctx.begin_path();
-                                                        ctx.set_stroke_style_str(&color);
-                                                        ctx.set_line_width(width);
-                                                        ctx.set_line_cap("round");
-                                                        ctx.move_to(from.0, from.1);
-                                                        ctx.line_to(to.0, to.1);
-                                                        ctx.stroke();
+                                                    board.borrow_mut().start_at(frame.seq.unwrap_or(0));
+                                                    own_user = Some(user.clone());
+                                                    me.set(Some((conn, user)));
+                                                    continue;
+                                                }
+                                                WhiteboardEvent::OwnerToken { token } => {
+                                                    if let Some(user) = &own_user {
+                                                        keep_owner_token(user, &token);
                                                     }
-                                                    WhiteboardEvent::DrawRect { from, to, color, width } => {
-                                                        ctx.begin_path();
-                                                        ctx.set_stroke_style_str(&color);
-                                                        ctx.set_line_width(width);
-                                                        ctx.set_line_cap("round");
-                                                        ctx.stroke_rect(from.0, from.1, to.0 - from.0, to.1 - from.1);
+                                                    continue;
+                                                }
+                                                WhiteboardEvent::ChatHistory { messages } => {
+                                                    chat.dispatch(ChatAction::History(messages.iter().filter_map(ChatLine::from_frame).collect()));
+                                                    continue;
+                                                }
+                                                WhiteboardEvent::ChatMessage { .. } => {
+                                                    if let Some(line) = ChatLine::from_frame(&frame) {
+                                                        let mine = own_user.as_ref() == Some(&line.user);
+                                                        chat.dispatch(ChatAction::Message(line, mine));
                                                     }
-                                                    WhiteboardEvent::DrawCircle { center, radius, color, width } => {
-                                                        ctx.begin_path();
-                                                        ctx.set_stroke_style_str(&color);
-                                                        ctx.set_line_width(width);
-                                                        ctx.set_line_cap("round");
-                                                        ctx.arc(center.0, center.1, radius, 0.0, std::f64::consts::PI * 2.0).unwrap();
-                                                        ctx.stroke();
+                                                    continue;
+                                                }
+                                                WhiteboardEvent::Checkpoints { checkpoints: list } => {
+                                                    checkpoints.set(list);
+                                                    continue;
+                                                }
+                                                WhiteboardEvent::RoomState { owners, locked, connections } => {
+                                                    room_info.set(RoomInfo { owners, locked, connections });
+                                                    continue;
+                                                }
+                                                WhiteboardEvent::Ack => {
+                                                    if let Some(reference) = &frame.reference {
+                                                        ws.borrow_mut().ack(reference);
                                                     }
-                                                    WhiteboardEvent::AddText { pos, text, color, size } => {
-                                                        ctx.set_fill_style_str(&color);
-                                                        ctx.set_font(&format!("{}px 'Inter', -apple-system, system-ui, sans-serif", size));
-                                                        ctx.fill_text(&text, pos.0, pos.1).unwrap();
+                                                    continue;
+                                                }
+                                                WhiteboardEvent::Reject { reason } => {
+                                                    let ours = frame.reference.as_ref().is_some_and(|r| ws.borrow_mut().reject(r));
+                                                    if ours {
+                                                        roll_back(&canvas_ref, &board.borrow(), *pan, *zoom, &notice, format!("Change undone: {}", reason));
                                                     }
-                                                    _ => {}
+                                                    continue;
+                                                }
+                                                _ => {}
+                                            }
+                                            let full_redraw = board.borrow_mut().apply(&frame);
+                                            if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+                                                if full_redraw {
+                                                    render::redraw(&canvas, &board.borrow(), *pan, *zoom);
+                                                } else {
+                                                    render::draw(&canvas, &frame.event, *pan, *zoom);
                                                 }
-                                                ctx.restore();
                                             }
                                         }
                                     }
                                     Ok(Message::Bytes(_)) => {
                                         // Handle binary messages if needed
                                     }
+                                    Err(WebSocketError::ConnectionClose(close)) if close.code == CLOSE_KICKED => {
+                                        connection_status.set("kicked".to_string());
+                                        break;
+                                    }
+                                    Err(WebSocketError::ConnectionClose(close)) if close.code == CLOSE_SLOW => {
+                                        connection_status.set("behind".to_string());
+                                        break;
+                                    }
                                     Err(_) => {
                                         connection_status.set("disconnected".to_string());
                                         break;
                                     }
                                 }
                             }
+                            // Nothing sent on this connection will be acknowledged any more
+                            if ws.borrow_mut().disconnect() {
+                                roll_back(&canvas_ref, &board.borrow(), *pan, *zoom, &notice, "Unsaved changes were lost".to_string());
+                            }
                         });
-                        *ws.borrow_mut() = Some(Rc::new(RefCell::new(write)));
+                        ws.borrow_mut().connect(Rc::new(Mutex::new(write)));
                     }
                     Err(_) => {
                         connection_status.set("failed".to_string());
@@ -179,12 +486,246 @@ fn app() -> Html {
         });
     }
 
+    // Send overdue events again, and roll back those the backend never acknowledged
+    {
+        let ws = ws.clone();
+        let canvas_ref = canvas_ref.clone();
+        let board = board.clone();
+        let notice = notice.clone();
+        use_effect_with((*pan, *zoom), move |&(pan, zoom)| {
+            let interval = Interval::new(RETRY_INTERVAL_MS, move || {
+                if ws.borrow_mut().retry() {
+                    roll_back(&canvas_ref, &board.borrow(), pan, zoom, &notice, "Change undone: the server did not confirm it".to_string());
+                }
+            });
+            move || drop(interval)
+        });
+    }
+
+    // Draw the board again when the view moves, and whenever an image on it finishes loading
+    {
+        let canvas_ref = canvas_ref.clone();
+        let board = board.clone();
+        use_effect_with((*pan, *zoom), move |&(pan, zoom)| {
+            let redraw = move || {
+                if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+                    render::redraw(&canvas, &board.borrow(), pan, zoom);
+                }
+            };
+            redraw();
+            render::on_image_load(redraw);
+        });
+    }
+
     // Color palette
     let colors = vec![
         "#2563eb", "#dc2626", "#059669", "#d97706", "#7c3aed", 
         "#db2777", "#0891b2", "#65a30d", "#be185d", "#0f172a"
     ];
 
+    // What this client may do in the room
+    let my_conn = me.as_ref().map(|(conn, _)| *conn);
+    let is_owner = room_info.connections.iter().any(|c| Some(c.conn) == my_conn && c.owner);
+    let muted = room_info.connections.iter().any(|c| Some(c.conn) == my_conn && c.muted);
+    let can_edit = !muted && (!room_info.locked || is_owner);
+    let picked_checkpoint = (*chosen_checkpoint)
+        .clone()
+        .filter(|name| checkpoints.iter().any(|checkpoint| &checkpoint.name == name))
+        .or_else(|| checkpoints.last().map(|checkpoint| checkpoint.name.clone()));
+
+    // Show how the board changed since the picked checkpoint, or stop showing it
+    let toggle_compare = {
+        let comparing = comparing.clone();
+        let canvas_ref = canvas_ref.clone();
+        let board = board.clone();
+        let notice = notice.clone();
+        let name = picked_checkpoint.clone();
+        let view = (*pan, *zoom);
+        Callback::from(move |_: MouseEvent| {
+            let redraw = {
+                let canvas_ref = canvas_ref.clone();
+                let board = board.clone();
+                move || {
+                    if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+                        render::redraw(&canvas, &board.borrow(), view.0, view.1);
+                    }
+                }
+            };
+            if comparing.is_some() {
+                diff::show(None);
+                comparing.set(None);
+                redraw();
+                return;
+            }
+            let Some(name) = name.clone() else { return };
+            let comparing = comparing.clone();
+            let notice = notice.clone();
+            spawn_local(async move {
+                match diff::since_checkpoint(&room_name(), &name).await {
+                    Ok(changes) => {
+                        show_notice(&notice, format!("Since “{}”: {}", name, changes.summary()));
+                        diff::show(Some(changes));
+                        comparing.set(Some(name));
+                        redraw();
+                    }
+                    Err(reason) => show_notice(&notice, format!("Could not compare: {}", reason)),
+                }
+            });
+        })
+    };
+
+    // Owner-only controls and the participant list
+    let room_section = html! {
+        <div class="toolbar-section">
+            <label class="toolbar-label">{"Room"}</label>
+            <div class="tool-buttons">
+                <button
+                    class="tool-btn"
+                    onclick={{
+                        let new_board = new_board.clone();
+                        move |_| new_board.set(true)
+                    }}
+                    title="Start a new board, blank or from a template"
+                >
+                    {"➕"}
+                    <span>{"New board"}</span>
+                </button>
+            </div>
+            if is_owner {
+                <div class="tool-buttons">
+                    <button
+                        class="tool-btn"
+                        onclick={{
+                            let ws = ws.clone();
+                            move |_| ws.borrow_mut().send(&WhiteboardEvent::ClearBoard)
+                        }}
+                        title="Clear board"
+                    >
+                        {"🗑️"}
+                        <span>{"Clear"}</span>
+                    </button>
+                    <button
+                        class={classes!("tool-btn", room_info.locked.then_some("active"))}
+                        onclick={{
+                            let ws = ws.clone();
+                            let locked = !room_info.locked;
+                            move |_| ws.borrow_mut().send(&WhiteboardEvent::LockRoom { locked })
+                        }}
+                        title="Make the board read-only for everyone but owners"
+                    >
+                        {"🔒"}
+                        <span>{if room_info.locked { "Unlock" } else { "Lock" }}</span>
+                    </button>
+                </div>
+            }
+            <ul class="participants">
+                {for room_info.connections.iter().map(|c| {
+                    let is_me = Some(c.conn) == my_conn;
+                    let owner = c.owner;
+                    let admin_event = |event: WhiteboardEvent| {
+                        let ws = ws.clone();
+                        Callback::from(move |_: MouseEvent| ws.borrow_mut().send(&event))
+                    };
+                    html! {
+                        <li class="participant">
+                            <span class="participant-name">
+                                <span class="presence-dot" style={format!("background-color: {}", presence_color(&c.user))} />
+                                {&c.user}
+                                if is_me { {" (you)"} }
+                                if owner { {" 👑"} }
+                                if c.muted { {" 🔇"} }
+                            </span>
+                            if is_owner && !is_me {
+                                <span class="participant-actions">
+                                    <button
+                                        class="participant-btn"
+                                        onclick={admin_event(WhiteboardEvent::Mute { conn: c.conn, muted: !c.muted })}
+                                    >{if c.muted { "Unmute" } else { "Mute" }}</button>
+                                    <button
+                                        class="participant-btn"
+                                        onclick={admin_event(WhiteboardEvent::Kick { conn: c.conn })}
+                                    >{"Kick"}</button>
+                                    if !owner {
+                                        <button
+                                            class="participant-btn"
+                                            onclick={admin_event(WhiteboardEvent::TransferOwnership { to: c.user.clone() })}
+                                        >{"Make owner"}</button>
+                                    }
+                                </span>
+                            }
+                        </li>
+                    }
+                })}
+            </ul>
+        </div>
+    };
+
+    // Downloads of the board as the backend last saw it
+    let export_section = {
+        let room_path = format!("/rooms/{}", String::from(js_sys::encode_uri_component(&room_name())));
+        let mut svg_url = backend_http_url(&format!("{}/board.svg", room_path), &[]);
+        svg_url.push_str("download");
+        if *crop_export {
+            svg_url.push_str("&crop=true");
+        }
+        let (paper, layout) = &*pdf_options;
+        let pdf_url = format!(
+            "{}download&paper={}&layout={}",
+            backend_http_url(&format!("{}/board.pdf", room_path), &[]),
+            paper,
+            layout
+        );
+        let select = |set: fn(&mut (String, String), String)| {
+            let pdf_options = pdf_options.clone();
+            Callback::from(move |e: Event| {
+                let select: HtmlSelectElement = e.target_unchecked_into();
+                let mut options = (*pdf_options).clone();
+                set(&mut options, select.value());
+                pdf_options.set(options);
+            })
+        };
+        html! {
+            <div class="toolbar-section">
+                <label class="toolbar-label">{"Export"}</label>
+                <div class="tool-buttons">
+                    <a class="tool-btn" href={svg_url} download="" title="Download the board as an SVG image">
+                        {"🖼️"}
+                        <span>{"Export SVG"}</span>
+                    </a>
+                    <a class="tool-btn" href={pdf_url} download="" title="Download the board as a PDF">
+                        {"📄"}
+                        <span>{"Export PDF"}</span>
+                    </a>
+                </div>
+                <label class="export-option">
+                    <input
+                        type="checkbox"
+                        checked={*crop_export}
+                        onchange={{
+                            let crop_export = crop_export.clone();
+                            Callback::from(move |e: Event| {
+                                let input: HtmlInputElement = e.target_unchecked_into();
+                                crop_export.set(input.checked());
+                            })
+                        }}
+                    />
+                    {"Crop SVG to content"}
+                </label>
+                <div class="export-option">
+                    <select class="export-select" onchange={select(|options, paper| options.0 = paper)}>
+                        {for [("a4", "A4"), ("a3", "A3"), ("letter", "Letter"), ("legal", "Legal")].into_iter().map(|(value, label)| html! {
+                            <option value={value} selected={paper == value}>{label}</option>
+                        })}
+                    </select>
+                    <select class="export-select" onchange={select(|options, layout| options.1 = layout)}>
+                        <option value="fit" selected={layout == "fit"}>{"Fit to one page"}</option>
+                        <option value="tile" selected={layout == "tile"}>{"Tile at print size"}</option>
+                    </select>
+                </div>
+            </div>
+        }
+    };
+
     // Toolbar UI
     let toolbar = html! {
         <div class="toolbar">
@@ -195,9 +736,30 @@ fn app() -> Html {
                         "connected" => "🟢 Connected",
                         "connecting" => "🟡 Connecting...",
                         "disconnected" => "🔴 Disconnected",
+                        "kicked" => "⛔ Removed by a room owner",
+                        "behind" => "🔴 Connection too slow to keep up; reload to catch up",
                         _ => "🔴 Failed to connect"
                     }}
                 </div>
+                if let Some(notice) = &*notice {
+                    <div class="notice-badge">{notice}</div>
+                }
+                if let Some(name) = &*comparing {
+                    <div class="diff-legend">
+                        {format!("Changes since “{}”: ", name)}
+                        <span class="diff-added">{"added"}</span>
+                        <span class="diff-removed">{"removed"}</span>
+                        <span class="diff-modified">{"changed"}</span>
+                        <span class="diff-moved">{"moved from"}</span>
+                    </div>
+                }
+                if muted {
+                    <div class="room-badge">{"🔇 Muted by a room owner"}</div>
+                } else if room_info.locked {
+                    <div class="room-badge">
+                        {if is_owner { "🔒 Locked for everyone else" } else { "🔒 Read-only" }}
+                    </div>
+                }
             </div>
             
             <div class="toolbar-section">
@@ -210,7 +772,7 @@ fn app() -> Html {
                         let is_active = *tool == current_tool;
                         html! {
                             <button 
-                                class={classes!("tool-btn", is_active.then(|| "active"))}
+                                class={classes!("tool-btn", is_active.then_some("active"))}
                                 onclick={move |_| tool_clone.set(current_tool_for_onclick.clone())}
                                 title={format!("{:?}", current_tool)}
                             >
@@ -222,6 +784,125 @@ fn app() -> Html {
                 </div>
             </div>
 
+            <div class="toolbar-section">
+                <label class="toolbar-label">{"History"}</label>
+                <div class="tool-buttons">
+                    {for [("↩️", "Undo", WhiteboardEvent::Undo), ("↪️", "Redo", WhiteboardEvent::Redo)].into_iter().map(|(icon, label, event)| {
+                        let ws = ws.clone();
+                        html! {
+                            <button
+                                class="tool-btn"
+                                disabled={!can_edit}
+                                onclick={move |_| ws.borrow_mut().send(&event)}
+                                title={label}
+                            >
+                                {icon}
+                                <span>{label}</span>
+                            </button>
+                        }
+                    })}
+                </div>
+                <div class="tool-buttons">
+                    <button
+                        class="tool-btn"
+                        disabled={!can_edit}
+                        onclick={{
+                            let ws = ws.clone();
+                            Callback::from(move |_: MouseEvent| {
+                                let name = web_sys::window().unwrap().prompt_with_message("Name this checkpoint").ok().flatten();
+                                if let Some(name) = name.filter(|name| !name.trim().is_empty()) {
+                                    ws.borrow_mut().send(&WhiteboardEvent::CreateCheckpoint { name });
+                                }
+                            })
+                        }}
+                        title="Save the board as it is now, to go back to later"
+                    >
+                        {"📌"}
+                        <span>{"Checkpoint"}</span>
+                    </button>
+                </div>
+                if !checkpoints.is_empty() {
+                    <div class="export-option">
+                        <select
+                            class="export-select"
+                            onchange={{
+                                let chosen_checkpoint = chosen_checkpoint.clone();
+                                Callback::from(move |e: Event| {
+                                    let select: HtmlSelectElement = e.target_unchecked_into();
+                                    chosen_checkpoint.set(Some(select.value()));
+                                })
+                            }}
+                        >
+                            {for checkpoints.iter().rev().map(|checkpoint| html! {
+                                <option
+                                    value={checkpoint.name.clone()}
+                                    selected={chosen_checkpoint.as_ref() == Some(&checkpoint.name)}
+                                >
+                                    {&checkpoint.name}
+                                </option>
+                            })}
+                        </select>
+                        <button
+                            class={classes!("participant-btn", comparing.is_some().then_some("active"))}
+                            onclick={toggle_compare}
+                            title="Highlight what was added, removed, moved or changed since this checkpoint"
+                        >
+                            {if comparing.is_some() { "Hide diff" } else { "Compare" }}
+                        </button>
+                        <button
+                            class="participant-btn"
+                            disabled={!can_edit}
+                            onclick={{
+                                let ws = ws.clone();
+                                let name = picked_checkpoint.clone();
+                                Callback::from(move |_: MouseEvent| {
+                                    if let Some(name) = name.clone() {
+                                        ws.borrow_mut().send(&WhiteboardEvent::RestoreCheckpoint { name });
+                                    }
+                                })
+                            }}
+                            title="Put the board back the way it was at this checkpoint; undo takes it back"
+                        >
+                            {"Restore"}
+                        </button>
+                    </div>
+                }
+            </div>
+
+            <div class="toolbar-section">
+                <label class="toolbar-label">{"Insert"}</label>
+                <div class="tool-buttons">
+                    <label class={classes!("tool-btn", (!can_edit).then_some("disabled"))} title="Add an image to the board, or drop one onto it">
+                        {"🖼️"}
+                        <span>{"Image"}</span>
+                        <input
+                            type="file"
+                            accept={IMAGE_TYPES}
+                            hidden=true
+                            disabled={!can_edit}
+                            onchange={{
+                                let ws = ws.clone();
+                                let notice = notice.clone();
+                                // Placed in the middle of the view
+                                let center = ((600.0 - pan.0) / *zoom, (400.0 - pan.1) / *zoom);
+                                Callback::from(move |e: Event| {
+                                    let input: HtmlInputElement = e.target_unchecked_into();
+                                    if let Some(file) = input.files().and_then(|files| files.get(0)) {
+                                        place_image(file, center, ws.clone(), notice.clone());
+                                    }
+                                    // Choosing the same file again should add it again
+                                    input.set_value("");
+                                })
+                            }}
+                        />
+                    </label>
+                </div>
+            </div>
+
+            {room_section}
+
+            {export_section}
+
             <div class="toolbar-section">
                 <label class="toolbar-label">{"Colors"}</label>
                 <div class="color-palette">
@@ -231,7 +912,7 @@ fn app() -> Html {
                         let is_active = *color == current_color;
                         html! {
                             <button 
-                                class={classes!("color-btn", is_active.then(|| "active"))}
+                                class={classes!("color-btn", is_active.then_some("active"))}
                                 style={format!("background-color: {}", current_color)}
                                 onclick={move |_| color_clone.set(current_color.clone())}
                             />
@@ -292,6 +973,7 @@ fn app() -> Html {
     // Mouse event handlers
     let onmousedown = {
         let drawing = drawing.clone();
+        let ws = ws.clone();
         let last_pos = last_pos.clone();
         let tool = tool.clone();
         let start_pos = start_pos.clone();
@@ -304,10 +986,15 @@ fn app() -> Html {
             let canvas_y = (e.offset_y() as f64 - pan.1) / *zoom;
             
             match *tool {
+                // Panning only moves this client's view, so it works on a read-only board
+                Tool::Freehand | Tool::Line | Tool::Rect | Tool::Circle | Tool::Text if !can_edit => {}
                 Tool::Freehand => {
                     drawing.set(true);
                     last_pos.borrow_mut().0 = canvas_x;
                     last_pos.borrow_mut().1 = canvas_y;
+                    // First point of a new stroke
+                    let event = WhiteboardEvent::DrawFreehand { x: canvas_x, y: canvas_y, dragging: false };
+                    ws.borrow_mut().send(&event);
                 }
                 Tool::Line | Tool::Rect | Tool::Circle => {
                     start_pos.borrow_mut().replace((canvas_x, canvas_y));
@@ -326,7 +1013,6 @@ fn app() -> Html {
                     }
                     
                 }
-                _ => {}
             }
         })
     };
@@ -344,50 +1030,41 @@ fn app() -> Html {
         Callback::from(move |e: MouseEvent| {
             match *tool {
                 Tool::Freehand => drawing.set(false),
-                Tool::Line | Tool::Rect | Tool::Circle => {
-                    if *drawing {
-                        if let Some((sx, sy)) = *start_pos.borrow() {
-                            let canvas_x = (e.offset_x() as f64 - pan.0) / *zoom;
-                            let canvas_y = (e.offset_y() as f64 - pan.1) / *zoom;
-                            
-                            let event = match *tool {
-                                Tool::Line => WhiteboardEvent::DrawLine {
-                                    from: (sx, sy),
-                                    to: (canvas_x, canvas_y),
-                                    color: color.to_string(),
-                                    width: *width,
-                                },
-                                Tool::Rect => WhiteboardEvent::DrawRect {
-                                    from: (sx, sy),
-                                    to: (canvas_x, canvas_y),
+                Tool::Line | Tool::Rect | Tool::Circle if *drawing => {
+                    if let Some((sx, sy)) = *start_pos.borrow() {
+                        let canvas_x = (e.offset_x() as f64 - pan.0) / *zoom;
+                        let canvas_y = (e.offset_y() as f64 - pan.1) / *zoom;
+                        
+                        let event = match *tool {
+                            Tool::Line => WhiteboardEvent::DrawLine {
+                                from: (sx, sy),
+                                to: (canvas_x, canvas_y),
+                                color: color.to_string(),
+                                width: *width,
+                            },
+                            Tool::Rect => WhiteboardEvent::DrawRect {
+                                from: (sx, sy),
+                                to: (canvas_x, canvas_y),
+                                color: color.to_string(),
+                                width: *width,
+                            },
+                            Tool::Circle => {
+                                let dx = canvas_x - sx;
+                                let dy = canvas_y - sy;
+                                let radius = (dx * dx + dy * dy).sqrt();
+                                WhiteboardEvent::DrawCircle {
+                                    center: (sx, sy),
+                                    radius,
                                     color: color.to_string(),
                                     width: *width,
-                                },
-                                Tool::Circle => {
-                                    let dx = canvas_x - sx;
-                                    let dy = canvas_y - sy;
-                                    let radius = (dx * dx + dy * dy).sqrt();
-                                    WhiteboardEvent::DrawCircle {
-                                        center: (sx, sy),
-                                        radius,
-                                        color: color.to_string(),
-                                        width: *width,
-                                    }
                                 }
-                                _ => unreachable!(),
-                            };
-                            if let Some(writer_rc) = &*ws.borrow() {
-                                let writer = writer_rc.clone();
-                                let msg = Message::Text(serde_json::to_string(&event).unwrap());
-                                spawn_local(async move {
-                                    let mut writer = writer.borrow_mut();
-                                    let _ = writer.send(msg).await;
-                                });
                             }
-                        }
-                        drawing.set(false);
-                        start_pos.borrow_mut().take();
+                            _ => unreachable!(),
+                        };
+                        ws.borrow_mut().send(&event);
                     }
+                    drawing.set(false);
+                    start_pos.borrow_mut().take();
                 }
                 Tool::Pan => {
                     pan_start.borrow_mut().take();
@@ -415,45 +1092,36 @@ fn app() -> Html {
         let zoom = zoom.clone();
         Callback::from(move |e: MouseEvent| {
             match *tool {
-                Tool::Freehand => {
-                    if *drawing {
-                        let canvas_x = (e.offset_x() as f64 - pan.0) / *zoom;
-                        let canvas_y = (e.offset_y() as f64 - pan.1) / *zoom;
-                        
-                        // Draw locally with smooth line
-                        if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
-                            let ctx = canvas
-                                .get_context("2d")
-                                .unwrap()
-                                .unwrap()
-                                .dyn_into::<CanvasRenderingContext2d>()
-                                .unwrap();
-                            ctx.save();
-                            ctx.translate(pan.0, pan.1).unwrap();
-                            ctx.scale(*zoom, *zoom).unwrap();
-                            ctx.set_stroke_style_str(&color);
-                            ctx.set_line_width(3.0);
-                            ctx.set_line_cap("round");
-                            ctx.begin_path();
-                            ctx.move_to(last_pos.borrow().0, last_pos.borrow().1);
-                            ctx.line_to(canvas_x, canvas_y);
-                            ctx.stroke();
-                            ctx.restore();
-                        }
-                        
-                        // Send to backend
-                        let event = WhiteboardEvent::DrawFreehand { x: canvas_x, y: canvas_y, dragging: true };
-                        if let Some(writer_rc) = &*ws.borrow() {
-                            let writer = writer_rc.clone();
-                            let msg = Message::Text(serde_json::to_string(&event).unwrap());
-                            spawn_local(async move {
-                                let mut writer = writer.borrow_mut();
-                                let _ = writer.send(msg).await;
-                            });
-                        }
-                        last_pos.borrow_mut().0 = canvas_x;
-                        last_pos.borrow_mut().1 = canvas_y;
+                Tool::Freehand if *drawing => {
+                    let canvas_x = (e.offset_x() as f64 - pan.0) / *zoom;
+                    let canvas_y = (e.offset_y() as f64 - pan.1) / *zoom;
+                    
+                    // Draw locally with smooth line
+                    if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+                        let ctx = canvas
+                            .get_context("2d")
+                            .unwrap()
+                            .unwrap()
+                            .dyn_into::<CanvasRenderingContext2d>()
+                            .unwrap();
+                        ctx.save();
+                        ctx.translate(pan.0, pan.1).unwrap();
+                        ctx.scale(*zoom, *zoom).unwrap();
+                        ctx.set_stroke_style_str(&color);
+                        ctx.set_line_width(3.0);
+                        ctx.set_line_cap("round");
+                        ctx.begin_path();
+                        ctx.move_to(last_pos.borrow().0, last_pos.borrow().1);
+                        ctx.line_to(canvas_x, canvas_y);
+                        ctx.stroke();
+                        ctx.restore();
                     }
+                    
+                    // Send to backend
+                    let event = WhiteboardEvent::DrawFreehand { x: canvas_x, y: canvas_y, dragging: true };
+                    ws.borrow_mut().send(&event);
+                    last_pos.borrow_mut().0 = canvas_x;
+                    last_pos.borrow_mut().1 = canvas_y;
                 }
                 Tool::Pan => {
                     if let Some((start_x, start_y)) = *pan_start.borrow() {
@@ -478,6 +1146,24 @@ fn app() -> Html {
         })
     };
 
+    // Images dropped onto the canvas are placed where they land
+    let ondragover = Callback::from(|e: DragEvent| e.prevent_default());
+    let ondrop = {
+        let ws = ws.clone();
+        let notice = notice.clone();
+        let pan = pan.clone();
+        let zoom = zoom.clone();
+        Callback::from(move |e: DragEvent| {
+            e.prevent_default();
+            if !can_edit {
+                return;
+            }
+            let Some(file) = e.data_transfer().and_then(|data| data.files()).and_then(|files| files.get(0)) else { return };
+            let center = ((e.offset_x() as f64 - pan.0) / *zoom, (e.offset_y() as f64 - pan.1) / *zoom);
+            place_image(file, center, ws.clone(), notice.clone());
+        })
+    };
+
     // Text input overlay
     let text_input_overlay = {
         let text_input = text_input.clone();
@@ -507,14 +1193,7 @@ fn app() -> Html {
                                         color: color.to_string(),
                                         size: 18.0,
                                     };
-                                    if let Some(writer_rc) = &*ws.borrow() {
-                                        let writer = writer_rc.clone();
-                                        let msg = Message::Text(serde_json::to_string(&event).unwrap());
-                                        spawn_local(async move {
-                                            let mut writer = writer.borrow_mut();
-                                            let _ = writer.send(msg).await;
-                                        });
-                                    }
+                                    ws.borrow_mut().send(&event);
                                 }
                             }
                             text_input.set(None);
@@ -557,8 +1236,22 @@ fn app() -> Html {
                 {include_str!("../styles.css")}
             </style>
             {toolbar}
+            if *new_board {
+                <TemplatePicker on_close={{
+                    let new_board = new_board.clone();
+                    Callback::from(move |()| new_board.set(false))
+                }} />
+            }
             <div class="canvas-container">
                 {text_input_overlay}
+                <ChatPanel
+                    log={chat.clone()}
+                    can_send={!muted}
+                    on_send={{
+                        let ws = ws.clone();
+                        Callback::from(move |text: String| ws.borrow_mut().send(&WhiteboardEvent::ChatMessage { text, ts: 0 }))
+                    }}
+                />
                 <canvas
                     ref={canvas_ref}
                     width={1200}
@@ -569,6 +1262,8 @@ fn app() -> Html {
                     onmouseup={onmouseup}
                     onmousemove={onmousemove}
                     onwheel={onwheel}
+                    ondragover={ondragover}
+                    ondrop={ondrop}
                 />
             </div>
         </div>
@@ -577,5 +1272,10 @@ fn app() -> Html {
 
 #[wasm_bindgen(start)]
 pub fn run_app() {
-    yew::Renderer::<App>::new().render();
+    // `?replay` plays the room's history back instead of joining it
+    if page_params().has("replay") {
+        yew::Renderer::<replay::Replay>::new().render();
+    } else {
+        yew::Renderer::<App>::new().render();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+
+use futures_util::sink::SinkExt;
+use gloo_net::websocket::Message;
+use serde::Serialize;
+use wasm_bindgen_futures::spawn_local;
+
+use crate::{WhiteboardEvent, WsWriter};
+
+// How long to wait for the backend to acknowledge an event before sending it again
+const ACK_TIMEOUT_MS: f64 = 3000.0;
+// Sends per event before giving up on it
+const MAX_ATTEMPTS: u32 = 3;
+
+// An event on its way to the backend, named so the backend's ack can refer to it
+#[derive(Serialize)]
+struct Outgoing<'a> {
+    #[serde(rename = "ref")]
+    reference: &'a str,
+    #[serde(flatten)]
+    event: &'a WhiteboardEvent,
+}
+
+struct Sent {
+    event: WhiteboardEvent,
+    sent_at: f64,
+    attempts: u32,
+}
+
+// The backend connection plus every event sent on it that has not been acknowledged yet
+pub struct Outbox {
+    writer: Option<WsWriter>,
+    // Keeps refs unique across page loads of the same user
+    session: String,
+    next: u64,
+    pending: BTreeMap<String, Sent>,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox {
+            writer: None,
+            session: format!("{:08x}", (js_sys::Math::random() * u32::MAX as f64) as u32),
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+impl Outbox {
+    pub fn connect(&mut self, writer: WsWriter) {
+        self.writer = Some(writer);
+    }
+
+    // Forget the connection. Returns true if unacknowledged events were dropped with it.
+    pub fn disconnect(&mut self) -> bool {
+        self.writer = None;
+        let dropped = !self.pending.is_empty();
+        self.pending.clear();
+        dropped
+    }
+
+    pub fn send(&mut self, event: &WhiteboardEvent) {
+        if self.writer.is_none() {
+            return;
+        }
+        self.next += 1;
+        let reference = format!("{}.{}", self.session, self.next);
+        self.transmit(&reference, event);
+        self.pending.insert(
+            reference,
+            Sent {
+                event: event.clone(),
+                sent_at: js_sys::Date::now(),
+                attempts: 1,
+            },
+        );
+    }
+
+    pub fn ack(&mut self, reference: &str) {
+        self.pending.remove(reference);
+    }
+
+    // The backend refused an event. Returns whether it was one of ours.
+    pub fn reject(&mut self, reference: &str) -> bool {
+        self.pending.remove(reference).is_some()
+    }
+
+    // Send again every event whose ack is overdue, and give up on those out of attempts.
+    // Returns true if any event was given up on.
+    pub fn retry(&mut self) -> bool {
+        let now = js_sys::Date::now();
+        let overdue: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, sent)| now - sent.sent_at >= ACK_TIMEOUT_MS)
+            .map(|(reference, _)| reference.clone())
+            .collect();
+
+        let mut gave_up = false;
+        for reference in overdue {
+            let Some(sent) = self.pending.get_mut(&reference) else { continue };
+            if sent.attempts >= MAX_ATTEMPTS {
+                self.pending.remove(&reference);
+                gave_up = true;
+                continue;
+            }
+            sent.attempts += 1;
+            sent.sent_at = now;
+            // The backend acks a retried event without applying it twice
+            let event = sent.event.clone();
+            self.transmit(&reference, &event);
+        }
+        gave_up
+    }
+
+    fn transmit(&self, reference: &str, event: &WhiteboardEvent) {
+        let Some(writer) = &self.writer else { return };
+        let writer = writer.clone();
+        let msg = Message::Text(serde_json::to_string(&Outgoing { reference, event }).unwrap());
+        spawn_local(async move {
+            let _ = writer.lock().await.send(msg).await;
+        });
+    }
+}
@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use wasm_bindgen::prelude::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, HtmlImageElement};
+
+use crate::{backend_http_url, diff, Frame, WhiteboardEvent};
+
+thread_local! {
+    // Images on the board by asset id, fetched once per page
+    static IMAGES: RefCell<HashMap<String, HtmlImageElement>> = RefCell::new(HashMap::new());
+    // Draws the board again, for when an image finishes loading
+    static ON_IMAGE_LOAD: RefCell<Option<Rc<dyn Fn()>>> = RefCell::new(None);
+}
+
+// Set what to do when an image on the board has loaded, replacing what was set before.
+// Images show as a placeholder until then.
+pub fn on_image_load(redraw: impl Fn() + 'static) {
+    ON_IMAGE_LOAD.with(|on_load| *on_load.borrow_mut() = Some(Rc::new(redraw)));
+}
+
+// The image stored as `asset`, once it has loaded; starts loading it the first time
+fn image(asset: &str) -> Option<HtmlImageElement> {
+    IMAGES.with(|images| {
+        let mut images = images.borrow_mut();
+        let image = images.entry(asset.to_string()).or_insert_with(|| {
+            let image = HtmlImageElement::new().unwrap();
+            let onload = Closure::<dyn Fn()>::new(|| {
+                let redraw = ON_IMAGE_LOAD.with(|on_load| on_load.borrow().clone());
+                if let Some(redraw) = redraw {
+                    redraw();
+                }
+            });
+            image.set_onload(Some(onload.as_ref().unchecked_ref()));
+            // Lives as long as the image, which is as long as the page
+            onload.forget();
+            image.set_src(&backend_http_url(&format!("/assets/{}", asset), &[]));
+            image
+        });
+        (image.complete() && image.natural_width() > 0).then(|| image.clone())
+    })
+}
+
+// Elements currently on the board, in drawing order, as confirmed by the backend
+#[derive(Default)]
+pub struct Board {
+    elements: Vec<(String, WhiteboardEvent)>,
+    // Place in the room's order of the last change applied
+    seq: u64,
+}
+
+impl Board {
+    // Where the room's order stood when this client joined; the snapshot after it covers
+    // everything up to there
+    pub fn start_at(&mut self, seq: u64) {
+        self.seq = seq;
+    }
+
+    // Apply a frame from the backend.
+    // Returns true when existing elements changed and the whole canvas must be redrawn;
+    // otherwise drawing the frame's event on top is enough.
+    pub fn apply(&mut self, frame: &Frame) -> bool {
+        if let Some(seq) = frame.seq {
+            // Already applied
+            if seq <= self.seq {
+                return false;
+            }
+            self.seq = seq;
+        }
+        if frame.event == WhiteboardEvent::ClearBoard {
+            self.elements.clear();
+            return true;
+        }
+        if let WhiteboardEvent::ResetBoard { elements } = &frame.event {
+            self.elements = elements
+                .iter()
+                .filter_map(|element| Some((element.id.clone()?, element.event.clone())))
+                .collect();
+            return true;
+        }
+        let Some(id) = &frame.id else { return false };
+        let existing = self.elements.iter().position(|(element_id, _)| element_id == id);
+
+        match (&frame.event, existing) {
+            (WhiteboardEvent::DrawFreehand { x, y, .. }, Some(index)) => {
+                if let WhiteboardEvent::DrawStroke { points } = &mut self.elements[index].1 {
+                    points.push((*x, *y));
+                }
+                false
+            }
+            (WhiteboardEvent::DrawFreehand { x, y, .. }, None) => {
+                self.elements.push((id.clone(), WhiteboardEvent::DrawStroke { points: vec![(*x, *y)] }));
+                false
+            }
+            (WhiteboardEvent::DeleteElement, Some(index)) => {
+                self.elements.remove(index);
+                true
+            }
+            (event, Some(index)) if event.is_element() => {
+                self.elements[index].1 = event.clone();
+                true
+            }
+            (event, None) if event.is_element() => {
+                self.elements.push((id.clone(), event.clone()));
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+pub fn context(canvas: &HtmlCanvasElement) -> CanvasRenderingContext2d {
+    canvas
+        .get_context("2d")
+        .unwrap()
+        .unwrap()
+        .dyn_into::<CanvasRenderingContext2d>()
+        .unwrap()
+}
+
+// Draw a single event on top of what is already on the canvas
+pub fn draw(canvas: &HtmlCanvasElement, event: &WhiteboardEvent, pan: (f64, f64), zoom: f64) {
+    let ctx = context(canvas);
+    ctx.save();
+    ctx.translate(pan.0, pan.1).unwrap();
+    ctx.scale(zoom, zoom).unwrap();
+    draw_event(&ctx, event);
+    ctx.restore();
+}
+
+// Clear the canvas and draw every element of the board again, with the diff overlay if it is on
+pub fn redraw(canvas: &HtmlCanvasElement, board: &Board, pan: (f64, f64), zoom: f64) {
+    let ctx = context(canvas);
+    ctx.clear_rect(0.0, 0.0, canvas.width() as f64, canvas.height() as f64);
+    ctx.save();
+    ctx.translate(pan.0, pan.1).unwrap();
+    ctx.scale(zoom, zoom).unwrap();
+    for (_, event) in &board.elements {
+        draw_event(&ctx, event);
+    }
+    diff::draw_overlay(&ctx);
+    ctx.restore();
+}
+
+pub fn draw_event(ctx: &CanvasRenderingContext2d, event: &WhiteboardEvent) {
+    match event {
+        WhiteboardEvent::DrawFreehand { x, y, .. } => draw_dot(ctx, *x, *y),
+        WhiteboardEvent::DrawStroke { points } => {
+            for (x, y) in points {
+                draw_dot(ctx, *x, *y);
+            }
+        }
+        WhiteboardEvent::DrawLine { from, to, color, width } => {
+            ctx.begin_path();
+            ctx.set_stroke_style_str(color);
+            ctx.set_line_width(*width);
+            ctx.set_line_cap("round");
+            ctx.move_to(from.0, from.1);
+            ctx.line_to(to.0, to.1);
+            ctx.stroke();
+        }
+        WhiteboardEvent::DrawRect { from, to, color, width } => {
+            ctx.begin_path();
+            ctx.set_stroke_style_str(color);
+            ctx.set_line_width(*width);
+            ctx.set_line_cap("round");
+            ctx.stroke_rect(from.0, from.1, to.0 - from.0, to.1 - from.1);
+        }
+        WhiteboardEvent::DrawCircle { center, radius, color, width } => {
+            ctx.begin_path();
+            ctx.set_stroke_style_str(color);
+            ctx.set_line_width(*width);
+            ctx.set_line_cap("round");
+            ctx.arc(center.0, center.1, *radius, 0.0, std::f64::consts::PI * 2.0).unwrap();
+            ctx.stroke();
+        }
+        WhiteboardEvent::AddText { pos, text, color, size } => {
+            ctx.set_fill_style_str(color);
+            ctx.set_font(&format!("{}px 'Inter', -apple-system, system-ui, sans-serif", size));
+            ctx.fill_text(text, pos.0, pos.1).unwrap();
+        }
+        WhiteboardEvent::AddImage { pos, width, height, asset } => match image(asset) {
+            Some(image) => {
+                let _ = ctx.draw_image_with_html_image_element_and_dw_and_dh(&image, pos.0, pos.1, *width, *height);
+            }
+            // Outline where the image goes while it loads
+            None => {
+                ctx.set_stroke_style_str("#cbd5e1");
+                ctx.set_line_width(1.0);
+                ctx.set_line_dash(&js_sys::Array::of2(&6.0.into(), &4.0.into())).unwrap();
+                ctx.stroke_rect(pos.0, pos.1, *width, *height);
+                ctx.set_line_dash(&js_sys::Array::new()).unwrap();
+            }
+        },
+        _ => {}
+    }
+}
+
+fn draw_dot(ctx: &CanvasRenderingContext2d, x: f64, y: f64) {
+    ctx.set_fill_style_str("#2563eb");
+    ctx.begin_path();
+    ctx.arc(x, y, 2.0, 0.0, std::f64::consts::PI * 2.0).unwrap();
+    ctx.fill();
+}
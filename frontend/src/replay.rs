@@ -0,0 +1,259 @@
+use futures_util::future::abortable;
+use futures_util::stream::StreamExt;
+use gloo_net::websocket::{futures::WebSocket, Message};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{HtmlCanvasElement, HtmlInputElement};
+use yew::prelude::*;
+
+use crate::{backend_url, page_params, render, room_name, Frame, WhiteboardEvent};
+
+const SPEEDS: [f64; 6] = [0.5, 1.0, 2.0, 4.0, 8.0, 16.0];
+
+// Everything received from one replay connection
+#[derive(Default)]
+struct Received {
+    // The board where the replay starts, then every change in order
+    frames: Vec<Frame>,
+    // The board with every change received so far applied
+    board: render::Board,
+}
+
+// Draw the board as it was after change `position`, or as far as the replay has got
+fn render_at(canvas_ref: &NodeRef, received: &Received, position: Option<u64>, zoom: f64) {
+    let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() else { return };
+    let Some(position) = position else {
+        render::redraw(&canvas, &received.board, (0.0, 0.0), zoom);
+        return;
+    };
+    let mut board = render::Board::default();
+    for frame in &received.frames {
+        if frame.seq.is_some_and(|seq| seq > position) {
+            break;
+        }
+        board.apply(frame);
+    }
+    render::redraw(&canvas, &board, (0.0, 0.0), zoom);
+}
+
+// Read-only view that plays a room's history back from the backend, opened with
+// `?room=retro&replay`. `from`/`to` (seqs) and `since`/`until` (Unix ms) narrow it down.
+#[function_component(Replay)]
+pub fn replay() -> Html {
+    let canvas_ref = use_node_ref();
+    let received = use_mut_ref(Received::default);
+    // Range of changes the replay covers, once the backend has said
+    let range = use_state(|| None::<(u64, u64)>);
+    // Newest change received
+    let latest = use_state(|| 0u64);
+    // Change the scrubber is on; `None` follows the playback
+    let position = use_state(|| None::<u64>);
+    let status = use_state(|| "connecting");
+    let speed = use_state(|| {
+        page_params()
+            .get("speed")
+            .and_then(|speed| speed.parse::<f64>().ok())
+            .filter(|speed| *speed > 0.0)
+            .unwrap_or(1.0)
+    });
+    // Bumped to play the replay again from the start
+    let run = use_state(|| 0u32);
+    let zoom = use_state(|| 1.0);
+    // Scrubber position and zoom as of the last render, for the playback task
+    let view = use_mut_ref(|| (None::<u64>, 1.0));
+
+    // Play the replay, again whenever the speed changes or it is restarted
+    {
+        let canvas_ref = canvas_ref.clone();
+        let received = received.clone();
+        let range = range.clone();
+        let latest = latest.clone();
+        let position = position.clone();
+        let status = status.clone();
+        let view = view.clone();
+        use_effect_with((*speed, *run), move |&(speed, _)| {
+            *received.borrow_mut() = Received::default();
+            range.set(None);
+            latest.set(0);
+            position.set(None);
+            status.set("connecting");
+            if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+                render::redraw(&canvas, &render::Board::default(), (0.0, 0.0), view.borrow().1);
+            }
+
+            let url = format!(
+                "{}&speed={}",
+                backend_url("/ws/replay", &["room", "from", "to", "since", "until"]),
+                speed
+            );
+            let (task, handle) = abortable(async move {
+                let Ok(mut socket) = WebSocket::open(&url) else {
+                    status.set("failed");
+                    return;
+                };
+                status.set("playing");
+                while let Some(msg) = socket.next().await {
+                    let Ok(Message::Text(txt)) = msg else { break };
+                    let Ok(frame) = serde_json::from_str::<Frame>(&txt) else { continue };
+                    match frame.event {
+                        WhiteboardEvent::ReplayStart { from, to } => {
+                            range.set(Some((from, to)));
+                            latest.set(from);
+                            continue;
+                        }
+                        WhiteboardEvent::ReplayEnd => {
+                            status.set("finished");
+                            return;
+                        }
+                        _ => {}
+                    }
+
+                    let mut received = received.borrow_mut();
+                    let full_redraw = received.board.apply(&frame);
+                    if let Some(seq) = frame.seq {
+                        latest.set(seq);
+                    }
+                    // While the scrubber is elsewhere, only keep the change for later
+                    let (scrubbed, zoom) = *view.borrow();
+                    if scrubbed.is_none() {
+                        if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+                            if full_redraw {
+                                render::redraw(&canvas, &received.board, (0.0, 0.0), zoom);
+                            } else {
+                                render::draw(&canvas, &frame.event, (0.0, 0.0), zoom);
+                            }
+                        }
+                    }
+                    received.frames.push(frame);
+                }
+                status.set("disconnected");
+            });
+            spawn_local(async move {
+                let _ = task.await;
+            });
+            // Dropping the aborted task closes the socket
+            move || handle.abort()
+        });
+    }
+
+    // Draw again when the scrubber moves or the zoom changes, or an image on the board loads
+    {
+        let canvas_ref = canvas_ref.clone();
+        let received = received.clone();
+        let view = view.clone();
+        use_effect_with((*position, *zoom), move |&(position, zoom)| {
+            *view.borrow_mut() = (position, zoom);
+            render_at(&canvas_ref, &received.borrow(), position, zoom);
+            render::on_image_load(move || render_at(&canvas_ref, &received.borrow(), position, zoom));
+        });
+    }
+
+    let (from, to) = range.unwrap_or((0, 0));
+    let shown = position.unwrap_or(*latest);
+
+    let onscrub = {
+        let position = position.clone();
+        let latest = *latest;
+        Callback::from(move |e: InputEvent| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            let value = input.value().parse::<u64>().unwrap_or(latest);
+            // Past what has been received so far is the same as following
+            position.set((value < latest).then_some(value));
+        })
+    };
+
+    let onwheel = {
+        let zoom = zoom.clone();
+        Callback::from(move |e: yew::events::WheelEvent| {
+            e.prevent_default();
+            let factor = if e.delta_y() < 0.0 { 1.1 } else { 0.9 };
+            zoom.set((*zoom * factor).clamp(0.2, 5.0));
+        })
+    };
+
+    let room = room_name();
+
+    html! {
+        <div class="app">
+            <style>
+                {include_str!("../styles.css")}
+            </style>
+            <div class="toolbar">
+                <div class="toolbar-section">
+                    <h3 class="toolbar-title">{"⏪ Replay"}</h3>
+                    <div class="connection-status" data-status={status.to_string()}>
+                        {match *status {
+                            "connecting" => "🟡 Connecting...",
+                            "playing" => "▶️ Playing",
+                            "finished" => "⏹️ Finished",
+                            "disconnected" => "🔴 Disconnected",
+                            _ => "🔴 Failed to connect",
+                        }}
+                    </div>
+                    <a class="room-badge" href={format!("?room={}", js_sys::encode_uri_component(&room))}>{format!("Back to {}", room)}</a>
+                </div>
+
+                <div class="toolbar-section">
+                    <label class="toolbar-label">{"Speed"}</label>
+                    <div class="tool-buttons">
+                        {for SPEEDS.iter().map(|choice| {
+                            let speed = speed.clone();
+                            let choice = *choice;
+                            html! {
+                                <button
+                                    class={classes!("tool-btn", (*speed == choice).then_some("active"))}
+                                    onclick={move |_| speed.set(choice)}
+                                >
+                                    <span>{format!("{}×", choice)}</span>
+                                </button>
+                            }
+                        })}
+                    </div>
+                </div>
+
+                <div class="toolbar-section">
+                    <label class="toolbar-label">{"Timeline"}</label>
+                    <div class="scrubber">
+                        <input
+                            type="range"
+                            min={from.to_string()}
+                            max={to.max(from).to_string()}
+                            step="1"
+                            value={shown.to_string()}
+                            class="brush-slider scrubber-slider"
+                            disabled={range.is_none()}
+                            oninput={onscrub}
+                        />
+                        <span class="brush-value">{format!("{} / {}", shown, to)}</span>
+                    </div>
+                    <div class="tool-buttons">
+                        if position.is_some() {
+                            <button class="tool-btn" onclick={{
+                                let position = position.clone();
+                                move |_| position.set(None)
+                            }}>
+                                {"⏩"}
+                                <span>{"Follow"}</span>
+                            </button>
+                        }
+                        <button class="tool-btn" onclick={{
+                            let run = run.clone();
+                            move |_| run.set(*run + 1)
+                        }}>
+                            {"🔄"}
+                            <span>{"Restart"}</span>
+                        </button>
+                    </div>
+                </div>
+            </div>
+            <div class="canvas-container">
+                <canvas
+                    ref={canvas_ref}
+                    width={1200}
+                    height={800}
+                    class="whiteboard-canvas"
+                    onwheel={onwheel}
+                />
+            </div>
+        </div>
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+
+use crate::{backend_http_url, page_params};
+
+// A template as the backend lists it
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+struct Summary {
+    name: String,
+    description: String,
+    // Placeholders to ask for, besides the room's name
+    placeholders: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CreateBody<'a> {
+    template: &'a str,
+    values: &'a HashMap<String, String>,
+}
+
+// Open the board for `room`, keeping the other page parameters
+fn open_room(room: &str) {
+    let params = page_params();
+    params.set("room", room);
+    let _ = web_sys::window().unwrap().location().set_search(&String::from(params.to_string()));
+}
+
+// Fill `room` from `template` on the backend
+async fn create_room(room: &str, template: &str, values: &HashMap<String, String>) -> Result<(), String> {
+    let path = format!("/rooms/{}/template", String::from(js_sys::encode_uri_component(room)));
+    let response = gloo_net::http::Request::post(&backend_http_url(&path, &[]))
+        .json(&CreateBody { template, values })
+        .map_err(|e| e.to_string())?
+        .send()
+        .await
+        .map_err(|_| "the server could not be reached".to_string())?;
+    match response.status() {
+        201 => Ok(()),
+        409 => Err(format!("“{}” already has a board; pick another name", room)),
+        _ => Err(response.text().await.unwrap_or_else(|_| format!("error {}", response.status()))),
+    }
+}
+
+#[derive(Properties, PartialEq)]
+pub struct PickerProps {
+    pub on_close: Callback<()>,
+}
+
+// Dialog for starting a new board, blank or from one of the backend's templates
+#[function_component(TemplatePicker)]
+pub fn template_picker(props: &PickerProps) -> Html {
+    let templates = use_state(|| None::<Result<Vec<Summary>, String>>);
+    // Chosen template; `None` is a blank board
+    let chosen = use_state(|| None::<String>);
+    let room = use_state(String::new);
+    let values = use_state(HashMap::<String, String>::new);
+    let error = use_state(|| None::<String>);
+    let busy = use_state(|| false);
+
+    {
+        let templates = templates.clone();
+        use_effect_with((), move |_| {
+            spawn_local(async move {
+                let listed = match gloo_net::http::Request::get(&backend_http_url("/templates", &[])).send().await {
+                    Ok(response) if response.ok() => response.json::<Vec<Summary>>().await.map_err(|e| e.to_string()),
+                    Ok(response) => Err(format!("error {}", response.status())),
+                    Err(_) => Err("the server could not be reached".to_string()),
+                };
+                templates.set(Some(listed));
+            });
+        });
+    }
+
+    let placeholders = match (&*templates, &*chosen) {
+        (Some(Ok(list)), Some(name)) => list
+            .iter()
+            .find(|template| &template.name == name)
+            .map(|template| template.placeholders.clone())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+
+    let create = {
+        let chosen = chosen.clone();
+        let room = room.clone();
+        let values = values.clone();
+        let error = error.clone();
+        let busy = busy.clone();
+        Callback::from(move |_: MouseEvent| {
+            let name = room.trim().to_string();
+            if name.is_empty() || name.len() > 64 {
+                error.set(Some("Room names must be 1 to 64 bytes".to_string()));
+                return;
+            }
+            let Some(template) = (*chosen).clone() else {
+                open_room(&name);
+                return;
+            };
+            let values = (*values).clone();
+            let error = error.clone();
+            let busy = busy.clone();
+            busy.set(true);
+            spawn_local(async move {
+                match create_room(&name, &template, &values).await {
+                    Ok(()) => open_room(&name),
+                    Err(reason) => {
+                        error.set(Some(reason));
+                        busy.set(false);
+                    }
+                }
+            });
+        })
+    };
+
+    let option = |name: Option<String>, title: String, description: String| {
+        let chosen_handle = chosen.clone();
+        let values = values.clone();
+        let active = *chosen == name;
+        html! {
+            <button
+                class={classes!("template-option", active.then_some("active"))}
+                onclick={move |_| {
+                    values.set(HashMap::new());
+                    chosen_handle.set(name.clone());
+                }}
+            >
+                <span class="template-name">{title}</span>
+                <span class="template-description">{description}</span>
+            </button>
+        }
+    };
+
+    html! {
+        <div class="dialog-backdrop">
+            <div class="dialog">
+                <h2 class="dialog-title">{"New board"}</h2>
+                <input
+                    class="dialog-input"
+                    type="text"
+                    placeholder="Room name"
+                    value={(*room).clone()}
+                    oninput={{
+                        let room = room.clone();
+                        Callback::from(move |e: InputEvent| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            room.set(input.value());
+                        })
+                    }}
+                />
+                <div class="template-options">
+                    {option(None, "Blank".to_string(), "Start from an empty board".to_string())}
+                    {match &*templates {
+                        None => html! { <p class="template-description">{"Loading templates…"}</p> },
+                        Some(Err(reason)) => html! { <p class="template-description">{format!("Templates unavailable: {}", reason)}</p> },
+                        Some(Ok(list)) => html! {
+                            {for list.iter().map(|template| option(Some(template.name.clone()), template.name.clone(), template.description.clone()))}
+                        },
+                    }}
+                </div>
+                {for placeholders.into_iter().map(|placeholder| {
+                    let values = values.clone();
+                    let key = placeholder.clone();
+                    html! {
+                        <input
+                            class="dialog-input"
+                            type="text"
+                            placeholder={placeholder.clone()}
+                            value={values.get(&placeholder).cloned().unwrap_or_default()}
+                            oninput={Callback::from(move |e: InputEvent| {
+                                let input: HtmlInputElement = e.target_unchecked_into();
+                                let mut filled = (*values).clone();
+                                filled.insert(key.clone(), input.value());
+                                values.set(filled);
+                            })}
+                        />
+                    }
+                })}
+                if let Some(reason) = &*error {
+                    <div class="notice-badge">{reason}</div>
+                }
+                <div class="dialog-actions">
+                    <button class="participant-btn" onclick={props.on_close.reform(|_| ())}>{"Cancel"}</button>
+                    <button class="tool-btn active" disabled={*busy} onclick={create}>{"Create board"}</button>
+                </div>
+            </div>
+        </div>
+    }
+}